@@ -0,0 +1,161 @@
+//! Measures the latency and throughput of reading documents over a loopback connection,
+//! with and without frame compression.
+//!
+//! Run with `cargo run --release -p liserk-client --example compression_bench`.
+//!
+//! A peer answers every `GetById` query with a `SingleValueResponse`, framed as the
+//! server frames it, so no database is needed. Each profile reads a document of a fixed
+//! size the same number of times in every compression mode, after a few unmeasured round
+//! trips, so the numbers of the modes are comparable. Documents are random bytes, as
+//! incompressible as the ciphertexts the server stores: what compression saves is the
+//! overhead of their CBOR encoding, at the cost of compressing every frame.
+
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use liserk_shared::{
+    compression::{Compression, FrameError},
+    message::{FrameHeader, Message},
+    query::Query,
+};
+use rand::RngCore;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A document size and the number of measured reads of it.
+struct Profile {
+    name: &'static str,
+    size: usize,
+    round_trips: usize,
+}
+
+const PROFILES: [Profile; 3] = [
+    Profile { name: "small", size: 512, round_trips: 2000 },
+    Profile { name: "medium", size: 64 * 1024, round_trips: 200 },
+    Profile {
+        name: "large",
+        size: 4 * 1024 * 1024,
+        round_trips: 10,
+    },
+];
+
+const MODES: [Compression; 2] = [Compression::None, Compression::Zstd];
+
+/// Round trips made before measuring, to warm the connection and the allocator up.
+const WARM_UP: usize = 3;
+
+/// What one profile measured in one compression mode.
+struct Measure {
+    wire_bytes: usize,
+    median: Duration,
+    p99: Duration,
+    elapsed: Duration,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    println!(
+        "{:<8} {:>10} {:>6} {:>12} {:>12} {:>12} {:>12}",
+        "profile", "size", "mode", "wire/read", "median", "p99", "MiB/s"
+    );
+    for profile in &PROFILES {
+        let mut document = vec![0; profile.size];
+        rand::thread_rng().fill_bytes(&mut document);
+        for compression in MODES {
+            let measure = measure(profile, &document, compression).await?;
+            let read = (profile.size * profile.round_trips) as f64 / (1024.0 * 1024.0);
+            println!(
+                "{:<8} {:>10} {:>6} {:>12} {:>12.2?} {:>12.2?} {:>12.1}",
+                profile.name,
+                profile.size,
+                format!("{:?}", compression),
+                measure.wire_bytes / profile.round_trips,
+                measure.median,
+                measure.p99,
+                read / measure.elapsed.as_secs_f64(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads the document of a profile from a loopback peer, one query at a time.
+async fn measure(
+    profile: &Profile,
+    document: &[u8],
+    compression: Compression,
+) -> Result<Measure, Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let response = Message::SingleValueResponse {
+        data: Some(document.to_vec()),
+        nonce: Some(vec![0; 12]),
+    };
+    let peer = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await?;
+        socket.set_nodelay(true)?;
+        while let Some((request_id, _)) = read_message(&mut socket, compression).await? {
+            let frame = response.setup_for_network_as(request_id, compression)?;
+            socket.write_all(&frame).await?;
+        }
+        Ok::<_, FrameError>(())
+    });
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    let query = Query::GetById {
+        id: "1".to_string(),
+        collection: "users".to_string(),
+    };
+    let request = Message::Query(query);
+    let mut latencies = Vec::with_capacity(profile.round_trips);
+    let mut wire_bytes = 0;
+    let mut elapsed = Duration::ZERO;
+    for request_id in 0..(WARM_UP + profile.round_trips) as u32 {
+        let start = Instant::now();
+        let frame = request.setup_for_network_as(request_id, compression)?;
+        stream.write_all(&frame).await?;
+        let (_, length) = read_message(&mut stream, compression)
+            .await?
+            .ok_or("the peer closed the connection")?;
+        let latency = start.elapsed();
+        if request_id as usize >= WARM_UP {
+            latencies.push(latency);
+            wire_bytes += frame.len() + FrameHeader::LEN + length;
+            elapsed += latency;
+        }
+    }
+    drop(stream);
+    peer.await??;
+
+    latencies.sort_unstable();
+    let percentile = |percent: usize| latencies[(latencies.len() - 1) * percent / 100];
+    Ok(Measure {
+        wire_bytes,
+        median: percentile(50),
+        p99: percentile(99),
+        elapsed,
+    })
+}
+
+/// Reads and decodes a frame, returning its request id and the length of its body, or
+/// `None` once the connection is closed.
+async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    compression: Compression,
+) -> Result<Option<(u32, usize)>, FrameError> {
+    let mut header = [0; FrameHeader::LEN];
+    match stream.read_exact(&mut header).await {
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    };
+    let header = FrameHeader::from_bytes(&header);
+    let mut body = vec![0; header.length as usize];
+    stream.read_exact(&mut body).await?;
+    Message::from_network_body(body, compression)?;
+    Ok(Some((header.request_id, header.length as usize)))
+}
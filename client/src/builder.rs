@@ -0,0 +1,313 @@
+//! Configuration of the clients, see `ClientBuilder`.
+
+use std::time::Duration;
+
+use liserk_shared::{auth::AuthMechanism, compression::Compression, format::Format};
+
+use crate::stream::UnconnectedClient;
+
+/// Capacity, in bytes, of the buffer frames are read through when none is configured.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Options applied to the connections of a client.
+///
+/// `ClientOptions::default()` waits without limit, does not compress, serializes frames
+/// in CBOR, reads them through a `DEFAULT_READ_BUFFER_SIZE` buffer, writes them without
+/// buffering, leaves the socket buffers to the system, sets `TCP_NODELAY` without
+/// keepalive probes, connects without TLS, caches no document and fully jitters its
+/// reconnection delays.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Maximum time to open the TCP connection and complete the setup.
+    pub connect_timeout: Option<Duration>,
+
+    /// Maximum time to wait for the response to a request once connected.
+    pub request_timeout: Option<Duration>,
+
+    /// Compressions proposed to the server, in order of preference.
+    pub compression: Vec<Compression>,
+
+    /// Serialization formats proposed to the server, in order of preference.
+    pub format: Vec<Format>,
+
+    /// Authentication mechanisms proposed to the server, in order of preference.
+    pub auth_mechanisms: Vec<AuthMechanism>,
+
+    /// Capacity, in bytes, of the buffer frames are read through.
+    pub read_buffer_size: Option<usize>,
+
+    /// Size, in bytes, of the send and receive buffers of the TCP socket.
+    pub socket_buffer_size: Option<u32>,
+
+    /// Whether `TCP_NODELAY` is set on the TCP socket, unless set to `false`.
+    pub nodelay: Option<bool>,
+
+    /// Keepalive probes of the TCP socket, none when unset.
+    pub keepalive: Option<Keepalive>,
+
+    /// TLS wrapping the TCP connections, none when unset.
+    pub tls: Option<Tls>,
+
+    /// Capacity, in bytes, of the buffer requests are written through once authenticated,
+    /// see `AuthenticatedClient::flush`.
+    pub write_buffer_size: Option<usize>,
+
+    /// Maximum number of decrypted documents kept by `AuthenticatedClient::get_document`.
+    pub document_cache_capacity: Option<usize>,
+
+    /// Context, such as a tenant or session identifier, bound to every document encrypted
+    /// or decrypted by the connections, see `liserk_client::bind_context`.
+    pub encryption_context: Option<Vec<u8>>,
+
+    /// Tenant the connections are scoped to on the server, see `ClientBuilder::tenant`.
+    pub tenant: Option<String>,
+
+    /// Randomization of the delays waited before reconnecting, see `ReconnectJitter`.
+    pub reconnect_jitter: ReconnectJitter,
+}
+
+impl ClientOptions {
+    /// Returns the capacity of the buffer frames are read through.
+    pub fn read_buffer_capacity(&self) -> usize {
+        self.read_buffer_size.unwrap_or(DEFAULT_READ_BUFFER_SIZE).max(1)
+    }
+
+    /// Returns whether `TCP_NODELAY` is set on the TCP socket.
+    pub fn nodelay(&self) -> bool {
+        self.nodelay.unwrap_or(true)
+    }
+}
+
+/// How the delays waited before reconnecting are randomized, so clients losing their
+/// connections together, to a server restart for instance, do not all reconnect at once.
+///
+/// Each delay is drawn afresh from the backoff reached by the attempt, see
+/// `liserk_client::stream::RECONNECT_BACKOFF`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReconnectJitter {
+    /// The backoff is waited as is, every client retrying at the same times.
+    None,
+
+    /// A random delay between zero and the backoff, spreading the attempts the most.
+    #[default]
+    Full,
+
+    /// Half the backoff, plus a random delay up to the other half.
+    Equal,
+}
+
+/// TCP keepalive probes of a connection, see `SO_KEEPALIVE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time the connection stays idle before the first probe.
+    pub idle: Duration,
+
+    /// Time between two probes left unanswered.
+    pub interval: Duration,
+}
+
+/// TLS of the TCP connections, see `ClientBuilder::tls`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tls {
+    /// Name the certificate of the server must be issued for.
+    pub server_name: String,
+
+    /// DER encoded certificates of the authorities trusted to issue the certificate of
+    /// the server.
+    pub root_certificates: Vec<Vec<u8>>,
+}
+
+/// Builder accumulating the options of an `UnconnectedClient`.
+///
+/// # Example
+///
+/// ```
+/// # use std::time::Duration;
+/// # use liserk_client::builder::ClientBuilder;
+/// # use liserk_shared::compression::Compression;
+/// let client = ClientBuilder::new()
+///     .connect_timeout(Duration::from_secs(2))
+///     .request_timeout(Duration::from_secs(10))
+///     .compression(vec![Compression::Zstd])
+///     .build();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientBuilder {
+    options: ClientOptions,
+}
+
+impl ClientBuilder {
+    /// Creates a builder with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.options.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn compression(mut self, compression: Vec<Compression>) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn format(mut self, format: Vec<Format>) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    pub fn auth_mechanisms(mut self, auth_mechanisms: Vec<AuthMechanism>) -> Self {
+        self.options.auth_mechanisms = auth_mechanisms;
+        self
+    }
+
+    /// Reads frames through a buffer of `size` bytes, fewer reads of the socket being
+    /// needed for the responses of high-throughput workloads with a larger one.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.options.read_buffer_size = Some(size);
+        self
+    }
+
+    /// Asks the system for TCP send and receive buffers of `size` bytes, which it may
+    /// round or cap, see `SO_SNDBUF` and `SO_RCVBUF`.
+    pub fn socket_buffer_size(mut self, size: u32) -> Self {
+        self.options.socket_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on the socket, the default, so small requests are sent at once
+    /// instead of being delayed by Nagle's algorithm. `false` trades latency for fewer
+    /// packets.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.options.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Probes the connection once idle for `idle`, then every `interval` while the
+    /// probes are left unanswered, so a dead peer is noticed by the system.
+    pub fn keepalive(mut self, idle: Duration, interval: Duration) -> Self {
+        self.options.keepalive = Some(Keepalive { idle, interval });
+        self
+    }
+
+    /// Runs the TCP connections over TLS, checking that the certificate of the server is
+    /// issued for `server_name` by one of the DER encoded `root_certificates`.
+    /// Connections over a Unix domain socket are not wrapped.
+    pub fn tls(
+        mut self,
+        server_name: impl Into<String>,
+        root_certificates: Vec<Vec<u8>>,
+    ) -> Self {
+        self.options.tls =
+            Some(Tls { server_name: server_name.into(), root_certificates });
+        self
+    }
+
+    /// Holds the frames of the requests in a buffer of `size` bytes, written out once
+    /// full, before waiting for a response, on `AuthenticatedClient::flush` and on close.
+    /// Requests not answered by the server, like `close_cursor`, are batched meanwhile.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.options.write_buffer_size = Some(size);
+        self
+    }
+
+    /// Keeps up to `capacity` decrypted documents read by `get_document` in memory, see
+    /// `liserk_client::cache`.
+    pub fn document_cache(mut self, capacity: usize) -> Self {
+        self.options.document_cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Binds the documents of the connections to `context`, a tenant or session
+    /// identifier: documents encrypted under a context only decrypt under the same one.
+    /// Documents encrypted without context only decrypt without one.
+    pub fn encryption_context<C: Into<Vec<u8>>>(mut self, context: C) -> Self {
+        self.options.encryption_context = Some(context.into());
+        self
+    }
+
+    /// Scopes the connections to `tenant`: the server keeps the collections of a tenant
+    /// apart from those of the other tenants and of the connections without tenant, even
+    /// under the same names.
+    pub fn tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.options.tenant = Some(tenant.into());
+        self
+    }
+
+    /// Randomizes the delays waited before reconnecting with `jitter`, fully by default.
+    pub fn reconnect_jitter(mut self, jitter: ReconnectJitter) -> Self {
+        self.options.reconnect_jitter = jitter;
+        self
+    }
+
+    pub fn build(self) -> UnconnectedClient {
+        UnconnectedClient::with_options(self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fully_configured_client() {
+        let client = ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(2))
+            .request_timeout(Duration::from_secs(10))
+            .compression(vec![Compression::Zstd, Compression::None])
+            .format(vec![Format::Json])
+            .auth_mechanisms(vec![AuthMechanism::ChallengeResponse])
+            .read_buffer_size(1024)
+            .socket_buffer_size(256 * 1024)
+            .nodelay(false)
+            .keepalive(Duration::from_secs(30), Duration::from_secs(5))
+            .tls("db.example.com", vec![vec![1, 2, 3]])
+            .write_buffer_size(16 * 1024)
+            .document_cache(100)
+            .encryption_context("tenant-a")
+            .tenant("acme")
+            .reconnect_jitter(ReconnectJitter::Equal)
+            .build();
+
+        let options = client.options();
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(options.request_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(options.compression, vec![Compression::Zstd, Compression::None]);
+        assert_eq!(options.format, vec![Format::Json]);
+        assert_eq!(options.auth_mechanisms, vec![AuthMechanism::ChallengeResponse]);
+        assert_eq!(options.read_buffer_capacity(), 1024);
+        assert_eq!(options.socket_buffer_size, Some(256 * 1024));
+        assert!(!options.nodelay());
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(5),
+        };
+        assert_eq!(options.keepalive, Some(keepalive));
+        let tls = Tls {
+            server_name: "db.example.com".to_string(),
+            root_certificates: vec![vec![1, 2, 3]],
+        };
+        assert_eq!(options.tls, Some(tls));
+        assert_eq!(options.write_buffer_size, Some(16 * 1024));
+        assert_eq!(options.document_cache_capacity, Some(100));
+        assert_eq!(options.encryption_context.as_deref(), Some(&b"tenant-a"[..]));
+        assert_eq!(options.tenant.as_deref(), Some("acme"));
+        assert_eq!(options.reconnect_jitter, ReconnectJitter::Equal);
+    }
+
+    #[test]
+    fn test_default_client_has_default_options() {
+        assert_eq!(UnconnectedClient::default().options(), &ClientOptions::default());
+        assert_eq!(ClientBuilder::new().build().options(), &ClientOptions::default());
+        let options = ClientOptions::default();
+        assert_eq!(options.read_buffer_capacity(), DEFAULT_READ_BUFFER_SIZE);
+        assert!(options.nodelay());
+        assert_eq!(options.reconnect_jitter, ReconnectJitter::Full);
+    }
+}
@@ -0,0 +1,159 @@
+//! Bounded cache of decrypted documents, see `ClientBuilder::document_cache`.
+//!
+//! `AuthenticatedClient::get_document` answers from the cache when it can, saving the
+//! round trip and the decryption. Documents are keyed by their collection and id, the
+//! least recently used one being evicted once the capacity is reached. The client drops
+//! a document from the cache whenever it updates or deletes it, but a document changed
+//! by another client is served stale until it is evicted. The plaintext of a document is
+//! zeroized when it leaves the cache, whether evicted, invalidated or dropped.
+
+use std::collections::{BTreeMap, HashMap};
+
+use zeroize::Zeroize;
+
+use crate::stream::Document;
+
+/// A cached document with the tick it was last used at.
+#[derive(Debug)]
+struct Entry {
+    document: Document,
+    used_at: u64,
+}
+
+/// Least recently used cache of decrypted documents, caching nothing with no capacity.
+#[derive(Debug)]
+pub struct DocumentCache {
+    capacity: usize,
+    entries: HashMap<(String, String), Entry>,
+
+    /// Keys of the entries by the tick they were last used at, the oldest first.
+    recency: BTreeMap<u64, (String, String)>,
+    tick: u64,
+}
+
+impl DocumentCache {
+    /// Creates a cache holding at most `capacity` documents.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the number of cached documents.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether no document is cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the cached document, marking it as the most recently used.
+    pub fn get(&mut self, collection: &str, id: &str) -> Option<Document> {
+        let key = (collection.to_string(), id.to_string());
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(&key)?;
+        self.recency.remove(&entry.used_at);
+        entry.used_at = tick;
+        self.recency.insert(tick, key);
+        Some(entry.document.clone())
+    }
+
+    /// Caches a document, evicting the least recently used one if the cache is full.
+    pub fn insert(&mut self, document: Document) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&document.collection, &document.id);
+        while self.entries.len() >= self.capacity {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                zeroize_entry(entry);
+            }
+        }
+        let key = (document.collection.clone(), document.id.clone());
+        let used_at = self.next_tick();
+        self.recency.insert(used_at, key.clone());
+        self.entries.insert(key, Entry { document, used_at });
+    }
+
+    /// Drops a document from the cache, after it was updated or deleted.
+    pub fn remove(&mut self, collection: &str, id: &str) {
+        let key = (collection.to_string(), id.to_string());
+        if let Some(entry) = self.entries.remove(&key) {
+            self.recency.remove(&entry.used_at);
+            zeroize_entry(entry);
+        }
+    }
+
+    /// Drops every cached document.
+    pub fn clear(&mut self) {
+        self.recency.clear();
+        for (_, entry) in self.entries.drain() {
+            zeroize_entry(entry);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl Drop for DocumentCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Overwrites the plaintext of an entry leaving the cache.
+fn zeroize_entry(mut entry: Entry) {
+    entry.document.data.zeroize();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(id: &str) -> Document {
+        Document {
+            collection: "users".to_string(),
+            id: id.to_string(),
+            data: id.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_least_recently_used_document_is_evicted() {
+        let mut cache = DocumentCache::new(2);
+        cache.insert(document("1"));
+        cache.insert(document("2"));
+        assert_eq!(cache.get("users", "1"), Some(document("1")));
+
+        cache.insert(document("3"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("users", "2"), None);
+        assert_eq!(cache.get("users", "1"), Some(document("1")));
+        assert_eq!(cache.get("users", "3"), Some(document("3")));
+    }
+
+    #[test]
+    fn test_removed_and_uncached_documents_are_missing() {
+        let mut cache = DocumentCache::new(2);
+        cache.insert(document("1"));
+        cache.insert(document("1"));
+        assert_eq!(cache.len(), 1);
+        cache.remove("users", "1");
+        assert!(cache.is_empty());
+
+        let mut disabled = DocumentCache::new(0);
+        disabled.insert(document("1"));
+        assert_eq!(disabled.get("users", "1"), None);
+    }
+}
@@ -0,0 +1,407 @@
+//! Chunked encryption for payloads too large to be handled in a single buffer.
+//!
+//! A stream is split in chunks of at most `chunk_size` bytes, each encrypted on its own with
+//! AES-GCM-SIV. An encrypted chunk is a flag byte, `1` for the last chunk and `0` otherwise,
+//! followed by the ciphertext. The nonce of a chunk is the stream nonce with its last four
+//! bytes xored with the big endian chunk index, and the associated data of a chunk is the
+//! stream associated data followed by the big endian chunk index and the flag byte.
+//! Reordering, dropping, or truncating chunks therefore fails authentication.
+
+use std::io::Read;
+
+use crate::{
+    basic_decrypt, basic_encrypt,
+    error::{AesError, Error},
+    keys::EncKey,
+};
+
+/// Default size of the plaintext carried by a chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+const LAST_CHUNK: u8 = 1;
+const INNER_CHUNK: u8 = 0;
+
+/// How `ChunkDecryptor` releases plaintext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamVerifyMode {
+    /// Authenticates every chunk of the stream before yielding any plaintext.
+    ///
+    /// No plaintext is released unless the whole stream is authentic and complete,
+    /// at the cost of holding the whole plaintext in memory.
+    BufferAndVerify,
+
+    /// Yields each chunk as soon as it is authenticated.
+    ///
+    /// Memory stays bounded by the chunk size, but the caller may already have consumed
+    /// the beginning of a stream whose later chunks turn out to be tampered or missing.
+    /// Only use it when acting on a prefix of the data is acceptable.
+    PerChunk,
+}
+
+fn chunk_nonce(nonce: &[u8; 12], index: u32) -> [u8; 12] {
+    let mut chunk_nonce = *nonce;
+    for (byte, counter) in chunk_nonce[8..].iter_mut().zip(index.to_be_bytes()) {
+        *byte ^= counter;
+    }
+    chunk_nonce
+}
+
+fn chunk_associated_data(associated_data: &[u8], index: u32, flag: u8) -> Vec<u8> {
+    [associated_data, &index.to_be_bytes(), &[flag]].concat()
+}
+
+fn read_chunk<R: Read>(reader: &mut R, chunk_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(chunk_size);
+    reader.by_ref().take(chunk_size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Iterator encrypting the content of a reader chunk by chunk.
+#[derive(Debug)]
+pub struct ChunkEncryptor<R> {
+    key: EncKey,
+    nonce: [u8; 12],
+    associated_data: Vec<u8>,
+    reader: R,
+    chunk_size: usize,
+    index: u32,
+    lookahead: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl<R: Read> ChunkEncryptor<R> {
+    /// Creates an encryptor reading plaintext from `reader`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A reference to the 256-bit key for encryption.
+    /// * `nonce` - A reference to the 12-byte nonce of the stream.
+    /// * `reader` - The source of the plaintext.
+    /// * `associated_data` - The associated data bound to every chunk.
+    /// * `chunk_size` - The maximum number of plaintext bytes per chunk.
+    pub fn new(
+        key: &EncKey,
+        nonce: &[u8; 12],
+        reader: R,
+        associated_data: &[u8],
+        chunk_size: usize,
+    ) -> Self {
+        Self {
+            key: key.clone(),
+            nonce: *nonce,
+            associated_data: associated_data.to_vec(),
+            reader,
+            chunk_size: chunk_size.max(1),
+            index: 0,
+            lookahead: None,
+            finished: false,
+        }
+    }
+
+    fn encrypt_next(&mut self) -> Result<Vec<u8>, Error> {
+        let current = match self.lookahead.take() {
+            Some(chunk) => chunk,
+            None => read_chunk(&mut self.reader, self.chunk_size)?,
+        };
+        let following = read_chunk(&mut self.reader, self.chunk_size)?;
+        let flag = if following.is_empty() { LAST_CHUNK } else { INNER_CHUNK };
+        if flag == INNER_CHUNK {
+            self.lookahead = Some(following);
+        }
+
+        let nonce = chunk_nonce(&self.nonce, self.index);
+        let associated_data =
+            chunk_associated_data(&self.associated_data, self.index, flag);
+        let ciphertext = basic_encrypt(&self.key, &nonce, &current, &associated_data)?;
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or(Error::encryption(AesError::Encrypt))?;
+        if flag == LAST_CHUNK {
+            self.finished = true;
+        }
+        Ok([&[flag][..], &ciphertext].concat())
+    }
+}
+
+impl<R: Read> Iterator for ChunkEncryptor<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let chunk = self.encrypt_next();
+        if chunk.is_err() {
+            self.finished = true;
+        }
+        Some(chunk)
+    }
+}
+
+/// Decrypts the chunks produced by `ChunkEncryptor` one at a time, as they arrive.
+///
+/// Each chunk is authenticated on its own, so the caller is responsible for not acting
+/// on the plaintext of a stream before `finish` succeeds, see `StreamVerifyMode`.
+#[derive(Debug, Clone)]
+pub struct ChunkOpener {
+    key: EncKey,
+    nonce: [u8; 12],
+    associated_data: Vec<u8>,
+    index: u32,
+    seen_last: bool,
+}
+
+impl ChunkOpener {
+    /// Creates an opener expecting the first chunk of a stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A reference to the 256-bit key for decryption.
+    /// * `nonce` - A reference to the 12-byte nonce of the stream.
+    /// * `associated_data` - The associated data bound to every chunk.
+    pub fn new(key: &EncKey, nonce: &[u8; 12], associated_data: &[u8]) -> Self {
+        Self {
+            key: key.clone(),
+            nonce: *nonce,
+            associated_data: associated_data.to_vec(),
+            index: 0,
+            seen_last: false,
+        }
+    }
+
+    /// Authenticates and decrypts the next chunk of the stream.
+    ///
+    /// A chunk following the last one is refused.
+    pub fn open(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.seen_last {
+            return Err(Error::encryption(AesError::Decrypt));
+        }
+        let Some((&flag, ciphertext)) = chunk.split_first() else {
+            return Err(Error::encryption(AesError::Decrypt));
+        };
+
+        let nonce = chunk_nonce(&self.nonce, self.index);
+        let associated_data =
+            chunk_associated_data(&self.associated_data, self.index, flag);
+        let plaintext = basic_decrypt(&self.key, &nonce, ciphertext, &associated_data)?;
+        self.seen_last = flag == LAST_CHUNK;
+        self.index = self.index.wrapping_add(1);
+        Ok(plaintext)
+    }
+
+    /// Returns whether the last chunk of the stream has been opened.
+    pub fn is_complete(&self) -> bool {
+        self.seen_last
+    }
+
+    /// Checks that the stream ended with its last chunk, and was not truncated.
+    pub fn finish(&self) -> Result<(), Error> {
+        if !self.seen_last {
+            return Err(Error::encryption(AesError::TruncatedStream));
+        }
+        Ok(())
+    }
+}
+
+/// Iterator decrypting chunks produced by `ChunkEncryptor`.
+///
+/// Once an error has been yielded the iterator is exhausted.
+#[derive(Debug)]
+pub struct ChunkDecryptor<I> {
+    opener: ChunkOpener,
+    chunks: I,
+    mode: StreamVerifyMode,
+    verified: Option<std::vec::IntoIter<Vec<u8>>>,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> ChunkDecryptor<I> {
+    /// Creates a decryptor over encrypted chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A reference to the 256-bit key for decryption.
+    /// * `nonce` - A reference to the 12-byte nonce of the stream.
+    /// * `chunks` - The encrypted chunks, in order.
+    /// * `associated_data` - The associated data bound to every chunk.
+    /// * `mode` - Whether plaintext is released per chunk or once the whole stream is verified.
+    pub fn new<C: IntoIterator<IntoIter = I>>(
+        key: &EncKey,
+        nonce: &[u8; 12],
+        chunks: C,
+        associated_data: &[u8],
+        mode: StreamVerifyMode,
+    ) -> Self {
+        Self {
+            opener: ChunkOpener::new(key, nonce, associated_data),
+            chunks: chunks.into_iter(),
+            mode,
+            verified: None,
+            finished: false,
+        }
+    }
+
+    fn decrypt_next(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        let Some(chunk) = self.chunks.next() else {
+            return self.opener.finish().err().map(Err);
+        };
+        Some(self.opener.open(&chunk))
+    }
+
+    fn verify_all(&mut self) -> Result<std::vec::IntoIter<Vec<u8>>, Error> {
+        let mut plaintexts = Vec::new();
+        while let Some(plaintext) = self.decrypt_next() {
+            plaintexts.push(plaintext?);
+        }
+        Ok(plaintexts.into_iter())
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Iterator for ChunkDecryptor<I> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let item = match self.mode {
+            StreamVerifyMode::PerChunk => self.decrypt_next(),
+            StreamVerifyMode::BufferAndVerify => {
+                if self.verified.is_none() {
+                    match self.verify_all() {
+                        Ok(verified) => self.verified = Some(verified),
+                        Err(err) => {
+                            self.finished = true;
+                            return Some(Err(err));
+                        }
+                    }
+                }
+                self.verified.as_mut().and_then(|verified| verified.next()).map(Ok)
+            }
+        };
+        if !matches!(item, Some(Ok(_))) {
+            self.finished = true;
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> EncKey {
+        EncKey::from_bytes([9; 32])
+    }
+    const NONCE: [u8; 12] = [4; 12];
+
+    fn encrypt(plaintext: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+        ChunkEncryptor::new(&key(), &NONCE, plaintext, b"aad", chunk_size)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_chunk_index_is_big_endian() {
+        let nonce = chunk_nonce(&[0xff; 12], 0x0102_0304);
+        assert_eq!(
+            nonce,
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0xfd, 0xfc, 0xfb]
+        );
+        assert_eq!(
+            chunk_associated_data(b"aad", 258, LAST_CHUNK),
+            b"aad\0\0\x01\x02\x01"
+        );
+    }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let plaintext: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let chunks = encrypt(&plaintext, 64);
+        assert_eq!(chunks.len(), 16);
+
+        for mode in [StreamVerifyMode::BufferAndVerify, StreamVerifyMode::PerChunk] {
+            let decrypted: Vec<u8> =
+                ChunkDecryptor::new(&key(), &NONCE, chunks.clone(), b"aad", mode)
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap()
+                    .concat();
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_empty_stream_round_trip() {
+        let chunks = encrypt(&[], 64);
+        assert_eq!(chunks.len(), 1);
+        let decrypted: Vec<Vec<u8>> = ChunkDecryptor::new(
+            &key(),
+            &NONCE,
+            chunks,
+            b"aad",
+            StreamVerifyMode::BufferAndVerify,
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(decrypted, vec![Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn test_tampered_middle_chunk_releases_nothing_when_buffering() {
+        let mut chunks = encrypt(&[1; 300], 100);
+        chunks[1][10] ^= 1;
+
+        let mut decryptor = ChunkDecryptor::new(
+            &key(),
+            &NONCE,
+            chunks,
+            b"aad",
+            StreamVerifyMode::BufferAndVerify,
+        );
+        assert!(decryptor.next().unwrap().is_err());
+        assert!(decryptor.next().is_none());
+    }
+
+    #[test]
+    fn test_tampered_middle_chunk_is_caught_per_chunk() {
+        let mut chunks = encrypt(&[1; 300], 100);
+        chunks[1][10] ^= 1;
+
+        let mut decryptor = ChunkDecryptor::new(
+            &key(),
+            &NONCE,
+            chunks,
+            b"aad",
+            StreamVerifyMode::PerChunk,
+        );
+        assert_eq!(decryptor.next().unwrap().unwrap(), vec![1; 100]);
+        assert!(decryptor.next().unwrap().is_err());
+        assert!(decryptor.next().is_none());
+    }
+
+    #[test]
+    fn test_opener_refuses_chunks_after_the_last_one() {
+        let chunks = encrypt(&[1; 150], 100);
+        let mut opener = ChunkOpener::new(&key(), &NONCE, b"aad");
+        assert_eq!(opener.open(&chunks[0]).unwrap(), vec![1; 100]);
+        assert!(opener.finish().is_err());
+        assert_eq!(opener.open(&chunks[1]).unwrap(), vec![1; 50]);
+        assert!(opener.is_complete());
+        opener.finish().unwrap();
+        assert!(opener.open(&chunks[1]).is_err());
+    }
+
+    #[test]
+    fn test_truncated_stream_is_caught() {
+        let mut chunks = encrypt(&[1; 300], 100);
+        chunks.pop();
+
+        for mode in [StreamVerifyMode::BufferAndVerify, StreamVerifyMode::PerChunk] {
+            let result: Result<Vec<_>, _> =
+                ChunkDecryptor::new(&key(), &NONCE, chunks.clone(), b"aad", mode)
+                    .collect();
+            assert!(result.is_err());
+        }
+    }
+}
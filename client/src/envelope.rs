@@ -0,0 +1,224 @@
+//! Self-contained encrypted payloads.
+//!
+//! An envelope is the 12-byte nonce followed by the AES-GCM-SIV ciphertext, so it can be
+//! decrypted with the key alone.
+//!
+//! # Byte order
+//!
+//! Data encrypted on one platform must decrypt on any other, so every multi-byte integer
+//! of the encrypted formats of the client is big endian, network order, never the native
+//! order: the plaintext length of a padded envelope (`padding`), the chunk index mixed in
+//! the nonce and the associated data of a chunk (`chunked`), the chunk size of an
+//! encrypted file header (`file`) and the counter of counter nonces (`nonce`). The
+//! envelope itself holds no integer, its nonce and ciphertext are plain byte strings.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    basic_decrypt, basic_encrypt, deserialize,
+    error::{AesError, Error},
+    generate_nonce,
+    keys::EncKey,
+    padding::{pad, unpad, PaddingMode},
+    serialize,
+};
+
+/// Length of the nonce at the start of an envelope.
+pub const NONCE_LENGTH: usize = 12;
+
+/// Encrypts plaintext under a fresh random nonce and prepends the nonce.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for encryption.
+/// * `plaintext` - A reference to the data to be encrypted.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The envelope, or an error if encryption fails.
+pub fn seal(
+    key: &EncKey,
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let nonce = generate_nonce();
+    let ciphertext = basic_encrypt(key, &nonce, plaintext, associated_data)?;
+    Ok([&nonce[..], &ciphertext].concat())
+}
+
+/// Decrypts an envelope produced by `seal`.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for decryption.
+/// * `envelope` - The nonce followed by the ciphertext.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The decrypted data, or an error if the envelope is malformed or decryption fails.
+pub fn open(
+    key: &EncKey,
+    envelope: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if envelope.len() < NONCE_LENGTH {
+        return Err(Error::encryption(AesError::Decrypt));
+    }
+    let (nonce, ciphertext) = envelope.split_at(NONCE_LENGTH);
+    let nonce: &[u8; 12] = nonce.try_into().expect("split at the nonce length");
+    basic_decrypt(key, nonce, ciphertext, associated_data)
+}
+
+/// Pads plaintext according to the mode, then seals it, see `padding`.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for encryption.
+/// * `plaintext` - A reference to the data to be encrypted.
+/// * `associated_data` - A reference to the associated data.
+/// * `mode` - How far the plaintext is padded, must not be `PaddingMode::None`.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The envelope, or an error if padding or encryption fails.
+pub fn seal_padded(
+    key: &EncKey,
+    plaintext: &[u8],
+    associated_data: &[u8],
+    mode: PaddingMode,
+) -> Result<Vec<u8>, Error> {
+    if mode == PaddingMode::None {
+        return Err(Error::encryption(AesError::Padding));
+    }
+    seal(key, &pad(plaintext, mode)?, associated_data)
+}
+
+/// Opens an envelope produced by `seal_padded` and strips its padding.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for decryption.
+/// * `envelope` - The nonce followed by the ciphertext.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The decrypted data, or an error if the envelope or its padding is malformed.
+pub fn open_padded(
+    key: &EncKey,
+    envelope: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    unpad(&open(key, envelope, associated_data)?)
+}
+
+/// Types that can be encrypted into an envelope and decrypted back.
+///
+/// Implemented for every serializable type, which is serialized with `serialize` and
+/// sealed with a random nonce.
+///
+/// # Example
+///
+/// ```
+/// use liserk_client::{generate_key, keys::EncKey, Encryptable};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+/// }
+///
+/// let key = EncKey::from_bytes(generate_key());
+/// let user = User { name: "Bob".to_string() };
+/// let encrypted = user.encrypt(&key).unwrap();
+/// let user = User::decrypt(&encrypted, &key).unwrap();
+/// assert_eq!(user.name, "Bob");
+/// ```
+pub trait Encryptable: Sized {
+    /// Serializes and encrypts the value into an envelope.
+    fn encrypt(&self, key: &EncKey) -> Result<Vec<u8>, Error>;
+
+    /// Decrypts an envelope and deserializes the value it contains.
+    fn decrypt(bytes: &[u8], key: &EncKey) -> Result<Self, Error>;
+}
+
+impl<T: Serialize + DeserializeOwned> Encryptable for T {
+    fn encrypt(&self, key: &EncKey) -> Result<Vec<u8>, Error> {
+        let plaintext = serialize(self)?;
+        seal(key, &plaintext, &[])
+    }
+
+    fn decrypt(bytes: &[u8], key: &EncKey) -> Result<Self, Error> {
+        let plaintext = open(key, bytes, &[])?;
+        deserialize(&plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+        roles: Vec<String>,
+    }
+
+    fn bob() -> User {
+        User {
+            name: "Bob".to_string(),
+            age: 42,
+            roles: vec!["read".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_encryptable_round_trip() {
+        let key = EncKey::from_bytes([1; 32]);
+        let encrypted = bob().encrypt(&key).unwrap();
+        assert_eq!(User::decrypt(&encrypted, &key).unwrap(), bob());
+    }
+
+    #[test]
+    fn test_encryptable_uses_a_fresh_nonce() {
+        let key = EncKey::from_bytes([1; 32]);
+        assert_ne!(bob().encrypt(&key).unwrap(), bob().encrypt(&key).unwrap());
+    }
+
+    #[test]
+    fn test_padded_envelope_round_trip() {
+        let key = EncKey::from_bytes([1; 32]);
+        let short =
+            seal_padded(&key, b"no", b"aad", PaddingMode::FixedBlock(32)).unwrap();
+        let long =
+            seal_padded(&key, &[9; 20], b"aad", PaddingMode::FixedBlock(32)).unwrap();
+        assert_eq!(short.len(), long.len());
+        assert_eq!(open_padded(&key, &short, b"aad").unwrap(), b"no");
+        assert_eq!(open_padded(&key, &long, b"aad").unwrap(), vec![9; 20]);
+    }
+
+    #[test]
+    fn test_padded_length_is_big_endian() {
+        let padded = pad(b"ab", PaddingMode::FixedBlock(8)).unwrap();
+        assert_eq!(padded, [0, 0, 0, 2, b'a', b'b', 0, 0]);
+
+        // Read as little endian, the length would be 16777216 and the padding refused.
+        assert_eq!(unpad(&[0, 0, 0, 1, 7, 9]).unwrap(), [7]);
+        let mut long = vec![0, 0, 1, 0];
+        long.extend_from_slice(&[5; 256]);
+        assert_eq!(unpad(&long).unwrap(), vec![5; 256]);
+    }
+
+    #[test]
+    fn test_encryptable_with_wrong_key_fails() {
+        let (key, other_key) = (EncKey::from_bytes([1; 32]), EncKey::from_bytes([2; 32]));
+        let encrypted = bob().encrypt(&key).unwrap();
+        assert!(User::decrypt(&encrypted, &other_key).is_err());
+        assert!(User::decrypt(&encrypted[..4], &key).is_err());
+    }
+}
@@ -1,28 +1,274 @@
 use config::ConfigError;
-use liserk_shared::message_type::MessageTypeError;
+use liserk_shared::{
+    compression::FrameError,
+    message::{InsertionError, ServerError},
+    message_type::{MessageType, MessageTypeError},
+    name::InvalidName,
+    query::FieldTypeMismatch,
+};
+
+use crate::shamir::ShareError;
 
 /// Enum representing the possible errors that can be encountered by the client.
+///
+/// Every variant displays what failed along what it carries, and wraps the error causing
+/// it, if any, as its `source`, so the chain can be walked down to the root cause.
 #[derive(Debug, thiserror::Error)]
-#[error("...")]
 pub enum Error {
     /// Represents an I/O error from the Tokio runtime.
-    TokioIoError(#[from] tokio::io::Error),
+    ///
+    /// Failures of the connection itself are reported by the `Connection*` variants.
+    #[error("I/O error")]
+    TokioIoError(#[source] tokio::io::Error),
+
+    /// The server refused the connection, usually because it is not listening yet.
+    #[error("the server refused the connection")]
+    ConnectionRefused(#[source] tokio::io::Error),
+
+    /// The server reset or aborted the connection.
+    #[error("the server reset the connection")]
+    ConnectionReset(#[source] tokio::io::Error),
+
+    /// The connection was closed while a message was being sent or received.
+    #[error("the connection was closed")]
+    ConnectionClosed(#[source] tokio::io::Error),
 
     /// Represents a configuration error.
+    #[error("invalid configuration")]
     ConfigError(#[from] ConfigError),
 
-    /// Represents an error encountered during serialization using CBOR format.
-    SerializationError(#[from] serde_cbor::Error),
+    /// Represents an error encountered during serialization using CBOR format, of a
+    /// message of `message_type` when known.
+    #[error("failed to encode or decode CBOR{}", of_message(.message_type))]
+    SerializationError {
+        message_type: Option<MessageType>,
+        #[source]
+        source: serde_cbor::Error,
+    },
+
+    /// Represents an error encountered while compressing or decoding a frame.
+    #[error("invalid frame")]
+    FrameError(#[from] FrameError),
+
+    /// Represents an error encountered while producing JSON.
+    #[error("failed to encode or decode JSON")]
+    JsonError(#[from] serde_json::Error),
 
     /// Represents an error regarding the type of message.
+    #[error("unexpected message type")]
     MessageTypeError(#[from] MessageTypeError),
 
-    /// Represents an encryption error when using AES-GCM-SIV.
-    EcryptionError(AesError),
+    /// The server did not answer a request of type `request` within the configured
+    /// timeout, `after`.
+    #[error("the server did not answer the {request:?} request within {after:?}")]
+    Timeout { request: MessageType, after: std::time::Duration },
+
+    /// The server answered with an unexpected message, carried here by its type.
+    ///
+    /// Usually means the client and the server implement different protocol versions.
+    #[error("the server answered with an unexpected {0} message")]
+    ProtocolError(MessageType),
+
+    /// Represents an encryption error when using AES-GCM-SIV, on a document of
+    /// `collection` when known.
+    #[error("encryption failed{}", in_collection(.collection))]
+    EcryptionError {
+        collection: Option<String>,
+        #[source]
+        source: AesError,
+    },
+
+    /// Represents a request of type `request` refused or failed by the server.
+    #[error("the server refused the {request:?} request")]
+    ServerError {
+        request: MessageType,
+        #[source]
+        error: ServerError,
+    },
+
+    /// A key given as bytes is not 32 bytes long.
+    #[error("the key is {got} bytes long instead of 32")]
+    InvalidKeyLength { got: usize },
+
+    /// Represents an insertion refused by its builder before being sent.
+    #[error("invalid insertion")]
+    InvalidInsertion(#[from] InsertionError),
+
+    /// A conditional update expected the document at another version than its current one.
+    #[error(
+        "the document is at version {current} where version {expected} was expected"
+    )]
+    VersionConflict { expected: u64, current: u64 },
+
+    /// A predicate of a query met a field of another type than the one it expects.
+    #[error("a predicate does not apply to its field")]
+    FieldTypeMismatch(#[from] FieldTypeMismatch),
+
+    /// A collection or usecase name is refused before being sent, see `validate_name`.
+    #[error("invalid name")]
+    InvalidName(#[from] InvalidName),
+
+    /// A document has another schema version than expected, see `deserialize_versioned`.
+    #[error("the document has schema version {stored} where {expected} was expected")]
+    SchemaVersionMismatch { stored: u32, expected: u32 },
+
+    /// A key cannot be split into shares, or the shares cannot rebuild it, see `shamir`.
+    #[error("invalid key shares")]
+    InvalidShares(#[from] ShareError),
+
+    /// A key cannot be verified on a document stored as OPE, whose value is not
+    /// authenticated, nor on a document stored in chunks, see `verify_key_for_document`.
+    #[error("the key cannot be verified on the document {id:?} of {collection:?}")]
+    UnverifiableDocument { collection: String, id: String },
+
+    /// A document is already stored under the id chosen for an insertion without upsert.
+    #[error("a document is already stored under the id {id:?} in {collection:?}")]
+    DuplicateId { collection: String, id: String },
+
+    /// A subquery of a compound query has predicates. The client checks predicates on
+    /// the decrypted documents, which do not tell which subquery matched them.
+    #[error("the subqueries of a compound query cannot have predicates")]
+    PredicatesInCompoundQuery,
 }
 
-#[derive(Debug)]
+impl Error {
+    /// An encryption error, on a document of a collection not known here, see
+    /// `in_collection`.
+    pub fn encryption(source: AesError) -> Self {
+        Error::EcryptionError { collection: None, source }
+    }
+
+    /// Tells the collection of the document an encryption error happened on, leaving the
+    /// other errors as they are.
+    pub fn in_collection(self, collection: &str) -> Self {
+        match self {
+            Error::EcryptionError { collection: None, source } => {
+                Error::EcryptionError { collection: Some(collection.to_string()), source }
+            }
+            err => err,
+        }
+    }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(source: serde_cbor::Error) -> Self {
+        Error::SerializationError { message_type: None, source }
+    }
+}
+
+/// Names the message a serialization error happened on, if known.
+fn of_message(message_type: &Option<MessageType>) -> String {
+    message_type
+        .as_ref()
+        .map(|message_type| format!(" of the {:?} message", message_type))
+        .unwrap_or_default()
+}
+
+/// Names the collection an encryption error happened in, if known.
+fn in_collection(collection: &Option<String>) -> String {
+    collection
+        .as_ref()
+        .map(|collection| format!(" in {:?}", collection))
+        .unwrap_or_default()
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum AesError {
+    #[error("failed to encrypt")]
     Encrypt,
+    #[error("failed to decrypt, under the wrong key or nonce or of tampered data")]
     Decrypt,
+    /// A chunked stream ended before its last chunk.
+    #[error("the chunked stream ended before its last chunk")]
+    TruncatedStream,
+    /// A plaintext cannot be padded, or its padding is malformed.
+    #[error("the plaintext cannot be padded, or its padding is malformed")]
+    Padding,
+    /// A nonce counter reached its maximum value.
+    #[error("the nonce counter reached its maximum value")]
+    NonceExhausted,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error as _, io, iter};
+
+    use super::*;
+
+    /// Displays an error and its sources, from the outermost to the root cause.
+    fn chain(err: &Error) -> Vec<String> {
+        iter::successors(Some(err as &dyn std::error::Error), |err| err.source())
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_source_chain_is_walkable_to_the_root_cause() {
+        let err = Error::from(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+        assert!(matches!(err, Error::ConnectionClosed(_)));
+        assert_eq!(chain(&err), vec!["the connection was closed", "pipe closed"]);
+
+        let cbor = serde_cbor::from_slice::<String>(&[0xff]).unwrap_err();
+        let root = cbor.to_string();
+        let err = Error::from(cbor);
+        assert_eq!(
+            chain(&err),
+            vec!["failed to encode or decode CBOR".to_string(), root.clone()]
+        );
+        let cbor = serde_cbor::from_slice::<String>(&[0xff]).unwrap_err();
+        let err = Error::SerializationError {
+            message_type: Some(MessageType::Update),
+            source: cbor,
+        };
+        assert_eq!(
+            chain(&err),
+            vec![
+                "failed to encode or decode CBOR of the Update message".to_string(),
+                root
+            ]
+        );
+
+        let cbor = serde_cbor::from_slice::<String>(&[0xff]).unwrap_err();
+        let err = Error::FrameError(FrameError::Serialization(cbor));
+        assert_eq!(chain(&err).len(), 3);
+
+        let err = Error::ServerError {
+            request: MessageType::Setup,
+            error: ServerError::TooManyConnections { max_connections: 8 },
+        };
+        assert_eq!(
+            chain(&err),
+            vec![
+                "the server refused the Setup request",
+                "the server already serves its maximum of 8 connections"
+            ]
+        );
+
+        let err = Error::encryption(AesError::Decrypt).in_collection("users");
+        let Error::EcryptionError { collection, .. } = &err else {
+            panic!("not an encryption error");
+        };
+        assert_eq!(collection.as_deref(), Some("users"));
+        assert_eq!(
+            chain(&err),
+            vec![
+                "encryption failed in \"users\"",
+                "failed to decrypt, under the wrong key or nonce or of tampered data"
+            ]
+        );
+        let err = Error::Timeout {
+            request: MessageType::HealthCheck,
+            after: std::time::Duration::from_millis(50),
+        };
+        assert_eq!(
+            err.to_string(),
+            "the server did not answer the HealthCheck request within 50ms"
+        );
+        let err = Error::ProtocolError(MessageType::Insert);
+        assert_eq!(
+            err.to_string(),
+            "the server answered with an unexpected Insert message"
+        );
+        assert!(err.source().is_none());
+    }
 }
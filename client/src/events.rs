@@ -0,0 +1,48 @@
+//! Events emitted by a client for observability, see `UnconnectedClient::with_events`.
+//!
+//! Hosts receive the events on a channel and feed their own metrics from them. A client
+//! without a registered channel builds no event at all.
+
+use liserk_shared::message_type::MessageType;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Something that happened on the connection of a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// The connection to the server is open and set up.
+    Connected,
+
+    /// The client authenticated with its credentials.
+    Authenticated { username: String },
+
+    /// The client authenticated with the session token of a previous connection.
+    Reconnected { username: String },
+
+    /// A request was written to the connection.
+    RequestSent { request_id: u32, message_type: MessageType },
+
+    /// The response to a request was handed to its caller.
+    ResponseReceived { request_id: u32, message_type: MessageType },
+
+    /// Sending a request or receiving its response failed, described by the error.
+    Error { description: String },
+}
+
+/// Where the events of a client go, nowhere unless a channel is registered.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventSink(Option<UnboundedSender<ClientEvent>>);
+
+impl EventSink {
+    pub(crate) fn new(sender: UnboundedSender<ClientEvent>) -> Self {
+        Self(Some(sender))
+    }
+
+    /// Emits the event built by `event`, which is only called if a channel is registered.
+    ///
+    /// A channel whose receiver was dropped is ignored.
+    pub(crate) fn emit<F: FnOnce() -> ClientEvent>(&self, event: F) {
+        if let Some(sender) = &self.0 {
+            let _ = sender.send(event());
+        }
+    }
+}
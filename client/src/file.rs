@@ -0,0 +1,237 @@
+//! Encryption of files from one path to another, for documents kept outside the database.
+//!
+//! An encrypted file is a header, the 12-byte random nonce followed by the big endian
+//! `u32` chunk size, then the chunks of a `ChunkEncryptor` stream of the file content,
+//! bound to the header as associated data. Every chunk but the last one holds exactly the
+//! chunk size of plaintext, so the chunks are read back without any framing, and files of
+//! any size are processed one chunk at a time.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    chunked::{ChunkEncryptor, ChunkOpener, DEFAULT_CHUNK_SIZE},
+    error::{AesError, Error},
+    generate_nonce,
+    keys::EncKey,
+};
+
+/// Length of the header at the start of an encrypted file.
+const HEADER_LENGTH: usize = 12 + 4;
+
+/// Bytes an encrypted chunk adds to its plaintext, the flag byte and the tag.
+const CHUNK_OVERHEAD: usize = 1 + 16;
+
+/// Largest chunk size accepted from the header of a file, bounding the memory used.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// What to do when the output path of `encrypt_file` or `decrypt_file` already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Fails with an `ErrorKind::AlreadyExists` I/O error, leaving the file as it is.
+    #[default]
+    CreateNew,
+
+    /// Replaces the content of the existing file.
+    Overwrite,
+}
+
+/// Encrypts the file at `input` under a fresh random nonce into the file at `output`.
+///
+/// On failure the output file is removed, see `OutputMode` for an existing one.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for encryption.
+/// * `input` - The path of the plaintext file.
+/// * `output` - The path of the encrypted file, which must not be `input`.
+/// * `mode` - Whether an existing output file is replaced.
+pub fn encrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    key: &EncKey,
+    input: P,
+    output: Q,
+    mode: OutputMode,
+) -> Result<(), Error> {
+    let reader = open_input(input.as_ref(), output.as_ref())?;
+    write_output(output.as_ref(), mode, |writer| {
+        let nonce = generate_nonce();
+        let chunk_size =
+            u32::try_from(DEFAULT_CHUNK_SIZE).expect("chunk size fits a u32");
+        let header = [&nonce[..], &chunk_size.to_be_bytes()].concat();
+        writer.write_all(&header)?;
+        let chunks =
+            ChunkEncryptor::new(key, &nonce, reader, &header, DEFAULT_CHUNK_SIZE);
+        for chunk in chunks {
+            writer.write_all(&chunk?)?;
+        }
+        Ok(())
+    })
+}
+
+/// Decrypts a file produced by `encrypt_file` at `input` into the file at `output`.
+///
+/// Chunks are written as they are authenticated, so on failure, a tampered or truncated
+/// file, the output file is removed and no plaintext is left behind.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for decryption.
+/// * `input` - The path of the encrypted file.
+/// * `output` - The path of the plaintext file, which must not be `input`.
+/// * `mode` - Whether an existing output file is replaced.
+pub fn decrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    key: &EncKey,
+    input: P,
+    output: Q,
+    mode: OutputMode,
+) -> Result<(), Error> {
+    let mut reader = open_input(input.as_ref(), output.as_ref())?;
+    let mut header = [0; HEADER_LENGTH];
+    match reader.read_exact(&mut header) {
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            return Err(Error::encryption(AesError::Decrypt))
+        }
+        result => result?,
+    }
+    let (nonce, chunk_size) = header.split_at(12);
+    let nonce: [u8; 12] = nonce.try_into().expect("split at the nonce length");
+    let chunk_size =
+        u32::from_be_bytes(chunk_size.try_into().expect("u32 length")) as usize;
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(Error::encryption(AesError::Decrypt));
+    }
+
+    write_output(output.as_ref(), mode, |writer| {
+        let mut opener = ChunkOpener::new(key, &nonce, &header);
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size + CHUNK_OVERHEAD);
+            (&mut reader)
+                .take((chunk_size + CHUNK_OVERHEAD) as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                return opener.finish();
+            }
+            writer.write_all(&opener.open(&chunk)?)?;
+        }
+    })
+}
+
+/// Opens the input file, refusing to read a file that is also the output.
+fn open_input(input: &Path, output: &Path) -> Result<BufReader<File>, Error> {
+    let file = File::open(input)?;
+    if output.exists() && fs::canonicalize(input)? == fs::canonicalize(output)? {
+        let err =
+            io::Error::new(ErrorKind::InvalidInput, "input and output are the same");
+        return Err(err.into());
+    }
+    Ok(BufReader::new(file))
+}
+
+/// Creates the output file and fills it with `write`, removing it if `write` fails.
+fn write_output<F>(path: &Path, mode: OutputMode, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+{
+    let mut options = OpenOptions::new();
+    match mode {
+        OutputMode::CreateNew => options.write(true).create_new(true),
+        OutputMode::Overwrite => options.write(true).create(true).truncate(true),
+    };
+    let mut writer = BufWriter::new(options.open(path)?);
+    let written = write(&mut writer).and_then(|()| writer.flush().map_err(Error::from));
+    if written.is_err() {
+        drop(writer);
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn key() -> EncKey {
+        EncKey::from_bytes([7; 32])
+    }
+
+    /// A path in the temporary directory, removed when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("liserk-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let sizes = [0, 10, DEFAULT_CHUNK_SIZE, 2 * DEFAULT_CHUNK_SIZE + 5];
+        for size in sizes {
+            let (input, encrypted, decrypted) =
+                (TempPath::new(), TempPath::new(), TempPath::new());
+            let content: Vec<u8> = (0..=255).cycle().take(size).collect();
+            fs::write(&input.0, &content).unwrap();
+
+            encrypt_file(&key(), &input.0, &encrypted.0, OutputMode::CreateNew).unwrap();
+            let chunks = size.div_ceil(DEFAULT_CHUNK_SIZE).max(1);
+            let length = fs::metadata(&encrypted.0).unwrap().len() as usize;
+            assert_eq!(length, HEADER_LENGTH + size + chunks * CHUNK_OVERHEAD);
+
+            decrypt_file(&key(), &encrypted.0, &decrypted.0, OutputMode::CreateNew)
+                .unwrap();
+            assert_eq!(fs::read(&decrypted.0).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_existing_output_is_only_replaced_when_asked() {
+        let (input, output) = (TempPath::new(), TempPath::new());
+        fs::write(&input.0, b"secret").unwrap();
+        fs::write(&output.0, b"kept").unwrap();
+
+        let err =
+            encrypt_file(&key(), &input.0, &output.0, OutputMode::CreateNew).unwrap_err();
+        let Error::TokioIoError(err) = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&output.0).unwrap(), b"kept");
+
+        encrypt_file(&key(), &input.0, &output.0, OutputMode::Overwrite).unwrap();
+        assert_ne!(fs::read(&output.0).unwrap(), b"kept");
+        assert!(encrypt_file(&key(), &input.0, &input.0, OutputMode::Overwrite).is_err());
+        assert_eq!(fs::read(&input.0).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_tampered_file_leaves_no_output() {
+        let (input, encrypted, decrypted) =
+            (TempPath::new(), TempPath::new(), TempPath::new());
+        fs::write(&input.0, vec![1; DEFAULT_CHUNK_SIZE + 1]).unwrap();
+        encrypt_file(&key(), &input.0, &encrypted.0, OutputMode::CreateNew).unwrap();
+        let ciphertext = fs::read(&encrypted.0).unwrap();
+
+        let mut tampered = ciphertext.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let truncated =
+            ciphertext[..HEADER_LENGTH + DEFAULT_CHUNK_SIZE + CHUNK_OVERHEAD].to_vec();
+        for corrupted in [tampered, truncated, ciphertext[..5].to_vec()] {
+            fs::write(&encrypted.0, corrupted).unwrap();
+            let mode = OutputMode::CreateNew;
+            assert!(decrypt_file(&key(), &encrypted.0, &decrypted.0, mode).is_err());
+            assert!(!decrypted.0.exists());
+        }
+    }
+}
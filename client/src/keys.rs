@@ -0,0 +1,129 @@
+//! Keys typed by their use, so a key encrypting documents is never passed where a key
+//! computing index tokens is expected, nor the other way around.
+//!
+//! `EncKey` and `MacKey` both wrap 32 bytes but neither converts to the other, and both
+//! convert from and to bytes only explicitly, through `from_bytes` and `as_bytes`. The
+//! keys of a collection are derived from the master key with `for_collection`: the
+//! encryption key is the collection key of `derive_collection_key`, and the token key is
+//! derived under a salt of its own, so the two keys of a collection are independent and
+//! the tokens a server sees reveal nothing of the key of the ciphertexts. A key is
+//! zeroized when dropped.
+//!
+//! A key of one kind does not compile where the other is expected:
+//!
+//! ```compile_fail
+//! # use liserk_client::{index_token, keys::EncKey};
+//! let key = EncKey::for_collection(&[0; 32], "users");
+//! index_token(&key, "email", &serde_cbor::Value::Null).unwrap();
+//! ```
+//!
+//! ```compile_fail
+//! # use liserk_client::{basic_encrypt, keys::MacKey};
+//! let key = MacKey::for_collection(&[0; 32], "users");
+//! basic_encrypt(&key, &[0; 12], b"document", &[]).unwrap();
+//! ```
+//!
+//! ```
+//! # use liserk_client::{index_token, keys::MacKey};
+//! let key = MacKey::for_collection(&[0; 32], "users");
+//! index_token(&key, "email", &serde_cbor::Value::Null).unwrap();
+//! ```
+
+use std::fmt;
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::derive_collection_key;
+
+/// Salt used to domain-separate the token keys from the collection encryption keys.
+const MAC_KEY_SALT: &[u8] = b"liserk-mac-key-v1";
+
+/// A 256-bit key encrypting and decrypting the documents of a collection.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncKey([u8; 32]);
+
+/// A 256-bit key computing the blind index and search tokens of a collection.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MacKey([u8; 32]);
+
+impl EncKey {
+    /// Wraps the bytes of a key meant for encryption.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Derives the encryption key of a collection from the master key.
+    pub fn for_collection(master: &[u8; 32], collection: &str) -> Self {
+        Self(derive_collection_key(master, collection))
+    }
+
+    /// Returns the bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl MacKey {
+    /// Wraps the bytes of a key meant for index and search tokens.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Derives the token key of a collection from the master key, with HKDF-SHA256 as
+    /// `derive_collection_key` but under its own salt.
+    pub fn for_collection(master: &[u8; 32], collection: &str) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(MAC_KEY_SALT), master);
+        let mut key = [0u8; 32];
+        hkdf.expand(collection.as_bytes(), &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self(key)
+    }
+
+    /// Returns the bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for EncKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncKey(..)")
+    }
+}
+
+impl fmt::Debug for MacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MacKey(..)")
+    }
+}
+
+impl Drop for EncKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for MacKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_keys_keep_the_derived_bytes_hidden() {
+        let master = [3; 32];
+        let encryption = EncKey::for_collection(&master, "users");
+        let mac = MacKey::for_collection(&master, "users");
+        assert_eq!(encryption.as_bytes(), &derive_collection_key(&master, "users"));
+        assert_ne!(mac.as_bytes(), encryption.as_bytes());
+        assert_eq!(mac, MacKey::for_collection(&master, "users"));
+        assert_ne!(mac, MacKey::for_collection(&master, "orders"));
+        assert_eq!(format!("{:?} {:?}", encryption, mac), "EncKey(..) MacKey(..)");
+    }
+}
@@ -1,18 +1,48 @@
 use std::{
+    collections::BTreeSet,
     fs::File,
     io::{Read, Write},
 };
 
 use aes_gcm_siv::{
-    aead::{generic_array::GenericArray, Aead, Payload},
+    aead::{generic_array::GenericArray, Aead, AeadInPlace, Payload},
     Aes256GcmSiv, KeyInit,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use error::{AesError, Error};
+use hkdf::Hkdf;
+use keys::{EncKey, MacKey};
+use liserk_shared::{message_type::MessageType, query::IndexEntry};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
+pub mod builder;
+pub mod cache;
+pub mod chunked;
+pub mod envelope;
 pub mod error;
+pub mod events;
+pub mod file;
+pub mod keys;
+pub mod nonce;
+pub mod padding;
+pub mod read_only;
+pub mod rng;
+pub mod shamir;
+pub mod shared_client;
 pub mod stream;
 
+pub use envelope::Encryptable;
+
+/// Salt used to domain-separate collection keys from any other HKDF usage of the master key.
+const COLLECTION_KEY_SALT: &[u8] = b"liserk-collection-key-v1";
+
+/// Salt used to domain-separate index tokens from the collection encryption keys.
+const INDEX_TOKEN_SALT: &[u8] = b"liserk-index-token-v1";
+
+/// Salt used to domain-separate search tokens from the index tokens.
+const SEARCH_TOKEN_SALT: &[u8] = b"liserk-search-token-v1";
+
 /// Serializes a data structure into a Vec<u8> using CBOR format.
 ///
 /// # Arguments
@@ -41,6 +71,156 @@ pub fn deserialize<T: for<'a> Deserialize<'a>>(cbor_data: &Vec<u8>) -> Result<T,
     Ok(data)
 }
 
+/// Serializes a data structure in CBOR format straight into a writer, without building
+/// the whole encoding in memory first like `serialize` does.
+///
+/// The writer is not buffered here, wrap it in a `BufWriter` when its writes are costly.
+///
+/// # Arguments
+///
+/// * `writer` - The writer the serialized data is written to.
+/// * `data` - A reference to the data to be serialized.
+pub fn serialize_to_writer<W: Write, T: Serialize>(
+    writer: W,
+    data: &T,
+) -> Result<(), Error> {
+    serde_cbor::to_writer(writer, data)?;
+    Ok(())
+}
+
+/// Deserializes a data structure in CBOR format read from a reader, see
+/// `serialize_to_writer`.
+///
+/// Fails if the reader holds anything after the data structure.
+///
+/// # Arguments
+///
+/// * `reader` - The reader the serialized data is read from.
+pub fn deserialize_from_reader<R: Read, T: for<'a> Deserialize<'a>>(
+    reader: R,
+) -> Result<T, Error> {
+    let data = serde_cbor::from_reader(reader)?;
+    Ok(data)
+}
+
+/// A document serialized along the version of its schema.
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    #[serde(rename = "liserk_schema_version")]
+    schema_version: u32,
+    document: T,
+}
+
+/// The schema version of a document, read without decoding the document.
+#[derive(Deserialize)]
+struct SchemaVersion {
+    #[serde(rename = "liserk_schema_version")]
+    schema_version: u32,
+}
+
+/// Serializes a data structure like `serialize`, tagged with the version of its schema.
+///
+/// Versions start at 1, version 0 being the one of the documents serialized without a
+/// version, so `serialize_versioned(data, 0)` is `serialize(data)`.
+///
+/// # Arguments
+///
+/// * `data` - A reference to the data to be serialized.
+/// * `schema_version` - The version of the schema of `data`.
+pub fn serialize_versioned<T: Serialize>(
+    data: &T,
+    schema_version: u32,
+) -> Result<Vec<u8>, Error> {
+    if schema_version == 0 {
+        return serialize(data);
+    }
+    serialize(&Versioned { schema_version, document: data })
+}
+
+/// Deserializes a document serialized by `serialize_versioned`, checking its version.
+///
+/// A document of another version than `expected` is not decoded and fails with
+/// `Error::SchemaVersionMismatch`, so a migration can tell an old document from a
+/// corrupted one.
+///
+/// # Arguments
+///
+/// * `cbor_data` - The serialized document.
+/// * `expected` - The schema version of `T`, 0 for documents serialized without one.
+pub fn deserialize_versioned<T: for<'a> Deserialize<'a>>(
+    cbor_data: &[u8],
+    expected: u32,
+) -> Result<T, Error> {
+    let stored = serde_cbor::from_slice::<SchemaVersion>(cbor_data)
+        .map_or(0, |version| version.schema_version);
+    if stored != expected {
+        return Err(Error::SchemaVersionMismatch { stored, expected });
+    }
+    if stored == 0 {
+        return Ok(serde_cbor::from_slice(cbor_data)?);
+    }
+    let versioned: Versioned<T> = serde_cbor::from_slice(cbor_data)?;
+    Ok(versioned.document)
+}
+
+/// Transcodes a CBOR document into pretty-printed JSON without knowing its concrete type.
+///
+/// Byte strings that are valid UTF-8 are written as JSON strings, other byte strings
+/// are base64 encoded. Map keys which are not text are written as their JSON form.
+///
+/// # Arguments
+///
+/// * `bytes` - The CBOR encoded document, typically a decrypted payload.
+///
+/// # Returns
+///
+/// * `Result<String, Error>` - The JSON representation, or an error if the bytes are not valid CBOR.
+pub fn cbor_to_json(bytes: &[u8]) -> Result<String, Error> {
+    let value: serde_cbor::Value = serde_cbor::from_slice(bytes)?;
+    let json = serde_json::to_string_pretty(&cbor_value_to_json(value))?;
+    Ok(json)
+}
+
+fn cbor_value_to_json(value: serde_cbor::Value) -> serde_json::Value {
+    use serde_cbor::Value as Cbor;
+    use serde_json::Value as Json;
+
+    match value {
+        Cbor::Null => Json::Null,
+        Cbor::Bool(boolean) => Json::Bool(boolean),
+        Cbor::Integer(integer) => match i64::try_from(integer) {
+            Ok(integer) => Json::from(integer),
+            Err(_) => match u64::try_from(integer) {
+                Ok(integer) => Json::from(integer),
+                Err(_) => Json::String(integer.to_string()),
+            },
+        },
+        Cbor::Float(float) => Json::from(float),
+        Cbor::Bytes(bytes) => match String::from_utf8(bytes) {
+            Ok(text) => Json::String(text),
+            Err(err) => Json::String(BASE64.encode(err.into_bytes())),
+        },
+        Cbor::Text(text) => Json::String(text),
+        Cbor::Array(values) => {
+            Json::Array(values.into_iter().map(cbor_value_to_json).collect())
+        }
+        Cbor::Map(fields) => Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match cbor_value_to_json(key) {
+                        Json::String(key) => key,
+                        key => key.to_string(),
+                    };
+                    (key, cbor_value_to_json(value))
+                })
+                .collect(),
+        ),
+        Cbor::Tag(_, value) => cbor_value_to_json(*value),
+        _ => Json::Null,
+    }
+}
+
 /// Encrypts plaintext using AES-GCM-SIV algorithm.
 ///
 /// # Arguments
@@ -54,17 +234,17 @@ pub fn deserialize<T: for<'a> Deserialize<'a>>(cbor_data: &Vec<u8>) -> Result<T,
 ///
 /// * `Result<Vec<u8>, Error>` - The encrypted data as a vector of bytes, or an error if encryption fails.
 pub fn basic_encrypt(
-    key: &[u8; 32],
+    key: &EncKey,
     nonce: &[u8; 12],
     plaintext: &[u8],
     associated_data: &[u8],
 ) -> Result<Vec<u8>, Error> {
-    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key));
+    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key.as_bytes()));
     let nonce = GenericArray::from_slice(nonce);
     let payload = Payload { msg: plaintext, aad: associated_data };
     let ciphertext = cipher
         .encrypt(nonce, payload)
-        .map_err(|_| Error::EcryptionError(AesError::Encrypt));
+        .map_err(|_| Error::encryption(AesError::Encrypt));
 
     ciphertext
 }
@@ -82,32 +262,427 @@ pub fn basic_encrypt(
 ///
 /// * `Result<Vec<u8>, Error>` - The decrypted data as a vector of bytes, or an error if decryption fails.
 pub fn basic_decrypt(
-    key: &[u8; 32],
+    key: &EncKey,
     nonce: &[u8; 12],
     ciphertext: &[u8],
     associated_data: &[u8],
 ) -> Result<Vec<u8>, Error> {
-    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key));
+    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key.as_bytes()));
     let nonce = GenericArray::from_slice(nonce);
     let payload = Payload { msg: ciphertext, aad: associated_data };
     let plaintext = cipher
         .decrypt(nonce, payload)
-        .map_err(|_| Error::EcryptionError(AesError::Decrypt));
+        .map_err(|_| Error::encryption(AesError::Decrypt));
 
     plaintext
 }
 
+/// Decrypts a ciphertext in place using AES-GCM-SIV algorithm.
+///
+/// The buffer holding the ciphertext is reused for the plaintext, avoiding the allocation
+/// of `basic_decrypt` when decrypting many documents. On failure the content of the buffer
+/// must not be used.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for decryption.
+/// * `nonce` - A reference to the 12-byte nonce.
+/// * `buffer` - The encrypted data, replaced by the decrypted data.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<(), Error>` - `Ok(())` if the buffer now holds the plaintext, or an error if decryption fails.
+pub fn decrypt_in_place(
+    key: &EncKey,
+    nonce: &[u8; 12],
+    buffer: &mut Vec<u8>,
+    associated_data: &[u8],
+) -> Result<(), Error> {
+    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key.as_bytes()));
+    let nonce = GenericArray::from_slice(nonce);
+    cipher
+        .decrypt_in_place(nonce, associated_data, buffer)
+        .map_err(|_| Error::encryption(AesError::Decrypt))
+}
+
+/// Encrypts plaintext using AES-GCM-SIV algorithm, returning the tag apart.
+///
+/// For storage layouts keeping the authentication tag away from the ciphertext. The
+/// ciphertext followed by the tag is the output of `basic_encrypt`.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for encryption.
+/// * `nonce` - A reference to the 12-byte nonce.
+/// * `plaintext` - A reference to the data to be encrypted.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, [u8; 16]), Error>` - The ciphertext, as long as the plaintext, and its tag, or an error if encryption fails.
+pub fn encrypt_detached(
+    key: &EncKey,
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<(Vec<u8>, [u8; 16]), Error> {
+    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key.as_bytes()));
+    let nonce = GenericArray::from_slice(nonce);
+    let mut ciphertext = plaintext.to_vec();
+    let tag = cipher
+        .encrypt_in_place_detached(nonce, associated_data, &mut ciphertext)
+        .map_err(|_| Error::encryption(AesError::Encrypt))?;
+    Ok((ciphertext, tag.into()))
+}
+
+/// Decrypts a ciphertext and its tag produced by `encrypt_detached`.
+///
+/// # Arguments
+///
+/// * `key` - A reference to the 256-bit key for decryption.
+/// * `nonce` - A reference to the 12-byte nonce.
+/// * `ciphertext` - A reference to the encrypted data, without its tag.
+/// * `tag` - A reference to the authentication tag of the ciphertext.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The decrypted data as a vector of bytes, or an error if decryption fails.
+pub fn decrypt_detached(
+    key: &EncKey,
+    nonce: &[u8; 12],
+    ciphertext: &[u8],
+    tag: &[u8; 16],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(key.as_bytes()));
+    let nonce = GenericArray::from_slice(nonce);
+    let mut plaintext = ciphertext.to_vec();
+    cipher
+        .decrypt_in_place_detached(
+            nonce,
+            associated_data,
+            &mut plaintext,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| Error::encryption(AesError::Decrypt))?;
+    Ok(plaintext)
+}
+
+/// Binds associated data to an encryption context, such as a tenant or session identifier.
+///
+/// The context is written after its length, a big endian `u32`, then comes the associated
+/// data, so two different pairs never give the same bytes. Without context the associated
+/// data is returned as it is.
+///
+/// # Arguments
+///
+/// * `context` - The encryption context, if any.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The associated data to encrypt and decrypt with.
+pub fn bind_context(context: Option<&[u8]>, associated_data: &[u8]) -> Vec<u8> {
+    match context {
+        Some(context) => {
+            let length =
+                u32::try_from(context.len()).expect("context shorter than 4 GiB");
+            [&length.to_be_bytes()[..], context, associated_data].concat()
+        }
+        None => associated_data.to_vec(),
+    }
+}
+
+/// AES-GCM-SIV cipher initialized once for a key, for encrypting many values under it.
+///
+/// `basic_encrypt` and `basic_decrypt` run the key schedule on every call, a context runs
+/// it once and produces the same output.
+#[derive(Clone)]
+pub struct CipherContext {
+    cipher: Aes256GcmSiv,
+}
+
+impl CipherContext {
+    /// Initializes the cipher for the 256-bit key.
+    pub fn new(key: &EncKey) -> Self {
+        Self {
+            cipher: Aes256GcmSiv::new(GenericArray::from_slice(key.as_bytes())),
+        }
+    }
+
+    /// Encrypts plaintext like `basic_encrypt` with the key of the context.
+    pub fn encrypt(
+        &self,
+        nonce: &[u8; 12],
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let payload = Payload { msg: plaintext, aad: associated_data };
+        self.cipher
+            .encrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| Error::encryption(AesError::Encrypt))
+    }
+
+    /// Decrypts ciphertext like `basic_decrypt` with the key of the context.
+    pub fn decrypt(
+        &self,
+        nonce: &[u8; 12],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let payload = Payload { msg: ciphertext, aad: associated_data };
+        self.cipher
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| Error::encryption(AesError::Decrypt))
+    }
+}
+
+impl std::fmt::Debug for CipherContext {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The key schedule is key material, never printed.
+        formatter.debug_struct("CipherContext").finish_non_exhaustive()
+    }
+}
+
+/// Encrypts the payload of a message, binding the type of the message carrying it.
+///
+/// The message type is written in clear as the first byte of the output, so the server
+/// can reject a payload sent with the wrong message, and is also part of the associated
+/// data, so changing that byte makes decryption fail.
+///
+/// # Arguments
+///
+/// * `message_type` - The type of the message the payload is sent with.
+/// * `key` - A reference to the 256-bit key for encryption.
+/// * `nonce` - A reference to the 12-byte nonce.
+/// * `plaintext` - A reference to the data to be encrypted.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The message type byte followed by the ciphertext, or an error if encryption fails.
+pub fn encrypt_for_message(
+    message_type: MessageType,
+    key: &EncKey,
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let header = [message_type as u8];
+    let associated_data = [&header[..], associated_data].concat();
+    let ciphertext = basic_encrypt(key, nonce, plaintext, &associated_data)?;
+    Ok([&header[..], &ciphertext].concat())
+}
+
+/// Decrypts a payload produced by `encrypt_for_message` for the expected message type.
+///
+/// The expected type, not the byte in clear, is bound into the associated data, so a
+/// payload encrypted for another message fails to decrypt whatever its first byte says.
+///
+/// # Arguments
+///
+/// * `expected` - The type of the message the payload must have been encrypted for.
+/// * `key` - A reference to the 256-bit key for decryption.
+/// * `nonce` - A reference to the 12-byte nonce.
+/// * `payload` - The message type byte followed by the ciphertext.
+/// * `associated_data` - A reference to the associated data.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, Error>` - The decrypted data, or an error if the payload was not encrypted for `expected` or fails authentication.
+pub fn decrypt_for_message(
+    expected: MessageType,
+    key: &EncKey,
+    nonce: &[u8; 12],
+    payload: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let header = [expected as u8];
+    let Some(ciphertext) = payload.strip_prefix(&header[..]) else {
+        return Err(Error::encryption(AesError::Decrypt));
+    };
+    let associated_data = [&header[..], associated_data].concat();
+    basic_decrypt(key, nonce, ciphertext, &associated_data)
+}
+
 /// Generates a random 256-bit key.
 ///
+/// Reproducible in tests with the `test-rng` feature, see `rng`.
+///
 /// # Returns
 ///
 /// * `[u8; 32]` - The generated key.
 pub fn generate_key() -> [u8; 32] {
     let mut key = [0u8; 32];
-    getrandom::getrandom(&mut key).expect("Error generating key");
+    rng::fill_random(&mut key);
+    key
+}
+
+/// Generates a random 12-byte nonce.
+///
+/// Reproducible in tests with the `test-rng` feature, see `rng`.
+///
+/// # Returns
+///
+/// * `[u8; 12]` - The generated nonce.
+pub fn generate_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    rng::fill_random(&mut nonce);
+    nonce
+}
+
+/// Derives a 256-bit key scoped to a collection from a master key using HKDF-SHA256.
+///
+/// The derivation is deterministic, so the same master key and collection always
+/// yield the same key, while distinct collections yield independent keys.
+///
+/// # Arguments
+///
+/// * `master` - A reference to the 256-bit master key.
+/// * `collection` - The name of the collection the key is scoped to.
+///
+/// # Returns
+///
+/// * `[u8; 32]` - The derived collection key.
+pub fn derive_collection_key(master: &[u8; 32], collection: &str) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(COLLECTION_KEY_SALT), master);
+    let mut key = [0u8; 32];
+    hkdf.expand(collection.as_bytes(), &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
     key
 }
 
+/// Computes the blind index token of a field value.
+///
+/// The token is an HKDF-SHA256 output keyed by the MAC key of the collection, so it is
+/// the same for equal values of the field but reveals nothing about them without the key.
+///
+/// # Arguments
+///
+/// * `mac_key` - The MAC key of the collection, see `MacKey::for_collection`.
+/// * `field` - The name of the indexed field.
+/// * `value` - The value of the field.
+///
+/// # Returns
+///
+/// * `Result<IndexEntry, Error>` - The index entry of the value, or an error if the value cannot be serialized.
+pub fn index_token(
+    mac_key: &MacKey,
+    field: &str,
+    value: &serde_cbor::Value,
+) -> Result<IndexEntry, Error> {
+    let hkdf = Hkdf::<Sha256>::new(Some(INDEX_TOKEN_SALT), mac_key.as_bytes());
+    let info = [field.as_bytes(), &[0], &serde_cbor::to_vec(value)?].concat();
+    let mut token = vec![0u8; 32];
+    hkdf.expand(&info, &mut token)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(IndexEntry { field: field.to_string(), token })
+}
+
+/// Computes the index entries of the top level fields of a CBOR document.
+///
+/// Fields missing from the document are not indexed.
+///
+/// # Arguments
+///
+/// * `mac_key` - A reference to the MAC key of the collection.
+/// * `document` - The CBOR document, before encryption.
+/// * `fields` - The names of the fields to index.
+pub fn index_entries(
+    mac_key: &MacKey,
+    document: &[u8],
+    fields: &[String],
+) -> Result<Vec<IndexEntry>, Error> {
+    if fields.is_empty() {
+        return Ok(Vec::new());
+    }
+    let serde_cbor::Value::Map(document) = serde_cbor::from_slice(document)? else {
+        return Ok(Vec::new());
+    };
+    let mut entries = Vec::with_capacity(fields.len());
+    for field in fields {
+        if let Some(value) = document.get(&serde_cbor::Value::Text(field.clone())) {
+            entries.push(index_token(mac_key, field, value)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Splits a text in the distinct lowercase words searchable with `search_token`.
+///
+/// Words are the maximal runs of alphanumeric characters.
+pub fn search_terms(text: &str) -> BTreeSet<String> {
+    text.split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Computes the blinded search token of a word of a text field.
+///
+/// Like `index_token`, the token is an HKDF-SHA256 output keyed by the MAC key,
+/// under a distinct salt so a search token never equals the index token of a value. The
+/// word is normalized as by `search_terms`, so a search is case insensitive.
+///
+/// The server stores the tokens of every document and matches them against the token of
+/// a search, which leaks:
+/// * the number of distinct words of the searchable fields of each document;
+/// * which documents share a word, without revealing the word;
+/// * which searches are repeated, and the documents matching each of them.
+///
+/// Over a large collection, word frequencies may let an attacker who knows the
+/// distribution of the plaintexts guess common words.
+///
+/// # Arguments
+///
+/// * `mac_key` - The MAC key of the collection, see `MacKey::for_collection`.
+/// * `field` - The name of the searchable field.
+/// * `term` - The searched word.
+pub fn search_token(mac_key: &MacKey, field: &str, term: &str) -> IndexEntry {
+    let hkdf = Hkdf::<Sha256>::new(Some(SEARCH_TOKEN_SALT), mac_key.as_bytes());
+    let info = [field.as_bytes(), &[0], term.to_lowercase().as_bytes()].concat();
+    let mut token = vec![0u8; 32];
+    hkdf.expand(&info, &mut token)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    IndexEntry { field: field.to_string(), token }
+}
+
+/// Computes the search tokens of the words of the top level text fields of a CBOR
+/// document.
+///
+/// Fields missing from the document or not holding text are not searchable.
+///
+/// # Arguments
+///
+/// * `mac_key` - A reference to the MAC key of the collection.
+/// * `document` - The CBOR document, before encryption.
+/// * `fields` - The names of the text fields to make searchable.
+pub fn search_entries(
+    mac_key: &MacKey,
+    document: &[u8],
+    fields: &[String],
+) -> Result<Vec<IndexEntry>, Error> {
+    if fields.is_empty() {
+        return Ok(Vec::new());
+    }
+    let serde_cbor::Value::Map(document) = serde_cbor::from_slice(document)? else {
+        return Ok(Vec::new());
+    };
+    let mut entries = Vec::new();
+    for field in fields {
+        if let Some(serde_cbor::Value::Text(text)) =
+            document.get(&serde_cbor::Value::Text(field.clone()))
+        {
+            for term in search_terms(text) {
+                entries.push(search_token(mac_key, field, &term));
+            }
+        }
+    }
+    Ok(entries)
+}
+
 /// Saves a 256-bit key to a file.
 ///
 /// # Arguments
@@ -126,16 +701,464 @@ pub fn save_key_to_file(key: &[u8; 32], file_path: &str) -> std::io::Result<()>
 
 /// Loads a 256-bit key from a file.
 ///
+/// The file must hold exactly the 32 bytes of the key, see `validate_key_bytes`.
+///
 /// # Arguments
 ///
 /// * `file_path` - The path to the file from which the key should be loaded.
 ///
 /// # Returns
 ///
-/// * `std::io::Result<[u8; 32]>` - The loaded key or an error if there was a problem loading the key.
-pub fn load_key_from_file(file_path: &str) -> std::io::Result<[u8; 32]> {
+/// * `Result<[u8; 32], Error>` - The loaded key or an error if there was a problem loading the key.
+pub fn load_key_from_file(file_path: &str) -> Result<[u8; 32], Error> {
     let mut file = File::open(file_path)?;
-    let mut key = [0u8; 32];
-    file.read_exact(&mut key)?;
-    Ok(key)
+    let mut key = Vec::with_capacity(32);
+    file.read_to_end(&mut key)?;
+    validate_key_bytes(&key)
+}
+
+/// Checks that bytes are a 256-bit key and returns it.
+///
+/// Every entry point taking a key as bytes goes through this check, so an empty,
+/// truncated or oversized key is refused the same way whatever its source.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes of the key.
+///
+/// # Returns
+///
+/// * `Result<[u8; 32], Error>` - The key, or `Error::InvalidKeyLength` if it is not 32 bytes long.
+pub fn validate_key_bytes(bytes: &[u8]) -> Result<[u8; 32], Error> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::InvalidKeyLength { got: bytes.len() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cbor_to_json_with_mixed_fields() {
+        use serde_cbor::Value;
+        use std::collections::BTreeMap;
+
+        let mut fields = BTreeMap::new();
+        fields.insert(Value::Text("name".into()), Value::Text("Bob".into()));
+        fields.insert(Value::Text("age".into()), Value::Integer(42));
+        fields.insert(Value::Text("score".into()), Value::Float(1.5));
+        fields.insert(Value::Text("admin".into()), Value::Bool(false));
+        fields.insert(Value::Text("nickname".into()), Value::Null);
+        fields.insert(Value::Text("tag".into()), Value::Bytes(b"abc".to_vec()));
+        fields.insert(Value::Text("raw".into()), Value::Bytes(vec![0xff, 0x00, 0xfe]));
+        fields.insert(
+            Value::Text("roles".into()),
+            Value::Array(vec![Value::Text("read".into()), Value::Text("write".into())]),
+        );
+        fields.insert(Value::Integer(7), Value::Text("numeric key".into()));
+        let bytes = serde_cbor::to_vec(&Value::Map(fields)).unwrap();
+
+        let json = cbor_to_json(&bytes).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(json["name"], "Bob");
+        assert_eq!(json["age"], 42);
+        assert_eq!(json["score"], 1.5);
+        assert_eq!(json["admin"], false);
+        assert!(json["nickname"].is_null());
+        assert_eq!(json["tag"], "abc");
+        assert_eq!(json["raw"], "/wD+");
+        assert_eq!(json["roles"], serde_json::json!(["read", "write"]));
+        assert_eq!(json["7"], "numeric key");
+    }
+
+    #[test]
+    fn test_document_of_another_schema_version_is_refused() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct UserV1 {
+            name: String,
+        }
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct UserV2 {
+            first_name: String,
+            last_name: String,
+        }
+
+        let user = UserV1 { name: "Bob".to_string() };
+        let stored = serialize_versioned(&user, 1).unwrap();
+        let err = deserialize_versioned::<UserV2>(&stored, 2).unwrap_err();
+        assert!(matches!(err, Error::SchemaVersionMismatch { stored: 1, expected: 2 }));
+        assert_eq!(deserialize_versioned::<UserV1>(&stored, 1).unwrap(), user);
+
+        let unversioned = serialize(&user).unwrap();
+        let err = deserialize_versioned::<UserV1>(&unversioned, 1).unwrap_err();
+        assert!(matches!(err, Error::SchemaVersionMismatch { stored: 0, expected: 1 }));
+        assert_eq!(deserialize_versioned::<UserV1>(&unversioned, 0).unwrap(), user);
+    }
+
+    #[test]
+    fn test_large_vector_round_trips_through_a_writer() {
+        let data: Vec<u64> = (0..1_000_000).map(|value| value * 7).collect();
+        let mut written = Vec::new();
+        serialize_to_writer(&mut written, &data).unwrap();
+        assert_eq!(written, serialize(&data).unwrap());
+
+        let read: Vec<u64> = deserialize_from_reader(written.as_slice()).unwrap();
+        assert_eq!(read, data);
+        let truncated = &written[..written.len() - 1];
+        assert!(deserialize_from_reader::<_, Vec<u64>>(truncated).is_err());
+    }
+
+    #[test]
+    fn test_cbor_to_json_rejects_invalid_cbor() {
+        assert!(cbor_to_json(&[0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_payload_swapped_between_message_types_is_detected() {
+        let key = EncKey::from_bytes([3; 32]);
+        let nonce = [5u8; 12];
+        let payload =
+            encrypt_for_message(MessageType::Insert, &key, &nonce, b"document", &[])
+                .unwrap();
+
+        let plaintext =
+            decrypt_for_message(MessageType::Insert, &key, &nonce, &payload, &[])
+                .unwrap();
+        assert_eq!(plaintext, b"document");
+        let update = MessageType::Update;
+        assert!(decrypt_for_message(update, &key, &nonce, &payload, &[]).is_err());
+
+        let mut swapped = payload.clone();
+        swapped[0] = MessageType::Update as u8;
+        assert!(decrypt_for_message(update, &key, &nonce, &swapped, &[]).is_err());
+        let insert = MessageType::Insert;
+        assert!(decrypt_for_message(insert, &key, &nonce, &swapped, &[]).is_err());
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Key and nonce of the AEAD_AES_256_GCM_SIV vectors of RFC 8452, appendix C.2.
+    const VECTOR_KEY: [u8; 32] = [
+        1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0,
+    ];
+    const VECTOR_NONCE: [u8; 12] = [3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    /// Stored documents are only readable as long as these outputs never change.
+    #[test]
+    fn test_known_answer_vectors_of_basic_encrypt() {
+        let vectors = [
+            ("", "", "07f5f4169bbf55a8400cd47ea6fd400f"),
+            ("0100000000000000", "", "c2ef328e5c71c83b843122130f7364b761e0b97427e3df28"),
+            (
+                "0200000000000000",
+                "01",
+                "1de22967237a813291213f267e3b452f02d01ae33e4ec854",
+            ),
+            ("", "7573657273", "3ddc5585e8ec329c2e0f7cbd4497ce28"),
+        ];
+        let key = EncKey::from_bytes(VECTOR_KEY);
+        for (plaintext, associated_data, ciphertext) in vectors {
+            let (plaintext, associated_data) = (hex(plaintext), hex(associated_data));
+            let encrypted =
+                basic_encrypt(&key, &VECTOR_NONCE, &plaintext, &associated_data).unwrap();
+            assert_eq!(encrypted, hex(ciphertext));
+            let decrypted =
+                basic_decrypt(&key, &VECTOR_NONCE, &encrypted, &associated_data).unwrap();
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_known_answer_vectors_of_the_message_envelope() {
+        let vectors = [
+            (&b""[..], "025aa431b0204774d9f553e2dabb937a0211fe41b3b98dd730"),
+            (&b"users"[..], "02daa8d5b9721ef654f37968382c395f7645490ea02287a51b"),
+        ];
+        let key = EncKey::from_bytes(VECTOR_KEY);
+        for (associated_data, payload) in vectors {
+            let encrypted = encrypt_for_message(
+                MessageType::Insert,
+                &key,
+                &VECTOR_NONCE,
+                b"document",
+                associated_data,
+            )
+            .unwrap();
+            assert_eq!(encrypted, hex(payload));
+            let decrypted = decrypt_for_message(
+                MessageType::Insert,
+                &key,
+                &VECTOR_NONCE,
+                &encrypted,
+                associated_data,
+            )
+            .unwrap();
+            assert_eq!(decrypted, b"document".to_vec());
+        }
+    }
+
+    #[test]
+    fn test_known_answer_vector_of_a_stored_document() {
+        let key = EncKey::for_collection(&[42; 32], "users");
+        assert_eq!(
+            key.as_bytes().to_vec(),
+            hex("9f5a09be96d52ec1473f2d3b0a00f6bd428e3f97536d76b479fbe0b787b10fa2")
+        );
+        let nonce = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+        let stored =
+            hex("02e7a13cc8c0447ad2f53bb94aeddc59681d95963aac400cbc581412acef45");
+        let document = br#"{"name":"Bob"}"#;
+        let encrypted =
+            encrypt_for_message(MessageType::Insert, &key, &nonce, document, &[])
+                .unwrap();
+        assert_eq!(encrypted, stored);
+        let decrypted =
+            decrypt_for_message(MessageType::Insert, &key, &nonce, &stored, &[]).unwrap();
+        assert_eq!(decrypted, document);
+    }
+
+    #[test]
+    fn test_cipher_context_matches_the_per_call_functions() {
+        let key = EncKey::from_bytes([9; 32]);
+        let context = CipherContext::new(&key);
+        for index in 0..16u8 {
+            let nonce = [index; 12];
+            let plaintext = vec![index; index as usize * 7];
+            let ciphertext = context.encrypt(&nonce, &plaintext, b"users").unwrap();
+            assert_eq!(
+                ciphertext,
+                basic_encrypt(&key, &nonce, &plaintext, b"users").unwrap()
+            );
+            assert_eq!(
+                context.decrypt(&nonce, &ciphertext, b"users").unwrap(),
+                plaintext
+            );
+            assert_eq!(
+                basic_decrypt(&key, &nonce, &ciphertext, b"users").unwrap(),
+                plaintext
+            );
+        }
+        assert!(CipherContext::new(&EncKey::from_bytes([1; 32]))
+            .decrypt(&[0; 12], &[0; 16], b"")
+            .is_err());
+    }
+
+    #[test]
+    fn test_key_bytes_must_be_32_bytes_long() {
+        for length in [0, 16, 64] {
+            assert!(matches!(
+                validate_key_bytes(&vec![1; length]),
+                Err(Error::InvalidKeyLength { got }) if got == length
+            ));
+        }
+        assert_eq!(validate_key_bytes(&[1; 32]).unwrap(), [1; 32]);
+    }
+
+    #[test]
+    fn test_key_file_of_wrong_length_is_refused() {
+        let path =
+            std::env::temp_dir().join(format!("liserk-key-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, [1; 64]).unwrap();
+        assert!(matches!(
+            load_key_from_file(path),
+            Err(Error::InvalidKeyLength { got: 64 })
+        ));
+
+        let key = generate_key();
+        save_key_to_file(&key, path).unwrap();
+        assert_eq!(load_key_from_file(path).unwrap(), key);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_derive_collection_key_is_deterministic() {
+        let master = [7u8; 32];
+        let first = derive_collection_key(&master, "users");
+        let second = derive_collection_key(&master, "users");
+        assert_eq!(first, second);
+        assert_ne!(first, master);
+    }
+
+    #[test]
+    fn test_derive_collection_key_differs_per_collection() {
+        let master = [7u8; 32];
+        let users = derive_collection_key(&master, "users");
+        let orders = derive_collection_key(&master, "orders");
+        assert_ne!(users, orders);
+    }
+
+    #[test]
+    fn test_index_token_is_keyed_and_deterministic() {
+        let key = MacKey::for_collection(&[7; 32], "users");
+        let other_key = MacKey::for_collection(&[7; 32], "orders");
+        let value = serde_cbor::Value::Text("bob@example.com".to_string());
+
+        let token = index_token(&key, "email", &value).unwrap();
+        assert_eq!(token, index_token(&key, "email", &value).unwrap());
+        assert_ne!(token.token, index_token(&other_key, "email", &value).unwrap().token);
+        assert_ne!(token.token, index_token(&key, "login", &value).unwrap().token);
+    }
+
+    #[test]
+    fn test_index_entries_skip_missing_fields() {
+        let key = MacKey::from_bytes([3; 32]);
+        let document: std::collections::BTreeMap<&str, &str> =
+            [("email", "bob@example.com")].into_iter().collect();
+        let document = serde_cbor::to_vec(&document).unwrap();
+
+        let fields = ["email".to_string(), "phone".to_string()];
+        let entries = index_entries(&key, &document, &fields).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].field, "email");
+    }
+
+    #[test]
+    fn test_search_tokens_match_words_of_a_text() {
+        let key = MacKey::from_bytes([3; 32]);
+        let document: std::collections::BTreeMap<&str, &str> =
+            [("bio", "Writes Rust, reads rust-lang news")].into_iter().collect();
+        let document = serde_cbor::to_vec(&document).unwrap();
+
+        let entries = search_entries(&key, &document, &["bio".to_string()]).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert!(entries.contains(&search_token(&key, "bio", "RUST")));
+        assert!(!entries.contains(&search_token(&key, "bio", "python")));
+
+        let value = serde_cbor::Value::Text("rust".to_string());
+        assert_ne!(
+            search_token(&key, "bio", "rust"),
+            index_token(&key, "bio", &value).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decrypt_in_place_matches_basic_decrypt() {
+        let key = EncKey::from_bytes([5; 32]);
+        let nonce = [6; 12];
+        let plaintext: Vec<u8> = (0..=255).collect();
+        let ciphertext = basic_encrypt(&key, &nonce, &plaintext, b"aad").unwrap();
+
+        let mut buffer = ciphertext.clone();
+        let capacity = buffer.capacity();
+        decrypt_in_place(&key, &nonce, &mut buffer, b"aad").unwrap();
+        assert_eq!(buffer, basic_decrypt(&key, &nonce, &ciphertext, b"aad").unwrap());
+        assert_eq!(buffer.capacity(), capacity);
+
+        let mut tampered = ciphertext;
+        tampered[0] ^= 1;
+        assert!(decrypt_in_place(&key, &nonce, &mut tampered, b"aad").is_err());
+    }
+
+    #[test]
+    fn test_detached_and_combined_modes_interoperate() {
+        let key = EncKey::from_bytes([5; 32]);
+        let nonce = [6; 12];
+        let plaintext: Vec<u8> = (0..=255).collect();
+        let combined = basic_encrypt(&key, &nonce, &plaintext, b"aad").unwrap();
+
+        let (ciphertext, tag) =
+            encrypt_detached(&key, &nonce, &plaintext, b"aad").unwrap();
+        assert_eq!(ciphertext.len(), plaintext.len());
+        assert_eq!([&ciphertext[..], &tag[..]].concat(), combined);
+
+        let (ciphertext, tag) = combined.split_at(plaintext.len());
+        let tag: [u8; 16] = tag.try_into().unwrap();
+        let decrypted = decrypt_detached(&key, &nonce, ciphertext, &tag, b"aad");
+        assert_eq!(decrypted.unwrap(), plaintext);
+
+        let mut tampered = tag;
+        tampered[0] ^= 1;
+        assert!(decrypt_detached(&key, &nonce, ciphertext, &tampered, b"aad").is_err());
+        assert!(decrypt_detached(&key, &nonce, ciphertext, &tag, b"other").is_err());
+    }
+
+    #[test]
+    fn test_empty_plaintext_and_associated_data_round_trip() {
+        let (key, nonce) = (EncKey::from_bytes([3; 32]), [4; 12]);
+        let ciphertext = basic_encrypt(&key, &nonce, &[], &[]).unwrap();
+        // Only the tag is left of an empty plaintext, and it still authenticates.
+        assert_eq!(ciphertext.len(), 16);
+        assert_eq!(
+            basic_decrypt(&key, &nonce, &ciphertext, &[]).unwrap(),
+            Vec::<u8>::new()
+        );
+        assert!(basic_decrypt(&key, &nonce, &ciphertext, &[0]).is_err());
+        assert!(basic_decrypt(&key, &nonce, &[], &[]).is_err());
+    }
+
+    mod properties {
+        use proptest::{collection::vec, prelude::*, sample::Index};
+
+        use super::*;
+
+        // Plaintexts and associated data start empty, vectors shrink towards fewer and
+        // smaller bytes and indexes towards the first byte, so a failure is reported on
+        // the smallest input showing it.
+        fn bytes(max_length: usize) -> impl Strategy<Value = Vec<u8>> {
+            vec(any::<u8>(), 0..=max_length)
+        }
+
+        proptest! {
+            #[test]
+            fn test_decrypt_inverts_encrypt(
+                key in any::<[u8; 32]>().prop_map(EncKey::from_bytes),
+                nonce in any::<[u8; 12]>(),
+                plaintext in bytes(1024),
+                associated_data in bytes(64),
+            ) {
+                let ciphertext =
+                    basic_encrypt(&key, &nonce, &plaintext, &associated_data).unwrap();
+                prop_assert_eq!(ciphertext.len(), plaintext.len() + 16);
+                let decrypted =
+                    basic_decrypt(&key, &nonce, &ciphertext, &associated_data).unwrap();
+                prop_assert_eq!(decrypted, plaintext);
+            }
+
+            #[test]
+            fn test_tampered_ciphertext_fails_to_decrypt(
+                key in any::<[u8; 32]>().prop_map(EncKey::from_bytes),
+                nonce in any::<[u8; 12]>(),
+                plaintext in bytes(1024),
+                associated_data in bytes(64),
+                position in any::<Index>(),
+                flipped_bits in 1..=u8::MAX,
+            ) {
+                let mut ciphertext =
+                    basic_encrypt(&key, &nonce, &plaintext, &associated_data).unwrap();
+                let position = position.index(ciphertext.len());
+                ciphertext[position] ^= flipped_bits;
+                prop_assert!(
+                    basic_decrypt(&key, &nonce, &ciphertext, &associated_data).is_err()
+                );
+            }
+
+            #[test]
+            fn test_tampered_associated_data_fails_to_decrypt(
+                key in any::<[u8; 32]>().prop_map(EncKey::from_bytes),
+                nonce in any::<[u8; 12]>(),
+                plaintext in bytes(1024),
+                associated_data in bytes(64),
+                position in any::<Index>(),
+                flipped_bits in 1..=u8::MAX,
+            ) {
+                let ciphertext =
+                    basic_encrypt(&key, &nonce, &plaintext, &associated_data).unwrap();
+                let mut tampered = associated_data.clone();
+                match tampered.len() {
+                    0 => tampered.push(flipped_bits),
+                    length => tampered[position.index(length)] ^= flipped_bits,
+                }
+                prop_assert!(basic_decrypt(&key, &nonce, &ciphertext, &tampered).is_err());
+            }
+        }
+    }
 }
@@ -0,0 +1,232 @@
+//! Strategies producing the nonces of the documents encrypted by the client.
+//!
+//! AES-GCM-SIV tolerates an accidental nonce reuse, but a repeated nonce still reveals that
+//! two documents are equal. A counter never repeats a nonce as long as it is never reset,
+//! which `PersistentCounterNonce` guarantees across restarts by persisting it.
+
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{AesError, Error},
+    generate_nonce,
+};
+
+/// Default number of nonces reserved by a single write of the counter file.
+pub const DEFAULT_NONCE_BATCH: u64 = 1024;
+
+/// Produces a fresh 12-byte nonce for every encryption.
+pub trait NonceStrategy {
+    /// Returns a nonce never returned before for the same key.
+    fn next_nonce(&mut self) -> Result<[u8; 12], Error>;
+}
+
+/// Draws every nonce from the operating system random number generator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomNonce;
+
+impl NonceStrategy for RandomNonce {
+    fn next_nonce(&mut self) -> Result<[u8; 12], Error> {
+        Ok(generate_nonce())
+    }
+}
+
+/// Counter based nonces, persisted to a file so they keep increasing across restarts.
+///
+/// The file holds the big endian value of the first counter not reserved yet. Counters
+/// are reserved by batches: the file is bumped past a whole batch before any counter of
+/// it is used, so a crash only skips the unused end of a batch and never reuses a value.
+/// The file is replaced by writing a temporary file and renaming it over it, so it is
+/// never left half written.
+///
+/// A nonce is four zero bytes followed by the big endian counter. Use one file per key,
+/// and never share a file between two processes.
+#[derive(Debug)]
+pub struct PersistentCounterNonce {
+    path: PathBuf,
+    next: u64,
+    reserved_end: u64,
+    batch: u64,
+}
+
+impl PersistentCounterNonce {
+    /// Loads the counter from its file, starting from zero if the file does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file persisting the counter.
+    /// * `batch` - The number of counters reserved by a write of the file, at least 1.
+    pub fn open<P: AsRef<Path>>(path: P, batch: u64) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let next = read_counter(&path)?;
+        Ok(Self {
+            path,
+            next,
+            reserved_end: next,
+            batch: batch.max(1),
+        })
+    }
+
+    /// Returns the first counter the next reservation would hand out.
+    pub fn persisted(&self) -> u64 {
+        self.reserved_end
+    }
+
+    /// Reserves `count` counters for the caller to use in memory, with a single write.
+    ///
+    /// The file is bumped past the range before it is returned, so a crash never reuses
+    /// a counter of the range, used or not. The counters left in the batch of
+    /// `next_nonce` are skipped, the next nonce starting a batch after the range. Turn
+    /// the counters of the range into nonces with `counter_nonce`.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of counters to reserve, at least 1.
+    pub fn reserve_nonce_range(
+        &mut self,
+        count: u64,
+    ) -> Result<RangeInclusive<u64>, Error> {
+        if count == 0 {
+            let err = io::Error::new(ErrorKind::InvalidInput, "empty nonce range");
+            return Err(err.into());
+        }
+        self.reserve(count)?;
+        let range = self.next..=self.reserved_end - 1;
+        self.next = self.reserved_end;
+        Ok(range)
+    }
+
+    /// Reserves `count` counters by persisting the end of the range before using it.
+    fn reserve(&mut self, count: u64) -> Result<(), Error> {
+        let end = self
+            .reserved_end
+            .checked_add(count)
+            .ok_or(Error::encryption(AesError::NonceExhausted))?;
+        write_counter(&self.path, end)?;
+        self.next = self.reserved_end;
+        self.reserved_end = end;
+        Ok(())
+    }
+}
+
+impl NonceStrategy for PersistentCounterNonce {
+    fn next_nonce(&mut self) -> Result<[u8; 12], Error> {
+        if self.next == self.reserved_end {
+            self.reserve(self.batch)?;
+        }
+        let counter = self.next;
+        self.next += 1;
+        Ok(counter_nonce(counter))
+    }
+}
+
+/// Returns the nonce of a counter, four zero bytes followed by the big endian counter.
+pub fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn read_counter(path: &Path) -> io::Result<u64> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut counter = [0; 8];
+    file.read_exact(&mut counter).map_err(|_| {
+        io::Error::new(ErrorKind::InvalidData, "malformed nonce counter file")
+    })?;
+    Ok(u64::from_be_bytes(counter))
+}
+
+fn write_counter(path: &Path, counter: u64) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = File::create(&temporary)?;
+    file.write_all(&counter.to_be_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    if let Some(directory) = path.parent().filter(|parent| !parent.as_os_str().is_empty())
+    {
+        // Makes the rename itself durable, where directories can be opened.
+        if let Ok(directory) = File::open(directory) {
+            directory.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter_path() -> PathBuf {
+        std::env::temp_dir().join(format!("liserk-nonce-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn counter_of(nonce: [u8; 12]) -> u64 {
+        assert_eq!(nonce[..4], [0; 4]);
+        u64::from_be_bytes(nonce[4..].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_counter_nonces_increase() {
+        let path = counter_path();
+        let mut nonces = PersistentCounterNonce::open(&path, 2).unwrap();
+        let counters: Vec<u64> =
+            (0..5).map(|_| counter_of(nonces.next_nonce().unwrap())).collect();
+        assert_eq!(counters, vec![0, 1, 2, 3, 4]);
+        assert_eq!(nonces.persisted(), 6);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_continues_from_the_persisted_value() {
+        let path = counter_path();
+        let mut nonces = PersistentCounterNonce::open(&path, 10).unwrap();
+        for _ in 0..3 {
+            nonces.next_nonce().unwrap();
+        }
+        drop(nonces);
+
+        // The unused end of the first batch is skipped, never handed out again.
+        let mut reloaded = PersistentCounterNonce::open(&path, 10).unwrap();
+        assert_eq!(counter_of(reloaded.next_nonce().unwrap()), 10);
+        assert_eq!(read_counter(&path).unwrap(), 20);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reserved_ranges_never_overlap() {
+        let path = counter_path();
+        let mut nonces = PersistentCounterNonce::open(&path, 4).unwrap();
+        assert_eq!(counter_of(nonces.next_nonce().unwrap()), 0);
+        let first = nonces.reserve_nonce_range(100).unwrap();
+        let second = nonces.reserve_nonce_range(50).unwrap();
+        assert_eq!((first.clone(), second.clone()), (4..=103, 104..=153));
+        assert_eq!(counter_of(nonces.next_nonce().unwrap()), 154);
+        assert!(nonces.reserve_nonce_range(0).is_err());
+        drop(nonces);
+
+        // After a crash, the reserved ranges are skipped even though none was used.
+        let mut reloaded = PersistentCounterNonce::open(&path, 4).unwrap();
+        let after = reloaded.reserve_nonce_range(10).unwrap();
+        assert!(*after.start() > *second.end());
+        assert_eq!(counter_of(counter_nonce(*after.start())), 158);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_counter_file_is_refused() {
+        let path = counter_path();
+        fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(PersistentCounterNonce::open(&path, 10).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
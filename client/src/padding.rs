@@ -0,0 +1,103 @@
+//! Padding hiding the length of plaintexts.
+//!
+//! AES-GCM-SIV ciphertexts are as long as their plaintext plus the tag, so small structured
+//! documents can be told apart by their size. A padded plaintext is the big endian `u32`
+//! length of the plaintext, the plaintext, then zeros up to the size chosen by the
+//! `PaddingMode`. The length is encrypted with the rest, so it is hidden and authenticated.
+
+use crate::error::{AesError, Error};
+
+/// Length of the field recording the plaintext length.
+const LENGTH_FIELD: usize = 4;
+
+/// How far plaintexts are padded before encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingMode {
+    /// No padding, the ciphertext reveals the plaintext length.
+    #[default]
+    None,
+
+    /// Pads to the next power of two, revealing the length only up to a factor of two.
+    PowerOfTwo,
+
+    /// Pads to the next multiple of the block size, in bytes.
+    FixedBlock(usize),
+}
+
+/// Pads a plaintext according to the mode, see the module documentation for the layout.
+///
+/// `PaddingMode::None` returns the plaintext unchanged, without length field.
+pub fn pad(plaintext: &[u8], mode: PaddingMode) -> Result<Vec<u8>, Error> {
+    let length = plaintext.len() + LENGTH_FIELD;
+    let padded_length = match mode {
+        PaddingMode::None => return Ok(plaintext.to_vec()),
+        PaddingMode::PowerOfTwo => length.next_power_of_two(),
+        PaddingMode::FixedBlock(block) if block > 0 => length.div_ceil(block) * block,
+        PaddingMode::FixedBlock(_) => return Err(Error::encryption(AesError::Padding)),
+    };
+    let plaintext_length = u32::try_from(plaintext.len())
+        .map_err(|_| Error::encryption(AesError::Padding))?;
+
+    let mut padded = Vec::with_capacity(padded_length);
+    padded.extend_from_slice(&plaintext_length.to_be_bytes());
+    padded.extend_from_slice(plaintext);
+    padded.resize(padded_length, 0);
+    Ok(padded)
+}
+
+/// Strips the padding added by `pad` with a mode other than `PaddingMode::None`.
+pub fn unpad(padded: &[u8]) -> Result<Vec<u8>, Error> {
+    if padded.len() < LENGTH_FIELD {
+        return Err(Error::encryption(AesError::Padding));
+    }
+    let (length, rest) = padded.split_at(LENGTH_FIELD);
+    let length =
+        u32::from_be_bytes(length.try_into().expect("split at the length field"));
+    rest.get(..length as usize)
+        .map(|plaintext| plaintext.to_vec())
+        .ok_or(Error::encryption(AesError::Padding))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_encrypt, keys::EncKey};
+
+    #[test]
+    fn test_fixed_block_hides_length() {
+        let key = EncKey::from_bytes([1; 32]);
+        let nonce = [2; 12];
+        let short = pad(b"yes", PaddingMode::FixedBlock(64)).unwrap();
+        let long = pad(b"a somewhat longer answer", PaddingMode::FixedBlock(64)).unwrap();
+
+        let short = basic_encrypt(&key, &nonce, &short, &[]).unwrap();
+        let long = basic_encrypt(&key, &nonce, &long, &[]).unwrap();
+        assert_eq!(short.len(), long.len());
+    }
+
+    #[test]
+    fn test_padding_round_trip() {
+        for mode in [PaddingMode::PowerOfTwo, PaddingMode::FixedBlock(16)] {
+            for plaintext in [&b""[..], b"a", &[7; 100]] {
+                let padded = pad(plaintext, mode).unwrap();
+                assert_eq!(unpad(&padded).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn test_padded_sizes() {
+        assert_eq!(pad(&[0; 4], PaddingMode::PowerOfTwo).unwrap().len(), 8);
+        assert_eq!(pad(&[0; 5], PaddingMode::PowerOfTwo).unwrap().len(), 16);
+        assert_eq!(pad(&[0; 12], PaddingMode::FixedBlock(16)).unwrap().len(), 16);
+        assert_eq!(pad(&[0; 13], PaddingMode::FixedBlock(16)).unwrap().len(), 32);
+        assert_eq!(pad(&[0; 13], PaddingMode::None).unwrap().len(), 13);
+    }
+
+    #[test]
+    fn test_invalid_padding_is_refused() {
+        assert!(pad(b"a", PaddingMode::FixedBlock(0)).is_err());
+        assert!(unpad(&[0, 0]).is_err());
+        assert!(unpad(&[0, 0, 0, 9, 1, 2]).is_err());
+    }
+}
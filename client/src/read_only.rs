@@ -0,0 +1,220 @@
+//! A client statically limited to reading, for services that must never change data.
+//!
+//! `AuthenticatedClient::into_read_only` turns a client into a `ReadOnlyClient`, which
+//! only exposes the requests reading documents. It also makes the session read-only on
+//! the server, which refuses any mutation of the session with a
+//! `ServerError::ReadOnlySession`, again after a reconnection.
+//!
+//! Mutations do not compile on a read-only client:
+//!
+//! ```compile_fail
+//! # use liserk_client::read_only::ReadOnlyClient;
+//! async fn remove(client: &mut ReadOnlyClient) {
+//!     client.delete("1".to_string(), "users".to_string()).await.unwrap();
+//! }
+//! ```
+//!
+//! ```
+//! # use liserk_client::read_only::ReadOnlyClient;
+//! # use liserk_shared::query::Query;
+//! async fn read(client: &mut ReadOnlyClient) {
+//!     let query = Query::GetById { id: "1".into(), collection: "users".into() };
+//!     client.query(query).await.unwrap();
+//! }
+//! ```
+
+use std::{io::Write, ops::ControlFlow, time::Duration};
+
+use futures::Stream;
+use liserk_shared::{
+    audit::{AuditEntry, AuditFilter},
+    message::DocumentMeta,
+    plan::QueryPlan,
+    query::Query,
+};
+
+use crate::{
+    chunked::StreamVerifyMode,
+    error::Error,
+    stream::{
+        AuthenticatedClient, Document, DocumentResult, QueryCursor, QueryPage,
+        QueryResult,
+    },
+};
+
+/// An `AuthenticatedClient` on a read-only session, exposing only its reading requests.
+#[derive(Debug)]
+pub struct ReadOnlyClient {
+    client: AuthenticatedClient,
+}
+
+impl ReadOnlyClient {
+    pub(crate) fn new(client: AuthenticatedClient) -> Self {
+        Self { client }
+    }
+
+    /// See `AuthenticatedClient::username`.
+    pub fn username(&self) -> &str {
+        self.client.username()
+    }
+
+    /// See `AuthenticatedClient::is_alive`.
+    pub fn is_alive(&self) -> bool {
+        self.client.is_alive()
+    }
+
+    /// See `AuthenticatedClient::reconnect`.
+    pub async fn reconnect(&mut self) -> Result<(), Error> {
+        self.client.reconnect().await
+    }
+
+    /// See `AuthenticatedClient::ping`.
+    pub async fn ping(&mut self) -> Result<Duration, Error> {
+        self.client.ping().await
+    }
+
+    /// See `AuthenticatedClient::flush`.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.client.flush().await
+    }
+
+    /// See `AuthenticatedClient::close`.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.client.close().await
+    }
+
+    /// See `AuthenticatedClient::query`.
+    pub async fn query(&mut self, query: Query) -> Result<QueryResult, Error> {
+        self.client.query(query).await
+    }
+
+    /// See `AuthenticatedClient::query_many`.
+    pub async fn query_many(
+        &mut self,
+        queries: Vec<Query>,
+    ) -> Result<Vec<QueryResult>, Error> {
+        self.client.query_many(queries).await
+    }
+
+    /// See `AuthenticatedClient::query_documents`.
+    pub async fn query_documents(
+        &mut self,
+        query: Query,
+    ) -> Result<Vec<DocumentResult>, Error> {
+        self.client.query_documents(query).await
+    }
+
+    /// See `AuthenticatedClient::get_document`.
+    pub async fn get_document(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Document>, Error> {
+        self.client.get_document(collection, id).await
+    }
+
+    /// See `AuthenticatedClient::clear_document_cache`.
+    pub fn clear_document_cache(&mut self) {
+        self.client.clear_document_cache()
+    }
+
+    /// See `AuthenticatedClient::query_stream`.
+    pub async fn query_stream(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.client.query_stream(collection, id).await
+    }
+
+    /// See `AuthenticatedClient::download_to`.
+    pub async fn download_to<W: Write>(
+        &mut self,
+        collection: String,
+        id: String,
+        writer: W,
+        mode: StreamVerifyMode,
+    ) -> Result<Option<u64>, Error> {
+        self.client.download_to(collection, id, writer, mode).await
+    }
+
+    /// See `AuthenticatedClient::scan_collection`.
+    pub fn scan_collection<'a>(
+        &'a mut self,
+        collection: &str,
+    ) -> impl Stream<Item = Result<Document, Error>> + 'a {
+        self.client.scan_collection(collection)
+    }
+
+    /// See `AuthenticatedClient::explain`.
+    pub async fn explain(&mut self, query: Query) -> Result<QueryPlan, Error> {
+        self.client.explain(query).await
+    }
+
+    /// See `AuthenticatedClient::fetch_audit_log`.
+    pub async fn fetch_audit_log(
+        &mut self,
+        filter: AuditFilter,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        self.client.fetch_audit_log(filter).await
+    }
+
+    /// See `AuthenticatedClient::describe_document`.
+    pub async fn describe_document(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<DocumentMeta, Error> {
+        self.client.describe_document(collection, id).await
+    }
+
+    /// See `AuthenticatedClient::verify_key_for_document`.
+    pub async fn verify_key_for_document(
+        &mut self,
+        key: &[u8; 32],
+        collection: String,
+        id: String,
+        associated_data: &[u8],
+    ) -> Result<bool, Error> {
+        self.client
+            .verify_key_for_document(key, collection, id, associated_data)
+            .await
+    }
+
+    /// See `AuthenticatedClient::collection_stats`.
+    pub async fn collection_stats(&mut self) -> Result<Vec<(String, usize)>, Error> {
+        self.client.collection_stats().await
+    }
+
+    /// See `AuthenticatedClient::open_cursor`.
+    pub async fn open_cursor(
+        &mut self,
+        query: Query,
+        page_size: u32,
+    ) -> Result<QueryPage, Error> {
+        self.client.open_cursor(query, page_size).await
+    }
+
+    /// See `AuthenticatedClient::next_page`.
+    pub async fn next_page(&mut self, cursor: QueryCursor) -> Result<QueryPage, Error> {
+        self.client.next_page(cursor).await
+    }
+
+    /// See `AuthenticatedClient::close_cursor`.
+    pub async fn close_cursor(&mut self, cursor: QueryCursor) -> Result<(), Error> {
+        self.client.close_cursor(cursor).await
+    }
+
+    /// See `AuthenticatedClient::query_each`.
+    pub async fn query_each<F>(
+        &mut self,
+        query: Query,
+        page_size: u32,
+        on_document: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Vec<u8>) -> ControlFlow<()>,
+    {
+        self.client.query_each(query, page_size, on_document).await
+    }
+}
@@ -0,0 +1,87 @@
+//! Source of the random bytes of the keys and nonces generated by the client.
+//!
+//! Bytes come from the operating system random number generator. With the `test-rng`
+//! feature, a thread can seed a deterministic generator with `seed_test_rng`, making the
+//! keys and random nonces it generates reproducible for round trip and vector tests. The
+//! seeded generator is not cryptographically secure and must never be used in production.
+
+#[cfg(feature = "test-rng")]
+use std::cell::RefCell;
+
+#[cfg(feature = "test-rng")]
+thread_local! {
+    static TEST_RNG: RefCell<Option<SplitMix64>> = RefCell::new(None);
+}
+
+/// Fills `bytes` with random bytes, from the seeded generator of the thread if any.
+pub(crate) fn fill_random(bytes: &mut [u8]) {
+    #[cfg(feature = "test-rng")]
+    if TEST_RNG
+        .with(|rng| rng.borrow_mut().as_mut().map(|rng| rng.fill(bytes)))
+        .is_some()
+    {
+        return;
+    }
+    getrandom::getrandom(bytes).expect("Error generating random bytes");
+}
+
+/// Makes the random bytes of the current thread come from a generator seeded with `seed`.
+#[cfg(feature = "test-rng")]
+pub fn seed_test_rng(seed: u64) {
+    TEST_RNG.with(|rng| *rng.borrow_mut() = Some(SplitMix64(seed)));
+}
+
+/// Makes the random bytes of the current thread come from the operating system again.
+#[cfg(feature = "test-rng")]
+pub fn clear_test_rng() {
+    TEST_RNG.with(|rng| *rng.borrow_mut() = None);
+}
+
+/// The SplitMix64 generator, small and with a well known output for a seed.
+#[cfg(feature = "test-rng")]
+struct SplitMix64(u64);
+
+#[cfg(feature = "test-rng")]
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut value = self.0;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^ (value >> 31)
+    }
+
+    fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let value = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test-rng"))]
+mod tests {
+    use super::*;
+    use crate::{generate_key, generate_nonce};
+
+    #[test]
+    fn test_fixed_seed_yields_a_fixed_key() {
+        seed_test_rng(42);
+        assert_eq!(
+            generate_key(),
+            [
+                149, 110, 235, 47, 38, 50, 215, 189, 3, 241, 102, 178, 51, 227, 239, 40,
+                82, 159, 15, 19, 87, 103, 82, 71, 148, 227, 74, 14, 255, 225, 28, 88
+            ]
+        );
+        assert_eq!(generate_nonce(), [242, 35, 72, 36, 90, 88, 188, 9, 6, 219, 128, 60]);
+
+        seed_test_rng(42);
+        let key = generate_key();
+        seed_test_rng(43);
+        assert_ne!(generate_key(), key);
+
+        clear_test_rng();
+        assert_ne!(generate_key(), generate_key());
+    }
+}
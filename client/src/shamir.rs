@@ -0,0 +1,208 @@
+//! Shamir's secret sharing of master keys, so that no single party holds a whole key.
+//!
+//! `split_key` splits a key into shares, any `threshold` of which rebuild it with
+//! `combine_shares`, while fewer tell nothing about it. Each byte of the key is the
+//! constant term of its own random polynomial of degree `threshold - 1` over GF(2^8),
+//! and a share holds the values of these polynomials at its index. A share records the
+//! threshold it was made with, so combining too few shares fails instead of silently
+//! rebuilding a wrong key. The values of a share are zeroized when it is dropped.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::{error::Error, rng::fill_random};
+
+/// One share of a key, see `split_key`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Share {
+    /// The point the polynomials are evaluated at, from 1 up.
+    pub index: u8,
+
+    /// The number of shares needed to rebuild the key.
+    pub threshold: u8,
+
+    /// The values of the polynomials of the bytes of the key at `index`.
+    pub values: [u8; 32],
+}
+
+/// Error while splitting a key or combining its shares.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ShareError {
+    #[error("a threshold of {threshold} is not between 1 and the {shares} shares")]
+    InvalidThreshold { threshold: u8, shares: u8 },
+
+    #[error("{got} shares cannot rebuild a key split with a threshold of {threshold}")]
+    NotEnoughShares { got: usize, threshold: u8 },
+
+    #[error("the shares were made with different thresholds")]
+    MismatchedThresholds,
+
+    #[error("the share index {0} is zero or given twice")]
+    InvalidIndex(u8),
+}
+
+/// Splits a key into `shares` shares, any `threshold` of which rebuild it.
+///
+/// Shares are indexed from 1 to `shares`. A threshold of 1 makes every share a copy of
+/// the key.
+pub fn split_key(key: &[u8; 32], threshold: u8, shares: u8) -> Result<Vec<Share>, Error> {
+    if threshold == 0 || threshold > shares {
+        return Err(ShareError::InvalidThreshold { threshold, shares }.into());
+    }
+    let mut coefficients = vec![[0; 32]; threshold as usize];
+    coefficients[0] = *key;
+    for coefficient in &mut coefficients[1..] {
+        fill_random(coefficient);
+    }
+    let shares = (1..=shares)
+        .map(|index| {
+            let mut values = [0; 32];
+            for (byte, value) in values.iter_mut().enumerate() {
+                // Horner's method, from the coefficient of the highest degree.
+                *value = coefficients
+                    .iter()
+                    .rev()
+                    .fold(0, |sum, coefficient| mul(sum, index) ^ coefficient[byte]);
+            }
+            Share { index, threshold, values }
+        })
+        .collect();
+    coefficients.zeroize();
+    Ok(shares)
+}
+
+/// Rebuilds a key from at least the threshold of its shares.
+///
+/// Shares of different splits with the same threshold cannot be told apart, combining
+/// them rebuilds a wrong key.
+pub fn combine_shares(shares: &[Share]) -> Result<[u8; 32], Error> {
+    let threshold = shares.first().map_or(1, |share| share.threshold);
+    if shares.len() < threshold as usize {
+        return Err(ShareError::NotEnoughShares { got: shares.len(), threshold }.into());
+    }
+    for (position, share) in shares.iter().enumerate() {
+        if share.threshold != threshold {
+            return Err(ShareError::MismatchedThresholds.into());
+        }
+        if share.index == 0 || shares[..position].iter().any(|s| s.index == share.index) {
+            return Err(ShareError::InvalidIndex(share.index).into());
+        }
+    }
+    let mut key = [0; 32];
+    for share in shares {
+        // The Lagrange basis polynomial of the share, evaluated at 0.
+        let basis = shares.iter().filter(|other| other.index != share.index).fold(
+            1,
+            |basis, other| {
+                mul(basis, mul(other.index, inverse(other.index ^ share.index)))
+            },
+        );
+        for (byte, value) in key.iter_mut().zip(share.values) {
+            *byte ^= mul(value, basis);
+        }
+    }
+    Ok(key)
+}
+
+/// Multiplies in GF(2^8) modulo the polynomial of AES, without branching on the values.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+/// Inverts a non-zero element of GF(2^8), as its power 254.
+fn inverse(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    for bit in 0..8 {
+        if (254 >> bit) & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+    }
+    result
+}
+
+impl fmt::Debug for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Share")
+            .field("index", &self.index)
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.values.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_key;
+
+    #[test]
+    fn test_any_threshold_of_shares_rebuilds_the_key() {
+        let key = generate_key();
+        let shares = split_key(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        for subset in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset: Vec<Share> = subset.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine_shares(&subset).unwrap(), key);
+        }
+        assert_eq!(combine_shares(&shares).unwrap(), key);
+        assert_eq!(split_key(&key, 1, 1).unwrap()[0].values, key);
+    }
+
+    #[test]
+    fn test_fewer_shares_than_the_threshold_are_refused() {
+        let key = generate_key();
+        let shares = split_key(&key, 3, 5).unwrap();
+        assert!(matches!(
+            combine_shares(&shares[..2]),
+            Err(Error::InvalidShares(ShareError::NotEnoughShares {
+                got: 2,
+                threshold: 3
+            }))
+        ));
+        assert!(matches!(
+            combine_shares(&[]),
+            Err(Error::InvalidShares(ShareError::NotEnoughShares { got: 0, .. }))
+        ));
+        let repeated = [shares[0].clone(), shares[1].clone(), shares[0].clone()];
+        assert!(matches!(
+            combine_shares(&repeated),
+            Err(Error::InvalidShares(ShareError::InvalidIndex(1)))
+        ));
+    }
+
+    #[test]
+    fn test_threshold_must_be_between_one_and_the_shares() {
+        let key = [7; 32];
+        for (threshold, shares) in [(0, 3), (4, 3), (1, 0)] {
+            let refused = split_key(&key, threshold, shares).unwrap_err();
+            assert!(matches!(
+                refused,
+                Error::InvalidShares(ShareError::InvalidThreshold { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_field_inverse() {
+        assert_eq!(mul(0x53, 0xca), 1);
+        for a in 1..=255 {
+            assert_eq!(mul(a, inverse(a)), 1, "{}", a);
+        }
+    }
+}
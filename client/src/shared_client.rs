@@ -0,0 +1,269 @@
+//! A cloneable handle sharing one authenticated connection between tasks.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use liserk_shared::{
+    message::{Delete, Message},
+    query::Query,
+};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    error::Error,
+    stream::{
+        delete_answer, health_answer, inserted_id, update_answer, AuthenticatedClient,
+        QueryResult, SentRequest,
+    },
+};
+
+/// An `AuthenticatedClient` usable from several tasks at once.
+///
+/// Clones share the same connection. The requests of concurrent tasks are in flight
+/// together: the connection is only held to write a request and to read one frame at a
+/// time, and each response is matched by its request id, so it reaches the task that
+/// sent the request whatever the order the server answers in.
+///
+/// A request whose future is dropped before its response is read leaves that response
+/// pending on the connection until it is closed.
+#[derive(Debug, Clone)]
+pub struct SharedClient {
+    client: Arc<Mutex<AuthenticatedClient>>,
+}
+
+impl From<AuthenticatedClient> for SharedClient {
+    fn from(client: AuthenticatedClient) -> Self {
+        Self { client: Arc::new(Mutex::new(client)) }
+    }
+}
+
+impl SharedClient {
+    /// Waits for exclusive access to the connection, to run several requests in a row
+    /// or any request without a shortcut here.
+    pub async fn lock(&self) -> MutexGuard<'_, AuthenticatedClient> {
+        self.client.lock().await
+    }
+
+    /// Sends the request made by `prepare` and waits for its response without holding
+    /// the connection, returning it with what `prepare` kept to read it.
+    async fn round_trip<C>(
+        &self,
+        prepare: impl FnOnce(&mut AuthenticatedClient) -> Result<(Message, C), Error>,
+    ) -> Result<(SentRequest, Message, C), Error> {
+        let (request, context, wait) = {
+            let mut client = self.lock().await;
+            let (message, context) = prepare(&mut *client)?;
+            let request = client.send(message).await?;
+            client.flush().await?;
+            (request, context, client.response_wait())
+        };
+        match wait.wait(request).await {
+            Ok(message) => Ok((request, message, context)),
+            Err(err) => Err(self.lock().await.settle(&wait, err).await),
+        }
+    }
+
+    /// See `AuthenticatedClient::insert`.
+    pub async fn insert(
+        &self,
+        collection: String,
+        data: Vec<u8>,
+        associated_data: Vec<u8>,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+    ) -> Result<String, Error> {
+        let (request, message, (collection, id)) = self
+            .round_trip(|client| {
+                let insertion = client.insertion(
+                    collection,
+                    data,
+                    &associated_data,
+                    acl,
+                    usecases,
+                    Vec::new(),
+                )?;
+                let context = (insertion.collection.clone(), insertion.id.clone());
+                Ok((Message::Insert(insertion), context))
+            })
+            .await?;
+        inserted_id(request, collection, id, message)
+    }
+
+    /// See `AuthenticatedClient::query`.
+    pub async fn query(&self, query: Query) -> Result<QueryResult, Error> {
+        let (request, message, decryption) = self
+            .round_trip(|client| {
+                let decryption = client.query_decryption(&query)?;
+                Ok((Message::Query(query), decryption))
+            })
+            .await?;
+        self.lock().await.decrypt_query(request, decryption, message)
+    }
+
+    /// See `AuthenticatedClient::query_many`.
+    pub async fn query_many(
+        &self,
+        queries: Vec<Query>,
+    ) -> Result<Vec<QueryResult>, Error> {
+        let (request, message, decryptions) = self
+            .round_trip(|client| {
+                let decryptions = queries
+                    .iter()
+                    .map(|query| client.query_decryption(query))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok((Message::QueryBatch(queries), decryptions))
+            })
+            .await?;
+        self.lock().await.decrypt_query_batch(request, decryptions, message)
+    }
+
+    /// See `AuthenticatedClient::modify`.
+    pub async fn modify(
+        &self,
+        id: String,
+        collection: String,
+        new_value: Vec<u8>,
+    ) -> Result<Message, Error> {
+        let (_, message, ()) = self
+            .round_trip(|client| {
+                let update =
+                    client.update_request(id, collection, new_value, &[], None)?;
+                Ok((update, ()))
+            })
+            .await?;
+        update_answer(message)
+    }
+
+    /// See `AuthenticatedClient::delete`.
+    pub async fn delete(&self, id: String, collection: String) -> Result<Message, Error> {
+        let delete = Delete { collection, id, tombstone: false };
+        let (_, message, ()) = self
+            .round_trip(|client| Ok((client.delete_request(delete), ())))
+            .await?;
+        delete_answer(message)
+    }
+
+    /// See `AuthenticatedClient::ping`, timed from the moment the request is sent.
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        let (request, message, sent_at) = self
+            .round_trip(|_| Ok((Message::HealthCheck, Instant::now())))
+            .await?;
+        health_answer(request, message)?;
+        Ok(sent_at.elapsed())
+    }
+
+    /// Closes the shared connection, for every clone.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.lock().await.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use liserk_shared::compression::Compression;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::builder::ClientBuilder;
+    use crate::stream::{
+        tests::{accept_authenticated, read_request},
+        UnconnectedClient,
+    };
+
+    #[test]
+    fn test_shared_client_can_move_between_tasks() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<SharedClient>();
+    }
+
+    #[tokio::test]
+    async fn test_requests_of_concurrent_tasks_are_in_flight_together() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+
+            // Both requests are read before either is answered, the second one first.
+            let (first, first_message) = read_request(&mut read, &tag_key).await.unwrap();
+            let (second, second_message) =
+                read_request(&mut read, &tag_key).await.unwrap();
+            for (request_id, message) in
+                [(second, second_message), (first, first_message)]
+            {
+                let response = match message {
+                    Message::HealthCheck => Message::HealthResponse,
+                    _ => Message::DeleteResult(true),
+                };
+                let frame = response.setup_for_network_as(request_id, Compression::None);
+                write.write_all(&frame.unwrap()).await.unwrap();
+            }
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let client = SharedClient::from(client);
+        let pinging = client.clone();
+        let ping = tokio::spawn(async move { pinging.ping().await });
+        let delete = client.delete("1".to_string(), "users".to_string());
+        let (ping, delete) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(ping, delete)
+        })
+        .await
+        .expect("a request waited for the response of the other one");
+
+        assert!(ping.unwrap().is_ok());
+        assert_eq!(delete.unwrap(), Message::DeleteResult(true));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_in_the_middle_of_a_frame_closes_the_other_waits() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (first, _) = read_request(&mut read, &tag_key).await.unwrap();
+            read_request(&mut read, &tag_key).await.unwrap();
+
+            // Only the start of the response to the first request is sent.
+            let frame =
+                Message::HealthResponse.setup_for_network_as(first, Compression::None);
+            write.write_all(&frame.unwrap()[..5]).await.unwrap();
+            let mut rest = Vec::new();
+            let _ = read.read_to_end(&mut rest).await;
+        });
+
+        let client = ClientBuilder::new()
+            .request_timeout(Duration::from_millis(200))
+            .build()
+            .connect(&address)
+            .await
+            .unwrap();
+        let client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let client = SharedClient::from(client);
+        let pinging = client.clone();
+        let ping = tokio::spawn(async move { pinging.ping().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let delete = client.delete("1".to_string(), "users".to_string());
+        let (ping, delete) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(ping, delete)
+        })
+        .await
+        .expect("a wait read the connection after another one timed out");
+
+        assert!(matches!(ping.unwrap(), Err(Error::Timeout { .. })));
+        assert!(matches!(delete, Err(Error::ConnectionClosed(_))));
+        server.await.unwrap();
+    }
+}
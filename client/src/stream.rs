@@ -1,71 +1,600 @@
+use futures::{stream, Stream, TryStreamExt};
 use liserk_ope::simplified_version::encrypt_ope;
 use liserk_shared::{
+    audit::{AuditEntry, AuditFilter},
+    auth::{challenge_mac, frame_key, AuthMechanism},
+    compression::{Compression, FrameError},
+    format::Format,
     message::{
-        ClientAuthentication, ClientSetupSecureConnection, Delete, Insertion,
-        InsertionOpe, Message, Update,
+        validate_insertion_names, ChunkRequest, ChunkedResponse, ClientAuthentication,
+        ClientSetupSecureConnection, Delete, DocumentMeta, FrameHeader, InsertChunk,
+        InsertStreamStart, Insertion, InsertionBuilder, InsertionOpe, Message,
+        MetadataUpdate, PlainInsertion, ServerError, SessionToken, StoredChunk,
+        StoredDocument, Update, UpdateStatus, MAX_FRAME_LEN,
     },
     message_type::{MessageType, MessageTypeError},
-    query::Query,
+    name::{validate_document_id, validate_name},
+    plan::QueryPlan,
+    query::{IndexEntry, Query, SingleQuery},
 };
-use rand::Rng;
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(unix)]
+use std::path::Path;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io::{Read, Write},
+    net::SocketAddr,
+    ops::ControlFlow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+    io::{
+        self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ErrorKind,
+        ReadHalf, WriteHalf,
     },
+    net::{lookup_host, TcpSocket, TcpStream},
+    sync::{mpsc::UnboundedSender, Mutex},
+    time::timeout,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{ClientConfig, RootCertStore, ServerName},
+    TlsConnector,
+};
+use tracing::{debug, info, instrument, trace, warn};
+use zeroize::Zeroize;
+
+use crate::{
+    bind_context,
+    builder::{ClientOptions, ReconnectJitter, Tls},
+    cache::DocumentCache,
+    chunked::{ChunkEncryptor, ChunkOpener, StreamVerifyMode, DEFAULT_CHUNK_SIZE},
+    decrypt_for_message, encrypt_for_message,
+    error::{AesError, Error},
+    events::{ClientEvent, EventSink},
+    generate_nonce, index_entries, index_token,
+    keys::{EncKey, MacKey},
+    read_only::ReadOnlyClient,
+    rng, search_entries, search_token,
 };
-use tracing::{debug, info, trace};
 
-use crate::{basic_decrypt, basic_encrypt, error::Error};
+/// Maximum time `AuthenticatedClient::close` waits for the server to acknowledge the close.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of keys read by the server for a page of `AuthenticatedClient::scan_collection`.
+pub const SCAN_PAGE_SIZE: u32 = 256;
+
+/// Attempts at reconnecting made by `AuthenticatedClient::query_each` once its connection
+/// is lost, before giving up.
+pub const STREAM_RECONNECT_ATTEMPTS: u32 = 3;
+
+/// Time waited before the first attempt at reconnecting, doubled after each failure,
+/// randomized by the `ReconnectJitter` of the client.
+pub const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Byte stream carrying the frames of a connection, a TCP or a Unix domain socket.
+///
+/// The framing is the same whatever the transport, see `liserk_shared::compression`.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Sync + Unpin + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin + fmt::Debug> Transport for T {}
+
+impl From<io::Error> for Error {
+    /// Sorts I/O errors by the state of the connection they reveal.
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Error::ConnectionRefused(err),
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+                Error::ConnectionReset(err)
+            }
+            ErrorKind::UnexpectedEof
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected => Error::ConnectionClosed(err),
+            _ => Error::TokioIoError(err),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum QueryResult {
     EmptyResult,
     SingleValue(Vec<u8>),
     MultipleValues(Vec<Vec<u8>>),
+    /// No document of the collection declares the usecase, only answered to queries
+    /// built with `SingleQueryBuilder::warn_unknown_usecase`.
+    UnknownUsecase {
+        collection: String,
+        usecase: String,
+    },
+    /// The documents read before the query exceeded the time budget of the server, only
+    /// answered to queries built with `SingleQueryBuilder::allow_partial_results`.
+    PartialValues(Vec<Vec<u8>>),
+}
+
+/// A decrypted document, with where it is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    pub collection: String,
+    pub id: String,
+
+    /// The decrypted document, OPE values are returned as stored.
+    pub data: Vec<u8>,
+}
+
+/// A document of a query result, or the id of the document and why it failed to decrypt.
+pub type DocumentResult = Result<Document, (String, Error)>;
+
+/// A page of the results of a query cursor.
+#[derive(Debug)]
+pub struct QueryPage {
+    /// The decrypted documents of the page, OPE values are returned as stored.
+    pub values: Vec<Vec<u8>>,
+
+    /// The cursor to pass to `AuthenticatedClient::next_page`, `None` after the last page.
+    pub cursor: Option<QueryCursor>,
+}
+
+/// Outcome of `AuthenticatedClient::rekey_collection`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RekeyReport {
+    /// Number of documents re-encrypted under the new key.
+    pub rekeyed: usize,
+
+    /// Number of documents left as they were, already under the new key or OPE values.
+    pub skipped: usize,
+
+    /// Ids of the documents no key of the keyring decrypts or whose update was refused.
+    pub failed: Vec<String>,
+}
+
+/// A query whose matching ids are cached by the server, read page by page.
+#[derive(Debug, Clone)]
+pub struct QueryCursor {
+    /// The identifier of the cursor on the server.
+    pub id: String,
+
+    /// Collection keys able to decrypt the documents of the query.
+    keys: Vec<EncKey>,
+
+    /// Query whose predicates are checked on every decrypted page.
+    filter: Option<SingleQuery>,
+}
+
+/// The server a client connected to and how, to open another connection after losing it.
+#[derive(Debug, Clone)]
+struct ReconnectTarget {
+    url: String,
+    options: ClientOptions,
+}
+
+/// A request sent on a connection, whose response is read with its id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SentRequest {
+    /// The id the request is tagged with.
+    pub id: u32,
+
+    /// The type of the request, carried by the errors about it.
+    pub message_type: MessageType,
+}
+
+impl SentRequest {
+    /// Returns the error of the server refusing the request.
+    fn refused(self, error: ServerError) -> Error {
+        Error::ServerError { request: self.message_type, error }
+    }
+}
+
+/// What decrypting the response to a query takes, derived from the query it answers.
+#[derive(Debug)]
+pub(crate) struct QueryDecryption {
+    collections: Vec<String>,
+    keys: Vec<EncKey>,
+    filter: Option<SingleQuery>,
+}
+
+/// The read half of a connection, with the responses read before their turn.
+#[derive(Debug)]
+struct Responses {
+    read: ReadHalf<Box<dyn Transport>>,
+    pending: HashMap<u32, Message>,
+
+    /// Whether the connection was closed, so no wait reads it anymore: set when a wait
+    /// times out, when the connection is lost and when it is closed, and checked by
+    /// every wait under the lock of the responses.
+    closed: bool,
+}
+
+/// What waiting for responses takes from a client, to wait without borrowing it.
+///
+/// Every wait of a connection shares its responses: a task waiting for its response reads
+/// the responses of the others and keeps them for them, so requests of several tasks are
+/// answered in any order, see `SharedClient`.
+#[derive(Debug, Clone)]
+pub(crate) struct ResponseWait {
+    responses: Arc<Mutex<Responses>>,
+    compression: Compression,
+    format: Format,
+    request_timeout: Option<Duration>,
+    events: EventSink,
+}
+
+impl ResponseWait {
+    /// Reads frames until the response to the request, within the request timeout.
+    ///
+    /// The responses are locked for one frame at a time, so a task whose response was
+    /// read by another one gets it as soon as that frame is kept.
+    pub(crate) async fn wait(&self, request: SentRequest) -> Result<Message, Error> {
+        let response = async {
+            loop {
+                let mut responses = self.responses.lock().await;
+                if let Some(message) = responses.pending.remove(&request.id) {
+                    return Ok(message);
+                }
+                if responses.closed {
+                    let err = io::Error::new(
+                        ErrorKind::NotConnected,
+                        "the connection is closed",
+                    );
+                    return Err(Error::ConnectionClosed(err));
+                }
+                // Closed while a frame is read: a wait whose timeout fires in the
+                // middle of it leaves the connection closed for the other waits, which
+                // would otherwise read the rest of the frame as the start of another.
+                responses.closed = true;
+                let read = read_frame(&mut responses.read, self.compression, self.format);
+                let read = read.await;
+                responses.closed = matches!(&read, Err(err) if is_connection_lost(err));
+                let (id, message) = read?;
+                if id == request.id {
+                    return Ok(message);
+                }
+                trace!("response to request {} read before its turn", id);
+                responses.pending.insert(id, message);
+            }
+        };
+        let message =
+            with_timeout(self.request_timeout, request.message_type, response).await?;
+        let (request_id, message_type) = (request.id, message.message_type());
+        self.events
+            .emit(|| ClientEvent::ResponseReceived { request_id, message_type });
+        Ok(message)
+    }
 }
 
 /// Represents a client that has not yet established a connection to the server.
+///
+/// Use `ClientBuilder` to configure it, `UnconnectedClient::default()` uses the default options.
 #[derive(Debug, Default)]
-pub struct UnconnectedClient;
+pub struct UnconnectedClient {
+    options: ClientOptions,
+
+    /// Destination of the events of the connections, see `with_events`.
+    events: EventSink,
+}
 
 /// Represents a client that has established a connection to the server but is not yet authenticated.
 #[derive(Debug)]
 pub struct ConnectedClient {
-    /// The TCP stream representing the connection to the server.
-    pub stream: TcpStream,
+    /// The stream representing the connection to the server.
+    pub stream: Box<dyn Transport>,
+
+    /// The compression of the frame bodies, negotiated during setup.
+    compression: Compression,
+
+    /// The serialization of the frame bodies, negotiated during setup.
+    format: Format,
+
+    /// The requests the server announced it handles during setup.
+    capabilities: Vec<MessageType>,
+
+    /// The mechanism to authenticate with, negotiated during setup.
+    auth_mechanism: AuthMechanism,
+
+    /// The secret exchanged during setup and never sent, see `auth::frame_key`.
+    shared_secret: [u8; 32],
+
+    /// Maximum time to wait for the response to a request.
+    request_timeout: Option<Duration>,
+
+    /// Maximum number of documents of the cache of the authenticated client.
+    document_cache_capacity: usize,
+
+    /// Capacity of the buffer of the requests of the authenticated client.
+    write_buffer_size: usize,
+
+    /// Context bound to the documents of the session, see `ClientOptions`.
+    encryption_context: Option<Vec<u8>>,
+
+    /// Where to reconnect to, `None` for a transport that cannot be reopened.
+    reconnect: Option<ReconnectTarget>,
+
+    /// Destination of the events of the connection.
+    events: EventSink,
 }
 
 /// Represents a client that has been authenticated.
 #[derive(Debug)]
 pub struct AuthenticatedClient {
-    /// The read half of the TCP stream.
-    pub read: OwnedReadHalf,
+    /// The read half of the connection stream, with the responses read before their turn.
+    responses: Arc<Mutex<Responses>>,
 
-    /// The write half of the TCP stream.
-    pub write: OwnedWriteHalf,
+    /// The write half of the connection stream.
+    pub write: WriteHalf<Box<dyn Transport>>,
 
     pub key: [u8; 32],
+
+    /// The username the client authenticated as.
+    username: String,
+
+    /// The token issued by the server on authentication.
+    session_token: SessionToken,
+
+    /// The key tagging the requests, derived from the token and the setup secret.
+    frame_key: [u8; 32],
+
+    /// The compression of the frame bodies, negotiated during setup.
+    compression: Compression,
+
+    /// The serialization of the frame bodies, negotiated during setup.
+    format: Format,
+
+    /// The requests the server announced it handles during setup.
+    capabilities: Vec<MessageType>,
+
+    /// Maximum time to wait for the response to a request.
+    request_timeout: Option<Duration>,
+
+    /// Id tagging the next request sent.
+    next_request_id: u32,
+
+    /// Whether the connection was closed, by `close` or by the server.
+    closed: bool,
+
+    /// Decrypted documents read by `get_document`.
+    cache: DocumentCache,
+
+    /// Frames of the requests sent and not written out yet, see `flush`.
+    outbound: Vec<u8>,
+
+    /// Length of `outbound` from which it is written out.
+    write_buffer_size: usize,
+
+    /// Context bound to the documents of the session, see `ClientOptions`.
+    encryption_context: Option<Vec<u8>>,
+
+    /// Where to reconnect to, `None` for a transport that cannot be reopened.
+    reconnect: Option<ReconnectTarget>,
+
+    /// Whether the session was made read-only, again on every reconnection.
+    read_only: bool,
+
+    /// Destination of the events of the connection.
+    events: EventSink,
 }
 
 impl UnconnectedClient {
+    /// Creates a client with the given options, see `ClientBuilder`.
+    pub fn with_options(options: ClientOptions) -> Self {
+        Self { options, events: EventSink::default() }
+    }
+
+    /// Emits the events of the connections of the client on a channel, see `ClientEvent`.
+    ///
+    /// Without a channel the client does not build any event.
+    pub fn with_events(mut self, sender: UnboundedSender<ClientEvent>) -> Self {
+        self.events = EventSink::new(sender);
+        self
+    }
+
+    /// Returns the options applied to the connections of the client.
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
     /// Connects to the server at the given URL and returns a `ConnectedClient`.
     ///
+    /// Proposes the compressions and serialization formats of the client options.
+    ///
     /// # Arguments
     ///
     /// * `url` - The URL of the server to connect to.
     pub async fn connect(self, url: &str) -> Result<ConnectedClient, Error> {
-        let mut rng = rand::thread_rng();
-        let kyber_key = pqc_kyber::keypair(&mut rng);
-        let mut stream = TcpStream::connect(url).await?;
-        let setup_security = Message::ClientSetup(ClientSetupSecureConnection::new(
-            kyber_key.public.to_vec(),
-        ));
-        let message = setup_security.setup_for_network()?;
+        let compression = self.options.compression.clone();
+        self.connect_with_compression(url, compression).await
+    }
+
+    /// Connects to the server at the given URL, proposing to compress the frame bodies.
+    ///
+    /// The server picks the first proposed compression, which then applies to every
+    /// frame of the session. See `liserk_shared::compression` for the frame layout.
+    ///
+    /// The serialization formats of the client options are proposed the same way, see
+    /// `liserk_shared::format`.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the server to connect to.
+    /// * `compression` - The compressions supported, in order of preference.
+    pub async fn connect_with_compression(
+        self,
+        url: &str,
+        compression: Vec<Compression>,
+    ) -> Result<ConnectedClient, Error> {
+        let socket_options = self.options.clone();
+        let stream = connect_transport(url, &socket_options);
+        let options = ClientOptions {
+            compression: compression.clone(),
+            ..self.options.clone()
+        };
+        let reconnect = ReconnectTarget { url: url.to_string(), options };
+        self.connect_over(stream, compression, Some(reconnect)).await
+    }
+
+    /// Connects to a server listening on a Unix domain socket and returns a
+    /// `ConnectedClient`.
+    ///
+    /// Behaves as `connect`, access to the server being controlled by the permissions of
+    /// the socket file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the socket the server listens on.
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<Path>>(
+        self,
+        path: P,
+    ) -> Result<ConnectedClient, Error> {
+        let compression = self.options.compression.clone();
+        let stream = async {
+            let stream: Box<dyn Transport> = Box::new(UnixStream::connect(path).await?);
+            Ok(stream)
+        };
+        self.connect_over(stream, compression, None).await
+    }
+
+    /// Opens the stream to the server and completes the setup of the connection over it.
+    ///
+    /// The setup is the first exchange of a connection: the client sends `ClientSetup` and
+    /// only goes on once the server answers with a `SetupResponse` picking a proposed
+    /// compression and format, the server refusing to authenticate a connection that was
+    /// not set up.
+    ///
+    /// Frames are read through a buffer sized by `ClientOptions::read_buffer_size`.
+    async fn connect_over<F>(
+        self,
+        stream: F,
+        compression: Vec<Compression>,
+        reconnect: Option<ReconnectTarget>,
+    ) -> Result<ConnectedClient, Error>
+    where
+        F: Future<Output = Result<Box<dyn Transport>, Error>>,
+    {
+        let request_timeout = self.options.request_timeout;
+        let document_cache_capacity = self.options.document_cache_capacity.unwrap_or(0);
+        let write_buffer_size = self.options.write_buffer_size.unwrap_or(0);
+        let encryption_context = self.options.encryption_context.clone();
+        let format = self.options.format.clone();
+        let auth_mechanisms = self.options.auth_mechanisms.clone();
+        let tenant = self.options.tenant.clone();
+        let read_buffer_capacity = self.options.read_buffer_capacity();
+        let events = self.events;
+        let setup = async {
+            let kyber_key = pqc_kyber::keypair(&mut rand::thread_rng());
+            let stream = BufReader::with_capacity(read_buffer_capacity, stream.await?);
+            let mut stream: Box<dyn Transport> = Box::new(stream);
+            let mut proposal =
+                ClientSetupSecureConnection::new(kyber_key.public.to_vec())
+                    .with_compression(compression)
+                    .with_format(format)
+                    .with_auth_mechanisms(auth_mechanisms);
+            if let Some(tenant) = tenant {
+                proposal = proposal.with_tenant(tenant);
+            }
+            let message = Message::ClientSetup(proposal.clone()).setup_for_network()?;
+
+            stream.write_all(&message).await?;
+            match read_message(&mut stream, Compression::None, Format::Cbor).await? {
+                Message::SetupResponse {
+                    compression, format, auth_mechanism, ..
+                } if !is_accepted(compression, proposal.compression())
+                    || !is_accepted(format, proposal.format())
+                    || !is_accepted(auth_mechanism, proposal.auth_mechanisms()) =>
+                {
+                    Err(Error::ProtocolError(MessageType::SetupResponse))
+                }
+                Message::SetupResponse {
+                    compression,
+                    format,
+                    capabilities,
+                    auth_mechanism,
+                    key_encapsulation,
+                } => {
+                    let shared_secret =
+                        pqc_kyber::decapsulate(&key_encapsulation, &kyber_key.secret)
+                            .map_err(|_| {
+                                Error::ProtocolError(MessageType::SetupResponse)
+                            })?;
+                    events.emit(|| ClientEvent::Connected);
+                    Ok(ConnectedClient {
+                        stream,
+                        compression,
+                        format,
+                        capabilities,
+                        auth_mechanism,
+                        shared_secret,
+                        request_timeout,
+                        document_cache_capacity,
+                        write_buffer_size,
+                        encryption_context,
+                        reconnect,
+                        events,
+                    })
+                }
+                Message::ErrorResponse(error) => {
+                    Err(Error::ServerError { request: MessageType::Setup, error })
+                }
+                message => Err(Error::ProtocolError(message.message_type())),
+            }
+        };
+        with_timeout(self.options.connect_timeout, MessageType::Setup, setup).await
+    }
+
+    /// Connects to the server at the given URL and authenticates with a session token
+    /// issued to a previous connection, without sending the credentials again.
+    ///
+    /// The server replaces the token by a new one, returned by `session_token` of the new
+    /// client: a token resumes a single connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the server to connect to.
+    /// * `session_token` - The token returned by `AuthenticatedClient::session_token`.
+    /// * `key` - The secret key of the user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AuthenticatedClient, Error>` - The authenticated client, or
+    ///   `ServerError::InvalidToken` if the token is unknown or expired.
+    pub async fn connect_with_token(
+        self,
+        url: &str,
+        session_token: &SessionToken,
+        key: [u8; 32],
+    ) -> Result<AuthenticatedClient, Error> {
+        let connected_client = self.connect(url).await?;
+        let message =
+            Message::ClientTokenAuthentification { token: session_token.token.clone() };
+        connected_client.authenticate_with(message, key).await
+    }
+
+    /// Checks that the server at the given URL is alive, without authenticating.
+    ///
+    /// The connection is opened as by `connect`, over TLS and with the socket options of
+    /// the client, and the check fails with `Error::Timeout` after
+    /// `ClientOptions::request_timeout`.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the server to check.
+    pub async fn health_check(&self, url: &str) -> Result<(), Error> {
+        let check = async {
+            let mut stream = connect_transport(url, &self.options).await?;
+            let message = Message::HealthCheck.setup_for_network()?;
+            stream.write_all(&message).await?;
+            let response =
+                read_message(&mut stream, Compression::None, Format::Cbor).await?;
 
-        stream.write_all(&message).await?;
-        Ok(ConnectedClient { stream })
+            let message = Message::EndOfCommunication.setup_for_network()?;
+            stream.write_all(&message).await?;
+            let request = SentRequest { id: 0, message_type: MessageType::HealthCheck };
+            health_answer(request, response)
+        };
+        with_timeout(self.options.request_timeout, MessageType::HealthCheck, check).await
     }
 }
 
@@ -86,46 +615,426 @@ impl ConnectedClient {
     ///
     /// ```
     /// # async fn run_example() -> Result<(), Error> {
-    /// let unconnected_client = UnconnectedClient;
+    /// let unconnected_client = UnconnectedClient::default();
     /// let connected_client = unconnected_client.connect("127.0.0.1:12345").await?;
     /// let authenticated_client = connected_client.authenticate("username".to_string(), "password".to_string()).await?;
     /// # Ok(()) }
     /// ```
     pub async fn authenticate(
-        mut self,
+        self,
         username: String,
         password: String,
         key: [u8; 32],
     ) -> Result<AuthenticatedClient, Error> {
         let client_authentication = ClientAuthentication { username, password };
         let message = Message::ClientAuthentification(client_authentication);
-        let message = message.setup_for_network()?;
-        // debug!("message {:?}", message);
-        self.stream.write_all(&message).await?;
+        self.authenticate_with(message, key).await
+    }
+
+    /// Returns the mechanism the server expects the client to authenticate with.
+    ///
+    /// `authenticate` is for `AuthMechanism::Password`, `authenticate_with_key` for
+    /// `AuthMechanism::ChallengeResponse`, proposed with `ClientBuilder::auth_mechanisms`.
+    pub fn auth_mechanism(&self) -> AuthMechanism {
+        self.auth_mechanism
+    }
+
+    /// Authenticates the connected client by answering a challenge of the server with a
+    /// key shared with it, see `liserk_shared::auth`.
+    ///
+    /// The key is never sent. The server refuses the response with
+    /// `ServerError::AuthenticationFailed` if the user has another key, or if the
+    /// connection negotiated another mechanism than `AuthMechanism::ChallengeResponse`.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user the key is shared for.
+    /// * `pre_shared_key` - The key shared with the server.
+    /// * `key` - The secret key of the user.
+    pub async fn authenticate_with_key(
+        mut self,
+        username: String,
+        pre_shared_key: &[u8],
+        key: [u8; 32],
+    ) -> Result<AuthenticatedClient, Error> {
+        let (compression, format) = (self.compression, self.format);
+        let request =
+            Message::ClientChallengeAuthentification { username: username.clone() };
+        let request_type = request.message_type();
+        let frame = request.setup_for_network_in(0, compression, format)?;
+        self.stream.write_all(&frame).await?;
+        let response = read_message(&mut self.stream, compression, format);
+        let challenge =
+            match with_timeout(self.request_timeout, request_type, response).await? {
+                Message::AuthChallenge { challenge } => challenge,
+                Message::ErrorResponse(error) => {
+                    return Err(Error::ServerError { request: request_type, error })
+                }
+                message => return Err(Error::ProtocolError(message.message_type())),
+            };
+        let mac = challenge_mac(pre_shared_key, &username, &challenge);
+        self.authenticate_with(Message::ClientChallengeResponse { mac }, key)
+            .await
+    }
+
+    /// Sends an authentication message and waits for the session token of the server.
+    async fn authenticate_with(
+        self,
+        message: Message,
+        key: [u8; 32],
+    ) -> Result<AuthenticatedClient, Error> {
+        let (compression, format) = (self.compression, self.format);
+        let resumed = matches!(message, Message::ClientTokenAuthentification { .. });
+        let request_type = message.message_type();
+        let message = message.setup_for_network_in(0, compression, format)?;
+        let (mut read, mut write) = io::split(self.stream);
+        write.write_all(&message).await?;
 
-        let (read, write) = self.stream.into_split();
-        let auth_client = AuthenticatedClient { read, write, key };
-        Ok(auth_client)
+        let request_timeout = self.request_timeout;
+        let response = read_message(&mut read, compression, format);
+        match with_timeout(request_timeout, request_type, response).await? {
+            Message::AuthentificationResponse(session_token) => {
+                let username = session_token.username.clone();
+                self.events.emit(|| {
+                    let username = username.clone();
+                    if resumed {
+                        ClientEvent::Reconnected { username }
+                    } else {
+                        ClientEvent::Authenticated { username }
+                    }
+                });
+                let frame_key = frame_key(&self.shared_secret, &session_token.token);
+                let responses =
+                    Responses { read, pending: HashMap::new(), closed: false };
+                Ok(AuthenticatedClient {
+                    responses: Arc::new(Mutex::new(responses)),
+                    write,
+                    key,
+                    username,
+                    session_token,
+                    frame_key,
+                    compression,
+                    format,
+                    capabilities: self.capabilities,
+                    request_timeout,
+                    next_request_id: 1,
+                    closed: false,
+                    cache: DocumentCache::new(self.document_cache_capacity),
+                    outbound: Vec::with_capacity(self.write_buffer_size),
+                    write_buffer_size: self.write_buffer_size,
+                    encryption_context: self.encryption_context,
+                    reconnect: self.reconnect,
+                    read_only: false,
+                    events: self.events,
+                })
+            }
+            Message::ErrorResponse(error) => {
+                Err(Error::ServerError { request: request_type, error })
+            }
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
     }
 }
 
 impl AuthenticatedClient {
+    /// Returns the username the client is authenticated as.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the associated data a document is encrypted with, `associated_data` bound
+    /// to the encryption context of the session, see `bind_context`.
+    fn document_aad(&self, associated_data: &[u8]) -> Vec<u8> {
+        bind_context(self.encryption_context.as_deref(), associated_data)
+    }
+
+    /// Sends a request tagged with a new request id, and returns the request.
+    ///
+    /// The response is read with `receive`, so several requests can be sent before
+    /// reading any response, which saves a round trip per request. The message is sent
+    /// as it is: its payloads must already be encrypted, as the other methods do.
+    ///
+    /// Request ids increase with every request and are never reused on a connection: the
+    /// server refuses a request id it already saw with `ServerError::ReplayDetected`. The
+    /// frame is tagged under a key derived from the session token and the secret
+    /// exchanged during setup, which binds the request id to the session.
+    ///
+    /// Nothing is sent on a closed connection, see `reconnect`.
+    pub async fn send(&mut self, message: Message) -> Result<SentRequest, Error> {
+        if self.closed {
+            let err = io::Error::new(ErrorKind::NotConnected, "the connection is closed");
+            return Err(Error::ConnectionClosed(err));
+        }
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        let message_type = message.message_type();
+        let frame = message
+            .setup_for_network_tagged(
+                request_id,
+                self.compression,
+                self.format,
+                &self.frame_key,
+            )
+            .map_err(|source| Error::SerializationError {
+                message_type: Some(message_type),
+                source,
+            })?;
+        self.outbound.extend_from_slice(&frame);
+        if self.outbound.len() >= self.write_buffer_size {
+            self.flush().await?;
+        }
+        self.events
+            .emit(|| ClientEvent::RequestSent { request_id, message_type });
+        Ok(SentRequest { id: request_id, message_type })
+    }
+
+    /// Writes the frames of the buffered requests out and waits for the transport to
+    /// pass them on to the system, see `ClientBuilder::write_buffer_size`.
+    ///
+    /// The buffer is also written out before waiting for any response and on close, so
+    /// only requests the server does not answer stay buffered until a flush.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        let written = match self.write.write_all(&self.outbound).await {
+            Ok(()) => self.write.flush().await,
+            Err(err) => Err(err),
+        };
+        self.outbound.clear();
+        written.map_err(|err| self.note_error(err.into()))
+    }
+
+    /// Remembers that the connection is closed if the error says so.
+    fn note_error(&mut self, err: Error) -> Error {
+        if is_connection_lost(&err) {
+            self.closed = true;
+        }
+        self.events
+            .emit(|| ClientEvent::Error { description: format!("{:?}", err) });
+        err
+    }
+
+    /// Reads the response to a request sent by `send`, within the configured request
+    /// timeout.
+    ///
+    /// Responses to other requests read meanwhile are kept until they are asked for, so
+    /// requests may be answered in any order. The connection is closed when the timeout
+    /// fires, see `read_response`.
+    pub async fn receive(&mut self, request: SentRequest) -> Result<Message, Error> {
+        self.read_response(request).await
+    }
+
+    /// Reads frames until the response to the request, keeping the others pending.
+    ///
+    /// A timeout may fire in the middle of a frame, whose remaining bytes would then be
+    /// read as the start of the next one, so the connection is shut down on a timeout and
+    /// later requests fail until `reconnect`. The responses kept for other requests are
+    /// dropped with it, none of them is read anymore.
+    async fn read_response(&mut self, request: SentRequest) -> Result<Message, Error> {
+        if !self.closed {
+            self.flush().await?;
+        }
+        let wait = self.response_wait();
+        match wait.wait(request).await {
+            Ok(message) => Ok(message),
+            Err(err) => Err(self.settle(&wait, err).await),
+        }
+    }
+
+    /// Returns what waiting for the responses of the connection takes.
+    pub(crate) fn response_wait(&self) -> ResponseWait {
+        ResponseWait {
+            responses: self.responses.clone(),
+            compression: self.compression,
+            format: self.format,
+            request_timeout: self.request_timeout,
+            events: self.events.clone(),
+        }
+    }
+
+    /// Closes the connection after a wait timed out and remembers a lost connection, see
+    /// `read_response`.
+    ///
+    /// The error of a wait on a connection replaced since, by `reconnect`, leaves the
+    /// client as it is.
+    pub(crate) async fn settle(&mut self, wait: &ResponseWait, err: Error) -> Error {
+        if !Arc::ptr_eq(&wait.responses, &self.responses) {
+            return err;
+        }
+        if let Error::Timeout { .. } = err {
+            self.closed = true;
+            let mut responses = self.responses.lock().await;
+            responses.closed = true;
+            responses.pending.clear();
+            drop(responses);
+            let _ = self.write.shutdown().await;
+        }
+        self.note_error(err)
+    }
+
+    /// Computes the index entry to look documents up by a field value,
+    /// see `SingleQueryBuilder::with_index_lookup`.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection of the documents.
+    /// * `field` - The indexed field.
+    /// * `value` - The value the field must be equal to.
+    pub fn index_lookup(
+        &self,
+        collection: &str,
+        field: &str,
+        value: &serde_cbor::Value,
+    ) -> Result<IndexEntry, Error> {
+        index_token(&MacKey::for_collection(&self.key, collection), field, value)
+    }
+
+    /// Computes the index entry to look documents up by a word of a searchable field,
+    /// see `insert_searchable` and `SingleQueryBuilder::with_index_lookup`.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection of the documents.
+    /// * `field` - The searchable field.
+    /// * `term` - The word the field must contain, case insensitive.
+    pub fn search_lookup(&self, collection: &str, field: &str, term: &str) -> IndexEntry {
+        search_token(&MacKey::for_collection(&self.key, collection), field, term)
+    }
+
+    /// Returns the requests the server announced it handles when the connection was set up.
+    ///
+    /// A server predating capability discovery announces none, see `supports`.
+    pub fn server_capabilities(&self) -> &[MessageType] {
+        &self.capabilities
+    }
+
+    /// Returns whether the server handles requests of the type, so the client can avoid
+    /// sending requests it would not answer.
+    ///
+    /// Every request is assumed handled by a server that announced no capabilities.
+    pub fn supports(&self, message_type: MessageType) -> bool {
+        self.capabilities.is_empty() || self.capabilities.contains(&message_type)
+    }
+
+    /// Returns the token issued by the server, which lets further connections
+    /// authenticate with `UnconnectedClient::connect_with_token` until it expires.
+    pub fn session_token(&self) -> &SessionToken {
+        &self.session_token
+    }
+
+    /// Opens a new connection to the server the client connected to and resumes the
+    /// session on it with its session token, replacing the connection, lost or not.
+    ///
+    /// Responses still pending and requests still buffered on the previous connection are
+    /// dropped, the document cache is kept. A client connected over a Unix domain socket
+    /// cannot reconnect.
+    pub async fn reconnect(&mut self) -> Result<(), Error> {
+        let Some(target) = self.reconnect.clone() else {
+            let err =
+                io::Error::new(ErrorKind::NotConnected, "the transport cannot reopen");
+            return Err(Error::ConnectionClosed(err));
+        };
+        let client = UnconnectedClient {
+            options: target.options,
+            events: self.events.clone(),
+        };
+        let mut client = client
+            .connect_with_token(&target.url, &self.session_token, self.key)
+            .await?;
+        std::mem::swap(&mut client.cache, &mut self.cache);
+        if self.read_only {
+            client.send(Message::SetReadOnly).await?;
+            client.read_only = true;
+        }
+        *self = client;
+        Ok(())
+    }
+
+    /// Turns the client into a `ReadOnlyClient`, which exposes no request changing
+    /// documents, and makes its session read-only on the server.
+    ///
+    /// The server does not answer, but refuses any later mutation of the session, see
+    /// `Message::SetReadOnly`.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn into_read_only(mut self) -> Result<ReadOnlyClient, Error> {
+        self.send(Message::SetReadOnly).await?;
+        self.read_only = true;
+        Ok(ReadOnlyClient::new(self))
+    }
+
     /// Checks if the client connection is alive.
     ///
     /// # Returns
     ///
     /// * `bool` - `true` if the connection is alive, `false` otherwise.
     pub fn is_alive(&self) -> bool {
-        true
+        !self.closed
+    }
+
+    /// Sends a `HealthCheck` and returns the time its response took to come back.
+    ///
+    /// The response is matched by its request id like any other, so pings can be sent
+    /// while other requests are waiting for their responses.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn ping(&mut self) -> Result<Duration, Error> {
+        let sent_at = Instant::now();
+        let request = self.send(Message::HealthCheck).await?;
+        let message = self.receive(request).await?;
+        health_answer(request, message)?;
+        Ok(sent_at.elapsed())
     }
 
     /// Terminates the connection of the client.
+    ///
+    /// Same as `close`, kept for existing callers.
     pub async fn terminate_connection(&mut self) -> Result<(), Error> {
-        let message = Message::EndOfCommunication;
-        let message = message.setup_for_network()?;
-        // debug!("terminate Connection {:?}", message);
-        self.write.write_all(&message).await?;
-        Ok(())
+        self.close().await
+    }
+
+    /// Closes the connection once every in-flight response has been received.
+    ///
+    /// Sends `EndOfCommunication` and flushes it along the buffered requests, then drains
+    /// the responses the server still has to send
+    /// until it acknowledges with `CloseCommunication` or closes the socket. If no
+    /// acknowledgement arrives within `CLOSE_TIMEOUT` the connection is closed anyway.
+    ///
+    /// Closing a connection already closed, by an earlier call or by the server, does
+    /// nothing and succeeds.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn close(&mut self) -> Result<(), Error> {
+        if self.closed {
+            return Ok(());
+        }
+        let sent = match self.send(Message::EndOfCommunication).await {
+            Ok(_) => self.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = sent {
+            return if is_connection_lost(&err) { Ok(()) } else { Err(err) };
+        }
+        self.closed = true;
+
+        let mut responses = self.responses.lock().await;
+        let read = &mut responses.read;
+        let (compression, format) = (self.compression, self.format);
+        let acknowledgement = timeout(CLOSE_TIMEOUT, async move {
+            loop {
+                match read_message(read, compression, format).await {
+                    Ok(Message::CloseCommunication) => break,
+                    Ok(message) => debug!("drained before close: {:?}", message),
+                    Err(_) => break,
+                }
+            }
+        })
+        .await;
+        if acknowledgement.is_err() {
+            warn!("server did not acknowledge close within {:?}", CLOSE_TIMEOUT);
+        }
+        responses.closed = true;
+        drop(responses);
+
+        match self.write.shutdown().await.map_err(Error::from) {
+            Err(err) if !is_connection_lost(&err) => Err(err),
+            _ => Ok(()),
+        }
     }
 
     /// Inserts data into a specified collection.
@@ -137,6 +1046,7 @@ impl AuthenticatedClient {
     /// * `associated_data` - The associated data to be verified.
     /// * `acl` - The access control list.
     /// * `usecases` - The use cases associated with the data.
+    #[instrument(skip_all, fields(user = %self.username))]
     pub async fn insert(
         &mut self,
         collection: String,
@@ -145,34 +1055,408 @@ impl AuthenticatedClient {
         acl: Vec<String>,
         usecases: Vec<String>,
     ) -> Result<String, Error> {
-        let mut nonce = [0u8; 12];
-        rand::thread_rng().fill(&mut nonce);
-        let encrypt_data = basic_encrypt(&self.key, &nonce, &data, &associated_data)?;
-        let message = Message::Insert(Insertion {
-            acl,
-            collection,
-            data: encrypt_data,
-            usecases,
-            nonce: nonce.to_vec(),
-        });
-        let message = message.setup_for_network()?;
-        self.write.write_all(&message).await?;
-        let message = parse_message_from_tcp_stream(&mut self.read).await?;
-        info!("message: {:?}", message);
-        match message {
-            Message::InsertResponse { inserted_id } => Ok(inserted_id),
-            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
-        }
+        self.insert_indexed(collection, data, associated_data, acl, usecases, &[])
+            .await
     }
 
-    /// Inserts a number into the database with Order Preserving Encryption (OPE).
+    /// Inserts a document built by `PlainInsertion::builder`, encrypting its data here.
     ///
     /// # Arguments
     ///
-    /// * `number_to_encrypt` - The number to be encrypted and inserted.
-    /// * `acl` - The access control list.
-    /// * `usecases` - The use cases associated with the data.
-    /// * `collection` - The name of the collection to insert the data into.
+    /// * `insertion` - The document to insert with its collection, acl and usecases.
+    /// * `associated_data` - The associated data authenticated with the document.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn insert_built(
+        &mut self,
+        insertion: PlainInsertion,
+        associated_data: Vec<u8>,
+    ) -> Result<String, Error> {
+        let PlainInsertion { collection, acl, data, usecases, index, id, upsert } =
+            insertion;
+        if let Some(id) = &id {
+            validate_document_id(id)?;
+        }
+        let insertion = encrypt_insertion(
+            &self.key,
+            collection,
+            data,
+            &self.document_aad(&associated_data),
+            acl,
+            usecases,
+            index,
+        )?;
+        self.send_insertion(Insertion { id, upsert, ..insertion }).await
+    }
+
+    /// Inserts data under an id chosen by the client instead of one generated by the
+    /// server, returning that id.
+    ///
+    /// The insertion is refused with `Error::DuplicateId` if a document is already stored
+    /// under the id, use `PlainInsertion::builder` with `upsert` and `insert_built` to
+    /// replace it instead. The id is checked by `validate_document_id`.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to insert the data into.
+    /// * `id` - The id to store the document under.
+    /// * `data` - The data to be inserted.
+    /// * `associated_data` - The associated data to be verified.
+    /// * `acl` - The access control list.
+    /// * `usecases` - The use cases associated with the data.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn insert_with_id(
+        &mut self,
+        collection: String,
+        id: String,
+        data: Vec<u8>,
+        associated_data: Vec<u8>,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+    ) -> Result<String, Error> {
+        let insertion =
+            PlainInsertion::builder().collection(collection).id(id).data(data);
+        let insertion = acl.into_iter().fold(insertion, InsertionBuilder::acl);
+        let insertion = usecases.into_iter().fold(insertion, InsertionBuilder::usecase);
+        self.insert_built(insertion.build()?, associated_data).await
+    }
+
+    /// Inserts a CBOR document and indexes some of its top level fields.
+    ///
+    /// Indexed fields can be looked up with `index_lookup` without the server reading
+    /// every document of the usecase.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to insert the data into.
+    /// * `data` - The CBOR document to be inserted.
+    /// * `associated_data` - The associated data authenticated with the document.
+    /// * `acl` - The access control list.
+    /// * `usecases` - The use cases associated with the data.
+    /// * `indexed_fields` - The fields of the document to index.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn insert_indexed(
+        &mut self,
+        collection: String,
+        data: Vec<u8>,
+        associated_data: Vec<u8>,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+        indexed_fields: &[String],
+    ) -> Result<String, Error> {
+        let mac_key = MacKey::for_collection(&self.key, &collection);
+        let index = index_entries(&mac_key, &data, indexed_fields)?;
+        self.insert_with_index(collection, data, associated_data, acl, usecases, index)
+            .await
+    }
+
+    /// Inserts a CBOR document whose text fields can be searched by word.
+    ///
+    /// The blinded search tokens of the words are sent along the encrypted document, see
+    /// `search_token` for what they leak to the server. Documents containing a word are
+    /// queried with `search_lookup`.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to insert the data into.
+    /// * `data` - The CBOR document to be inserted.
+    /// * `associated_data` - The associated data authenticated with the document.
+    /// * `acl` - The access control list.
+    /// * `usecases` - The use cases associated with the data.
+    /// * `searchable_fields` - The text fields of the document to make searchable.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn insert_searchable(
+        &mut self,
+        collection: String,
+        data: Vec<u8>,
+        associated_data: Vec<u8>,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+        searchable_fields: &[String],
+    ) -> Result<String, Error> {
+        let mac_key = MacKey::for_collection(&self.key, &collection);
+        let index = search_entries(&mac_key, &data, searchable_fields)?;
+        self.insert_with_index(collection, data, associated_data, acl, usecases, index)
+            .await
+    }
+
+    /// Inserts several documents described by `Insertion`s in one round trip.
+    ///
+    /// Each document is encrypted like `insert_built` without associated data, and the
+    /// server inserts each one in its own transaction. The outcome of every document is
+    /// returned in the order of `insertions`: its id, or why the server refused it, a
+    /// refused document not keeping the others from being inserted.
+    ///
+    /// # Arguments
+    ///
+    /// * `insertions` - The documents to insert with their collection, acl and usecases.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn insert_many(
+        &mut self,
+        insertions: Vec<PlainInsertion>,
+    ) -> Result<Vec<Result<String, ServerError>>, Error> {
+        let count = insertions.len();
+        let aad = self.document_aad(&[]);
+        let insertions =
+            insertions
+                .into_iter()
+                .map(|insertion| -> Result<Insertion, Error> {
+                    let PlainInsertion {
+                        collection,
+                        acl,
+                        data,
+                        usecases,
+                        index,
+                        id,
+                        upsert,
+                    } = insertion;
+                    if let Some(id) = &id {
+                        validate_document_id(id)?;
+                    }
+                    let insertion = encrypt_insertion(
+                        &self.key, collection, data, &aad, acl, usecases, index,
+                    )?;
+                    Ok(Insertion { id, upsert, ..insertion })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+        let request = self.send(Message::InsertBatch(insertions)).await?;
+        match self.receive(request).await? {
+            Message::InsertBatchResponse(results) if results.len() == count => {
+                Ok(results)
+            }
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    async fn insert_with_index(
+        &mut self,
+        collection: String,
+        data: Vec<u8>,
+        associated_data: Vec<u8>,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+        index: Vec<IndexEntry>,
+    ) -> Result<String, Error> {
+        let insertion =
+            self.insertion(collection, data, &associated_data, acl, usecases, index)?;
+        self.send_insertion(insertion).await
+    }
+
+    /// Encrypts a document into the insertion storing it.
+    pub(crate) fn insertion(
+        &self,
+        collection: String,
+        data: Vec<u8>,
+        associated_data: &[u8],
+        acl: Vec<String>,
+        usecases: Vec<String>,
+        index: Vec<IndexEntry>,
+    ) -> Result<Insertion, Error> {
+        encrypt_insertion(
+            &self.key,
+            collection,
+            data,
+            &self.document_aad(associated_data),
+            acl,
+            usecases,
+            index,
+        )
+    }
+
+    /// Sends an encrypted insertion and returns the id of the inserted document.
+    async fn send_insertion(&mut self, insertion: Insertion) -> Result<String, Error> {
+        let collection = insertion.collection.clone();
+        let id = insertion.id.clone();
+        let request = self.send(Message::Insert(insertion)).await?;
+        let message = self.receive(request).await?;
+        inserted_id(request, collection, id, message)
+    }
+
+    /// Inserts the content of a reader as a chunked document, without holding it in memory.
+    ///
+    /// The content is encrypted chunk by chunk, see `chunked`, with the id of the document
+    /// as associated data so chunks cannot be moved between documents. Each chunk is sent
+    /// and stored on its own, the document becomes readable with `query_stream` once the
+    /// last one is. Chunked documents are not returned by `query`.
+    ///
+    /// The reader is read synchronously between two requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to insert the data into.
+    /// * `reader` - The source of the document.
+    /// * `acl` - The access control list.
+    /// * `usecases` - The use cases associated with the data.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn insert_stream<R: Read>(
+        &mut self,
+        collection: String,
+        reader: R,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+    ) -> Result<String, Error> {
+        validate_insertion_names(&collection, &usecases)?;
+        let nonce = generate_nonce();
+        let key = EncKey::for_collection(&self.key, &collection);
+        let start =
+            InsertStreamStart { collection, acl, usecases, nonce: nonce.to_vec() };
+        let id = self.send_insert(Message::InsertStream(start)).await?;
+
+        let aad = self.document_aad(id.as_bytes());
+        let mut chunks =
+            ChunkEncryptor::new(&key, &nonce, reader, &aad, DEFAULT_CHUNK_SIZE)
+                .peekable();
+        let mut index = 0;
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            let chunk = InsertChunk { id: id.clone(), index, chunk: chunk?, last };
+            self.send_insert(Message::InsertChunk(chunk)).await?;
+            index += 1;
+        }
+        Ok(id)
+    }
+
+    async fn send_insert(&mut self, message: Message) -> Result<String, Error> {
+        let request = self.send(message).await?;
+        match self.receive(request).await? {
+            Message::InsertResponse { inserted_id } => Ok(inserted_id),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Reads back a document inserted by `insert_stream`, or by any insert, see
+    /// `download_to`.
+    ///
+    /// Every chunk is authenticated before the document is returned, so a tampered or
+    /// truncated document is an error. Returns `None` if the document does not exist or
+    /// may not be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection of the document.
+    /// * `id` - The id returned by `insert_stream`.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn query_stream(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut document = Vec::new();
+        let mode = StreamVerifyMode::BufferAndVerify;
+        let found = self.download_to(collection, id, &mut document, mode).await?;
+        Ok(found.map(|_| document))
+    }
+
+    /// Downloads a document inserted by `insert_stream`, writing its plaintext to a writer.
+    ///
+    /// With `StreamVerifyMode::BufferAndVerify` nothing is written unless every chunk is
+    /// authentic and the document is complete, the plaintext being held in memory until
+    /// then. With `StreamVerifyMode::PerChunk` each chunk is written as soon as it is
+    /// authenticated, so memory stays bounded by the chunk size but a tampered or truncated
+    /// document leaves its authentic beginning in the writer before the error is returned.
+    /// No unauthenticated byte is ever written.
+    ///
+    /// A document inserted whole, by `insert` and the like, has no chunks: it is read by
+    /// its id as by `query` and written once decrypted, whatever the mode.
+    ///
+    /// Returns the number of bytes written, `None` if the document does not exist or may
+    /// not be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection of the document.
+    /// * `id` - The id of the document.
+    /// * `writer` - The destination of the plaintext.
+    /// * `mode` - Whether plaintext is written per chunk or once the document is verified.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn download_to<W: Write>(
+        &mut self,
+        collection: String,
+        id: String,
+        mut writer: W,
+        mode: StreamVerifyMode,
+    ) -> Result<Option<u64>, Error> {
+        let request = |index| ChunkRequest {
+            collection: collection.clone(),
+            id: id.clone(),
+            index,
+        };
+        let Some(mut chunk) = self.fetch_chunk(request(0)).await? else {
+            return self.download_whole(collection, id, writer).await;
+        };
+        let key = EncKey::for_collection(&self.key, &collection);
+        let nonce = convert_to_array12(&chunk.nonce)
+            .ok_or(Error::encryption(AesError::Decrypt))?;
+        let mut opener = ChunkOpener::new(&key, nonce, &self.document_aad(id.as_bytes()));
+        let mut buffered = Vec::new();
+        let mut written = 0;
+        for index in 1.. {
+            let plaintext = opener.open(&chunk.chunk)?;
+            if chunk.last != opener.is_complete() {
+                return Err(Error::encryption(AesError::Decrypt));
+            }
+            match mode {
+                StreamVerifyMode::PerChunk => {
+                    writer.write_all(&plaintext)?;
+                    written += plaintext.len() as u64;
+                }
+                StreamVerifyMode::BufferAndVerify => buffered.push(plaintext),
+            }
+            if opener.is_complete() {
+                break;
+            }
+            chunk = self
+                .fetch_chunk(request(index))
+                .await?
+                .ok_or(Error::encryption(AesError::TruncatedStream))?;
+        }
+        for plaintext in buffered {
+            writer.write_all(&plaintext)?;
+            written += plaintext.len() as u64;
+        }
+        writer.flush()?;
+        Ok(Some(written))
+    }
+
+    /// Downloads a document stored whole under its id, see `download_to`.
+    async fn download_whole<W: Write>(
+        &mut self,
+        collection: String,
+        id: String,
+        mut writer: W,
+    ) -> Result<Option<u64>, Error> {
+        match self.query(Query::GetById { id, collection }).await? {
+            QueryResult::SingleValue(document) => {
+                writer.write_all(&document)?;
+                writer.flush()?;
+                Ok(Some(document.len() as u64))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn fetch_chunk(
+        &mut self,
+        request: ChunkRequest,
+    ) -> Result<Option<StoredChunk>, Error> {
+        let request = self.send(Message::FetchChunk(request)).await?;
+        match self.receive(request).await? {
+            Message::ChunkResponse(chunk) => Ok(chunk),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Inserts a number into the database with Order Preserving Encryption (OPE).
+    ///
+    /// # Arguments
+    ///
+    /// * `number_to_encrypt` - The number to be encrypted and inserted.
+    /// * `acl` - The access control list.
+    /// * `usecases` - The use cases associated with the data.
+    /// * `collection` - The name of the collection to insert the data into.
+    #[instrument(skip_all, fields(user = %self.username))]
     pub async fn insert_ope(
         &mut self,
         number_to_encrypt: f64,
@@ -180,17 +1464,18 @@ impl AuthenticatedClient {
         usecases: Vec<String>,
         collection: String,
     ) -> Result<String, Error> {
+        validate_insertion_names(&collection, &usecases)?;
         let encrypted_number = encrypt_ope(number_to_encrypt);
         let data = encrypted_number.to_string().as_bytes().to_vec();
 
         let message =
             Message::InsertOpe(InsertionOpe { acl, collection, data, usecases });
-        let message = message.setup_for_network()?;
-        self.write.write_all(&message).await?;
-        let message = parse_message_from_tcp_stream(&mut self.read).await?;
+        let request = self.send(message).await?;
+        let message = self.receive(request).await?;
         info!("message: {:?}", message);
         match message {
             Message::InsertResponse { inserted_id } => Ok(inserted_id),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
             _ => Err(Error::MessageTypeError(MessageTypeError::default())),
         }
     }
@@ -200,128 +1485,2718 @@ impl AuthenticatedClient {
     /// # Arguments
     ///
     /// * `query` - The query object representing the database query.
+    #[instrument(skip_all, fields(user = %self.username))]
     pub async fn query(&mut self, query: Query) -> Result<QueryResult, Error> {
+        let decryption = self.query_decryption(&query)?;
         let message = Message::Query(query);
-        let message = message.setup_for_network()?;
-        self.write.write_all(&message).await?;
-        let message = parse_message_from_tcp_stream(&mut self.read).await?;
+        let request = self.send(message).await?;
+        let message = self.receive(request).await?;
         info!("message: {:?}", message);
+        self.decrypt_query(request, decryption, message)
+    }
+
+    /// Runs several independent queries in a single round trip.
+    ///
+    /// The server reads every query of the batch in the same transaction. The results are
+    /// returned in the order of the queries, and the batch fails if any of them fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to run.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn query_many(
+        &mut self,
+        queries: Vec<Query>,
+    ) -> Result<Vec<QueryResult>, Error> {
+        let decryptions = queries
+            .iter()
+            .map(|query| self.query_decryption(query))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let message = Message::QueryBatch(queries);
+        let request = self.send(message).await?;
+        let message = self.receive(request).await?;
+        self.decrypt_query_batch(request, decryptions, message)
+    }
+
+    /// Checks the names of a query and derives what decrypting its response takes.
+    pub(crate) fn query_decryption(
+        &self,
+        query: &Query,
+    ) -> Result<QueryDecryption, Error> {
+        query.validate_names()?;
+        let collections = query_collections(query);
+        let keys = collections
+            .iter()
+            .map(|collection| EncKey::for_collection(&self.key, collection))
+            .collect();
+        Ok(QueryDecryption {
+            collections,
+            keys,
+            filter: predicate_filter(query)?,
+        })
+    }
+
+    /// Decrypts the response to a query, naming its collection in the errors of a query
+    /// of a single collection.
+    pub(crate) fn decrypt_query(
+        &self,
+        request: SentRequest,
+        decryption: QueryDecryption,
+        message: Message,
+    ) -> Result<QueryResult, Error> {
+        let QueryDecryption { collections, keys, filter } = decryption;
+        self.decrypt_query_response(request, &keys, filter, message)
+            .map_err(|err| match collections.as_slice() {
+                [collection] => err.in_collection(collection),
+                _ => err,
+            })
+    }
+
+    /// Decrypts the responses to a batch of queries, in the order of the queries.
+    pub(crate) fn decrypt_query_batch(
+        &self,
+        request: SentRequest,
+        decryptions: Vec<QueryDecryption>,
+        message: Message,
+    ) -> Result<Vec<QueryResult>, Error> {
+        let responses = match message {
+            Message::QueryBatchResponse(responses) => responses,
+            Message::ErrorResponse(error) => return Err(request.refused(error)),
+            _ => return Err(Error::MessageTypeError(MessageTypeError::default())),
+        };
+        if responses.len() != decryptions.len() {
+            return Err(Error::ProtocolError(MessageType::QueryBatchResponse));
+        }
+        decryptions
+            .into_iter()
+            .zip(responses)
+            .map(|(decryption, response)| {
+                self.decrypt_query(request, decryption, response)
+            })
+            .collect()
+    }
+
+    /// Decrypts the documents of the response to a query and applies its predicates.
+    fn decrypt_query_response(
+        &self,
+        request: SentRequest,
+        keys: &[EncKey],
+        filter: Option<SingleQuery>,
+        message: Message,
+    ) -> Result<QueryResult, Error> {
+        let aad = self.document_aad(&[]);
         match message {
-            Message::QueryResponse((data, nonces)) => {
+            Message::QueryResponse(output) => {
+                let values = decrypt_output(keys, filter, output, &aad)?;
+                Ok(QueryResult::MultipleValues(values))
+            }
+            Message::PartialQueryResponse(output) => {
+                warn!(
+                    "query exceeded the time budget of the server, results are partial"
+                );
+                let values = decrypt_output(keys, filter, output, &aad)?;
+                Ok(QueryResult::PartialValues(values))
+            }
+            Message::PrefixQueryResponse { collections, output: (data, nonces) } => {
+                let keys: Vec<EncKey> = collections
+                    .iter()
+                    .map(|collection| EncKey::for_collection(&self.key, collection))
+                    .collect();
+                let Some(nonces) = nonces else {
+                    return Ok(QueryResult::MultipleValues(data));
+                };
                 let mut values = Vec::with_capacity(data.len());
-                for (cipher, nonce) in data.iter().zip(nonces.unwrap().iter()) {
-                    let value = basic_decrypt(
-                        &self.key,
-                        convert_to_array12(&nonce).expect("12 elements"),
-                        &cipher,
-                        &[],
-                    )?;
-                    values.push(value);
+                for (cipher, nonce) in data.iter().zip(nonces.iter()) {
+                    let nonce = convert_to_array12(nonce)
+                        .ok_or(Error::encryption(AesError::Decrypt))?;
+                    values
+                        .push(decrypt_with_collection_keys(&keys, nonce, cipher, &aad)?);
+                }
+                if let Some(filter) = filter {
+                    values = retain_matching(values, &filter)?;
                 }
                 Ok(QueryResult::MultipleValues(values))
             }
             Message::SingleValueResponse { data, nonce } => {
-                if data.is_none() || nonce.is_none() {
+                let (Some(data), Some(nonce)) = (data, nonce) else {
                     return Ok(QueryResult::EmptyResult);
-                }
-                let value = basic_decrypt(
-                    &self.key,
-                    convert_to_array12(&nonce.expect("Not ope")).expect("12 elements"),
-                    &data.expect("if is none reutrn empty result"),
-                    &[],
-                )?;
+                };
+                let nonce = convert_to_array12(&nonce)
+                    .ok_or(Error::encryption(AesError::Decrypt))?;
+                let value = decrypt_with_collection_keys(keys, nonce, &data, &aad)?;
                 Ok(QueryResult::SingleValue(value))
             }
+            Message::UnknownUsecase { collection, usecase } => {
+                warn!("unknown usecase {} of collection {}", usecase, collection);
+                Ok(QueryResult::UnknownUsecase { collection, usecase })
+            }
+            Message::ErrorResponse(error) => Err(request.refused(error)),
             _ => Err(Error::MessageTypeError(MessageTypeError::default())),
         }
     }
 
-    /// Modifies an existing document in the database.
+    /// Runs a query and decrypts each document on its own.
+    ///
+    /// Unlike `query`, a document failing to decrypt, because it is corrupted or was
+    /// encrypted with another key, does not fail the whole query: it is returned as an
+    /// error along its id, so the caller can skip or log it and still use the others.
+    /// Predicates are only checked on the documents that decrypted.
     ///
     /// # Arguments
     ///
-    /// * `id` - The identifier of the document to be modified.
-    /// * `collection` - The name of the collection containing the document.
-    /// * `new_value` - The new value to be set in the document.
-    pub async fn modify(
+    /// * `query` - The query object representing the database query.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn query_documents(
         &mut self,
-        id: String,
-        collection: String,
-        new_value: Vec<u8>,
-    ) -> Result<Message, Error> {
-        let update = Update { collection, id, new_value };
-        let message = Message::Update(update);
-        let message = message.setup_for_network()?;
-        self.write.write_all(&message).await?;
-        let message = parse_message_from_tcp_stream(&mut self.read).await?;
-
-        info!("message: {:?}", message);
-        match message {
-            Message::UpdateResponse { .. } => Ok(message),
+        query: Query,
+    ) -> Result<Vec<DocumentResult>, Error> {
+        query.validate_names()?;
+        let filter = predicate_filter(&query)?;
+        let request = self.send(Message::QueryDocuments(query)).await?;
+        match self.receive(request).await? {
+            Message::DocumentsResponse(documents) => {
+                let aad = self.document_aad(&[]);
+                Ok(decrypt_documents(&self.key, filter.as_ref(), documents, &aad))
+            }
+            Message::ErrorResponse(error) => Err(request.refused(error)),
             _ => Err(Error::MessageTypeError(MessageTypeError::default())),
         }
     }
 
-    /// Deletes a document from the database.
+    /// Reads a document by its id, `None` if there is no such document.
+    ///
+    /// With `ClientBuilder::document_cache`, the document is answered from the cache
+    /// without a round trip when it holds it, and cached once read otherwise. Only the
+    /// updates and deletions of this client drop it from the cache, see
+    /// `liserk_client::cache`.
     ///
     /// # Arguments
     ///
-    /// * `id` - The identifier of the document to be deleted.
     /// * `collection` - The name of the collection containing the document.
-    pub async fn delete(
+    /// * `id` - The identifier of the document.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn get_document(
         &mut self,
-        id: String,
         collection: String,
-    ) -> Result<Message, Error> {
-        let delete = Delete { collection, id };
-        let message = Message::Delete(delete);
-        let message = message.setup_for_network()?;
-        self.write.write_all(&message).await?;
-        let message = parse_message_from_tcp_stream(&mut self.read).await?;
+        id: String,
+    ) -> Result<Option<Document>, Error> {
+        if let Some(document) = self.cache.get(&collection, &id) {
+            trace!("document {} of {} read from the cache", id, collection);
+            return Ok(Some(document));
+        }
+        let query = Query::GetById { id, collection };
+        match self.query_documents(query).await?.into_iter().next() {
+            Some(Ok(document)) => {
+                self.cache.insert(document.clone());
+                Ok(Some(document))
+            }
+            Some(Err((_, err))) => Err(err),
+            None => Ok(None),
+        }
+    }
 
-        info!("message: {:?}", message);
-        match message {
-            Message::DeleteResult(_) => Ok(message),
+    /// Drops every document of the cache, for documents other clients may have changed.
+    pub fn clear_document_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Deletes the documents matching a query and returns them, in one atomic step.
+    ///
+    /// Meant for queue-like patterns: clients taking from the same query concurrently
+    /// never get the same document. Only the documents the user may both read and write
+    /// are taken. The server cannot evaluate predicates on encrypted documents, so
+    /// queries with predicates are refused with `ServerError::InvalidQuery`. A document
+    /// failing to decrypt is deleted all the same and returned as an error along its id.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query selecting the documents to take.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn query_and_delete(
+        &mut self,
+        query: Query,
+    ) -> Result<Vec<DocumentResult>, Error> {
+        query.validate_names()?;
+        let request = self.send(Message::QueryAndDelete(query)).await?;
+        match self.receive(request).await? {
+            Message::DocumentsResponse(documents) => {
+                for document in &documents {
+                    self.cache.remove(&document.collection, &document.id);
+                }
+                let aad = self.document_aad(&[]);
+                Ok(decrypt_documents(&self.key, None, documents, &aad))
+            }
+            Message::ErrorResponse(error) => Err(request.refused(error)),
             _ => Err(Error::MessageTypeError(MessageTypeError::default())),
         }
     }
-}
 
-/// Parses a message from a TCP stream.
-///
-/// # Arguments
-///
-/// * `stream` - A mutable reference to the read half of a TCP stream.
-///
-/// # Returns
-///
-/// * `Result<Message, Error>` - The parsed message, or an error if parsing fails.
-pub async fn parse_message_from_tcp_stream(
-    stream: &mut OwnedReadHalf,
-) -> Result<Message, Error> {
-    let mut buffer = [0; 1];
-    let _ = stream.read(&mut buffer).await;
-    let message_type = MessageType::try_from(buffer[0]);
-    info!("messageType: {:?}", message_type);
+    /// Streams every document of a collection, whatever their usecases, in key order.
+    ///
+    /// Meant for exports and migrations. The server reads the collection by pages of at
+    /// most `SCAN_PAGE_SIZE` keys, and the next page is only requested once the documents
+    /// of the previous one are consumed, so the collection is never buffered. Documents
+    /// the user may not read are left out. A document failing to decrypt is streamed as an
+    /// error and the scan goes on, a failed request ends the stream after its error.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to scan.
+    pub fn scan_collection<'a>(
+        &'a mut self,
+        collection: &str,
+    ) -> impl Stream<Item = Result<Document, Error>> + 'a {
+        let state = (self, collection.to_string(), Some(None));
+        stream::try_unfold(state, |(client, collection, after)| async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+            let (documents, next) = client.scan_page(&collection, after).await?;
+            let documents =
+                documents.into_iter().map(|document| document.map_err(|(_, err)| err));
+            let state = (client, collection, next.map(Some));
+            Ok::<_, Error>(Some((stream::iter(documents), state)))
+        })
+        .try_flatten()
+    }
 
-    let mut message_size = [0; 4];
-    let _size_error = stream.read(&mut message_size).await;
-    let decimal_size = u32::from_be_bytes(message_size);
-    trace!("message size: {}", decimal_size);
+    async fn scan_page(
+        &mut self,
+        collection: &str,
+        after: Option<String>,
+    ) -> Result<(Vec<DocumentResult>, Option<String>), Error> {
+        let (documents, next) = self.fetch_scan_page(collection, after).await?;
+        let aad = self.document_aad(&[]);
+        Ok((decrypt_documents(&self.key, None, documents, &aad), next))
+    }
 
-    let mut slice = vec![0; decimal_size as usize];
-    let _size_read = stream.read_exact(&mut slice).await;
-    trace!("slice: {:?}", slice);
-    let message: Message = serde_cbor::from_slice(&slice)?;
-    debug!("parsed message: {:#?}", message);
-    Ok(message)
-}
+    async fn fetch_scan_page(
+        &mut self,
+        collection: &str,
+        after: Option<String>,
+    ) -> Result<(Vec<StoredDocument>, Option<String>), Error> {
+        let message = Message::ScanCollection {
+            collection: collection.to_string(),
+            after,
+            limit: SCAN_PAGE_SIZE,
+        };
+        let request = self.send(message).await?;
+        match self.receive(request).await? {
+            Message::ScanPage { documents, next } => Ok((documents, next)),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
 
-fn convert_to_array12(slice: &Vec<u8>) -> Option<&[u8; 12]> {
-    if slice.len() == 12 {
-        let array_ref: &[u8; 12] = slice.as_slice().try_into().unwrap();
-        Some(array_ref)
-    } else {
-        None
+    /// Re-encrypts every document of a collection under a new master key.
+    ///
+    /// The server never holds keys, so the client drives the rotation: the collection is
+    /// scanned like `scan_collection`, each document is decrypted with the keyring of the
+    /// previous master keys and updated in place under `new_key`, the updates of a page
+    /// being sent together before their responses are read. A document the new key
+    /// already decrypts is skipped, so running the rekey again after an interruption
+    /// resumes it. The index entries of a document are recomputed from `indexed_fields`,
+    /// entries of other fields are dropped since their tokens depend on the key. Documents
+    /// inserted as a sequence of chunks and OPE values are not re-encrypted.
+    ///
+    /// The client keeps its key, set `key` to `new_key` once every collection is rekeyed.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to rekey.
+    /// * `old_keyring` - The master keys the documents may currently be encrypted under.
+    /// * `new_key` - The master key to encrypt the documents under.
+    /// * `indexed_fields` - The fields of the documents to index.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn rekey_collection(
+        &mut self,
+        collection: &str,
+        old_keyring: &[[u8; 32]],
+        new_key: [u8; 32],
+        indexed_fields: &[String],
+    ) -> Result<RekeyReport, Error> {
+        let old_keys: Vec<EncKey> = old_keyring
+            .iter()
+            .map(|key| EncKey::for_collection(key, collection))
+            .collect();
+        let new_keys = [EncKey::for_collection(&new_key, collection)];
+        let aad = self.document_aad(&[]);
+        let mut report = RekeyReport::default();
+        let mut after = None;
+        loop {
+            let (documents, next) = self.fetch_scan_page(collection, after).await?;
+            let mut pending = Vec::with_capacity(documents.len());
+            for document in documents {
+                let Some(nonce) = document.nonce.as_ref().and_then(convert_to_array12)
+                else {
+                    report.skipped += 1;
+                    continue;
+                };
+                let data = &document.data;
+                if decrypt_with_collection_keys(&new_keys, nonce, data, &aad).is_ok() {
+                    report.skipped += 1;
+                    continue;
+                }
+                let Ok(plaintext) =
+                    decrypt_with_collection_keys(&old_keys, nonce, data, &aad)
+                else {
+                    report.failed.push(document.id);
+                    continue;
+                };
+                let update = update_message(
+                    &new_key,
+                    document.id.clone(),
+                    collection.to_string(),
+                    plaintext,
+                    indexed_fields,
+                    None,
+                    &aad,
+                )?;
+                pending.push((document.id, self.send(update).await?));
+            }
+            for (id, request) in pending {
+                match self.receive(request).await? {
+                    Message::UpdateResponse { status: UpdateStatus::Success } => {
+                        report.rekeyed += 1
+                    }
+                    _ => report.failed.push(id),
+                }
+            }
+            match next {
+                Some(next) => after = Some(next),
+                None => return Ok(report),
+            }
+        }
+    }
+
+    /// Asks the server how it would run a query, without running it.
+    ///
+    /// The plan tells, per collection, whether documents are found through an index or
+    /// by reading the whole usecase, and estimates the number of documents read.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query object representing the database query.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn explain(&mut self, query: Query) -> Result<QueryPlan, Error> {
+        let message = Message::Explain(query);
+        let request = self.send(message).await?;
+        match self.receive(request).await? {
+            Message::ExplainResponse(plan) => Ok(plan),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Reads the entries of the audit log of the server selected by the filter.
+    ///
+    /// Every insert, update and delete is recorded with its time, user, collection and
+    /// document id, never with the data of the document. Only the audit admins
+    /// configured on the server read the entries of other users; the others receive
+    /// the entries of their own mutations, and `ServerError::Forbidden` when filtering
+    /// on another user.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The filter selecting the entries, oldest first.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn fetch_audit_log(
+        &mut self,
+        filter: AuditFilter,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        let message = Message::FetchAuditLog(filter);
+        let request = self.send(message).await?;
+        match self.receive(request).await? {
+            Message::AuditLogResponse(entries) => Ok(entries),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Runs a query on the server and returns the first page of its results.
+    ///
+    /// The server caches the matching ids under a cursor, the following pages are read
+    /// with `next_page` without running the query again. A cursor expires when it is not
+    /// read for the time to live configured on the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query object representing the database query.
+    /// * `page_size` - The maximum number of documents per page.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn open_cursor(
+        &mut self,
+        query: Query,
+        page_size: u32,
+    ) -> Result<QueryPage, Error> {
+        query.validate_names()?;
+        let keys: Vec<EncKey> = query_collections(&query)
+            .iter()
+            .map(|collection| EncKey::for_collection(&self.key, collection))
+            .collect();
+        let filter = predicate_filter(&query)?;
+        let message = Message::OpenCursor { query, page_size };
+        let request = self.send(message).await?;
+        self.receive_page(request, keys, filter).await
+    }
+
+    /// Fetches the next page of a cursor opened with `open_cursor`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor` - The cursor returned with the previous page.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn next_page(&mut self, cursor: QueryCursor) -> Result<QueryPage, Error> {
+        let message = Message::NextPage { cursor: cursor.id };
+        let request = self.send(message).await?;
+        self.receive_page(request, cursor.keys, cursor.filter).await
+    }
+
+    /// Drops a cursor opened with `open_cursor` before its last page, freeing it on the
+    /// server. The server does not answer.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn close_cursor(&mut self, cursor: QueryCursor) -> Result<(), Error> {
+        self.send(Message::CloseCursor { cursor: cursor.id }).await?;
+        Ok(())
+    }
+
+    /// Runs a query and calls `on_document` with each decrypted document, in the order of
+    /// the query, until it returns `ControlFlow::Break`.
+    ///
+    /// The server sends the pages without waiting for them to be asked. On a break the
+    /// client closes the cursor of the stream, so the server stops fetching pages, and
+    /// reads the pages already sent without handing them out. Useful when a condition on
+    /// the documents decides how many are needed, `latest` bounds a query beforehand.
+    ///
+    /// If the connection is lost before the last page, the client reconnects, see
+    /// `reconnect`, and the server streams the pages again after the last one handed out,
+    /// so no document is skipped or handed out twice. The stream fails once
+    /// `STREAM_RECONNECT_ATTEMPTS` attempts in a row could not reconnect.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query object representing the database query.
+    /// * `page_size` - The maximum number of documents per page the server sends.
+    /// * `on_document` - Called with each document, breaks to stop the query.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn query_each<F>(
+        &mut self,
+        query: Query,
+        page_size: u32,
+        mut on_document: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Vec<u8>) -> ControlFlow<()>,
+    {
+        query.validate_names()?;
+        let keys: Vec<EncKey> = query_collections(&query)
+            .iter()
+            .map(|collection| EncKey::for_collection(&self.key, collection))
+            .collect();
+        let filter = predicate_filter(&query)?;
+        let message = Message::StreamQuery { query: query.clone(), page_size };
+        let mut request = self.send(message).await?;
+        // Every page but the last covers `page_size` keys of the cursor, whatever the
+        // number of documents in it: a document deleted meanwhile is left out of its
+        // page, predicates leave out others.
+        let page_keys = u64::from(page_size.max(1));
+        let mut stopped = false;
+        let mut cursor_id = None;
+        let mut position = 0;
+        loop {
+            let received = self.receive_page(request, keys.clone(), filter.clone()).await;
+            let page = match received {
+                Err(err) if !stopped && is_connection_lost(&err) => {
+                    let resume = match &cursor_id {
+                        Some(id) => {
+                            Message::ResumeStream { cursor: id.clone(), position }
+                        }
+                        // No page was read, the stream starts over.
+                        None => Message::StreamQuery { query: query.clone(), page_size },
+                    };
+                    request = self.resend_after_reconnect(resume, err).await?;
+                    continue;
+                }
+                received => received?,
+            };
+            if let Some(cursor) = &page.cursor {
+                cursor_id = Some(cursor.id.clone());
+            }
+            position += page_keys;
+            if !stopped
+                && page.values.into_iter().any(|value| on_document(value).is_break())
+            {
+                stopped = true;
+                if let Some(cursor) = page.cursor.clone() {
+                    self.close_cursor(cursor).await?;
+                }
+            }
+            // The last page, or the empty one ending a stopped stream, has no cursor.
+            if page.cursor.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reconnects once the connection was lost with `lost` and sends the message on the
+    /// new connection, waiting `RECONNECT_BACKOFF`, then twice as long after a failure,
+    /// each delay being jittered, see `ReconnectJitter`.
+    async fn resend_after_reconnect(
+        &mut self,
+        message: Message,
+        lost: Error,
+    ) -> Result<SentRequest, Error> {
+        let jitter = self
+            .reconnect
+            .as_ref()
+            .map(|target| target.options.reconnect_jitter)
+            .unwrap_or_default();
+        let mut last_error = lost;
+        let mut backoff = RECONNECT_BACKOFF;
+        for _ in 0..STREAM_RECONNECT_ATTEMPTS {
+            tokio::time::sleep(reconnect_delay(backoff, jitter)).await;
+            backoff *= 2;
+            match self.reconnect().await {
+                Ok(()) => return self.send(message).await,
+                Err(err) => last_error = err,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn receive_page(
+        &mut self,
+        request: SentRequest,
+        keys: Vec<EncKey>,
+        filter: Option<SingleQuery>,
+    ) -> Result<QueryPage, Error> {
+        let message = self.receive(request).await?;
+        info!("message: {:?}", message);
+        let aad = self.document_aad(&[]);
+        let (cursor, mut values) = match message {
+            Message::QueryPageResponse { cursor, page: (data, Some(nonces)) } => {
+                let mut values = Vec::with_capacity(data.len());
+                for (cipher, nonce) in data.iter().zip(nonces.iter()) {
+                    let nonce = convert_to_array12(nonce)
+                        .ok_or(Error::encryption(AesError::Decrypt))?;
+                    values
+                        .push(decrypt_with_collection_keys(&keys, nonce, cipher, &aad)?);
+                }
+                (cursor, values)
+            }
+            Message::QueryPageResponse { cursor, page: (values, None) } => {
+                (cursor, values)
+            }
+            Message::ErrorResponse(error) => return Err(request.refused(error)),
+            _ => return Err(Error::MessageTypeError(MessageTypeError::default())),
+        };
+        if let Some(filter) = &filter {
+            values = retain_matching(values, filter)?;
+        }
+        let cursor = cursor.map(|id| QueryCursor { id, keys, filter });
+        Ok(QueryPage { values, cursor })
+    }
+
+    /// Modifies an existing document in the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of the document to be modified.
+    /// * `collection` - The name of the collection containing the document.
+    /// * `new_value` - The new value to be set in the document.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn modify(
+        &mut self,
+        id: String,
+        collection: String,
+        new_value: Vec<u8>,
+    ) -> Result<Message, Error> {
+        self.modify_indexed(id, collection, new_value, &[]).await
+    }
+
+    /// Modifies an existing document and replaces its index entries.
+    ///
+    /// The entries of the previous value are dropped, `modify` is `modify_indexed`
+    /// without indexed fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of the document to be modified.
+    /// * `collection` - The name of the collection containing the document.
+    /// * `new_value` - The new CBOR value to be set in the document.
+    /// * `indexed_fields` - The fields of the new value to index.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn modify_indexed(
+        &mut self,
+        id: String,
+        collection: String,
+        new_value: Vec<u8>,
+        indexed_fields: &[String],
+    ) -> Result<Message, Error> {
+        let message = self
+            .send_update(id, collection, new_value, indexed_fields, None)
+            .await?;
+        update_answer(message)
+    }
+
+    /// Modifies a document only if it is still at the expected version.
+    ///
+    /// A document is at version 0 when inserted and every update, conditional or not,
+    /// bumps its version, so a caller that read version `n` and updated it successfully
+    /// knows the document is at `n + 1`.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection containing the document.
+    /// * `id` - The identifier of the document to be modified.
+    /// * `expected_version` - The version the document must be at.
+    /// * `new_value` - The new value to be set in the document.
+    /// * `indexed_fields` - The fields of the new value to index, replacing the entries
+    ///   of the previous value as in `modify_indexed`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<UpdateStatus, Error>` - The status of the update, or
+    ///   `Error::VersionConflict` carrying the current version if it is not the expected one.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn update_if(
+        &mut self,
+        collection: String,
+        id: String,
+        expected_version: u64,
+        new_value: Vec<u8>,
+        indexed_fields: &[String],
+    ) -> Result<UpdateStatus, Error> {
+        let expected = Some(expected_version);
+        let response =
+            self.send_update(id, collection, new_value, indexed_fields, expected);
+        match response.await? {
+            Message::UpdateResponse { status } => Ok(status),
+            Message::ErrorResponse(ServerError::VersionConflict { current }) => {
+                Err(Error::VersionConflict { expected: expected_version, current })
+            }
+            Message::ErrorResponse(error) => {
+                Err(Error::ServerError { request: MessageType::Update, error })
+            }
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Encrypts the new value of a document and returns the response to its update.
+    async fn send_update(
+        &mut self,
+        id: String,
+        collection: String,
+        new_value: Vec<u8>,
+        indexed_fields: &[String],
+        expected_version: Option<u64>,
+    ) -> Result<Message, Error> {
+        let update = self.update_request(
+            id,
+            collection,
+            new_value,
+            indexed_fields,
+            expected_version,
+        )?;
+        let request = self.send(update).await?;
+        self.receive(request).await
+    }
+
+    /// Encrypts the new value of a document into its update, forgetting its cached value.
+    pub(crate) fn update_request(
+        &mut self,
+        id: String,
+        collection: String,
+        new_value: Vec<u8>,
+        indexed_fields: &[String],
+        expected_version: Option<u64>,
+    ) -> Result<Message, Error> {
+        self.cache.remove(&collection, &id);
+        update_message(
+            &self.key,
+            id,
+            collection,
+            new_value,
+            indexed_fields,
+            expected_version,
+            &self.document_aad(&[]),
+        )
+    }
+
+    /// Replaces the access control list and the usecases of a document.
+    ///
+    /// The encrypted data is left untouched, so nothing is re-encrypted. The server
+    /// authorizes the change against the current list of the document.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection containing the document.
+    /// * `id` - The identifier of the document.
+    /// * `acl` - The new access control list.
+    /// * `usecases` - The new use cases of the document, replacing the previous ones.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn update_metadata(
+        &mut self,
+        collection: String,
+        id: String,
+        acl: Vec<String>,
+        usecases: Vec<String>,
+    ) -> Result<UpdateStatus, Error> {
+        self.cache.remove(&collection, &id);
+        let update = MetadataUpdate { collection, id, acl, usecases };
+        let request = self.send(Message::UpdateMetadata(update)).await?;
+        match self.receive(request).await? {
+            Message::UpdateResponse { status } => Ok(status),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Reads the id, collection, access control list, usecases and insertion time of a
+    /// document, without its data.
+    ///
+    /// Nothing is decrypted, so listing who may access documents does not need their
+    /// key. The server answers if the user may read the metadata of the document or the
+    /// document itself, `ServerError::Forbidden` otherwise and
+    /// `ServerError::DocumentNotFound` if there is no such document.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection containing the document.
+    /// * `id` - The identifier of the document.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn describe_document(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<DocumentMeta, Error> {
+        validate_name(&collection)?;
+        let request = self.send(Message::DescribeDocument { collection, id }).await?;
+        match self.receive(request).await? {
+            Message::DocumentMetaResponse(meta) => Ok(meta),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Returns whether `key` is the master key a document was encrypted under, without
+    /// returning its plaintext.
+    ///
+    /// Meant to diagnose key mismatches, during migrations for instance. The document is
+    /// read from the server, bypassing the document cache, and decrypted with the
+    /// collection key derived from `key` under `associated_data`, the one the document
+    /// was inserted with, bound to the encryption context of the client. Its plaintext is
+    /// zeroized as soon as it is authenticated: a wrong key is only told apart by the
+    /// failing AEAD tag, in the time the decryption takes either way, and so is wrong
+    /// associated data.
+    ///
+    /// Fails with `Error::UnverifiableDocument` for an OPE value, which is not
+    /// authenticated, and for a document inserted by `insert_stream`, stored in chunks,
+    /// and with `ServerError::DocumentNotFound` if there is no such document.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The master key to verify.
+    /// * `collection` - The name of the collection containing the document.
+    /// * `id` - The identifier of the document.
+    /// * `associated_data` - The associated data the document was inserted with.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn verify_key_for_document(
+        &mut self,
+        key: &[u8; 32],
+        collection: String,
+        id: String,
+        associated_data: &[u8],
+    ) -> Result<bool, Error> {
+        validate_name(&collection)?;
+        let query = Query::GetById { id: id.clone(), collection: collection.clone() };
+        let request = self.send(Message::QueryDocuments(query)).await?;
+        match self.receive(request).await? {
+            Message::DocumentsResponse(documents) => match documents.first() {
+                // OPE values are stored without nonce.
+                Some(document) if document.nonce.as_ref().map_or(true, Vec::is_empty) => {
+                    Err(Error::UnverifiableDocument { collection, id })
+                }
+                Some(document) => {
+                    let aad = self.document_aad(associated_data);
+                    Ok(is_key_of_document(key, document, &aad))
+                }
+                // A document stored in chunks has no data under its key.
+                None => {
+                    let chunk = ChunkRequest {
+                        collection: collection.clone(),
+                        id: id.clone(),
+                        index: 0,
+                    };
+                    match self.fetch_chunk(chunk).await? {
+                        Some(_) => Err(Error::UnverifiableDocument { collection, id }),
+                        None => Err(request.refused(ServerError::DocumentNotFound)),
+                    }
+                }
+            },
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Returns each collection with the number of its documents the user may read, in
+    /// name order, to monitor the growth of the storage.
+    ///
+    /// Only the keys and access lists of documents are read, nothing is decrypted.
+    /// Tombstoned documents are not counted.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn collection_stats(&mut self) -> Result<Vec<(String, usize)>, Error> {
+        let request = self.send(Message::CollectionStats).await?;
+        match self.receive(request).await? {
+            Message::CollectionStatsResponse(stats) => Ok(stats
+                .into_iter()
+                .map(|(collection, count)| (collection, count as usize))
+                .collect()),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+
+    /// Deletes a document from the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of the document to be deleted.
+    /// * `collection` - The name of the collection containing the document.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn delete(
+        &mut self,
+        id: String,
+        collection: String,
+    ) -> Result<Message, Error> {
+        self.send_delete(Delete { collection, id, tombstone: false }).await
+    }
+
+    /// Marks a document as deleted, keeping it on the server until it is purged.
+    ///
+    /// A tombstoned document is left out of every query, as if it was deleted, and is
+    /// physically removed by a later `purge` of its collection.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of the document to be deleted.
+    /// * `collection` - The name of the collection containing the document.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn delete_tombstone(
+        &mut self,
+        id: String,
+        collection: String,
+    ) -> Result<Message, Error> {
+        self.send_delete(Delete { collection, id, tombstone: true }).await
+    }
+
+    async fn send_delete(&mut self, delete: Delete) -> Result<Message, Error> {
+        let message = self.delete_request(delete);
+        let request = self.send(message).await?;
+        let message = self.receive(request).await?;
+        delete_answer(message)
+    }
+
+    /// Returns the request deleting a document, forgetting its cached value.
+    pub(crate) fn delete_request(&mut self, delete: Delete) -> Message {
+        self.cache.remove(&delete.collection, &delete.id);
+        Message::Delete(delete)
+    }
+
+    /// Physically removes the documents of a collection tombstoned at least `older_than`
+    /// ago, returning how many were removed.
+    ///
+    /// Only the documents the user may write are removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The name of the collection to purge.
+    /// * `older_than` - How long ago a document must have been tombstoned.
+    #[instrument(skip_all, fields(user = %self.username))]
+    pub async fn purge(
+        &mut self,
+        collection: String,
+        older_than: Duration,
+    ) -> Result<u64, Error> {
+        validate_name(&collection)?;
+        let older_than_ms = u64::try_from(older_than.as_millis()).unwrap_or(u64::MAX);
+        let request = self.send(Message::Purge { collection, older_than_ms }).await?;
+        match self.receive(request).await? {
+            Message::PurgeResult(purged) => Ok(purged),
+            Message::ErrorResponse(error) => Err(request.refused(error)),
+            _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+        }
+    }
+}
+
+/// Parses a message from a stream, a TCP stream or any other transport.
+///
+/// # Arguments
+///
+/// * `stream` - A mutable reference to the read half of a stream.
+///
+/// # Returns
+///
+/// * `Result<Message, Error>` - The parsed message, or an error if parsing fails.
+pub async fn parse_message_from_tcp_stream<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Message, Error> {
+    read_message(stream, Compression::None, Format::Cbor).await
+}
+
+/// Opens the connection to the server at the given URL, over TLS when the options set
+/// it up.
+async fn connect_transport(
+    url: &str,
+    options: &ClientOptions,
+) -> Result<Box<dyn Transport>, Error> {
+    let stream = connect_tcp(url, options).await?;
+    let stream: Box<dyn Transport> = match &options.tls {
+        Some(tls) => Box::new(connect_tls(stream, tls).await?),
+        None => Box::new(stream),
+    };
+    Ok(stream)
+}
+
+/// Opens a TCP connection to the server with the socket options of the client.
+///
+/// The socket buffers are sized before connecting, when a size is set, and left to the
+/// system otherwise. `TCP_NODELAY` and the keepalive probes are set once connected.
+async fn connect_tcp(url: &str, options: &ClientOptions) -> io::Result<TcpStream> {
+    let stream = match options.socket_buffer_size {
+        None => TcpStream::connect(url).await?,
+        Some(buffer_size) => connect_sized(url, buffer_size).await?,
+    };
+    configure_socket(&stream, options)?;
+    Ok(stream)
+}
+
+/// Runs the TLS handshake over a connected socket, checking the certificate of the
+/// server against the options.
+async fn connect_tls(stream: TcpStream, tls: &Tls) -> io::Result<TlsStream<TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    let (_, invalid) = roots.add_parsable_certificates(&tls.root_certificates);
+    if invalid > 0 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "invalid root certificate"));
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(tls.server_name.as_str())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+    TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+}
+
+/// Opens a TCP connection to the server, asking for socket buffers of the given size.
+async fn connect_sized(url: &str, buffer_size: u32) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in lookup_host(url).await? {
+        match sized_socket(address, buffer_size)?.connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(ErrorKind::InvalidInput, "no address to connect to")
+    }))
+}
+
+/// Sets `TCP_NODELAY` and the keepalive probes of the options on a connected socket.
+fn configure_socket(stream: &TcpStream, options: &ClientOptions) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay())?;
+    if let Some(keepalive) = options.keepalive {
+        let probes = TcpKeepalive::new()
+            .with_time(keepalive.idle)
+            .with_interval(keepalive.interval);
+        SockRef::from(stream).set_tcp_keepalive(&probes)?;
+    }
+    Ok(())
+}
+
+/// Creates a socket for the address whose send and receive buffers have the given size.
+fn sized_socket(address: SocketAddr, buffer_size: u32) -> io::Result<TcpSocket> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_recv_buffer_size(buffer_size)?;
+    socket.set_send_buffer_size(buffer_size)?;
+    Ok(socket)
+}
+
+/// Returns the delay waited before an attempt at reconnecting, the backoff reached by the
+/// attempt jittered.
+fn reconnect_delay(backoff: Duration, jitter: ReconnectJitter) -> Duration {
+    let mut bytes = [0; 8];
+    rng::fill_random(&mut bytes);
+    // The 53 high bits make a uniform fraction in [0, 1), as many as an f64 holds.
+    let fraction = (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64;
+    match jitter {
+        ReconnectJitter::None => backoff,
+        ReconnectJitter::Full => backoff.mul_f64(fraction),
+        ReconnectJitter::Equal => backoff / 2 + (backoff / 2).mul_f64(fraction),
+    }
+}
+
+/// Returns whether the server picked a proposed mode, or the default one.
+fn is_accepted<T: Copy + Default + PartialEq>(picked: T, proposed: &[T]) -> bool {
+    picked == T::default() || proposed.contains(&picked)
+}
+
+/// Runs a future awaiting the response to a request of type `request`, failing with
+/// `Error::Timeout` if it does not complete in time.
+async fn with_timeout<T, F>(
+    duration: Option<Duration>,
+    request: MessageType,
+    future: F,
+) -> Result<T, Error>
+where
+    F: std::future::Future<Output = Result<T, Error>>,
+{
+    match duration {
+        Some(after) => timeout(after, future)
+            .await
+            .unwrap_or(Err(Error::Timeout { request, after })),
+        None => future.await,
+    }
+}
+
+/// Reads a frame whose body is serialized and compressed as negotiated.
+async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    compression: Compression,
+    format: Format,
+) -> Result<Message, Error> {
+    let (_, message) = read_frame(stream, compression, format).await?;
+    Ok(message)
+}
+
+/// Reads a frame and returns the request id it answers along its message.
+///
+/// A response sent as `ResponseChunk`s is read chunk after chunk and returned whole.
+async fn read_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    compression: Compression,
+    format: Format,
+) -> Result<(u32, Message), Error> {
+    let (request_id, message) = read_single_frame(stream, compression, format).await?;
+    let Message::ResponseChunk { mut index, mut last, mut chunk } = message else {
+        return Ok((request_id, message));
+    };
+    let mut response = ChunkedResponse::new();
+    loop {
+        if let Some(whole) = response.push(index, last, chunk, format)? {
+            return Ok((request_id, whole));
+        }
+        match read_single_frame(stream, compression, format).await? {
+            (id, Message::ResponseChunk { index: i, last: l, chunk: c })
+                if id == request_id =>
+            {
+                (index, last, chunk) = (i, l, c);
+            }
+            _ => return Err(FrameError::InterruptedChunks.into()),
+        }
+    }
+}
+
+/// Reads one frame, refusing a frame longer than `MAX_FRAME_LEN`.
+async fn read_single_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    compression: Compression,
+    format: Format,
+) -> Result<(u32, Message), Error> {
+    let mut header = [0; FrameHeader::LEN];
+    stream.read_exact(&mut header).await?;
+    let header = FrameHeader::from_bytes(&header);
+    let message_type = MessageType::try_from(header.message_type);
+    info!("messageType: {:?}", message_type);
+    trace!("request id: {}, message size: {}", header.request_id, header.length);
+    let length = header.length as usize;
+    if length.saturating_add(FrameHeader::LEN) > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge { length, max: MAX_FRAME_LEN }.into());
+    }
+
+    let mut slice = vec![0; header.length as usize];
+    stream.read_exact(&mut slice).await?;
+    trace!("slice: {:?}", slice);
+    let message = Message::from_network_body_in(slice, compression, format)?;
+    debug!("parsed message: {:#?}", message);
+    Ok((header.request_id, message))
+}
+
+/// Lists the distinct collections targeted by a query, in the order they appear.
+fn query_collections(query: &Query) -> Vec<String> {
+    match query {
+        Query::Single(single_query) => vec![single_query.collection.clone()],
+        Query::Compound(compound_query) => {
+            let mut collections = Vec::new();
+            for query in compound_query.queries.iter() {
+                for collection in query_collections(query) {
+                    if !collections.contains(&collection) {
+                        collections.push(collection);
+                    }
+                }
+            }
+            collections
+        }
+        Query::GetById { collection, .. } => vec![collection.clone()],
+        Query::GetByIds { collection, .. } => vec![collection.clone()],
+    }
+}
+
+/// Checks the response to a health check.
+pub(crate) fn health_answer(request: SentRequest, message: Message) -> Result<(), Error> {
+    match message {
+        Message::HealthResponse => Ok(()),
+        Message::ErrorResponse(error) => Err(request.refused(error)),
+        _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+    }
+}
+
+/// Returns the id of the document inserted by the request, given the response to it.
+pub(crate) fn inserted_id(
+    request: SentRequest,
+    collection: String,
+    id: Option<String>,
+    message: Message,
+) -> Result<String, Error> {
+    info!("message: {:?}", message);
+    match message {
+        Message::InsertResponse { inserted_id } => Ok(inserted_id),
+        Message::ErrorResponse(ServerError::DuplicateId) => {
+            Err(Error::DuplicateId { collection, id: id.unwrap_or_default() })
+        }
+        Message::ErrorResponse(error) => Err(request.refused(error)),
+        _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+    }
+}
+
+/// Checks the response to an update, see `AuthenticatedClient::modify`.
+pub(crate) fn update_answer(message: Message) -> Result<Message, Error> {
+    info!("message: {:?}", message);
+    match message {
+        Message::UpdateResponse { .. } => Ok(message),
+        _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+    }
+}
+
+/// Checks the response to a delete, see `AuthenticatedClient::delete`.
+pub(crate) fn delete_answer(message: Message) -> Result<Message, Error> {
+    info!("message: {:?}", message);
+    match message {
+        Message::DeleteResult(_) => Ok(message),
+        _ => Err(Error::MessageTypeError(MessageTypeError::default())),
+    }
+}
+
+/// Returns the single query whose predicates must be checked on the decrypted results.
+///
+/// Refuses a compound query with predicates in any of its subqueries: the documents of
+/// the response do not tell which subquery matched them.
+fn predicate_filter(query: &Query) -> Result<Option<SingleQuery>, Error> {
+    match query {
+        Query::Single(single_query) if !single_query.predicates.is_empty() => {
+            Ok(Some(single_query.clone()))
+        }
+        Query::Compound(compound_query) => {
+            let filtered = |query| !matches!(predicate_filter(query), Ok(None));
+            if compound_query.queries.iter().any(filtered) {
+                return Err(Error::PredicatesInCompoundQuery);
+            }
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Decrypts the documents of a query response, keeping those matching the predicates.
+///
+/// Documents without nonces are OPE values, returned as stored.
+fn decrypt_output(
+    keys: &[EncKey],
+    filter: Option<SingleQuery>,
+    (data, nonces): (Vec<Vec<u8>>, Option<Vec<Vec<u8>>>),
+    associated_data: &[u8],
+) -> Result<Vec<Vec<u8>>, Error> {
+    let Some(nonces) = nonces else {
+        return Ok(data);
+    };
+    let mut values = Vec::with_capacity(data.len());
+    for (cipher, nonce) in data.iter().zip(nonces.iter()) {
+        let nonce =
+            convert_to_array12(nonce).ok_or(Error::encryption(AesError::Decrypt))?;
+        values.push(decrypt_with_collection_keys(keys, nonce, cipher, associated_data)?);
+    }
+    if let Some(filter) = filter {
+        values = retain_matching(values, &filter)?;
+    }
+    Ok(values)
+}
+
+/// Decrypts every document with the key of its collection, keeping each failure apart.
+fn decrypt_documents(
+    master_key: &[u8; 32],
+    filter: Option<&SingleQuery>,
+    documents: Vec<StoredDocument>,
+    associated_data: &[u8],
+) -> Vec<DocumentResult> {
+    documents
+        .into_iter()
+        .map(|document| {
+            let data = match &document.nonce {
+                None => document.data,
+                Some(nonce) => {
+                    let key = EncKey::for_collection(master_key, &document.collection);
+                    convert_to_array12(nonce)
+                        .ok_or(Error::encryption(AesError::Decrypt))
+                        .and_then(|nonce| {
+                            let data = &document.data;
+                            decrypt_with_collection_keys(
+                                &[key],
+                                nonce,
+                                data,
+                                associated_data,
+                            )
+                        })
+                        .map_err(|err| {
+                            let err = err.in_collection(&document.collection);
+                            (document.id.clone(), err)
+                        })?
+                }
+            };
+            Ok(Document {
+                collection: document.collection,
+                id: document.id,
+                data,
+            })
+        })
+        .filter_map(|document| match (document, filter) {
+            (Ok(document), Some(filter)) => match filter.evaluate(&document.data) {
+                Ok(true) => Some(Ok(document)),
+                Ok(false) => None,
+                Err(err) => Some(Err((document.id, err.into()))),
+            },
+            (document, _) => Some(document),
+        })
+        .collect()
+}
+
+/// Returns whether a stored document authenticates under the collection key derived
+/// from the master key, zeroizing the key and the plaintext.
+fn is_key_of_document(
+    master_key: &[u8; 32],
+    document: &StoredDocument,
+    associated_data: &[u8],
+) -> bool {
+    let Some(nonce) = document.nonce.as_ref().and_then(convert_to_array12) else {
+        return false;
+    };
+    let keys = [EncKey::for_collection(master_key, &document.collection)];
+    let verified =
+        match decrypt_with_collection_keys(&keys, nonce, &document.data, associated_data)
+        {
+            Ok(mut plaintext) => {
+                plaintext.zeroize();
+                true
+            }
+            Err(_) => false,
+        };
+    verified
+}
+
+/// Keeps the decrypted documents satisfying the predicates of the query.
+fn retain_matching(
+    values: Vec<Vec<u8>>,
+    filter: &SingleQuery,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let mut matching = Vec::with_capacity(values.len());
+    for value in values {
+        if filter.evaluate(&value)? {
+            matching.push(value);
+        }
+    }
+    Ok(matching)
+}
+
+/// Decrypts a stored document with the first collection key that authenticates it.
+///
+/// Compound queries may return documents from several collections without telling
+/// which one each document belongs to, AES-GCM-SIV authentication picks the right key.
+/// Stored documents are written either by an insert or by an update, and are decrypted
+/// for the message that wrote them, see `stored_message_type`.
+fn decrypt_with_collection_keys(
+    keys: &[EncKey],
+    nonce: &[u8; 12],
+    payload: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let expected = stored_message_type(payload)?;
+    keys.iter()
+        .find_map(|key| {
+            decrypt_for_message(expected, key, nonce, payload, associated_data).ok()
+        })
+        .ok_or(Error::encryption(AesError::Decrypt))
+}
+
+/// Returns the message a stored document was encrypted for, an insert or an update.
+///
+/// Any other message is refused before decryption, and decrypting for the returned
+/// type binds it into the associated data, see `decrypt_for_message`.
+fn stored_message_type(payload: &[u8]) -> Result<MessageType, Error> {
+    match payload.first().map(|header| MessageType::try_from(*header)) {
+        Some(Ok(message_type @ (MessageType::Insert | MessageType::Update))) => {
+            Ok(message_type)
+        }
+        _ => Err(Error::encryption(AesError::Decrypt)),
+    }
+}
+
+/// Encrypts a document under the master key into an `Insertion` with a fresh nonce.
+fn encrypt_insertion(
+    master_key: &[u8; 32],
+    collection: String,
+    data: Vec<u8>,
+    associated_data: &[u8],
+    acl: Vec<String>,
+    usecases: Vec<String>,
+    index: Vec<IndexEntry>,
+) -> Result<Insertion, Error> {
+    validate_insertion_names(&collection, &usecases)?;
+    let nonce = generate_nonce();
+    let key = EncKey::for_collection(master_key, &collection);
+    let data =
+        encrypt_for_message(MessageType::Insert, &key, &nonce, &data, associated_data)
+            .map_err(|err| err.in_collection(&collection))?;
+    Ok(Insertion {
+        acl,
+        collection,
+        data,
+        usecases,
+        nonce: nonce.to_vec(),
+        index,
+        id: None,
+        upsert: false,
+    })
+}
+
+/// Encrypts a new value of a document under the master key into an `Update` message.
+fn update_message(
+    master_key: &[u8; 32],
+    id: String,
+    collection: String,
+    new_value: Vec<u8>,
+    indexed_fields: &[String],
+    expected_version: Option<u64>,
+    associated_data: &[u8],
+) -> Result<Message, Error> {
+    let nonce = generate_nonce();
+    let key = EncKey::for_collection(master_key, &collection);
+    let mac_key = MacKey::for_collection(master_key, &collection);
+    let index = index_entries(&mac_key, &new_value, indexed_fields)?;
+    let new_value = encrypt_for_message(
+        MessageType::Update,
+        &key,
+        &nonce,
+        &new_value,
+        associated_data,
+    )
+    .map_err(|err| err.in_collection(&collection))?;
+    let update = Update {
+        collection,
+        id,
+        new_value,
+        nonce: nonce.to_vec(),
+        index,
+        expected_version,
+    };
+    Ok(Message::Update(update))
+}
+
+/// Whether the error means the connection is gone.
+fn is_connection_lost(err: &Error) -> bool {
+    matches!(err, Error::ConnectionClosed(_) | Error::ConnectionReset(_))
+}
+
+fn convert_to_array12(slice: &Vec<u8>) -> Option<&[u8; 12]> {
+    if slice.len() == 12 {
+        let array_ref: &[u8; 12] = slice.as_slice().try_into().unwrap();
+        Some(array_ref)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use liserk_shared::auth::open_tagged_body;
+    use liserk_shared::name::{InvalidName, MAX_NAME_LENGTH};
+    use tokio::net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_io_error_kinds_are_classified() {
+        let classify = |kind| Error::from(io::Error::from(kind));
+        assert!(matches!(
+            classify(ErrorKind::ConnectionRefused),
+            Error::ConnectionRefused(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::ConnectionReset),
+            Error::ConnectionReset(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::ConnectionAborted),
+            Error::ConnectionReset(_)
+        ));
+        assert!(matches!(classify(ErrorKind::UnexpectedEof), Error::ConnectionClosed(_)));
+        assert!(matches!(classify(ErrorKind::BrokenPipe), Error::ConnectionClosed(_)));
+        assert!(matches!(classify(ErrorKind::PermissionDenied), Error::TokioIoError(_)));
+    }
+
+    #[test]
+    fn test_compound_query_with_predicates_is_refused() {
+        use liserk_shared::query::{CompoundQuery, QueryType, SingleQueryBuilder};
+
+        let single = |predicates: bool| {
+            let query = SingleQueryBuilder::default()
+                .with_collection("users".to_owned())
+                .with_usecase("adults".to_owned());
+            let query = if predicates {
+                query.with_field_exists("email".to_owned())
+            } else {
+                query
+            };
+            Query::Single(query.build())
+        };
+        let compound =
+            |queries| Query::Compound(CompoundQuery::new(QueryType::Or, queries));
+
+        assert!(predicate_filter(&single(true)).unwrap().is_some());
+        assert!(predicate_filter(&compound(vec![single(false)])).unwrap().is_none());
+        for queries in [
+            vec![single(false), single(true)],
+            vec![single(false), compound(vec![single(true)])],
+        ] {
+            assert!(matches!(
+                predicate_filter(&compound(queries)),
+                Err(Error::PredicatesInCompoundQuery)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_tampered_document_fails_alone() {
+        let master_key = [8; 32];
+        let key = EncKey::for_collection(&master_key, "users");
+        let mut documents: Vec<StoredDocument> = (0..3u8)
+            .map(|index| {
+                let nonce = [index; 12];
+                let data =
+                    encrypt_for_message(MessageType::Insert, &key, &nonce, &[index], &[])
+                        .unwrap();
+                StoredDocument {
+                    collection: "users".to_string(),
+                    id: index.to_string(),
+                    data,
+                    nonce: Some(nonce.to_vec()),
+                }
+            })
+            .collect();
+        let last = documents[1].data.len() - 1;
+        documents[1].data[last] ^= 1;
+
+        let results = decrypt_documents(&master_key, None, documents, &[]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().data, vec![0]);
+        assert!(
+            matches!(&results[1], Err((id, Error::EcryptionError { .. })) if id == "1")
+        );
+        assert_eq!(results[2].as_ref().unwrap().data, vec![2]);
+    }
+
+    #[test]
+    fn test_only_the_key_of_a_document_verifies() {
+        let master_key = [8; 32];
+        let insertion = encrypt_insertion(
+            &master_key,
+            "users".to_string(),
+            vec![1, 2],
+            &[],
+            vec![],
+            vec![],
+            vec![],
+        )
+        .unwrap();
+        let document = StoredDocument {
+            collection: "users".to_string(),
+            id: "1".to_string(),
+            data: insertion.data,
+            nonce: Some(insertion.nonce),
+        };
+
+        assert!(is_key_of_document(&master_key, &document, &[]));
+        assert!(!is_key_of_document(&[9; 32], &document, &[]));
+        assert!(!is_key_of_document(&master_key, &document, b"other context"));
+        let ope_value = StoredDocument { nonce: None, ..document };
+        assert!(!is_key_of_document(&master_key, &ope_value, &[]));
+    }
+
+    #[test]
+    fn test_documents_of_a_tenant_do_not_decrypt_for_another() {
+        let master_key = [8; 32];
+        let tenant_a = bind_context(Some(b"tenant-a"), &[]);
+        let tenant_b = bind_context(Some(b"tenant-b"), &[]);
+        let usecases = vec!["filter".to_string()];
+        let insertion = encrypt_insertion(
+            &master_key,
+            "users".to_string(),
+            vec![1, 2],
+            &tenant_a,
+            vec![],
+            usecases,
+            vec![],
+        )
+        .unwrap();
+        let decrypt = |associated_data: &[u8]| {
+            let document = StoredDocument {
+                collection: "users".to_string(),
+                id: "1".to_string(),
+                data: insertion.data.clone(),
+                nonce: Some(insertion.nonce.clone()),
+            };
+            decrypt_documents(&master_key, None, vec![document], associated_data)
+                .remove(0)
+        };
+
+        assert_eq!(decrypt(&tenant_a).unwrap().data, vec![1, 2]);
+        assert!(matches!(decrypt(&tenant_b), Err((_, Error::EcryptionError { .. }))));
+        assert!(matches!(decrypt(&[]), Err((_, Error::EcryptionError { .. }))));
+        assert_eq!(bind_context(None, b"aad"), b"aad");
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_closed_port_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let result = UnconnectedClient::default().connect(&address).await;
+        assert!(matches!(result, Err(Error::ConnectionRefused(_))));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_setup_response_is_a_protocol_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = socket.into_split();
+            let setup = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            assert!(matches!(setup, Message::ClientSetup(_)));
+            let frame = Message::HealthResponse.setup_for_network().unwrap();
+            write.write_all(&frame).await.unwrap();
+        });
+
+        let result = UnconnectedClient::default().connect(&address).await;
+        server.await.unwrap();
+        assert!(matches!(result, Err(Error::ProtocolError(MessageType::HealthResponse))));
+    }
+
+    /// Encapsulates a secret to the public key of a client setup, returning the
+    /// `key_encapsulation` of the `SetupResponse` and the secret.
+    pub(crate) fn encapsulate(setup: &Message) -> (Vec<u8>, [u8; 32]) {
+        let Message::ClientSetup(setup) = setup else {
+            panic!("the connection did not start with its setup");
+        };
+        let (ciphertext, shared_secret) =
+            pqc_kyber::encapsulate(setup.public_key(), &mut rand::thread_rng()).unwrap();
+        (ciphertext.to_vec(), shared_secret)
+    }
+
+    /// Accepts a client and answers its setup and authentication, returning the key
+    /// tagging its requests.
+    pub(crate) async fn accept_authenticated(
+        listener: &TcpListener,
+    ) -> (OwnedReadHalf, OwnedWriteHalf, [u8; 32]) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (mut read, mut write) = socket.into_split();
+        let setup = parse_message_from_tcp_stream(&mut read).await.unwrap();
+        let (key_encapsulation, shared_secret) = encapsulate(&setup);
+        let setup = Message::SetupResponse {
+            compression: Compression::None,
+            format: Format::Cbor,
+            capabilities: vec![MessageType::Insert, MessageType::Delete],
+            auth_mechanism: AuthMechanism::Password,
+            key_encapsulation,
+        };
+        write.write_all(&setup.setup_for_network().unwrap()).await.unwrap();
+        parse_message_from_tcp_stream(&mut read).await.unwrap();
+        let session_token = SessionToken {
+            token: "token".to_string(),
+            username: "Bob".to_string(),
+            expires_at: 0,
+        };
+        let response = Message::AuthentificationResponse(session_token);
+        write.write_all(&response.setup_for_network().unwrap()).await.unwrap();
+        (read, write, frame_key(&shared_secret, "token"))
+    }
+
+    /// Reads a request of a client authenticated by `accept_authenticated`, checking the
+    /// tag of the frame under the key returned with it.
+    pub(crate) async fn read_request<R: AsyncRead + Unpin>(
+        read: &mut R,
+        frame_key: &[u8],
+    ) -> Result<(u32, Message), Error> {
+        let mut header = [0; FrameHeader::LEN];
+        read.read_exact(&mut header).await?;
+        let header = FrameHeader::from_bytes(&header);
+        let mut body = vec![0; header.length as usize];
+        read.read_exact(&mut body).await?;
+        let body =
+            open_tagged_body(frame_key, header.message_type, header.request_id, body)
+                .expect("request not tagged under the frame key");
+        let message = Message::from_network_body(body, Compression::None)?;
+        Ok((header.request_id, message))
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_matched_by_request_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+
+            // Answers the second request before the first one.
+            let (first, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let (second, _) = read_request(&mut read, &tag_key).await.unwrap();
+            for (request_id, deleted) in [(second, false), (first, true)] {
+                let response = Message::DeleteResult(deleted);
+                let frame = response.setup_for_network_as(request_id, Compression::None);
+                write.write_all(&frame.unwrap()).await.unwrap();
+            }
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let delete = |id: &str| {
+            Message::Delete(Delete {
+                collection: "users".to_string(),
+                id: id.to_string(),
+                tombstone: false,
+            })
+        };
+        let first = client.send(delete("1")).await.unwrap();
+        let second = client.send(delete("2")).await.unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(client.receive(first).await.unwrap(), Message::DeleteResult(true));
+        assert_eq!(client.receive(second).await.unwrap(), Message::DeleteResult(false));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_measures_the_round_trip_of_its_own_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (delete, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let (ping, message) = read_request(&mut read, &tag_key).await.unwrap();
+            assert_eq!(message, Message::HealthCheck);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // The ping is answered before the request sent earlier.
+            for (request_id, response) in
+                [(ping, Message::HealthResponse), (delete, Message::DeleteResult(true))]
+            {
+                let frame = response.setup_for_network_as(request_id, Compression::None);
+                write.write_all(&frame.unwrap()).await.unwrap();
+            }
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let delete = Delete {
+            collection: "users".to_string(),
+            id: "1".to_string(),
+            tombstone: false,
+        };
+        let delete = client.send(Message::Delete(delete)).await.unwrap();
+        let latency = client.ping().await.unwrap();
+        assert!(latency >= Duration::from_millis(20));
+        assert!(latency < Duration::from_secs(5));
+        assert_eq!(client.receive(delete).await.unwrap(), Message::DeleteResult(true));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_options_are_applied_on_the_connected_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let options = ClientOptions::default();
+        let stream = connect_tcp(&address, &options).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+        accepted.await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let options = crate::builder::ClientBuilder::new()
+            .nodelay(false)
+            .keepalive(Duration::from_secs(30), Duration::from_secs(5))
+            .socket_buffer_size(64 * 1024)
+            .build()
+            .options()
+            .clone();
+        let stream = connect_tcp(&address, &options).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        }
+        accepted.await.unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_delays_vary_within_the_backoff() {
+        let backoff = Duration::from_millis(400);
+        let full: Vec<Duration> = (0..32)
+            .map(|_| reconnect_delay(backoff, ReconnectJitter::Full))
+            .collect();
+        assert!(full.iter().all(|delay| *delay <= backoff));
+        assert!(full.iter().any(|delay| *delay != full[0]));
+
+        let equal: Vec<Duration> = (0..32)
+            .map(|_| reconnect_delay(backoff, ReconnectJitter::Equal))
+            .collect();
+        assert!(equal.iter().all(|delay| backoff / 2 <= *delay && *delay <= backoff));
+        assert!(equal.iter().any(|delay| *delay != equal[0]));
+
+        assert_eq!(reconnect_delay(backoff, ReconnectJitter::None), backoff);
+    }
+
+    #[test]
+    fn test_socket_buffer_size_is_applied() {
+        let address = "127.0.0.1:0".parse().unwrap();
+        let socket = sized_socket(address, 64 * 1024).unwrap();
+        // The system may round the size up, Linux doubles it.
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_frames_larger_than_the_read_buffer_are_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let inserted_id = "x".repeat(256 * 1024);
+        let response = Message::InsertResponse { inserted_id: inserted_id.clone() };
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+        });
+
+        let client = crate::builder::ClientBuilder::new()
+            .read_buffer_size(16)
+            .socket_buffer_size(128 * 1024)
+            .build();
+        let client = client.connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let request = client.send(Message::HealthCheck).await.unwrap();
+        assert_eq!(
+            client.receive(request).await.unwrap(),
+            Message::InsertResponse { inserted_id }
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_capabilities_come_from_the_setup() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(accept_authenticated(&listener));
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        assert_eq!(
+            client.server_capabilities(),
+            [MessageType::Insert, MessageType::Delete]
+        );
+        assert!(client.supports(MessageType::Delete));
+        assert!(!client.supports(MessageType::Count));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authentication_by_challenge_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = socket.into_split();
+            let setup = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            let Message::ClientSetup(proposal) = &setup else {
+                panic!("the connection did not start with its setup");
+            };
+            assert_eq!(proposal.auth_mechanisms(), [AuthMechanism::ChallengeResponse]);
+            let response = Message::SetupResponse {
+                compression: Compression::None,
+                format: Format::Cbor,
+                capabilities: vec![],
+                auth_mechanism: AuthMechanism::ChallengeResponse,
+                key_encapsulation: encapsulate(&setup).0,
+            };
+            write.write_all(&response.setup_for_network().unwrap()).await.unwrap();
+
+            let message = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            let Message::ClientChallengeAuthentification { username } = message else {
+                panic!("unexpected message {:?}", message);
+            };
+            let challenge = vec![3; 32];
+            let response = Message::AuthChallenge { challenge: challenge.clone() };
+            write.write_all(&response.setup_for_network().unwrap()).await.unwrap();
+
+            let message = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            let Message::ClientChallengeResponse { mac } = message else {
+                panic!("unexpected message {:?}", message);
+            };
+            assert!(liserk_shared::auth::verify_challenge_mac(
+                b"shared secret",
+                &username,
+                &challenge,
+                &mac
+            ));
+            let session_token = SessionToken {
+                token: "token".to_string(),
+                username,
+                expires_at: 0,
+            };
+            let response = Message::AuthentificationResponse(session_token);
+            write.write_all(&response.setup_for_network().unwrap()).await.unwrap();
+        });
+
+        let client = crate::builder::ClientBuilder::new()
+            .auth_mechanisms(vec![AuthMechanism::ChallengeResponse])
+            .build();
+        let client = client.connect(&address).await.unwrap();
+        assert_eq!(client.auth_mechanism(), AuthMechanism::ChallengeResponse);
+        let client = client
+            .authenticate_with_key(
+                "billing-service".to_string(),
+                b"shared secret",
+                [0; 32],
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.username(), "billing-service");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_setup_exchange_precedes_authentication() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = socket.into_split();
+            let setup = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            let Message::ClientSetup(proposal) = &setup else {
+                panic!("first message is not the setup: {:?}", setup);
+            };
+            assert_eq!(proposal.compression(), &[Compression::Zstd]);
+            let response = Message::SetupResponse {
+                compression: Compression::Zstd,
+                format: Format::Cbor,
+                capabilities: vec![],
+                auth_mechanism: AuthMechanism::Password,
+                key_encapsulation: encapsulate(&setup).0,
+            };
+            write.write_all(&response.setup_for_network().unwrap()).await.unwrap();
+
+            let (_, message) =
+                read_frame(&mut read, Compression::Zstd, Format::Cbor).await.unwrap();
+            assert!(matches!(message, Message::ClientAuthentification(_)));
+        });
+
+        let client = UnconnectedClient::default()
+            .connect_with_compression(&address, vec![Compression::Zstd])
+            .await
+            .unwrap();
+        let result = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await;
+        server.await.unwrap();
+        // The server closed the connection without answering the authentication.
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_setup_picking_an_unproposed_compression_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = socket.into_split();
+            let setup = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            let response = Message::SetupResponse {
+                compression: Compression::Zstd,
+                format: Format::Cbor,
+                capabilities: vec![],
+                auth_mechanism: AuthMechanism::Password,
+                key_encapsulation: encapsulate(&setup).0,
+            };
+            write.write_all(&response.setup_for_network().unwrap()).await.unwrap();
+        });
+
+        let result = UnconnectedClient::default().connect(&address).await;
+        server.await.unwrap();
+        assert!(matches!(result, Err(Error::ProtocolError(MessageType::SetupResponse))));
+    }
+
+    #[tokio::test]
+    async fn test_events_of_a_connect_and_insert() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let response = Message::InsertResponse { inserted_id: "42".to_string() };
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+        });
+
+        let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let client = UnconnectedClient::default().with_events(sender);
+        let client = client.connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let inserted_id = client
+            .insert("users".to_string(), vec![1], vec![], vec![], vec!["x".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(inserted_id, "42");
+        server.await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![
+                ClientEvent::Connected,
+                ClientEvent::Authenticated { username: "Bob".to_string() },
+                ClientEvent::RequestSent {
+                    request_id: 1,
+                    message_type: MessageType::Insert,
+                },
+                ClientEvent::ResponseReceived {
+                    request_id: 1,
+                    message_type: MessageType::InsertResponse,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_terminate_connection_twice_is_a_no_op() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, message) = read_request(&mut read, &tag_key).await.unwrap();
+            assert_eq!(message, Message::EndOfCommunication);
+            let response = Message::CloseCommunication;
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        client.terminate_connection().await.unwrap();
+        assert!(!client.is_alive());
+        client.terminate_connection().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered_chunk_is_never_written() {
+        let nonce = [7; 12];
+        let key = EncKey::for_collection(&[0; 32], "users");
+        let mut chunks: Vec<Vec<u8>> =
+            ChunkEncryptor::new(&key, &nonce, &[5; 300][..], b"blob", 100)
+                .collect::<Result<_, _>>()
+                .unwrap();
+        chunks[1][10] ^= 1;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            // Every download stops at the tampered second chunk.
+            for _ in 0..4 {
+                let (request_id, message) =
+                    read_request(&mut read, &tag_key).await.unwrap();
+                let Message::FetchChunk(request) = message else {
+                    panic!("unexpected message {:?}", message);
+                };
+                let index = request.index as usize;
+                let response = Message::ChunkResponse(Some(StoredChunk {
+                    nonce: nonce.to_vec(),
+                    chunk: chunks[index].clone(),
+                    last: index == chunks.len() - 1,
+                }));
+                let frame = response.setup_for_network_as(request_id, Compression::None);
+                write.write_all(&frame.unwrap()).await.unwrap();
+            }
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        for (mode, expected) in [
+            (StreamVerifyMode::PerChunk, vec![5; 100]),
+            (StreamVerifyMode::BufferAndVerify, vec![]),
+        ] {
+            let mut written = Vec::new();
+            let result = client
+                .download_to("users".to_string(), "blob".to_string(), &mut written, mode)
+                .await;
+            assert!(result.is_err());
+            assert_eq!(written, expected);
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collection_scan_requests_pages_until_the_last_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let pages = [
+                (None, vec!["1", "2"], Some("users:2:usecases")),
+                (Some("users:2:usecases"), vec![], Some("users:3:acl")),
+                (Some("users:3:acl"), vec!["4"], None),
+            ];
+            for (expected_after, ids, next) in pages {
+                let (request_id, message) =
+                    read_request(&mut read, &tag_key).await.unwrap();
+                let Message::ScanCollection { collection, after, limit } = message else {
+                    panic!("unexpected message {:?}", message);
+                };
+                assert_eq!(collection, "users");
+                assert_eq!(after.as_deref(), expected_after);
+                assert_eq!(limit, SCAN_PAGE_SIZE);
+                let documents = ids
+                    .into_iter()
+                    .map(|id| StoredDocument {
+                        collection: "users".to_string(),
+                        id: id.to_string(),
+                        data: id.as_bytes().to_vec(),
+                        nonce: None,
+                    })
+                    .collect();
+                let response =
+                    Message::ScanPage { documents, next: next.map(String::from) };
+                let frame = response.setup_for_network_as(request_id, Compression::None);
+                write.write_all(&frame.unwrap()).await.unwrap();
+            }
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let documents: Vec<Document> =
+            client.scan_collection("users").try_collect().await.unwrap();
+        let ids: Vec<&str> =
+            documents.iter().map(|document| document.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "4"]);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cached_document_is_read_without_a_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            // Answers a single read, the connection is closed after it.
+            let (request_id, message) = read_request(&mut read, &tag_key).await.unwrap();
+            let query = Query::GetById {
+                id: "1".to_string(),
+                collection: "users".to_string(),
+            };
+            assert_eq!(message, Message::QueryDocuments(query));
+            let document = StoredDocument {
+                collection: "users".to_string(),
+                id: "1".to_string(),
+                data: b"bob".to_vec(),
+                nonce: None,
+            };
+            let response = Message::DocumentsResponse(vec![document]);
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+        });
+
+        let client = crate::builder::ClientBuilder::new().document_cache(8).build();
+        let client = client.connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let expected = Document {
+            collection: "users".to_string(),
+            id: "1".to_string(),
+            data: b"bob".to_vec(),
+        };
+        for _ in 0..2 {
+            let document = client.get_document("users".into(), "1".into()).await;
+            assert_eq!(document.unwrap(), Some(expected.clone()));
+        }
+        server.await.unwrap();
+
+        client.clear_document_cache();
+        let result = client.get_document("users".into(), "1".into()).await;
+        assert!(matches!(
+            result,
+            Err(Error::ConnectionClosed(_) | Error::ConnectionReset(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_flush_writes_a_buffered_insert_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (buffered_tx, buffered_rx) = tokio::sync::oneshot::channel();
+        let server = tokio::spawn(async move {
+            let (mut read, _write, tag_key) = accept_authenticated(&listener).await;
+            let nothing =
+                timeout(Duration::from_millis(100), read_request(&mut read, &tag_key));
+            assert!(nothing.await.is_err());
+            buffered_tx.send(()).unwrap();
+            let (_, message) = read_request(&mut read, &tag_key).await.unwrap();
+            assert!(matches!(message, Message::Insert(_)));
+        });
+
+        let client = crate::builder::ClientBuilder::new().write_buffer_size(64 * 1024);
+        let client = client.build().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let insertion = Insertion {
+            collection: "users".to_string(),
+            acl: Vec::new(),
+            data: vec![1, 2, 3],
+            usecases: Vec::new(),
+            nonce: vec![0; 12],
+            index: Vec::new(),
+            id: None,
+            upsert: false,
+        };
+        client.send(Message::Insert(insertion)).await.unwrap();
+        buffered_rx.await.unwrap();
+        client.flush().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_document_larger_than_a_frame_is_read_from_chunks() {
+        let document = StoredDocument {
+            collection: "users".to_string(),
+            id: "1".to_string(),
+            data: vec![7; MAX_FRAME_LEN],
+            nonce: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let stored = document.clone();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let response = Message::DocumentsResponse(vec![stored]);
+            let frames = response
+                .setup_for_network_chunked(
+                    request_id,
+                    Compression::None,
+                    Format::Cbor,
+                    MAX_FRAME_LEN,
+                )
+                .unwrap();
+            assert!(frames.len() > 1);
+            for frame in frames {
+                assert!(frame.len() <= MAX_FRAME_LEN);
+                write.write_all(&frame).await.unwrap();
+            }
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let read = client.get_document("users".into(), "1".into()).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(read.unwrap().data, document.data);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_query_resumes_after_a_lost_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let page = |request_id, cursor: Option<&str>, documents: &[&str]| {
+                let documents = documents.iter().map(|data| data.as_bytes().to_vec());
+                let page = (documents.collect(), None);
+                let cursor = cursor.map(str::to_string);
+                let response = Message::QueryPageResponse { cursor, page };
+                response.setup_for_network_as(request_id, Compression::None).unwrap()
+            };
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, message) = read_request(&mut read, &tag_key).await.unwrap();
+            assert!(matches!(message, Message::StreamQuery { page_size: 2, .. }));
+            write
+                .write_all(&page(request_id, Some("c"), &["0", "1"]))
+                .await
+                .unwrap();
+            // The connection is lost in the middle of the second page.
+            let second = page(request_id, Some("c"), &["2", "3"]);
+            write.write_all(&second[..second.len() / 2]).await.unwrap();
+            drop((read, write));
+
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, message) = read_request(&mut read, &tag_key).await.unwrap();
+            let resume = Message::ResumeStream { cursor: "c".to_string(), position: 2 };
+            assert_eq!(message, resume);
+            write
+                .write_all(&page(request_id, Some("c"), &["2", "3"]))
+                .await
+                .unwrap();
+            write.write_all(&page(request_id, None, &["4"])).await.unwrap();
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let query = Query::Single(SingleQuery::new("users".into(), "filter".into()));
+        let mut documents = Vec::new();
+        client
+            .query_each(query, 2, |document| {
+                documents.push(document);
+                ControlFlow::Continue(())
+            })
+            .await
+            .unwrap();
+        server.await.unwrap();
+        let expected: Vec<Vec<u8>> = ["0", "1", "2", "3", "4"]
+            .iter()
+            .map(|data| data.as_bytes().to_vec())
+            .collect();
+        assert_eq!(documents, expected);
+        assert!(client.is_alive());
+    }
+
+    #[tokio::test]
+    async fn test_stream_resumes_after_the_keys_paged_not_the_documents_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let page = |request_id, cursor: Option<&str>, documents: &[&str]| {
+                let documents = documents.iter().map(|data| data.as_bytes().to_vec());
+                let cursor = cursor.map(str::to_string);
+                let response = Message::QueryPageResponse {
+                    cursor,
+                    page: (documents.collect(), None),
+                };
+                response.setup_for_network_as(request_id, Compression::None).unwrap()
+            };
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, _) = read_request(&mut read, &tag_key).await.unwrap();
+            // The second document of the first page was deleted during the stream.
+            write.write_all(&page(request_id, Some("c"), &["0"])).await.unwrap();
+            drop((read, write));
+
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, message) = read_request(&mut read, &tag_key).await.unwrap();
+            let resume = Message::ResumeStream { cursor: "c".to_string(), position: 2 };
+            assert_eq!(message, resume);
+            write.write_all(&page(request_id, None, &["2"])).await.unwrap();
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let query = Query::Single(SingleQuery::new("users".into(), "filter".into()));
+        let mut documents = Vec::new();
+        client
+            .query_each(query, 2, |document| {
+                documents.push(document);
+                ControlFlow::Continue(())
+            })
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(documents, vec![b"0".to_vec(), b"2".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_breaking_a_streamed_query_closes_its_cursor() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, message) = read_request(&mut read, &tag_key).await.unwrap();
+            assert!(matches!(message, Message::StreamQuery { page_size: 2, .. }));
+            let page = (vec![b"1".to_vec(), b"2".to_vec()], None);
+            let response =
+                Message::QueryPageResponse { cursor: Some("c".to_string()), page };
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+
+            let (_, message) = read_request(&mut read, &tag_key).await.unwrap();
+            assert_eq!(message, Message::CloseCursor { cursor: "c".to_string() });
+            let response =
+                Message::QueryPageResponse { cursor: None, page: (vec![], None) };
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let query = Query::Single(SingleQuery::new("users".into(), "filter".into()));
+        let mut documents = Vec::new();
+        client
+            .query_each(query, 2, |document| {
+                documents.push(document);
+                ControlFlow::Break(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(documents, vec![b"1".to_vec()]);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_names_are_refused_before_being_sent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, _write, tag_key) = accept_authenticated(&listener).await;
+            // Nothing is sent after the authentication.
+            assert!(read_request(&mut read, &tag_key).await.is_err());
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let inserted = client
+            .insert(
+                "users\nINFO forged".to_string(),
+                vec![1],
+                vec![],
+                vec![],
+                vec!["filter".to_string()],
+            )
+            .await;
+        assert!(matches!(
+            inserted,
+            Err(Error::InvalidName(InvalidName::ControlCharacter(_)))
+        ));
+        let query =
+            SingleQuery::new("users".to_string(), "f".repeat(MAX_NAME_LENGTH + 1));
+        let queried = client.query(Query::Single(query)).await;
+        assert!(matches!(queried, Err(Error::InvalidName(InvalidName::TooLong { .. }))));
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = socket.into_split();
+            let setup = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            let setup = Message::SetupResponse {
+                compression: Compression::None,
+                format: Format::Cbor,
+                capabilities: vec![],
+                auth_mechanism: AuthMechanism::Password,
+                key_encapsulation: encapsulate(&setup).0,
+            };
+            write.write_all(&setup.setup_for_network().unwrap()).await.unwrap();
+            // Never answers the authentication.
+            let _ = parse_message_from_tcp_stream(&mut read).await;
+            (read, write)
+        });
+
+        let client = crate::builder::ClientBuilder::new()
+            .request_timeout(Duration::from_millis(50))
+            .build();
+        let client = client.connect(&address).await.unwrap();
+        let result = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await;
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_single_value_with_a_malformed_nonce_fails_to_decrypt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let response = Message::SingleValueResponse {
+                data: Some(vec![1; 16]),
+                nonce: Some(vec![0; 5]),
+            };
+            let frame = response.setup_for_network_as(request_id, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+        });
+
+        let client = UnconnectedClient::default().connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let query = Query::Single(SingleQuery::new("users".into(), "filter".into()));
+        let result = client.query(query).await;
+        assert!(matches!(
+            result,
+            Err(Error::EcryptionError { source: AesError::Decrypt, .. })
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_check_reports_the_refusal_of_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = socket.into_split();
+            let message = parse_message_from_tcp_stream(&mut read).await.unwrap();
+            assert_eq!(message, Message::HealthCheck);
+            let error = ServerError::TooManyConnections { max_connections: 1 };
+            let response = Message::ErrorResponse(error).setup_for_network().unwrap();
+            write.write_all(&response).await.unwrap();
+            let _ = parse_message_from_tcp_stream(&mut read).await;
+        });
+
+        let result = UnconnectedClient::default().health_check(&address).await;
+        assert!(matches!(
+            result,
+            Err(Error::ServerError {
+                request: MessageType::HealthCheck,
+                error: ServerError::TooManyConnections { .. },
+            })
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_check_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // Never answers the check.
+            let _ = parse_message_from_tcp_stream(&mut socket).await;
+            socket
+        });
+
+        let client = crate::builder::ClientBuilder::new()
+            .request_timeout(Duration::from_millis(50))
+            .build();
+        let result = client.health_check(&address).await;
+        assert!(matches!(
+            result,
+            Err(Error::Timeout { request: MessageType::HealthCheck, .. })
+        ));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_over_tls_checks_the_server_certificate() {
+        use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = certificate.serialize_der().unwrap();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(der.clone())],
+                PrivateKey(certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = acceptor.accept(socket).await.unwrap();
+            let setup = parse_message_from_tcp_stream(&mut socket).await.unwrap();
+            let setup = Message::SetupResponse {
+                compression: Compression::None,
+                format: Format::Cbor,
+                capabilities: vec![],
+                auth_mechanism: AuthMechanism::Password,
+                key_encapsulation: encapsulate(&setup).0,
+            };
+            socket.write_all(&setup.setup_for_network().unwrap()).await.unwrap();
+            socket.flush().await.unwrap();
+            // The second client trusts no authority and aborts the handshake.
+            let (socket, _) = listener.accept().await.unwrap();
+            assert!(acceptor.accept(socket).await.is_err());
+        });
+
+        let client = crate::builder::ClientBuilder::new().tls("localhost", vec![der]);
+        client.build().connect(&address).await.unwrap();
+        let client = crate::builder::ClientBuilder::new().tls("localhost", vec![]);
+        assert!(client.build().connect(&address).await.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_in_the_middle_of_a_frame_closes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            let (request_id, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let frame = Message::HealthResponse
+                .setup_for_network_as(request_id, Compression::None);
+            let frame = frame.unwrap();
+            write.write_all(&frame[..frame.len() / 2]).await.unwrap();
+            // The rest of the frame comes after the timeout, never read by the client.
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _ = write.write_all(&frame[frame.len() / 2..]).await;
+            assert!(read_request(&mut read, &tag_key).await.is_err());
+        });
+
+        let client = crate::builder::ClientBuilder::new()
+            .request_timeout(Duration::from_millis(50))
+            .build();
+        let client = client.connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        assert!(matches!(client.ping().await, Err(Error::Timeout { .. })));
+        assert!(!client.is_alive());
+        assert!(matches!(client.ping().await, Err(Error::ConnectionClosed(_))));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_drops_the_pending_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut read, mut write, tag_key) = accept_authenticated(&listener).await;
+            // Only the second request is answered.
+            let _ = read_request(&mut read, &tag_key).await.unwrap();
+            let (second, _) = read_request(&mut read, &tag_key).await.unwrap();
+            let frame =
+                Message::HealthResponse.setup_for_network_as(second, Compression::None);
+            write.write_all(&frame.unwrap()).await.unwrap();
+            assert!(read_request(&mut read, &tag_key).await.is_err());
+        });
+
+        let client = crate::builder::ClientBuilder::new()
+            .request_timeout(Duration::from_millis(50))
+            .build();
+        let client = client.connect(&address).await.unwrap();
+        let mut client = client
+            .authenticate("Bob".to_string(), "Pomme".to_string(), [0; 32])
+            .await
+            .unwrap();
+        let first = client.send(Message::HealthCheck).await.unwrap();
+        let second = client.send(Message::HealthCheck).await.unwrap();
+        assert!(matches!(client.receive(first).await, Err(Error::Timeout { .. })));
+        assert!(client.responses.lock().await.pending.is_empty());
+        assert!(matches!(client.receive(second).await, Err(Error::ConnectionClosed(_))));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_closing_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            drop(socket);
+        });
+
+        let (mut read, _write) = TcpStream::connect(address).await.unwrap().into_split();
+        server.await.unwrap();
+        let result = parse_message_from_tcp_stream(&mut read).await;
+        assert!(matches!(result, Err(Error::ConnectionClosed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_server_reset_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            // A zero linger makes the close send a RST instead of a FIN.
+            socket.set_linger(Some(Duration::ZERO)).unwrap();
+            drop(socket);
+        });
+
+        let (mut read, _write) = TcpStream::connect(address).await.unwrap().into_split();
+        server.await.unwrap();
+        let result = parse_message_from_tcp_stream(&mut read).await;
+        assert!(matches!(result, Err(Error::ConnectionReset(_))));
     }
 }
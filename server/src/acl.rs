@@ -0,0 +1,199 @@
+//! Authorization of reads and writes of documents against their access control list.
+//!
+//! The list of a document is given by the client on insert and stored next to it. The
+//! server asks the installed `AclPolicy` whether the user of a session may read or write
+//! a document, documents a user may not read are left out of query results.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use lazy_static::lazy_static;
+use tikv_client::Transaction;
+
+use crate::Error;
+
+lazy_static! {
+    static ref POLICY: RwLock<Arc<dyn AclPolicy>> =
+        RwLock::new(Arc::new(DefaultAclPolicy));
+}
+
+/// Action a user wants to take on a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Read,
+    Write,
+    /// Reading the list, usecases and insertion time of a document, but not its data.
+    ReadMetadata,
+}
+
+impl AclAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AclAction::Read => "read",
+            AclAction::Write => "write",
+            AclAction::ReadMetadata => "metadata",
+        }
+    }
+
+    /// Returns whether an entry granting the action `granted` allows this action.
+    ///
+    /// Being allowed to read a document includes reading its metadata.
+    fn is_granted_by(&self, granted: &str) -> bool {
+        granted == self.as_str()
+            || (*self == AclAction::ReadMetadata && granted == "read")
+    }
+}
+
+/// Decides whether a user may take an action on a document.
+pub trait AclPolicy: Send + Sync {
+    /// Returns whether the user may take the action on a document with the given list.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user of the session, `None` if the session is not authenticated.
+    /// * `acl` - The access control list of the document.
+    /// * `action` - The action to authorize.
+    fn allows(&self, username: Option<&str>, acl: &[String], action: AclAction) -> bool;
+}
+
+/// Policy applied when no other is installed.
+///
+/// A document with an empty list is open to everyone. Otherwise an entry `read` or
+/// `read:all` lets everyone read the document and `read:<username>` lets that user
+/// read it, with the same forms for `write` and for `metadata`, which only lets the
+/// metadata of the document be read. Entries for `read` also grant `metadata`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultAclPolicy;
+
+impl AclPolicy for DefaultAclPolicy {
+    fn allows(&self, username: Option<&str>, acl: &[String], action: AclAction) -> bool {
+        if acl.is_empty() {
+            return true;
+        }
+        acl.iter().any(|entry| match entry.split_once(':') {
+            None => action.is_granted_by(entry),
+            Some((granted, principal)) => {
+                action.is_granted_by(granted)
+                    && (principal == "all" || Some(principal) == username)
+            }
+        })
+    }
+}
+
+/// Installs the policy deciding every following read and write.
+pub fn set_acl_policy<P: AclPolicy + 'static>(policy: P) {
+    *POLICY.write().expect("acl policy lock poisoned") = Arc::new(policy);
+}
+
+/// Returns the installed policy.
+pub fn acl_policy() -> Arc<dyn AclPolicy> {
+    POLICY.read().expect("acl policy lock poisoned").clone()
+}
+
+/// Reads the access control lists of documents, by document key.
+///
+/// Documents without a stored list are left out.
+pub async fn read_acls(
+    transaction: &mut Transaction,
+    data_keys: &[String],
+) -> Result<HashMap<String, Vec<String>>, Error> {
+    let acl_keys: Vec<String> =
+        data_keys.iter().map(|key| format!("{}:acl", key)).collect();
+    let mut acls = HashMap::with_capacity(data_keys.len());
+    for pair in transaction.batch_get(acl_keys).await? {
+        let key = String::from_utf8_lossy((&pair.0).into()).to_string();
+        let Some(data_key) = key.strip_suffix(":acl") else {
+            continue;
+        };
+        acls.insert(data_key.to_string(), serde_cbor::from_slice(&pair.1)?);
+    }
+    Ok(acls)
+}
+
+/// Returns whether the user may take the action on the document stored under the key.
+///
+/// A document without a stored list is treated as having an empty one.
+pub fn is_allowed(
+    policy: &dyn AclPolicy,
+    acls: &HashMap<String, Vec<String>>,
+    data_key: &str,
+    username: Option<&str>,
+    action: AclAction,
+) -> bool {
+    let acl = acls.get(data_key).map(Vec::as_slice).unwrap_or_default();
+    policy.allows(username, acl, action)
+}
+
+/// Checks that the user may write the document stored under the key.
+pub async fn check_write(
+    transaction: &mut Transaction,
+    data_key: &str,
+    username: Option<&str>,
+) -> Result<(), Error> {
+    let acls = read_acls(transaction, &[data_key.to_string()]).await?;
+    if !is_allowed(&*acl_policy(), &acls, data_key, username, AclAction::Write) {
+        return Err(Error::Forbidden);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct AdminPolicy;
+
+    impl AclPolicy for AdminPolicy {
+        fn allows(
+            &self,
+            username: Option<&str>,
+            acl: &[String],
+            action: AclAction,
+        ) -> bool {
+            username == Some("admin") || DefaultAclPolicy.allows(username, acl, action)
+        }
+    }
+
+    fn acl(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = DefaultAclPolicy;
+        assert!(policy.allows(None, &[], AclAction::Write));
+        assert!(policy.allows(None, &acl(&["read", "write"]), AclAction::Write));
+        assert!(policy.allows(Some("bob"), &acl(&["read:all"]), AclAction::Read));
+        assert!(!policy.allows(Some("bob"), &acl(&["read:all"]), AclAction::Write));
+        assert!(policy.allows(Some("bob"), &acl(&["write:bob"]), AclAction::Write));
+        assert!(!policy.allows(Some("eve"), &acl(&["write:bob"]), AclAction::Write));
+        assert!(!policy.allows(None, &acl(&["read:bob"]), AclAction::Read));
+    }
+
+    #[test]
+    fn test_metadata_is_granted_by_metadata_or_read_entries() {
+        let policy = DefaultAclPolicy;
+        let metadata = AclAction::ReadMetadata;
+        assert!(policy.allows(Some("bob"), &acl(&["metadata:bob"]), metadata));
+        assert!(!policy.allows(Some("bob"), &acl(&["metadata:bob"]), AclAction::Read));
+        assert!(policy.allows(Some("bob"), &acl(&["read:bob"]), metadata));
+        assert!(policy.allows(None, &acl(&["read"]), metadata));
+        assert!(!policy.allows(Some("bob"), &acl(&["write:bob"]), metadata));
+    }
+
+    #[test]
+    fn test_custom_policy_grants_admin_everything() {
+        let mut acls = HashMap::new();
+        acls.insert("users:1".to_string(), acl(&["read:alice"]));
+        let policy = AdminPolicy;
+
+        for action in [AclAction::Read, AclAction::Write] {
+            assert!(is_allowed(&policy, &acls, "users:1", Some("admin"), action));
+            assert!(!is_allowed(&policy, &acls, "users:1", Some("eve"), action));
+        }
+        assert!(is_allowed(&policy, &acls, "users:1", Some("alice"), AclAction::Read));
+        assert!(is_allowed(&policy, &acls, "users:2", Some("eve"), AclAction::Write));
+    }
+}
@@ -0,0 +1,207 @@
+//! Append-only audit log of the mutations of documents.
+//!
+//! An entry is written in the transaction of the mutation it records, so it is committed
+//! if and only if the mutation is. Entries are stored each under its own key, inserted
+//! and never overwritten: the server has no operation updating or deleting them.
+//!
+//! Only the users of the `audit_admins` setting read the entries of every user, the
+//! others only read the entries of their own mutations, on the documents the installed
+//! `AclPolicy` lets them read the metadata of.
+
+use liserk_shared::audit::{AuditEntry, AuditFilter, AuditOperation};
+use tikv_client::{KvPair, Transaction, TransactionClient};
+use uuid::Uuid;
+
+use crate::{
+    acl::{self, AclAction},
+    config::{SETTINGS, TIKV_URL},
+    token::now,
+    Error,
+};
+
+/// Prefix of the keys of the audit log.
+///
+/// It holds no `:`, so it cannot collide with the keys of a collection.
+pub const AUDIT_LOG_PREFIX: &str = "audit_log/";
+
+/// First key after the keys of the audit log, `0` following `/`.
+const AUDIT_LOG_END: &str = "audit_log0";
+
+/// Maximum number of entries returned by a fetch.
+const MAX_FETCHED_ENTRIES: usize = 10_000;
+
+/// Number of entries scanned at once by a fetch.
+const FETCH_BATCH: u32 = 1_000;
+
+/// Key of an entry, ordering entries by their timestamp.
+fn audit_key(entry: &AuditEntry) -> String {
+    format!("{}{:020}/{}", AUDIT_LOG_PREFIX, entry.timestamp, Uuid::new_v4())
+}
+
+/// Builds the entry of a mutation made now.
+pub fn entry(
+    username: Option<&str>,
+    collection: &str,
+    operation: AuditOperation,
+    document_id: &str,
+) -> AuditEntry {
+    AuditEntry {
+        timestamp: now(),
+        username: username.map(str::to_string),
+        collection: collection.to_string(),
+        operation,
+        document_id: document_id.to_string(),
+    }
+}
+
+/// Appends the entry to the audit log within the transaction of the mutation.
+pub async fn append(
+    transaction: &mut Transaction,
+    entry: &AuditEntry,
+) -> Result<(), Error> {
+    transaction
+        .insert(audit_key(entry), serde_cbor::to_vec(entry)?)
+        .await?;
+    Ok(())
+}
+
+/// Returns whether a user reads the entries of every user.
+pub fn is_audit_admin(username: &str) -> bool {
+    SETTINGS.audit_admins.iter().any(|admin| admin == username)
+}
+
+/// Returns the entries selected by the filter and `visible`, oldest first, at most
+/// `MAX_FETCHED_ENTRIES` of them.
+///
+/// With a `reader`, only the entries on documents whose metadata the policy lets the
+/// reader read are selected, a deleted document being decided on an empty list. Entries
+/// are selected while the log is scanned, so the limit only counts selected entries and
+/// none is left out for being after too many others.
+pub async fn fetch<F>(
+    filter: &AuditFilter,
+    reader: Option<&str>,
+    visible: F,
+) -> Result<Vec<AuditEntry>, Error>
+where
+    F: Fn(&AuditEntry) -> bool,
+{
+    let policy = acl::acl_policy();
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let mut start = match filter.since {
+        Some(since) => format!("{}{:020}", AUDIT_LOG_PREFIX, since),
+        None => AUDIT_LOG_PREFIX.to_string(),
+    }
+    .into_bytes();
+    let end = AUDIT_LOG_END.as_bytes().to_vec();
+    let mut entries = Vec::new();
+    loop {
+        let pairs: Vec<KvPair> = transaction
+            .scan(start.clone()..end.clone(), FETCH_BATCH)
+            .await?
+            .collect();
+        let scanned = pairs.len();
+        let Some(last) = pairs.last().map(|pair| Vec::<u8>::from(pair.0.clone())) else {
+            break;
+        };
+        let batch = pairs
+            .iter()
+            .map(|pair| serde_cbor::from_slice(pair.value()))
+            .collect::<Result<Vec<AuditEntry>, _>>()?;
+        let full = match reader {
+            None => select(batch, filter, &visible, &mut entries),
+            Some(reader) => {
+                let keys: Vec<String> = batch.iter().map(document_key).collect();
+                let acls = acl::read_acls(&mut transaction, &keys).await?;
+                let readable = |entry: &AuditEntry| {
+                    let key = document_key(entry);
+                    let action = AclAction::ReadMetadata;
+                    visible(entry)
+                        && acl::is_allowed(&*policy, &acls, &key, Some(reader), action)
+                };
+                select(batch, filter, &readable, &mut entries)
+            }
+        };
+        if full || scanned < FETCH_BATCH as usize {
+            break;
+        }
+        // The first key after the last one scanned.
+        start = last;
+        start.push(0);
+    }
+    transaction.commit().await?;
+    Ok(entries)
+}
+
+/// Key of the document an entry records the mutation of.
+fn document_key(entry: &AuditEntry) -> String {
+    format!("{}:{}", entry.collection, entry.document_id)
+}
+
+/// Adds the entries of the batch selected by the filter and `visible` until
+/// `MAX_FETCHED_ENTRIES` are selected, returning whether they are.
+fn select<F>(
+    batch: Vec<AuditEntry>,
+    filter: &AuditFilter,
+    visible: &F,
+    entries: &mut Vec<AuditEntry>,
+) -> bool
+where
+    F: Fn(&AuditEntry) -> bool,
+{
+    for entry in batch {
+        if entries.len() == MAX_FETCHED_ENTRIES {
+            return true;
+        }
+        if filter.matches(&entry) && visible(&entry) {
+            entries.push(entry);
+        }
+    }
+    entries.len() == MAX_FETCHED_ENTRIES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_keys_sort_by_timestamp_and_stay_in_range() {
+        let mut older = entry(Some("alice"), "users", AuditOperation::Insert, "1");
+        older.timestamp = 999;
+        let mut newer = older.clone();
+        newer.timestamp = 1_000;
+
+        let (older, newer) = (audit_key(&older), audit_key(&newer));
+        assert!(older < newer);
+        assert!(older.as_str() > AUDIT_LOG_PREFIX && newer.as_str() < AUDIT_LOG_END);
+        assert!(!newer.contains(':'));
+    }
+
+    #[test]
+    fn test_only_selected_entries_count_towards_the_limit() {
+        let of =
+            |username: &str| entry(Some(username), "users", AuditOperation::Insert, "1");
+        let filter = AuditFilter::default().with_username("alice".to_string());
+        let mut entries = Vec::new();
+        let batch = (0..MAX_FETCHED_ENTRIES).map(|_| of("bob")).collect();
+        assert!(!select(batch, &filter, &|_| true, &mut entries));
+        assert!(entries.is_empty());
+
+        let batch = vec![of("alice"), of("bob"), of("alice")];
+        let hidden = |entry: &AuditEntry| entry.document_id != "1";
+        assert!(!select(batch.clone(), &filter, &hidden, &mut entries));
+        assert!(entries.is_empty());
+        assert!(!select(batch, &filter, &|_| true, &mut entries));
+        assert_eq!(entries.len(), 2);
+
+        let mut full = vec![entries[0].clone(); MAX_FETCHED_ENTRIES];
+        assert!(select(vec![of("alice")], &filter, &|_| true, &mut full));
+        assert_eq!(full.len(), MAX_FETCHED_ENTRIES);
+    }
+
+    #[test]
+    fn test_two_entries_of_the_same_second_get_distinct_keys() {
+        let entry = entry(None, "users", AuditOperation::Delete, "1");
+        assert_ne!(audit_key(&entry), audit_key(&entry));
+    }
+}
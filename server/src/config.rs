@@ -1 +1,132 @@
+use std::collections::HashMap;
+
+use config::{Config, ConfigError, Environment, File};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
 pub const TIKV_URL: &str = "127.0.0.1:2379";
+
+/// Default maximum size, in bytes, of an encrypted document accepted on insert.
+pub const DEFAULT_MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default lifetime, in seconds, of the session tokens issued on authentication.
+pub const DEFAULT_SESSION_TOKEN_TTL: u64 = 60 * 60;
+
+/// Default time, in seconds, a query cursor stays cached after its last page request.
+pub const DEFAULT_CURSOR_TTL: u64 = 5 * 60;
+
+/// Default number of responses of a session buffered while its client reads them.
+pub const DEFAULT_RESPONSE_CHANNEL_CAPACITY: usize = 64;
+
+/// Default capacity, in bytes, of the buffer the frames of a connection are read through.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Default maximum number of keys read for a page of a collection scan, whatever the
+/// limit the client asks for.
+pub const DEFAULT_MAX_SCAN_LIMIT: u32 = 4096;
+
+/// What happens to a connection accepted while `max_connections` are already served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionOverflow {
+    /// The connection waits in the listen backlog until a served connection closes.
+    #[default]
+    Queue,
+
+    /// The setup of the connection is answered with `ServerError::TooManyConnections`,
+    /// then the connection is closed.
+    Reject,
+}
+
+/// Server settings, read from `config/server` and `LISERK_` prefixed environment variables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Settings {
+    /// Maximum size, in bytes, of an encrypted document accepted on insert.
+    pub max_document_size: usize,
+    /// Lifetime, in seconds, of the session tokens issued on authentication.
+    pub session_token_ttl: u64,
+    /// Time, in seconds, a query cursor stays cached after its last page request.
+    pub cursor_ttl: u64,
+    /// Number of responses of a session buffered while its client reads them.
+    pub response_channel_capacity: usize,
+    /// Capacity, in bytes, of the buffer the frames of a connection are read through.
+    pub read_buffer_size: usize,
+    /// Maximum number of keys read for a page of a collection scan, a larger limit asked
+    /// by a client being lowered to it.
+    pub max_scan_limit: u32,
+    /// Size, in bytes, of the send and receive buffers of the TCP sockets, left to the
+    /// system when unset. The system may round or cap it.
+    #[serde(default)]
+    pub socket_buffer_size: Option<u32>,
+    /// Whether `TCP_NODELAY` is set on the accepted TCP sockets, set by default.
+    pub tcp_nodelay: bool,
+    /// Time, in seconds, an accepted TCP connection stays idle before a keepalive probe,
+    /// no probe being sent when unset.
+    #[serde(default)]
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Time, in seconds, between two keepalive probes left unanswered, left to the
+    /// system when unset.
+    #[serde(default)]
+    pub tcp_keepalive_interval_secs: Option<u64>,
+    /// Time, in milliseconds, a query may run before it is aborted, unlimited when unset.
+    /// A batch of queries shares it, a cursor or stream gets it for its opening and for
+    /// each of its pages.
+    #[serde(default)]
+    pub query_time_budget_ms: Option<u64>,
+    /// Maximum number of connections served at once, unlimited when unset.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// What happens to the connections over `max_connections`, queued by default.
+    #[serde(default)]
+    pub connection_overflow: ConnectionOverflow,
+    /// Path of the Unix domain socket to listen on instead of TCP, where supported.
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+    /// PEM file of the certificate chain of the server. The TCP connections are served
+    /// over TLS when it is set, along with `tls_private_key`.
+    #[serde(default)]
+    pub tls_certificate: Option<String>,
+    /// PEM file of the PKCS #8 private key of `tls_certificate`.
+    #[serde(default)]
+    pub tls_private_key: Option<String>,
+    /// Secrets of the users authenticating by challenge-response, by username.
+    #[serde(default)]
+    pub pre_shared_keys: HashMap<String, String>,
+    /// Tenants each user may scope its connections to, by username. A user listed only
+    /// authenticates on connections scoped to one of its tenants, the others only on
+    /// connections without tenant.
+    #[serde(default)]
+    pub tenants: HashMap<String, Vec<String>>,
+    /// Users reading the audit entries of every user, the others only reading the
+    /// entries of their own mutations.
+    #[serde(default)]
+    pub audit_admins: Vec<String>,
+    /// Index fields derived on insert and update by concatenating the tokens of other
+    /// fields, by derived field, see `derivation::Concatenation`.
+    #[serde(default)]
+    pub derivations: HashMap<String, Vec<String>>,
+}
+
+impl Settings {
+    pub fn new() -> Result<Self, ConfigError> {
+        let settings = Config::builder()
+            .set_default("max_document_size", DEFAULT_MAX_DOCUMENT_SIZE as i64)?
+            .set_default("session_token_ttl", DEFAULT_SESSION_TOKEN_TTL as i64)?
+            .set_default("cursor_ttl", DEFAULT_CURSOR_TTL as i64)?
+            .set_default(
+                "response_channel_capacity",
+                DEFAULT_RESPONSE_CHANNEL_CAPACITY as i64,
+            )?
+            .set_default("read_buffer_size", DEFAULT_READ_BUFFER_SIZE as i64)?
+            .set_default("max_scan_limit", DEFAULT_MAX_SCAN_LIMIT as i64)?
+            .set_default("tcp_nodelay", true)?
+            .add_source(File::with_name("config/server").required(false))
+            .add_source(Environment::with_prefix("LISERK"))
+            .build()?;
+        settings.try_deserialize()
+    }
+}
+
+lazy_static! {
+    pub static ref SETTINGS: Settings = Settings::new().expect("config error");
+}
@@ -0,0 +1,50 @@
+//! Keys shared with the services authenticating by challenge-response.
+//!
+//! Keys are read from the `pre_shared_keys` setting, a map from username to secret, and
+//! can be registered by the host with `set_pre_shared_key`. See `liserk_shared::auth`.
+//!
+//! The mechanism a user authenticates with is decided here, not by the client: a user
+//! with a pre-shared key only authenticates by challenge-response, the others by
+//! password.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use lazy_static::lazy_static;
+use liserk_shared::auth::AuthMechanism;
+
+use crate::config::SETTINGS;
+
+lazy_static! {
+    static ref PRE_SHARED_KEYS: RwLock<HashMap<String, Vec<u8>>> = RwLock::new(
+        SETTINGS
+            .pre_shared_keys
+            .iter()
+            .map(|(username, key)| (username.clone(), key.as_bytes().to_vec()))
+            .collect()
+    );
+}
+
+/// Registers the key `username` authenticates with, replacing any previous one.
+pub fn set_pre_shared_key(username: &str, key: &[u8]) {
+    PRE_SHARED_KEYS
+        .write()
+        .expect("pre-shared keys lock poisoned")
+        .insert(username.to_string(), key.to_vec());
+}
+
+/// Returns the only mechanism `username` may authenticate with.
+pub fn mechanism_of(username: &str) -> AuthMechanism {
+    match pre_shared_key(username) {
+        Some(_) => AuthMechanism::ChallengeResponse,
+        None => AuthMechanism::Password,
+    }
+}
+
+/// Returns the key `username` authenticates with, if it has one.
+pub fn pre_shared_key(username: &str) -> Option<Vec<u8>> {
+    PRE_SHARED_KEYS
+        .read()
+        .expect("pre-shared keys lock poisoned")
+        .get(username)
+        .cloned()
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+lazy_static! {
+    /// Cursors opened by the clients of this server process.
+    pub static ref CURSORS: CursorStore = CursorStore::default();
+}
+
+/// Matching keys of a query, kept so its pages do not run the query again.
+#[derive(Debug)]
+struct Cursor {
+    keys: Vec<String>,
+    position: usize,
+    page_size: usize,
+    with_nonces: bool,
+    time_to_live: Duration,
+    expires_at: Instant,
+
+    /// Whether the cursor is kept after its last page, see `CursorStore::open_resumable`.
+    resumable: bool,
+
+    /// The user who opened the cursor, the only one who may read or close it.
+    owner: Option<String>,
+}
+
+/// A slice of the keys of a cursor.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Page {
+    /// The id of the cursor the page was read from.
+    pub id: String,
+
+    /// The keys of the documents of the page, in the order the cursor was opened with.
+    pub keys: Vec<String>,
+
+    /// Whether the documents are AES encrypted and must be sent with their nonce.
+    pub with_nonces: bool,
+
+    /// The cursor to request the next page with, `None` after the last page.
+    pub cursor: Option<String>,
+}
+
+/// In-memory cache of the query cursors.
+///
+/// A cursor expires when it is not read for its time to live, expired cursors
+/// are evicted whenever the store is accessed. A cursor is bound to the user who opened
+/// it: for any other user, it is as unknown as a cursor that never existed.
+#[derive(Debug, Default)]
+pub struct CursorStore {
+    cursors: Mutex<HashMap<String, Cursor>>,
+}
+
+impl CursorStore {
+    /// Caches the matching keys of a query and returns its first page.
+    pub fn open(
+        &self,
+        keys: Vec<String>,
+        page_size: usize,
+        with_nonces: bool,
+        time_to_live: Duration,
+        owner: Option<&str>,
+    ) -> Page {
+        self.insert(keys, page_size, with_nonces, time_to_live, false, owner)
+    }
+
+    /// Caches the matching keys of a streamed query and returns its first page.
+    ///
+    /// Unlike `open`, the cursor outlives its last page until it expires or is closed, so
+    /// a stream whose connection was lost can be resumed from any position, see `resume`.
+    /// The stream closes it once its last page is sent.
+    pub fn open_resumable(
+        &self,
+        keys: Vec<String>,
+        page_size: usize,
+        with_nonces: bool,
+        time_to_live: Duration,
+        owner: Option<&str>,
+    ) -> Page {
+        self.insert(keys, page_size, with_nonces, time_to_live, true, owner)
+    }
+
+    /// Caches a cursor on the keys deduplicated, keeping the order of their first
+    /// occurrence, so a key is on exactly one page. The query engine orders the keys,
+    /// newest first for a query asking for the `latest` documents.
+    fn insert(
+        &self,
+        mut keys: Vec<String>,
+        page_size: usize,
+        with_nonces: bool,
+        time_to_live: Duration,
+        resumable: bool,
+        owner: Option<&str>,
+    ) -> Page {
+        let mut seen = HashSet::with_capacity(keys.len());
+        keys.retain(|key| seen.insert(key.clone()));
+        let id = Uuid::new_v4().to_string();
+        let cursor = Cursor {
+            keys,
+            position: 0,
+            page_size: page_size.max(1),
+            with_nonces,
+            time_to_live,
+            expires_at: Instant::now() + time_to_live,
+            resumable,
+            owner: owner.map(str::to_string),
+        };
+        self.cursors
+            .lock()
+            .expect("cursor store poisoned")
+            .insert(id.clone(), cursor);
+        self.next_page(&id, owner).expect("cursor was just opened")
+    }
+
+    /// Returns the next page of a cursor, or `None` if it is unknown, exhausted, expired
+    /// or opened by another user than `owner`.
+    ///
+    /// Reading a page extends the cursor's lifetime, the cursor is dropped with its last page.
+    pub fn next_page(&self, id: &str, owner: Option<&str>) -> Option<Page> {
+        self.page_from(id, None, owner)
+    }
+
+    /// Moves a resumable cursor back or forth to `position`, the number of keys already
+    /// delivered, and returns the page starting there. `None` if the cursor is unknown,
+    /// expired, not resumable or opened by another user than `owner`.
+    pub fn resume(&self, id: &str, position: usize, owner: Option<&str>) -> Option<Page> {
+        self.page_from(id, Some(position), owner)
+    }
+
+    fn page_from(
+        &self,
+        id: &str,
+        position: Option<usize>,
+        owner: Option<&str>,
+    ) -> Option<Page> {
+        let mut cursors = self.cursors.lock().expect("cursor store poisoned");
+        let now = Instant::now();
+        cursors.retain(|_, cursor| cursor.expires_at > now);
+
+        let cursor = cursors
+            .get_mut(id)
+            .filter(|cursor| cursor.owner.as_deref() == owner)?;
+        if let Some(position) = position {
+            if !cursor.resumable {
+                return None;
+            }
+            cursor.position = position.min(cursor.keys.len());
+        }
+        let end = (cursor.position + cursor.page_size).min(cursor.keys.len());
+        let keys = cursor.keys[cursor.position..end].to_vec();
+        cursor.position = end;
+        cursor.expires_at = now + cursor.time_to_live;
+        let with_nonces = cursor.with_nonces;
+
+        if end == cursor.keys.len() {
+            if !cursor.resumable {
+                cursors.remove(id);
+            }
+            return Some(Page {
+                id: id.to_string(),
+                keys,
+                with_nonces,
+                cursor: None,
+            });
+        }
+        let cursor = Some(id.to_string());
+        Some(Page { id: id.to_string(), keys, with_nonces, cursor })
+    }
+
+    /// Drops a cursor before its last page, returns whether it was open. A cursor opened
+    /// by another user than `owner` is left open.
+    pub fn close(&self, id: &str, owner: Option<&str>) -> bool {
+        let mut cursors = self.cursors.lock().expect("cursor store poisoned");
+        match cursors.get(id) {
+            Some(cursor) if cursor.owner.as_deref() == owner => {
+                cursors.remove(id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: Option<&str> = Some("Bob");
+
+    fn keys(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("users:{}", index)).collect()
+    }
+
+    #[test]
+    fn test_three_pages_from_one_cursor() {
+        let store = CursorStore::default();
+        let first = store.open(keys(7), 3, true, Duration::from_secs(60), OWNER);
+        assert_eq!(first.keys, vec!["users:0", "users:1", "users:2"]);
+        let cursor = first.cursor.unwrap();
+
+        let second = store.next_page(&cursor, OWNER).unwrap();
+        assert_eq!(second.keys, vec!["users:3", "users:4", "users:5"]);
+        assert_eq!(second.cursor.as_deref(), Some(cursor.as_str()));
+
+        let third = store.next_page(&cursor, OWNER).unwrap();
+        assert_eq!(third.keys, vec!["users:6"]);
+        assert!(third.cursor.is_none());
+        assert!(store.next_page(&cursor, OWNER).is_none());
+    }
+
+    /// Reads every page of a cursor opened on the keys.
+    fn read_all(store: &CursorStore, keys: Vec<String>) -> Vec<String> {
+        let mut page = store.open(keys, 2, false, Duration::from_secs(60), OWNER);
+        let mut read = page.keys.clone();
+        while let Some(cursor) = page.cursor {
+            page = store.next_page(&cursor, OWNER).unwrap();
+            read.extend(page.keys.clone());
+        }
+        read
+    }
+
+    #[test]
+    fn test_pages_list_every_document_once_in_the_given_order() {
+        let store = CursorStore::default();
+        let mut matching = keys(9);
+        matching.extend(keys(3));
+        assert_eq!(read_all(&store, matching), keys(9));
+    }
+
+    #[test]
+    fn test_pages_of_latest_documents_stay_newest_first() {
+        let store = CursorStore::default();
+        // As ordered by the query engine for `latest`, newest first, not by key.
+        let newest_first: Vec<String> = ["users:7", "users:2", "users:9", "users:1"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(read_all(&store, newest_first.clone()), newest_first);
+    }
+
+    #[test]
+    fn test_single_page_closes_cursor() {
+        let store = CursorStore::default();
+        let page = store.open(keys(2), 10, false, Duration::from_secs(60), OWNER);
+        assert_eq!(page.keys.len(), 2);
+        assert!(page.cursor.is_none());
+        assert!(store.cursors.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_closed_cursor_has_no_next_page() {
+        let store = CursorStore::default();
+        let cursor = store
+            .open(keys(4), 2, true, Duration::from_secs(60), OWNER)
+            .cursor
+            .unwrap();
+        assert!(store.close(&cursor, OWNER));
+        assert!(store.next_page(&cursor, OWNER).is_none());
+        assert!(!store.close(&cursor, OWNER));
+    }
+
+    #[test]
+    fn test_cursor_of_another_user_is_unknown() {
+        let store = CursorStore::default();
+        let first =
+            store.open_resumable(keys(6), 2, false, Duration::from_secs(60), OWNER);
+        let cursor = first.cursor.unwrap();
+        for other in [Some("Alice"), None] {
+            assert!(store.next_page(&cursor, other).is_none());
+            assert!(store.resume(&cursor, 0, other).is_none());
+            assert!(!store.close(&cursor, other));
+        }
+        assert_eq!(
+            store.next_page(&cursor, OWNER).unwrap().keys,
+            vec!["users:2", "users:3"]
+        );
+        assert!(store.close(&cursor, OWNER));
+    }
+
+    #[test]
+    fn test_resumed_cursor_restarts_from_the_delivered_position() {
+        let store = CursorStore::default();
+        let first =
+            store.open_resumable(keys(5), 2, false, Duration::from_secs(60), OWNER);
+        let cursor = first.cursor.unwrap();
+        store.next_page(&cursor, OWNER).unwrap();
+        let last = store.next_page(&cursor, OWNER).unwrap();
+        assert_eq!(last.keys, vec!["users:4"]);
+        assert!(last.cursor.is_none());
+
+        // Only the first page was delivered before the connection was lost.
+        let resumed = store.resume(&cursor, 2, OWNER).unwrap();
+        assert_eq!(resumed.keys, vec!["users:2", "users:3"]);
+        assert_eq!(resumed.cursor.as_deref(), Some(cursor.as_str()));
+        assert!(store.resume(&cursor, 9, OWNER).unwrap().keys.is_empty());
+
+        let plain = store.open(keys(5), 2, false, Duration::from_secs(60), OWNER);
+        assert!(store.resume(&plain.cursor.unwrap(), 0, OWNER).is_none());
+        assert!(store.close(&cursor, OWNER));
+        assert!(store.resume(&cursor, 0, OWNER).is_none());
+    }
+
+    #[test]
+    fn test_expired_cursor_is_evicted() {
+        let store = CursorStore::default();
+        let page = store.open(keys(4), 2, true, Duration::ZERO, OWNER);
+        let cursor = page.cursor.unwrap();
+        assert!(store.next_page(&cursor, OWNER).is_none());
+        assert!(store.cursors.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+//! Index fields computed by the server when a document is inserted or updated.
+//!
+//! A client sends the index tokens of the fields of a document, see `IndexEntry`. A
+//! registered `Derivation` computes the token of another field from them, which is
+//! indexed along the tokens of the client and can be looked up the same way. The server
+//! still never sees a value: a derivation only combines tokens.
+//!
+//! Derivations are registered by the host with `register_derivation`, or configured with
+//! the `derivations` setting, see `Concatenation`.
+
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use liserk_shared::query::IndexEntry;
+
+use crate::config::SETTINGS;
+
+lazy_static! {
+    static ref DERIVATIONS: RwLock<Vec<Arc<dyn Derivation>>> =
+        RwLock::new(configured_derivations());
+}
+
+/// Computes the token of an index field from the tokens sent with a document.
+pub trait Derivation: Send + Sync {
+    /// Name of the field the derivation produces.
+    fn field(&self) -> &str;
+
+    /// Returns the token of the derived field, `None` if the tokens it needs are missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The index entries sent by the client with the document.
+    fn derive(&self, entries: &[IndexEntry]) -> Option<Vec<u8>>;
+}
+
+/// Indexes a field as the concatenation of the tokens of other fields, in order.
+///
+/// A client looks the field up with the concatenation of the tokens of the values it
+/// looks for, as computed by `AuthenticatedClient::index_lookup`. The document must be
+/// indexed on every part for the field to be derived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Concatenation {
+    field: String,
+    parts: Vec<String>,
+}
+
+impl Concatenation {
+    /// Returns the derivation of `field` from the tokens of `parts`.
+    pub fn new(field: impl Into<String>, parts: Vec<String>) -> Self {
+        Self { field: field.into(), parts }
+    }
+}
+
+impl Derivation for Concatenation {
+    fn field(&self) -> &str {
+        &self.field
+    }
+
+    fn derive(&self, entries: &[IndexEntry]) -> Option<Vec<u8>> {
+        let mut token = Vec::new();
+        for part in self.parts.iter() {
+            let entry = entries.iter().find(|entry| &entry.field == part)?;
+            token.extend_from_slice(&entry.token);
+        }
+        Some(token)
+    }
+}
+
+/// Returns the derivations of the `derivations` setting, in field order.
+fn configured_derivations() -> Vec<Arc<dyn Derivation>> {
+    let mut fields: Vec<_> = SETTINGS.derivations.iter().collect();
+    fields.sort();
+    fields
+        .into_iter()
+        .map(|(field, parts)| {
+            Arc::new(Concatenation::new(field.clone(), parts.clone()))
+                as Arc<dyn Derivation>
+        })
+        .collect()
+}
+
+/// Registers a derivation applied to every following insert and update.
+pub fn register_derivation<D: Derivation + 'static>(derivation: D) {
+    DERIVATIONS
+        .write()
+        .expect("derivations lock poisoned")
+        .push(Arc::new(derivation));
+}
+
+/// Returns the index entries of a document with the registered derived fields added.
+pub fn with_derived(entries: &[IndexEntry]) -> Vec<IndexEntry> {
+    let derivations = DERIVATIONS.read().expect("derivations lock poisoned");
+    derive_entries(&derivations, entries)
+}
+
+/// Adds the fields of the derivations to the entries.
+///
+/// An entry sent for a derived field is dropped, so a derived field only ever holds
+/// what the server computed.
+fn derive_entries(
+    derivations: &[Arc<dyn Derivation>],
+    entries: &[IndexEntry],
+) -> Vec<IndexEntry> {
+    let is_derived =
+        |field: &str| derivations.iter().any(|derivation| derivation.field() == field);
+    let mut derived: Vec<IndexEntry> = entries
+        .iter()
+        .filter(|entry| !is_derived(&entry.field))
+        .cloned()
+        .collect();
+    for derivation in derivations {
+        if let Some(token) = derivation.derive(entries) {
+            derived.push(IndexEntry { field: derivation.field().to_string(), token });
+        }
+    }
+    derived
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutation::index_key;
+
+    /// Indexes the full name as the concatenation of the tokens of its parts.
+    struct FullName;
+
+    impl Derivation for FullName {
+        fn field(&self) -> &str {
+            "full_name"
+        }
+
+        fn derive(&self, entries: &[IndexEntry]) -> Option<Vec<u8>> {
+            let token = |field: &str| {
+                entries
+                    .iter()
+                    .find(|entry| entry.field == field)
+                    .map(|entry| entry.token.clone())
+            };
+            Some([token("first_name")?, token("last_name")?].concat())
+        }
+    }
+
+    fn entry(field: &str, token: &[u8]) -> IndexEntry {
+        IndexEntry { field: field.to_string(), token: token.to_vec() }
+    }
+
+    #[test]
+    fn test_derived_field_is_indexed_and_can_be_looked_up() {
+        let derivations: Vec<Arc<dyn Derivation>> = vec![Arc::new(FullName)];
+        let sent = vec![
+            entry("first_name", &[1, 2]),
+            entry("last_name", &[3]),
+            entry("full_name", &[9]),
+        ];
+        let indexed = derive_entries(&derivations, &sent);
+        assert_eq!(
+            indexed,
+            vec![
+                entry("first_name", &[1, 2]),
+                entry("last_name", &[3]),
+                entry("full_name", &[1, 2, 3]),
+            ]
+        );
+
+        // A client looking the derived field up computes the same token from its own.
+        let lookup = entry("full_name", &[1, 2, 3]);
+        assert!(indexed
+            .iter()
+            .any(|entry| index_key("users", entry) == index_key("users", &lookup)));
+    }
+
+    #[test]
+    fn test_concatenation_derives_the_tokens_of_its_parts_in_order() {
+        let parts = vec!["first_name".to_string(), "last_name".to_string()];
+        let derivations: Vec<Arc<dyn Derivation>> =
+            vec![Arc::new(Concatenation::new("full_name", parts))];
+        let sent = vec![entry("last_name", &[3]), entry("first_name", &[1, 2])];
+        let indexed = derive_entries(&derivations, &sent);
+        assert_eq!(indexed[2], entry("full_name", &[1, 2, 3]));
+
+        let sent = vec![entry("first_name", &[1])];
+        assert_eq!(derive_entries(&derivations, &sent), sent);
+    }
+
+    #[test]
+    fn test_derivation_missing_its_tokens_is_skipped() {
+        let derivations: Vec<Arc<dyn Derivation>> = vec![Arc::new(FullName)];
+        let sent = vec![entry("first_name", &[1])];
+        assert_eq!(derive_entries(&derivations, &sent), sent);
+    }
+}
@@ -1,24 +1,57 @@
-use liserk_shared::message::Message;
+use async_channel::{Receiver, Sender};
+use liserk_shared::auth::open_tagged_body;
+use liserk_shared::compression::{Compression, FrameError};
+use liserk_shared::format::Format;
+use liserk_shared::message::{
+    ClientAuthentication, FrameHeader, Message, ServerError, MAX_FRAME_LEN,
+};
 use liserk_shared::message_type::MessageType;
+use liserk_shared::query::Query;
 use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
 use std::fmt::Display;
-use std::{io, net::SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, info, trace};
+use std::future::{ready, Future, Ready};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use uuid::Uuid;
 
 use crate::command::Command;
+use crate::config::{ConnectionOverflow, SETTINGS};
 use crate::message_parsing::parse_message;
+use crate::session::Session;
 
 pub const BINDED_URL_PORT: &str = "127.0.0.1:5545";
 
+/// Maximum time a connection over the limit is waited for its setup before being closed.
+const REJECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub mod acl;
+mod audit;
 mod command;
 mod config;
+pub mod credentials;
+mod cursor;
+pub mod derivation;
 mod message_parsing;
+pub mod metrics;
 mod mutation;
 mod query_engine;
+mod replay;
+mod session;
+mod tenant;
+mod tls;
+mod token;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -27,6 +60,32 @@ pub enum Error {
     Parsing(#[from] serde_cbor::Error),
     Storage(#[from] tikv_client::Error),
     Float(#[from] rug::float::ParseFloatError),
+    Frame(#[from] FrameError),
+    DocumentTooLarge { size: usize, max_size: usize },
+    Forbidden,
+    DocumentNotFound,
+    VersionConflict { current: u64 },
+    QueryTimeout,
+    DuplicateId,
+}
+
+impl Error {
+    /// Converts the error into the reason sent back to the client.
+    pub fn to_server_error(&self) -> ServerError {
+        match self {
+            Error::DocumentTooLarge { size, max_size } => {
+                ServerError::DocumentTooLarge { size: *size, max_size: *max_size }
+            }
+            Error::Forbidden => ServerError::Forbidden,
+            Error::DocumentNotFound => ServerError::DocumentNotFound,
+            Error::QueryTimeout => ServerError::QueryTimeout,
+            Error::DuplicateId => ServerError::DuplicateId,
+            Error::VersionConflict { current } => {
+                ServerError::VersionConflict { current: *current }
+            }
+            _ => ServerError::Internal,
+        }
+    }
 }
 
 impl Display for Error {
@@ -36,6 +95,21 @@ impl Display for Error {
             Error::Parsing(_) => write!(f, "Parsing Error serde"),
             Error::Storage(err) => write!(f, "Error with storage layer {}", err),
             Error::Float(err) => write!(f, "Error parsing float {}", err),
+            Error::Frame(err) => write!(f, "Error with frame {}", err),
+            Error::DocumentTooLarge { size, max_size } => {
+                write!(
+                    f,
+                    "Document of {} bytes exceeds the {} bytes limit",
+                    size, max_size
+                )
+            }
+            Error::Forbidden => write!(f, "Access denied by the document ACL"),
+            Error::DocumentNotFound => write!(f, "No document under this id"),
+            Error::QueryTimeout => write!(f, "Query exceeded its time budget"),
+            Error::DuplicateId => write!(f, "A document is already under this id"),
+            Error::VersionConflict { current } => {
+                write!(f, "Document is at version {}", current)
+            }
             Error::ChannelSend(sender_error) => {
                 write!(f, "ChannelSenderError {}", sender_error)
             }
@@ -43,24 +117,116 @@ impl Display for Error {
     }
 }
 
-async fn on_new_client(socket: TcpStream, _addr: &SocketAddr) -> Result<(), Error> {
-    let (tx, rx) = async_channel::unbounded::<Message>();
-    let (mut read, mut write) = socket.into_split();
+/// Creates a channel carrying responses to the connection writer.
+///
+/// The channel is bounded, so a handler producing responses faster than the client reads
+/// them waits for room instead of buffering them all in memory.
+fn response_channel<T>() -> (Sender<T>, Receiver<T>) {
+    async_channel::bounded(SETTINGS.response_channel_capacity.max(1))
+}
+
+/// Responses to a request, tagged with the id of the request when written.
+type RequestResponses = (u32, Receiver<Message>);
 
-    tokio::spawn(async move {
-        loop {
-            let message = rx.recv().await.expect("failed to recieve message");
+/// Writes the responses of a session on its connection until the session closes.
+///
+/// Requests are answered in the order they were read, each frame echoing the id of the
+/// request it answers.
+async fn write_responses<W: AsyncWrite + Unpin>(
+    requests: Receiver<RequestResponses>,
+    mut write: W,
+) {
+    let mut compression = Compression::None;
+    let mut format = Format::Cbor;
+    while let Ok((request_id, responses)) = requests.recv().await {
+        while let Ok(message) = responses.recv().await {
             if message == Message::CloseCommunication {
-                write.shutdown().await.expect("failed to shutdown communication");
-                break;
+                let acknowledgement = message
+                    .setup_for_network_in(request_id, compression, format)
+                    .unwrap();
+                if let Err(err) = write.write_all(&acknowledgement).await {
+                    debug!("connection closed while acknowledging close: {}", err);
+                    return;
+                }
+                if let Err(err) = write.shutdown().await {
+                    debug!("connection closed while shutting down: {}", err);
+                }
+                return;
+            }
+            // The setup response is the last frame sent before compression and the
+            // negotiated format apply. A response too large for a frame is chunked.
+            let frames = message
+                .setup_for_network_chunked(request_id, compression, format, MAX_FRAME_LEN)
+                .unwrap();
+            if let Message::SetupResponse {
+                compression: negotiated,
+                format: serialization,
+                ..
+            } = message
+            {
+                compression = negotiated;
+                format = serialization;
+            }
+            for frame in frames {
+                if let Err(err) = write.write_all(&frame).await {
+                    debug!("connection closed while writing: {}", err);
+                    return;
+                }
             }
-            let message = message.setup_for_network().unwrap();
-            write.write(&message).await.unwrap();
         }
-    });
+    }
+}
+
+/// Serves a connection, over any transport carrying the frames.
+async fn on_new_client<S>(socket: S) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (requests, requests_rx) = response_channel();
+    let (read, write) = tokio::io::split(socket);
+    let mut read = BufReader::with_capacity(SETTINGS.read_buffer_size.max(1), read);
+
+    tokio::spawn(write_responses(requests_rx, write));
+    let _connection = metrics::METRICS.track_connection();
+    let mut session = Session::default();
+    let served = serve_session(&mut read, &requests, &mut session).await;
+    if let Some(token) = &session.token {
+        replay::WINDOWS.forget(token);
+    }
+    served
+}
+
+/// Reads and answers the requests of a session until it ends.
+async fn serve_session<R: AsyncRead + Unpin>(
+    read: &mut R,
+    requests: &Sender<RequestResponses>,
+    session: &mut Session,
+) -> Result<(), Error> {
     loop {
-        let message = parse_message_from_tcp_stream(&mut read).await?;
-        let command = parse_message(message, tx.clone()).await;
+        let (header, body) = read_frame_body(read).await?;
+        let request_id = header.request_id;
+        // The handler's sender is dropped once it returns, which ends the request.
+        let (tx, rx) = response_channel();
+        let rx = match &session.tenant {
+            Some(tenant) => tenant::unscoped_responses(tenant.clone(), rx),
+            None => rx,
+        };
+        if requests.send((request_id, rx)).await.is_err() {
+            break;
+        }
+        let body = match open_request(session, &header, body) {
+            Ok(body) => body,
+            Err(reason) => {
+                warn!("refused request {} of {}: {}", request_id, session.user(), reason);
+                let _ = tx.send(Message::ErrorResponse(reason)).await;
+                continue;
+            }
+        };
+        let message =
+            Message::from_network_body_in(body, session.compression, session.format)?;
+        log_parsed_message(&message);
+        let span = info_span!("request", user = session.user(), request_id);
+        let command = parse_message(message, tx, session).instrument(span).await;
         info!("message parsing end communication: {:?}", command);
         if command == Command::Exit {
             break;
@@ -69,25 +235,89 @@ async fn on_new_client(socket: TcpStream, _addr: &SocketAddr) -> Result<(), Erro
     Ok(())
 }
 
-async fn parse_message_from_tcp_stream(
-    stream: &mut OwnedReadHalf,
-) -> Result<Message, Error> {
-    let mut buffer = [0; 1];
-    let _ = stream.read(&mut buffer).await;
-    let message_type = MessageType::try_from(buffer[0]);
+async fn parse_message_from_tcp_stream<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    compression: Compression,
+    format: Format,
+) -> Result<(u32, Message), Error> {
+    let (header, body) = read_frame_body(stream).await?;
+    let message = Message::from_network_body_in(body, compression, format)?;
+    log_parsed_message(&message);
+    Ok((header.request_id, message))
+}
+
+/// Checks the tag and the request id of a frame sent on an authenticated session,
+/// returning its body without the tag, see `replay`.
+fn open_request(
+    session: &Session,
+    header: &FrameHeader,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, ServerError> {
+    let Some(token) = &session.token else {
+        return Ok(body);
+    };
+    let key = session.frame_key().ok_or(ServerError::InvalidFrameTag)?;
+    let body = open_tagged_body(&key, header.message_type, header.request_id, body)
+        .ok_or(ServerError::InvalidFrameTag)?;
+    if !replay::WINDOWS.accept(token, header.request_id) {
+        return Err(ServerError::ReplayDetected);
+    }
+    Ok(body)
+}
+
+/// Reads the header of a frame and its body, still encoded.
+///
+/// A frame longer than `MAX_FRAME_LEN` is refused before its body is allocated.
+async fn read_frame_body<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<(FrameHeader, Vec<u8>), Error> {
+    let mut header = [0; FrameHeader::LEN];
+    stream.read_exact(&mut header).await?;
+    let header = FrameHeader::from_bytes(&header);
+    let message_type = MessageType::try_from(header.message_type);
     info!("messageType: {:?}", message_type);
+    trace!("request id: {}, message size: {}", header.request_id, header.length);
+    let length = header.length as usize;
+    if length.saturating_add(FrameHeader::LEN) > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge { length, max: MAX_FRAME_LEN }.into());
+    }
 
-    let mut message_size = [0; 4];
-    let _size_error = stream.read(&mut message_size).await;
-    let decimal_size = u32::from_be_bytes(message_size);
-    trace!("message size: {}", decimal_size);
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    Ok((header, body))
+}
 
-    let mut slice = vec![0; decimal_size as usize];
-    let _size_read = stream.read_exact(&mut slice).await;
-    trace!("slice: {:?}", slice);
-    let message: Message = serde_cbor::from_slice(&slice)?;
-    debug!("parsed message: {:#?}", message);
-    Ok(message)
+/// Logs a parsed message, a query only by its shape.
+///
+/// The operands of the predicates of a query are plaintext values the user looks for, so
+/// a query is logged with its collections, usecases, fields and operators only, see
+/// `Query::to_debug_string`. Authentications are logged without their password, token
+/// or MAC. Other messages only carry ciphertexts and are logged whole.
+fn log_parsed_message(message: &Message) {
+    let message_type = message.message_type();
+    match message {
+        Message::Query(query)
+        | Message::QueryDocuments(query)
+        | Message::QueryAndDelete(query)
+        | Message::Explain(query)
+        | Message::OpenCursor { query, .. }
+        | Message::StreamQuery { query, .. } => {
+            debug!(%message_type, query = %query.to_debug_string(), "parsed query");
+        }
+        Message::QueryBatch(queries) => {
+            let queries: Vec<String> =
+                queries.iter().map(Query::to_debug_string).collect();
+            debug!(%message_type, queries = %queries.join("; "), "parsed query batch");
+        }
+        Message::ClientAuthentification(ClientAuthentication { username, .. }) => {
+            debug!(%message_type, %username, "parsed authentication");
+        }
+        Message::ClientTokenAuthentification { .. }
+        | Message::ClientChallengeResponse { .. } => {
+            debug!(%message_type, "parsed authentication");
+        }
+        message => debug!("parsed message: {:#?}", message),
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -99,17 +329,710 @@ struct Authentification {
     compression: String,
 }
 
-pub async fn run_app() -> io::Result<()> {
-    let listener = TcpListener::bind(BINDED_URL_PORT).await?;
+/// Binds `BINDED_URL_PORT` and serves the connections made to it, see `serve`.
+///
+/// When the `unix_socket` setting is set, binds that Unix domain socket instead, see
+/// `run_unix`.
+pub async fn run() -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(path) = &SETTINGS.unix_socket {
+        return run_unix(path).await;
+    }
+    let listener = match SETTINGS.socket_buffer_size {
+        Some(buffer_size) => {
+            let address = BINDED_URL_PORT.parse().expect("valid listening address");
+            bind_sized(address, buffer_size)?
+        }
+        None => TcpListener::bind(BINDED_URL_PORT).await?,
+    };
     info!("Server started, listening on {}", BINDED_URL_PORT);
+    serve(listener).await
+}
+
+/// Binds a listener whose accepted sockets have send and receive buffers of the size.
+///
+/// The buffers are sized on the listening socket, which its accepted sockets inherit.
+fn bind_sized(address: SocketAddr, buffer_size: u32) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_recv_buffer_size(buffer_size)?;
+    socket.set_send_buffer_size(buffer_size)?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
 
+/// Same as `run`, kept for existing callers.
+pub async fn run_app() -> io::Result<()> {
+    run().await
+}
+
+/// Accepts the connections of a listener bound by the host and serves each of them on
+/// its own task.
+///
+/// Only returns on a failure to accept. Dropping the future stops accepting connections,
+/// which lets the host shut the server down, for instance by racing it against a signal
+/// in `tokio::select!`. Connections already accepted are served until they close.
+///
+/// At most `max_connections` connections are served at once, see `ConnectionOverflow`
+/// for the others.
+///
+/// `TCP_NODELAY` and the keepalive probes of the settings are set on every accepted
+/// socket, see `SocketOptions`. The connections are served over TLS when the settings
+/// configure it, see `tls`.
+pub async fn serve(listener: TcpListener) -> io::Result<()> {
+    serve_tcp(listener, tls::acceptor_from_settings()?).await
+}
+
+/// Same as `serve`, running the TLS handshake of `acceptor` on the accepted sockets when
+/// set.
+async fn serve_tcp(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+) -> io::Result<()> {
+    let listener = &listener;
+    let options = &SocketOptions::from_settings();
+    let accept = || async move {
+        let (socket, _) = listener.accept().await?;
+        options.apply(&socket);
+        Ok(socket)
+    };
+    let limit = ConnectionLimit::from_settings();
+    match acceptor {
+        Some(acceptor) => {
+            accept_connections(limit, accept, move |socket| acceptor.accept(socket)).await
+        }
+        None => accept_connections(limit, accept, unwrapped).await,
+    }
+}
+
+/// Opens an accepted connection as is, without handshake.
+fn unwrapped<S>(socket: S) -> Ready<io::Result<S>> {
+    ready(Ok(socket))
+}
+
+/// Options set on the accepted TCP sockets, see the `tcp_` settings.
+#[derive(Debug, Clone)]
+struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+}
+
+impl SocketOptions {
+    fn from_settings() -> Self {
+        let keepalive = SETTINGS.tcp_keepalive_secs.map(|idle| {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+            match SETTINGS.tcp_keepalive_interval_secs {
+                Some(interval) => keepalive.with_interval(Duration::from_secs(interval)),
+                None => keepalive,
+            }
+        });
+        Self { nodelay: SETTINGS.tcp_nodelay, keepalive }
+    }
+
+    /// Sets the options on an accepted socket.
+    ///
+    /// A socket the system refuses an option for is still served, with a warning.
+    fn apply(&self, socket: &TcpStream) {
+        if let Err(err) = socket.set_nodelay(self.nodelay) {
+            warn!("could not set TCP_NODELAY on an accepted socket: {}", err);
+        }
+        if let Some(keepalive) = &self.keepalive {
+            if let Err(err) = SockRef::from(socket).set_tcp_keepalive(keepalive) {
+                warn!("could not set keepalive on an accepted socket: {}", err);
+            }
+        }
+    }
+}
+
+/// Binds a Unix domain socket and serves the connections made to it, see `serve_unix`.
+///
+/// The socket file must not exist yet, access to the server is then controlled by its
+/// permissions.
+#[cfg(unix)]
+pub async fn run_unix<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let listener = UnixListener::bind(&path)?;
+    info!("Server started, listening on {}", path.as_ref().display());
+    serve_unix(listener).await
+}
+
+/// Same as `serve`, for a Unix domain socket listener.
+#[cfg(unix)]
+pub async fn serve_unix(listener: UnixListener) -> io::Result<()> {
+    let listener = &listener;
+    let accept = || async move { listener.accept().await.map(|(socket, _)| socket) };
+    accept_connections(ConnectionLimit::from_settings(), accept, unwrapped).await
+}
+
+/// Bound on the connections served at once, see the `max_connections` setting.
+#[derive(Debug, Clone)]
+struct ConnectionLimit {
+    /// One permit per connection that may be served, `None` when unlimited.
+    slots: Option<(usize, Arc<Semaphore>)>,
+    overflow: ConnectionOverflow,
+}
+
+impl ConnectionLimit {
+    fn new(max_connections: Option<usize>, overflow: ConnectionOverflow) -> Self {
+        let slots = max_connections.map(|max| (max, Arc::new(Semaphore::new(max))));
+        Self { slots, overflow }
+    }
+
+    fn from_settings() -> Self {
+        Self::new(SETTINGS.max_connections, SETTINGS.connection_overflow)
+    }
+}
+
+/// Serves the connections returned by `accept` within the limit, until it fails.
+///
+/// When queueing, the next connection is only accepted once a slot is free, so the
+/// waiting connections stay in the listen backlog of the system. Each connection is
+/// opened by `open`, a TLS handshake for instance, on the task serving it.
+async fn accept_connections<S, T, F, A, H, O>(
+    limit: ConnectionLimit,
+    mut accept: F,
+    open: H,
+) -> io::Result<()>
+where
+    F: FnMut() -> A,
+    A: Future<Output = io::Result<S>>,
+    H: Fn(S) -> O,
+    O: Future<Output = io::Result<T>> + Send + 'static,
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
     loop {
-        let (socket, addr) = listener.accept().await?;
+        let queued = match (&limit.slots, limit.overflow) {
+            (Some((_, slots)), ConnectionOverflow::Queue) => {
+                Some(slots.clone().acquire_owned().await.expect("semaphore never closed"))
+            }
+            _ => None,
+        };
+        let socket = accept().await?;
+        let slot = match (&limit.slots, queued) {
+            (_, Some(slot)) => Some(slot),
+            (None, None) => None,
+            (Some((max_connections, slots)), None) => {
+                match slots.clone().try_acquire_owned() {
+                    Ok(slot) => Some(slot),
+                    Err(_) => {
+                        info!("connection rejected, {} already served", max_connections);
+                        spawn_rejection(open(socket), *max_connections);
+                        continue;
+                    }
+                }
+            }
+        };
+        spawn_connection(open(socket), slot);
+    }
+}
+
+/// Opens a connection and serves it, releasing its slot once it closes.
+fn spawn_connection<O, S>(opening: O, slot: Option<OwnedSemaphorePermit>)
+where
+    O: Future<Output = io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    tokio::spawn(async move {
+        let socket = match opening.await {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!("could not open an accepted connection: {}", err);
+                return;
+            }
+        };
+        match on_new_client(socket).await {
+            Ok(_) => info!("connection closed"),
+            Err(err) => error!("connection failed: {}", err),
+        };
+        drop(slot);
+    });
+}
+
+/// Answers the setup of a connection over the limit with the reason, then closes it.
+///
+/// The setup is read first, so the client does not write it to a closed connection and
+/// miss the response.
+fn spawn_rejection<O, S>(opening: O, max_connections: usize)
+where
+    O: Future<Output = io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    tokio::spawn(async move {
+        let Ok(Ok(socket)) = tokio::time::timeout(REJECTION_TIMEOUT, opening).await
+        else {
+            return;
+        };
+        let (mut read, mut write) = tokio::io::split(socket);
+        let setup =
+            parse_message_from_tcp_stream(&mut read, Compression::None, Format::Cbor);
+        if tokio::time::timeout(REJECTION_TIMEOUT, setup).await.is_err() {
+            return;
+        }
+        let reason = ServerError::TooManyConnections { max_connections };
+        if let Ok(frame) = Message::ErrorResponse(reason).setup_for_network() {
+            let _ = write.write_all(&frame).await;
+            let _ = write.shutdown().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    async fn read_frame<R: tokio::io::AsyncRead + Unpin>(read: &mut R) -> (u32, Message) {
+        let mut header = [0; FrameHeader::LEN];
+        read.read_exact(&mut header).await.unwrap();
+        let header = FrameHeader::from_bytes(&header);
+        let mut body = vec![0; header.length as usize];
+        read.read_exact(&mut body).await.unwrap();
+        let message = Message::from_network_body(body, Compression::None).unwrap();
+        (header.request_id, message)
+    }
+
+    /// Collects what a subscriber writes, to assert on the logged text.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_logged_queries_show_operators_but_not_operands() {
+        use liserk_shared::query::SingleQueryBuilder;
+        use liserk_shared::value::Value;
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .without_time()
+            .with_writer(move || writer.clone())
+            .finish();
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("emails".to_owned())
+            .with_field_equal_to("email".to_string(), Value::from("alice@example.com"))
+            .with_field_greater_than("age".to_string(), Value::Int(987_654))
+            .build();
+        tracing::subscriber::with_default(subscriber, || {
+            log_parsed_message(&Message::Query(Query::Single(query.clone())));
+            log_parsed_message(&Message::QueryBatch(vec![Query::Single(query)]));
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("parsed query batch"), "{}", logs);
+        assert!(logs.contains("users:emails"), "{}", logs);
+        assert!(logs.contains("email = ?"), "{}", logs);
+        assert!(logs.contains("age > ?"), "{}", logs);
+        assert!(!logs.contains("alice@example.com"), "{}", logs);
+        assert!(!logs.contains("987654"), "{}", logs);
+    }
+
+    #[test]
+    fn test_logged_authentications_hide_their_secrets() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .without_time()
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            log_parsed_message(&Message::ClientAuthentification(ClientAuthentication {
+                username: "Bob".to_string(),
+                password: "Pomme".to_string(),
+            }));
+            let token = "secret-token".to_string();
+            log_parsed_message(&Message::ClientTokenAuthentification { token });
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Bob"), "{}", logs);
+        assert!(!logs.contains("Pomme"), "{}", logs);
+        assert!(!logs.contains("secret-token"), "{}", logs);
+    }
+
+    #[test]
+    fn test_response_channel_is_bounded() {
+        let (tx, _rx) = response_channel::<Message>();
+        assert_eq!(tx.capacity(), Some(SETTINGS.response_channel_capacity));
+    }
+
+    #[tokio::test]
+    async fn test_responses_echo_the_request_id() {
+        let (requests, requests_rx) = response_channel();
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(write_responses(requests_rx, server));
+
+        for request_id in [7, 3] {
+            let (tx, rx) = response_channel();
+            requests.send((request_id, rx)).await.unwrap();
+            tx.send(Message::DeleteResult(request_id == 7)).await.unwrap();
+        }
+        assert_eq!(read_frame(&mut client).await, (7, Message::DeleteResult(true)));
+        assert_eq!(read_frame(&mut client).await, (3, Message::DeleteResult(false)));
+    }
+
+    #[tokio::test]
+    async fn test_frame_over_the_maximum_length_is_refused() {
+        let header = FrameHeader::new(MessageType::Insert, 1, u32::MAX);
+        let mut stream = &header.to_bytes()[..];
+        assert!(matches!(
+            read_frame_body(&mut stream).await,
+            Err(Error::Frame(FrameError::TooLarge { max: MAX_FRAME_LEN, .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_close_on_a_gone_connection_ends_the_writer() {
+        let (requests, requests_rx) = response_channel();
+        let (client, server) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(write_responses(requests_rx, server));
+        drop(client);
+
+        let (tx, rx) = response_channel();
+        requests.send((1, rx)).await.unwrap();
+        tx.send(Message::CloseCommunication).await.unwrap();
+        assert!(writer.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_serve_on_a_provided_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let frame = Message::HealthCheck.setup_for_network_as(5, Compression::None);
+        client.write_all(&frame.unwrap()).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (5, Message::HealthResponse));
+
+        server.abort();
+    }
+
+    /// Opens a connection served by the server and authenticates it, by the password of
+    /// Bob or with a session token, returning it with its session token and the key its
+    /// frames are tagged under.
+    async fn authenticated_connection(
+        token: Option<&str>,
+    ) -> (tokio::io::DuplexStream, String, [u8; 32]) {
+        use liserk_shared::auth::frame_key;
+        use liserk_shared::message::{ClientAuthentication, ClientSetupSecureConnection};
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(on_new_client(server));
+        let keys = pqc_kyber::keypair(&mut rand::thread_rng());
+        let setup = ClientSetupSecureConnection::new(keys.public.to_vec());
+        let authentication = match token {
+            Some(token) => {
+                Message::ClientTokenAuthentification { token: token.to_string() }
+            }
+            None => Message::ClientAuthentification(ClientAuthentication {
+                username: "Bob".to_string(),
+                password: "Pomme".to_string(),
+            }),
+        };
+        // Frames before the authentication all have the request id 0.
+        client
+            .write_all(&Message::ClientSetup(setup).setup_for_network().unwrap())
+            .await
+            .unwrap();
+        let (_, Message::SetupResponse { key_encapsulation, .. }) =
+            read_frame(&mut client).await
+        else {
+            panic!("setup refused");
+        };
+        let shared_secret = pqc_kyber::decapsulate(&key_encapsulation, &keys.secret);
+        client
+            .write_all(&authentication.setup_for_network().unwrap())
+            .await
+            .unwrap();
+        let (_, Message::AuthentificationResponse(session_token)) =
+            read_frame(&mut client).await
+        else {
+            panic!("authentication refused");
+        };
+        let key = frame_key(&shared_secret.unwrap(), &session_token.token);
+        (client, session_token.token, key)
+    }
+
+    fn tagged_health_check(request_id: u32, frame_key: &[u8]) -> Vec<u8> {
+        Message::HealthCheck
+            .setup_for_network_tagged(
+                request_id,
+                Compression::None,
+                Format::Cbor,
+                frame_key,
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replayed_frame_of_an_authenticated_session_is_refused() {
+        let (mut client, token, key) = authenticated_connection(None).await;
+
+        let captured = tagged_health_check(1, &key);
+        client.write_all(&captured).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (1, Message::HealthResponse));
+        client.write_all(&captured).await.unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (1, Message::ErrorResponse(ServerError::ReplayDetected))
+        );
+
+        // The request id is tagged with the body, it cannot be edited into a new one.
+        let mut edited = captured.clone();
+        edited[1..5].copy_from_slice(&3u32.to_be_bytes());
+        client.write_all(&edited).await.unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (3, Message::ErrorResponse(ServerError::InvalidFrameTag))
+        );
+        let untagged = Message::HealthCheck.setup_for_network_as(4, Compression::None);
+        client.write_all(&untagged.unwrap()).await.unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (4, Message::ErrorResponse(ServerError::InvalidFrameTag))
+        );
+        // The token is sent in the clear without TLS, it does not tag frames on its own.
+        client
+            .write_all(&tagged_health_check(5, token.as_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (5, Message::ErrorResponse(ServerError::InvalidFrameTag))
+        );
+
+        client.write_all(&tagged_health_check(2, &key)).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (2, Message::HealthResponse));
+    }
+
+    #[tokio::test]
+    async fn test_frame_replayed_on_another_connection_is_refused() {
+        let (mut first, token, key) = authenticated_connection(None).await;
+        let captured = tagged_health_check(1, &key);
+        first.write_all(&captured).await.unwrap();
+        assert_eq!(read_frame(&mut first).await, (1, Message::HealthResponse));
+
+        let refused = (1, Message::ErrorResponse(ServerError::InvalidFrameTag));
+        let (mut second, _, _) = authenticated_connection(None).await;
+        second.write_all(&captured).await.unwrap();
+        assert_eq!(read_frame(&mut second).await, refused);
+
+        // Resuming the token on another connection replaces it, so the frames tagged
+        // under the token of the first connection are still refused there.
+        let (mut resumed, resumed_token, resumed_key) =
+            authenticated_connection(Some(&token)).await;
+        assert_ne!(resumed_token, token);
+        resumed.write_all(&captured).await.unwrap();
+        assert_eq!(read_frame(&mut resumed).await, refused);
+        resumed
+            .write_all(&tagged_health_check(1, &resumed_key))
+            .await
+            .unwrap();
+        assert_eq!(read_frame(&mut resumed).await, (1, Message::HealthResponse));
+    }
+
+    /// Serves the listener with at most `max_connections` connections at once.
+    fn serve_limited(
+        listener: TcpListener,
+        max_connections: usize,
+        overflow: ConnectionOverflow,
+    ) -> tokio::task::JoinHandle<io::Result<()>> {
         tokio::spawn(async move {
-            match on_new_client(socket, &addr).await {
-                Ok(_) => println!("c'est ok"),
-                Err(err) => eprintln!("err: {}", err),
-            };
+            let limit = ConnectionLimit::new(Some(max_connections), overflow);
+            let listener = &listener;
+            let accept =
+                || async move { listener.accept().await.map(|(socket, _)| socket) };
+            accept_connections(limit, accept, unwrapped).await
+        })
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_served_over_tls() {
+        use tokio_rustls::rustls::{
+            Certificate, ClientConfig, RootCertStore, ServerName,
+        };
+
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = certificate.serialize_der().unwrap();
+        let private_key = certificate.serialize_private_key_der();
+        let acceptor = tls::acceptor(vec![der.clone()], private_key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_tcp(listener, Some(acceptor)));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(der)).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let socket = TcpStream::connect(address).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = connector.connect(server_name, socket).await.unwrap();
+        let frame = Message::HealthCheck.setup_for_network_as(5, Compression::None);
+        client.write_all(&frame.unwrap()).await.unwrap();
+        client.flush().await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (5, Message::HealthResponse));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socket_options_are_applied_on_accepted_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(address).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        assert!(!SockRef::from(&socket).keepalive().unwrap());
+
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(60))
+            .with_interval(Duration::from_secs(10));
+        let options = SocketOptions { nodelay: true, keepalive: Some(keepalive) };
+        options.apply(&socket);
+        assert!(socket.nodelay().unwrap());
+        let socket = SockRef::from(&socket);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(10));
+        }
+    }
+
+    /// Sends a health check and returns the response.
+    async fn health_check(client: &mut TcpStream) -> (u32, Message) {
+        let frame = Message::HealthCheck.setup_for_network_as(5, Compression::None);
+        client.write_all(&frame.unwrap()).await.unwrap();
+        read_frame(client).await
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_the_limit_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = serve_limited(listener, 2, ConnectionOverflow::Reject);
+
+        let mut served = Vec::new();
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            assert_eq!(health_check(&mut client).await, (5, Message::HealthResponse));
+            served.push(client);
+        }
+        let mut rejected = TcpStream::connect(address).await.unwrap();
+        let reason = ServerError::TooManyConnections { max_connections: 2 };
+        assert_eq!(
+            health_check(&mut rejected).await,
+            (0, Message::ErrorResponse(reason))
+        );
+        let mut rest = Vec::new();
+        rejected.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        // Closing a served connection frees its slot.
+        drop(served.pop());
+        let mut client = loop {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let mut client = TcpStream::connect(address).await.unwrap();
+            if health_check(&mut client).await == (5, Message::HealthResponse) {
+                break client;
+            }
+        };
+        assert_eq!(health_check(&mut client).await, (5, Message::HealthResponse));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_the_limit_are_queued() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = serve_limited(listener, 1, ConnectionOverflow::Queue);
+
+        let mut served = TcpStream::connect(address).await.unwrap();
+        assert_eq!(health_check(&mut served).await, (5, Message::HealthResponse));
+        let mut queued = TcpStream::connect(address).await.unwrap();
+        let waiting = Duration::from_millis(100);
+        assert!(tokio::time::timeout(waiting, health_check(&mut queued))
+            .await
+            .is_err());
+
+        drop(served);
+        let response =
+            tokio::time::timeout(Duration::from_secs(5), read_frame(&mut queued));
+        assert_eq!(response.await.unwrap(), (5, Message::HealthResponse));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_sized_listener_reads_frames_larger_than_its_buffers() {
+        let address = "127.0.0.1:0".parse().unwrap();
+        let listener = bind_sized(address, 16 * 1024).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let collection = "c".repeat(4 * SETTINGS.read_buffer_size);
+        let scan = Message::ScanCollection { collection, after: None, limit: 1 };
+        let frame = scan.setup_for_network_as(9, Compression::None).unwrap();
+        assert!(frame.len() > SETTINGS.read_buffer_size);
+        client.write_all(&frame).await.unwrap();
+        let response = Message::ErrorResponse(ServerError::Unauthenticated);
+        assert_eq!(read_frame(&mut client).await, (9, response));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_slow_reader_holds_back_the_responses() {
+        let capacity = SETTINGS.response_channel_capacity;
+        let (requests, requests_rx) = response_channel();
+        let (tx, rx) = response_channel();
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(write_responses(requests_rx, server));
+        requests.send((1, rx)).await.unwrap();
+
+        let sender = tx.clone();
+        let producer = tokio::spawn(async move {
+            for _ in 0..1000 {
+                let output = (vec![vec![0; 512]], None);
+                sender.send(Message::QueryResponse(output)).await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!producer.is_finished());
+        assert!(tx.len() <= capacity);
+
+        let reader = tokio::spawn(async move {
+            let mut sink = Vec::new();
+            client.read_to_end(&mut sink).await.map(|_| sink.len())
         });
+        tokio::time::timeout(Duration::from_secs(5), producer)
+            .await
+            .expect("producer still blocked once the client reads")
+            .unwrap();
+        tx.send(Message::CloseCommunication).await.unwrap();
+        let read = reader.await.unwrap().unwrap();
+        assert!(read > 1000 * 512);
     }
 }
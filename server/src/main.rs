@@ -1,4 +1,4 @@
-use liserk_server::run_app;
+use liserk_server::run;
 use std::io;
 use tracing::{error, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -8,7 +8,7 @@ async fn main() -> io::Result<()> {
     let subscriber = FmtSubscriber::builder().with_max_level(Level::TRACE).finish();
     tracing::subscriber::set_global_default(subscriber)
         .expect("setting default subscriber failed");
-    match run_app().await {
+    match run().await {
         Ok(_) => {} // Do nothing
         Err(err) => error!("{:?}", err),
     }
@@ -1,76 +1,540 @@
+use std::time::Duration;
+
 use async_channel::Sender;
+use liserk_shared::audit::{AuditEntry, AuditFilter};
+use liserk_shared::auth::{verify_challenge_mac, AuthMechanism, CHALLENGE_LENGTH};
+use liserk_shared::compression::Compression;
+use liserk_shared::format::Format;
 use liserk_shared::message::{
-    ClientAuthentication, ClientSetupSecureConnection, CountSubject, Delete, Insertion,
-    InsertionOpe, Message, Update,
+    validate_insertion_names, ChunkRequest, ClientAuthentication,
+    ClientSetupSecureConnection, CountSubject, Delete, InsertChunk, InsertStreamStart,
+    Insertion, InsertionOpe, Message, MetadataUpdate, ServerError, Update,
 };
+use liserk_shared::message_type::MessageType;
+use liserk_shared::name::{scoped_name, validate_document_id, validate_tenant};
 use liserk_shared::query::Query;
+use rand::RngCore;
 use tracing::debug;
 use tracing::{error, info};
+use uuid::Uuid;
 
+use crate::audit;
 use crate::command::Command;
+use crate::config::SETTINGS;
+use crate::credentials;
+use crate::cursor::CURSORS;
+use crate::metrics::METRICS;
 use crate::mutation;
 use crate::query_engine;
+use crate::session::{Session, StreamUpload};
+use crate::tenant;
+use crate::token::TOKENS;
+use crate::Error;
 
-pub async fn parse_message(message: Message, tx: Sender<Message>) -> Command {
+pub async fn parse_message(
+    mut message: Message,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    METRICS.record_message(message.message_type());
+    if session.read_only && is_mutation(&message) {
+        info!("refused {} on a read-only session", message.message_type());
+        return send_error(ServerError::ReadOnlySession, &tx).await;
+    }
+    if let Err(err) = validate_request(&message) {
+        return send_error(err, &tx).await;
+    }
+    if let Err(err) = tenant::scope_request(&mut message, session.tenant.as_deref()) {
+        return send_error(ServerError::InvalidName(err), &tx).await;
+    }
     match message {
-        Message::ClientSetup(param) => parse_client_setup(param),
-        Message::ClientAuthentification(param) => parse_authentification(param),
-        Message::Insert(param) => insert(param, tx).await,
-        Message::InsertOpe(param) => insert_ope(param, tx).await,
-        Message::Query(param) => handle_query(param, tx).await,
-        Message::Count(param) => count(param, tx).await,
-        Message::Update(param) => update(param, tx).await,
-        Message::Delete(param) => delete(param, tx).await,
+        Message::ClientSetup(param) => parse_client_setup(param, tx, session).await,
+        Message::ClientAuthentification(_)
+        | Message::ClientTokenAuthentification { .. }
+        | Message::ClientChallengeAuthentification { .. }
+        | Message::ClientChallengeResponse { .. }
+            if !session.set_up =>
+        {
+            info!("refused authentification before setup");
+            send_error(ServerError::SetupRequired, &tx).await
+        }
+        Message::ClientAuthentification(param) => {
+            parse_authentification(param, tx, session).await
+        }
+        Message::ClientTokenAuthentification { token } => {
+            parse_token_authentification(token, tx, session).await
+        }
+        Message::ClientChallengeAuthentification { username } => {
+            send_challenge(username, tx, session).await
+        }
+        Message::ClientChallengeResponse { mac } => {
+            parse_challenge_response(mac, tx, session).await
+        }
+        Message::Insert(param) => insert(param, tx, session).await,
+        Message::InsertBatch(insertions) => insert_batch(insertions, tx, session).await,
+        Message::InsertOpe(param) => insert_ope(param, tx, session).await,
+        Message::Query(param) => handle_query(param, tx, session).await,
+        Message::QueryBatch(queries) => handle_query_batch(queries, tx, session).await,
+        Message::QueryDocuments(query) => {
+            handle_query_documents(query, tx, session).await
+        }
+        Message::QueryAndDelete(query) => query_and_delete(query, tx, session).await,
+        Message::OpenCursor { query, page_size } => {
+            let username = session.username.as_deref();
+            let opened =
+                query_engine::open_cursor(query, page_size, tx.clone(), username).await;
+            answer_query_result(opened, &tx).await
+        }
+        Message::NextPage { cursor } => {
+            let username = session.username.as_deref();
+            let page = query_engine::next_page(cursor, tx.clone(), username).await;
+            answer_query_result(page, &tx).await
+        }
+        Message::StreamQuery { query, page_size } => {
+            let username = session.username.as_deref();
+            let streamed =
+                query_engine::stream_query(query, page_size, tx.clone(), username).await;
+            answer_query_result(streamed, &tx).await
+        }
+        Message::ResumeStream { .. } if session.username.is_none() => {
+            send_error(ServerError::Unauthenticated, &tx).await
+        }
+        Message::ResumeStream { cursor, position } => {
+            let username = session.username.as_deref();
+            let resumed =
+                query_engine::resume_stream(cursor, position, tx.clone(), username).await;
+            answer_query_result(resumed, &tx).await
+        }
+        Message::SetReadOnly => {
+            // Not answered, the requests of a connection are processed in order.
+            session.read_only = true;
+            Command::Continue
+        }
+        Message::CloseCursor { cursor } => {
+            // Not answered, the stream of the cursor, if any, ends with an empty page.
+            CURSORS.close(&cursor, session.username.as_deref());
+            Command::Continue
+        }
+        Message::Explain(query) => explain(query, tx, session).await,
+        Message::Count(param) => count(param, tx, session).await,
+        Message::Update(param) => update(param, tx, session).await,
+        Message::Delete(param) => delete(param, tx, session).await,
+        Message::UpdateMetadata(param) => update_metadata(param, tx, session).await,
+        Message::DescribeDocument { collection, id } => {
+            describe_document(collection, id, tx, session).await
+        }
+        Message::InsertStream(start) => insert_stream(start, tx, session).await,
+        Message::InsertChunk(chunk) => insert_chunk(chunk, tx, session).await,
+        Message::FetchChunk(request) => fetch_chunk(request, tx, session).await,
+        Message::FetchAuditLog(filter) => fetch_audit_log(filter, tx, session).await,
+        Message::Purge { collection, older_than_ms } => {
+            purge(collection, older_than_ms, tx, session).await
+        }
+        Message::ScanCollection { collection, after, limit } => {
+            scan_collection(collection, after, limit, tx, session).await
+        }
+        Message::CollectionStats => collection_stats(tx, session).await,
         Message::DeleteForUsecase { .. } => todo!(),
         Message::Drop(_) => todo!(),
         Message::EndOfCommunication => end_communication(tx).await,
-        Message::DeleteResult(_) => unreachable!(),
-        Message::InsertResponse { .. } => unreachable!(),
-        Message::QueryResponse { .. } => unreachable!(),
-        Message::SingleValueResponse { .. } => unreachable!(),
-        Message::CloseCommunication => unreachable!(),
-        Message::UpdateResponse { .. } => unreachable!(),
-        Message::DropResult(_) => unreachable!(),
-        Message::CountResponse(_) => todo!(),
+        Message::HealthCheck => health_check(tx).await,
+        // A client sending a response gets an error instead of ending its connection.
+        Message::DeleteResult(_)
+        | Message::InsertResponse { .. }
+        | Message::QueryResponse { .. }
+        | Message::SingleValueResponse { .. }
+        | Message::CloseCommunication
+        | Message::UpdateResponse { .. }
+        | Message::DropResult(_)
+        | Message::CountResponse(_)
+        | Message::HealthResponse
+        | Message::ErrorResponse(_)
+        | Message::SetupResponse { .. }
+        | Message::AuthentificationResponse(_)
+        | Message::AuthChallenge { .. }
+        | Message::QueryPageResponse { .. }
+        | Message::PrefixQueryResponse { .. }
+        | Message::ExplainResponse(_)
+        | Message::AuditLogResponse(_)
+        | Message::QueryBatchResponse(_)
+        | Message::DocumentsResponse(_)
+        | Message::ChunkResponse(_)
+        | Message::DocumentMetaResponse(_)
+        | Message::UnknownUsecase { .. }
+        | Message::PartialQueryResponse(_)
+        | Message::PurgeResult(_)
+        | Message::ResponseChunk { .. }
+        | Message::ScanPage { .. }
+        | Message::CollectionStatsResponse(_)
+        | Message::InsertBatchResponse(_) => {
+            send_error(ServerError::UnexpectedMessage, &tx).await
+        }
+    }
+}
+
+/// Checks the names and the queries of a request as the client sent them.
+///
+/// Runs before `tenant::scope_request`, which checks the other collection names: once
+/// scoped to a tenant, an empty collection prefix is no longer empty and a name is longer
+/// than the one the client chose.
+fn validate_request(message: &Message) -> Result<(), ServerError> {
+    match message {
+        Message::Insert(Insertion { collection, usecases, .. })
+        | Message::InsertOpe(InsertionOpe { collection, usecases, .. })
+        | Message::InsertStream(InsertStreamStart { collection, usecases, .. }) => {
+            validate_insertion_names(collection, usecases)
+                .map_err(ServerError::InvalidName)
+        }
+        Message::InsertBatch(insertions) => insertions
+            .iter()
+            .try_for_each(|insertion| {
+                validate_insertion_names(&insertion.collection, &insertion.usecases)
+            })
+            .map_err(ServerError::InvalidName),
+        Message::Query(query)
+        | Message::QueryDocuments(query)
+        | Message::QueryAndDelete(query)
+        | Message::Explain(query)
+        | Message::OpenCursor { query, .. }
+        | Message::StreamQuery { query, .. } => query_engine::validate_query(query),
+        Message::QueryBatch(queries) => {
+            queries.iter().try_for_each(query_engine::validate_query)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns whether `parse_message` handles requests of the type.
+///
+/// Responses are never handled, and neither are requests whose handler is not written
+/// yet. Adding a message type does not compile until it is sorted here.
+pub fn handles(message_type: MessageType) -> bool {
+    match message_type {
+        MessageType::Setup
+        | MessageType::Authentification
+        | MessageType::TokenAuthentification
+        | MessageType::ChallengeAuthentification
+        | MessageType::ChallengeResponse
+        | MessageType::Insert
+        | MessageType::InsertOpe
+        | MessageType::InsertBatch
+        | MessageType::InsertStream
+        | MessageType::InsertChunk
+        | MessageType::Query
+        | MessageType::QueryBatch
+        | MessageType::QueryDocuments
+        | MessageType::QueryAndDelete
+        | MessageType::OpenCursor
+        | MessageType::NextPage
+        | MessageType::StreamQuery
+        | MessageType::ResumeStream
+        | MessageType::SetReadOnly
+        | MessageType::CloseCursor
+        | MessageType::CollectionStats
+        | MessageType::Explain
+        | MessageType::Count
+        | MessageType::ScanCollection
+        | MessageType::FetchChunk
+        | MessageType::FetchAuditLog
+        | MessageType::Purge
+        | MessageType::Update
+        | MessageType::UpdateMetadata
+        | MessageType::DescribeDocument
+        | MessageType::Delete
+        | MessageType::HealthCheck
+        | MessageType::EndOfCommunication => true,
+        MessageType::DeleteForUsecase | MessageType::Drop => false,
+        MessageType::SetupResponse
+        | MessageType::AuthentificationResponse
+        | MessageType::AuthChallenge
+        | MessageType::InsertResponse
+        | MessageType::InsertBatchResponse
+        | MessageType::QueryResponse
+        | MessageType::SingleValueResponse
+        | MessageType::PrefixQueryResponse
+        | MessageType::QueryBatchResponse
+        | MessageType::DocumentsResponse
+        | MessageType::QueryPageResponse
+        | MessageType::ExplainResponse
+        | MessageType::ScanPage
+        | MessageType::CollectionStatsResponse
+        | MessageType::CountResponse
+        | MessageType::ChunkResponse
+        | MessageType::DocumentMetaResponse
+        | MessageType::UnknownUsecase
+        | MessageType::PartialQueryResponse
+        | MessageType::PurgeResult
+        | MessageType::ResponseChunk
+        | MessageType::AuditLogResponse
+        | MessageType::UpdateResponse
+        | MessageType::DeleteResult
+        | MessageType::DropResult
+        | MessageType::HealthResponse
+        | MessageType::ErrorResponse
+        | MessageType::CloseCommunication => false,
     }
 }
 
-async fn count(param: CountSubject, tx: Sender<Message>) -> Command {
-    let command = query_engine::count(param, tx).await;
-    if command.is_err() {
-        error!("error in count: {:?}", command.unwrap_err());
-        return Command::Continue;
+/// Whether a request inserts, changes or deletes documents, refused on a read-only
+/// session.
+fn is_mutation(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Insert(_)
+            | Message::InsertOpe(_)
+            | Message::InsertBatch(_)
+            | Message::InsertStream(_)
+            | Message::InsertChunk(_)
+            | Message::Update(_)
+            | Message::UpdateMetadata(_)
+            | Message::Delete(_)
+            | Message::DeleteForUsecase { .. }
+            | Message::Drop(_)
+            | Message::QueryAndDelete(_)
+            | Message::Purge { .. }
+    )
+}
+
+/// The requests the server handles, announced to clients in the `SetupResponse`.
+pub fn capabilities() -> Vec<MessageType> {
+    (0..=u8::MAX)
+        .filter_map(|value| MessageType::try_from(value).ok())
+        .filter(|message_type| handles(*message_type))
+        .collect()
+}
+
+async fn count(param: CountSubject, tx: Sender<Message>, session: &Session) -> Command {
+    match query_engine::count(param, tx.clone(), session.username.as_deref()).await {
+        Ok(command) => command,
+        Err(err) => {
+            error!("error in count: {:?}", err);
+            send_error(err.to_server_error(), &tx).await
+        }
     }
-    command.expect("error checked before")
 }
 
-async fn update(query: Update, tx: Sender<Message>) -> Command {
-    let status = match mutation::update(query).await {
+/// Checks that an encrypted payload was produced for the message type carrying it.
+///
+/// Clients write the message type as the first byte of the payload and bind it in the
+/// associated data, so a payload replayed under another message type is refused here
+/// and would fail authentication on decryption anyway.
+fn is_payload_for(payload: &[u8], message_type: MessageType) -> bool {
+    payload.first() == Some(&(message_type as u8))
+}
+
+async fn update(query: Update, tx: Sender<Message>, session: &Session) -> Command {
+    if !is_payload_for(&query.new_value, MessageType::Update) {
+        error!("update payload was not encrypted for an update");
+        let status = liserk_shared::message::UpdateStatus::Failure;
+        return respond(Message::UpdateResponse { status }, &tx).await;
+    }
+    let status = match mutation::update(query, session.username.as_deref()).await {
         Ok(status) => status,
+        Err(err @ (Error::Forbidden | Error::VersionConflict { .. })) => {
+            return send_error(err.to_server_error(), &tx).await
+        }
         Err(_) => liserk_shared::message::UpdateStatus::Failure,
     };
-    if let Err(err) = tx.send(Message::UpdateResponse { status }).await {
-        error!("err while sending update response: {:?}", err);
+    respond(Message::UpdateResponse { status }, &tx).await
+}
+
+async fn update_metadata(
+    update: MetadataUpdate,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let username = session.username.as_deref();
+    let status = match mutation::update_metadata(update, username).await {
+        Ok(status) => status,
+        Err(Error::Forbidden) => return send_error(ServerError::Forbidden, &tx).await,
+        Err(err) => {
+            error!("metadata update failed: {}", err);
+            liserk_shared::message::UpdateStatus::Failure
+        }
+    };
+    respond(Message::UpdateResponse { status }, &tx).await
+}
+
+async fn describe_document(
+    collection: String,
+    id: String,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let username = session.username.as_deref();
+    match query_engine::describe_document(&collection, &id, username).await {
+        Ok(meta) => {
+            METRICS.record_query();
+            respond(Message::DocumentMetaResponse(meta), &tx).await
+        }
+        Err(err @ (Error::Forbidden | Error::DocumentNotFound)) => {
+            send_error(err.to_server_error(), &tx).await
+        }
+        Err(err) => {
+            error!("describing a document failed: {}", err);
+            send_error(err.to_server_error(), &tx).await
+        }
     }
-    Command::Continue
 }
 
-async fn delete(delete: Delete, tx: Sender<Message>) -> Command {
-    let result = mutation::delete(delete).await.unwrap_or(false);
-    if let Err(err) = tx.send(Message::DeleteResult(result)).await {
-        error!("delete message: {:?}", err);
+async fn delete(delete: Delete, tx: Sender<Message>, session: &Session) -> Command {
+    let result = match mutation::delete(delete, session.username.as_deref()).await {
+        Ok(is_deleted) => is_deleted,
+        Err(Error::Forbidden) => return send_error(ServerError::Forbidden, &tx).await,
+        Err(_) => false,
+    };
+    respond(Message::DeleteResult(result), &tx).await
+}
+
+async fn parse_authentification(
+    authentification: ClientAuthentication,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    if session.auth_mechanism != AuthMechanism::Password {
+        info!("refused password authentification, another mechanism was negotiated");
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
     }
-    Command::Continue
+    // Whatever the client proposed, a user holding a pre-shared key never authenticates
+    // with a password.
+    if credentials::mechanism_of(&authentification.username) != AuthMechanism::Password {
+        info!("refused password authentification of user: {}", authentification.username);
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
+    }
+    info!("authentification of user: {}", authentification.username);
+    authenticate(authentification.username, tx, session).await
+}
+
+/// Marks the session as authenticated as the user and sends it a session token.
+async fn authenticate(
+    username: String,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    if !tenant::may_authenticate(&username, session.tenant.as_deref()) {
+        info!("refused user {} on a connection of another tenant", username);
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
+    }
+    let time_to_live = Duration::from_secs(SETTINGS.session_token_ttl);
+    let session_token = TOKENS.issue(&username, time_to_live);
+    session.authenticate(username, session_token.token.clone());
+    respond(Message::AuthentificationResponse(session_token), &tx).await
+}
+
+/// Sends a fresh challenge to a client authenticating by challenge-response.
+///
+/// A challenge is sent even to an unknown user, whose response is then refused, so the
+/// exchange does not tell which users exist.
+async fn send_challenge(
+    username: String,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    if session.auth_mechanism != AuthMechanism::ChallengeResponse {
+        info!("refused challenge authentification, another mechanism was negotiated");
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
+    }
+    let mut challenge = vec![0; CHALLENGE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    session.challenge = Some((username, challenge.clone()));
+    respond(Message::AuthChallenge { challenge }, &tx).await
 }
 
-fn parse_authentification(authentification: ClientAuthentication) -> Command {
-    info!("authentification: {:?}", authentification);
-    Command::Continue
+/// Authenticates the user of the last challenge if the response was made with its key.
+///
+/// The challenge is dropped whatever the outcome, a response is never checked twice.
+async fn parse_challenge_response(
+    mac: Vec<u8>,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    let Some((username, challenge)) = session.challenge.take() else {
+        info!("refused challenge response without challenge");
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
+    };
+    let verified = credentials::pre_shared_key(&username)
+        .is_some_and(|key| verify_challenge_mac(&key, &username, &challenge, &mac));
+    if !verified {
+        info!("refused challenge response of user: {}", username);
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
+    }
+    info!("challenge authentification of user: {}", username);
+    authenticate(username, tx, session).await
+}
+
+async fn parse_token_authentification(
+    token: String,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    let Some(session_token) = TOKENS.validate(&token) else {
+        info!("refused unknown or expired session token");
+        return send_error(ServerError::InvalidToken, &tx).await;
+    };
+    if !tenant::may_authenticate(&session_token.username, session.tenant.as_deref()) {
+        info!(
+            "refused token of {} on a connection of another tenant",
+            session_token.username
+        );
+        return send_error(ServerError::AuthenticationFailed, &tx).await;
+    }
+    // The token is replaced only once the connection may resume it.
+    let Some(session_token) = TOKENS.resume(&token) else {
+        return send_error(ServerError::InvalidToken, &tx).await;
+    };
+    info!("token authentification of user: {}", session_token.username);
+    session.authenticate(session_token.username.clone(), session_token.token.clone());
+    respond(Message::AuthentificationResponse(session_token), &tx).await
 }
 
-fn parse_client_setup(secure_connection_message: ClientSetupSecureConnection) -> Command {
+async fn parse_client_setup(
+    secure_connection_message: ClientSetupSecureConnection,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
     info!("secure message: {:?}", secure_connection_message);
-    Command::Continue
+    let tenant = secure_connection_message.tenant().map(str::to_string);
+    if let Some(tenant) = &tenant {
+        if let Err(err) = validate_tenant(tenant) {
+            return send_error(ServerError::InvalidName(err), &tx).await;
+        }
+    }
+    // Documents read by an authenticated session stay out of reach of another tenant.
+    if session.username.is_some() && tenant != session.tenant {
+        return send_error(ServerError::TenantMismatch, &tx).await;
+    }
+    let public_key = secure_connection_message.public_key();
+    let Ok((key_encapsulation, shared_secret)) =
+        pqc_kyber::encapsulate(public_key, &mut rand::thread_rng())
+    else {
+        return send_error(ServerError::KeyExchangeFailed, &tx).await;
+    };
+    session.shared_secret = Some(shared_secret);
+    session.tenant = tenant;
+    let compression = Compression::negotiate(secure_connection_message.compression());
+    let format = Format::negotiate(secure_connection_message.format());
+    // Frames read after the setup are compressed and serialized in the negotiated format,
+    // the response itself is not.
+    session.compression = compression;
+    session.format = format;
+    session.auth_mechanism =
+        AuthMechanism::negotiate(secure_connection_message.auth_mechanisms());
+    session.set_up = true;
+    let response = Message::SetupResponse {
+        compression,
+        format,
+        capabilities: capabilities(),
+        auth_mechanism: session.auth_mechanism,
+        key_encapsulation: key_encapsulation.to_vec(),
+    };
+    respond(response, &tx).await
+}
+
+/// Answers a liveness probe.
+///
+/// Neither reads nor changes the session, so it cannot be used to skip authentication.
+async fn health_check(tx: Sender<Message>) -> Command {
+    respond(Message::HealthResponse, &tx).await
 }
 
 async fn end_communication(tx: Sender<Message>) -> Command {
@@ -80,38 +544,778 @@ async fn end_communication(tx: Sender<Message>) -> Command {
     Command::Exit
 }
 
-async fn insert(insertion: Insertion, tx: Sender<Message>) -> Command {
-    match mutation::insert(insertion).await {
+/// Sends the response of a request, ending the connection if it can no longer be sent.
+///
+/// The channel only closes once the writer of the connection is gone, the client having
+/// disconnected, so nothing else can be answered and the connection exits quietly.
+async fn respond(message: Message, tx: &Sender<Message>) -> Command {
+    match tx.send(message).await {
+        Ok(()) => Command::Continue,
+        Err(err) => {
+            debug!("connection closed before the {} response", err.0.message_type());
+            Command::Exit
+        }
+    }
+}
+
+async fn send_error(error: ServerError, tx: &Sender<Message>) -> Command {
+    METRICS.record_error();
+    respond(Message::ErrorResponse(error), tx).await
+}
+
+async fn insert(insertion: Insertion, tx: Sender<Message>, session: &Session) -> Command {
+    match insert_one(insertion, session).await {
+        Ok(inserted_id) => respond(Message::InsertResponse { inserted_id }, &tx).await,
+        Err(err) => send_error(err, &tx).await,
+    }
+}
+
+async fn insert_batch(
+    insertions: Vec<Insertion>,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let mut results = Vec::with_capacity(insertions.len());
+    for insertion in insertions {
+        results.push(insert_one(insertion, session).await);
+    }
+    respond(Message::InsertBatchResponse(results), &tx).await
+}
+
+/// Inserts a document in its own transaction, returning its id or why it was refused.
+async fn insert_one(
+    insertion: Insertion,
+    session: &Session,
+) -> Result<String, ServerError> {
+    if !is_payload_for(&insertion.data, MessageType::Insert) {
+        error!("insert payload was not encrypted for an insert");
+        return Err(ServerError::InvalidPayload);
+    }
+    if let Some(id) = &insertion.id {
+        validate_document_id(id).map_err(ServerError::InvalidName)?;
+    }
+    match mutation::insert(insertion, session.username.as_deref()).await {
         Ok(inserted_id) => {
+            METRICS.record_insert();
             debug!("inserted uuid: {}", inserted_id);
-            if let Err(err) = tx.send(Message::InsertResponse { inserted_id }).await {
-                error!("err: {:?}", err);
-            }
+            Ok(inserted_id)
+        }
+        Err(err) => {
+            error!("insert failed: {}", err);
+            Err(err.to_server_error())
         }
-        Err(err) => debug!("{:?}", err),
     }
-    Command::Continue
 }
 
-async fn insert_ope(insertion: InsertionOpe, tx: Sender<Message>) -> Command {
-    match mutation::insert_ope(insertion).await {
+async fn insert_ope(
+    insertion: InsertionOpe,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    match mutation::insert_ope(insertion, session.username.as_deref()).await {
         Ok(inserted_id) => {
+            METRICS.record_insert();
             debug!("inserted uuid: {}", inserted_id);
-            if let Err(err) = tx.send(Message::InsertResponse { inserted_id }).await {
-                error!("err: {:?}", err);
+            respond(Message::InsertResponse { inserted_id }, &tx).await
+        }
+        Err(err) => {
+            error!("insert failed: {}", err);
+            send_error(err.to_server_error(), &tx).await
+        }
+    }
+}
+
+/// Opens a chunked insertion and answers with the id the document will be stored under.
+async fn insert_stream(
+    start: InsertStreamStart,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    let inserted_id = Uuid::new_v4().to_string();
+    session
+        .uploads
+        .insert(inserted_id.clone(), StreamUpload { start, next_index: 0 });
+    respond(Message::InsertResponse { inserted_id }, &tx).await
+}
+
+/// Stores the next chunk of an insertion opened on this connection.
+///
+/// A chunk out of order or failing to be stored aborts the insertion, its document never
+/// becomes readable.
+async fn insert_chunk(
+    chunk: InsertChunk,
+    tx: Sender<Message>,
+    session: &mut Session,
+) -> Command {
+    let upload = match session.uploads.remove(&chunk.id) {
+        Some(upload) if upload.next_index == chunk.index => upload,
+        _ => return send_error(ServerError::UnknownUpload, &tx).await,
+    };
+    let (inserted_id, last) = (chunk.id.clone(), chunk.last);
+    let username = session.username.as_deref();
+    match mutation::insert_chunk(&upload.start, chunk, username).await {
+        Ok(()) => {
+            if last {
+                METRICS.record_insert();
+            } else {
+                let upload = StreamUpload { next_index: upload.next_index + 1, ..upload };
+                session.uploads.insert(inserted_id.clone(), upload);
             }
+            respond(Message::InsertResponse { inserted_id }, &tx).await
+        }
+        Err(err) => {
+            error!("insert of a chunk failed: {}", err);
+            send_error(err.to_server_error(), &tx).await
         }
-        Err(err) => debug!("{:?}", err),
     }
-    Command::Continue
 }
 
-async fn handle_query(query: Query, tx: Sender<Message>) -> Command {
-    match query_engine::handle_query(query, tx).await {
+async fn fetch_chunk(
+    request: ChunkRequest,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    match query_engine::fetch_chunk(request, session.username.as_deref()).await {
+        Ok(chunk) => {
+            METRICS.record_query();
+            respond(Message::ChunkResponse(chunk), &tx).await
+        }
+        Err(err) => {
+            error!("fetching a chunk failed: {}", err);
+            send_error(err.to_server_error(), &tx).await
+        }
+    }
+}
+
+/// Sends the entries of the audit log selected by the filter.
+///
+/// The log names the users and documents of every mutation, so it is only sent to an
+/// authenticated session, and only holds the entries of its user on the documents the
+/// `AclPolicy` lets it read the metadata of, unless that user is an audit admin, see
+/// `audit::is_audit_admin`.
+async fn fetch_audit_log(
+    mut filter: AuditFilter,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let Some(username) = session.username.as_deref() else {
+        return send_error(ServerError::Unauthenticated, &tx).await;
+    };
+    let reader = (!audit::is_audit_admin(username)).then_some(username);
+    if reader.is_some() {
+        if filter
+            .username
+            .as_deref()
+            .is_some_and(|filtered| filtered != username)
+        {
+            return send_error(ServerError::Forbidden, &tx).await;
+        }
+        filter.username = Some(username.to_string());
+    }
+    // The entries of tenants are unscoped for their connections, and hidden from the
+    // connections without tenant.
+    let scope = session.tenant.as_deref().map(|tenant| scoped_name(tenant, ""));
+    let scope = scope.as_deref().unwrap_or("");
+    let visible = |entry: &AuditEntry| tenant::is_visible(&entry.collection, scope);
+    let message = match audit::fetch(&filter, reader, visible).await {
+        Ok(entries) => Message::AuditLogResponse(entries),
+        Err(err) => {
+            error!("fetching the audit log failed: {}", err);
+            return send_error(err.to_server_error(), &tx).await;
+        }
+    };
+    respond(message, &tx).await
+}
+
+async fn purge(
+    collection: String,
+    older_than_ms: u64,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    if session.username.is_none() {
+        return send_error(ServerError::Unauthenticated, &tx).await;
+    }
+    let username = session.username.as_deref();
+    let message = match mutation::purge(&collection, older_than_ms, username).await {
+        Ok(purged) => Message::PurgeResult(purged),
+        Err(err) => {
+            error!("purging {} failed: {}", collection, err);
+            return send_error(err.to_server_error(), &tx).await;
+        }
+    };
+    respond(message, &tx).await
+}
+
+async fn scan_collection(
+    collection: String,
+    after: Option<String>,
+    limit: u32,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    if session.username.is_none() {
+        return send_error(ServerError::Unauthenticated, &tx).await;
+    }
+    let username = session.username.as_deref();
+    let result = query_engine::scan_collection(&collection, after, limit, tx, username);
+    command_of(result.await)
+}
+
+/// Counts the documents the user may read in each collection of the tenant of the
+/// session.
+async fn collection_stats(tx: Sender<Message>, session: &Session) -> Command {
+    if session.username.is_none() {
+        return send_error(ServerError::Unauthenticated, &tx).await;
+    }
+    let scope = session.tenant.as_deref().map(|tenant| scoped_name(tenant, ""));
+    let username = session.username.as_deref();
+    let result = query_engine::collection_stats(scope.as_deref().unwrap_or(""), username);
+    match result.await {
+        Ok(stats) => respond(Message::CollectionStatsResponse(stats), &tx).await,
+        Err(err) => {
+            error!("collection stats failed: {}", err);
+            send_error(err.to_server_error(), &tx).await
+        }
+    }
+}
+
+async fn handle_query(query: Query, tx: Sender<Message>, session: &Session) -> Command {
+    let username = session.username.as_deref();
+    handle_query_result(query_engine::handle_query(query, tx, username).await)
+}
+
+async fn handle_query_batch(
+    queries: Vec<Query>,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let username = session.username.as_deref();
+    let responses = query_engine::handle_query_batch(queries, tx.clone(), username);
+    answer_query_result(responses.await, &tx).await
+}
+
+async fn handle_query_documents(
+    query: Query,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let username = session.username.as_deref();
+    let documents = query_engine::handle_query_documents(query, tx.clone(), username);
+    answer_query_result(documents.await, &tx).await
+}
+
+async fn query_and_delete(
+    query: Query,
+    tx: Sender<Message>,
+    session: &Session,
+) -> Command {
+    let username = session.username.as_deref();
+    handle_query_result(query_engine::query_and_delete(query, tx, username).await)
+}
+
+async fn explain(query: Query, tx: Sender<Message>, session: &Session) -> Command {
+    let plan = query_engine::explain(query, tx.clone(), session.username.as_deref());
+    match plan.await {
         Ok(command) => command,
+        Err(err @ Error::ChannelSend(_)) => command_of(Err(err)),
+        Err(err) => {
+            error!("explain failed: {:?}", err);
+            send_error(err.to_server_error(), &tx).await
+        }
+    }
+}
+
+/// Returns the command of a query, counting it as a query once answered.
+fn handle_query_result(result: Result<Command, Error>) -> Command {
+    if result.is_ok() {
+        METRICS.record_query();
+    }
+    command_of(result)
+}
+
+/// Same as `handle_query_result`, but answers a failure with an `ErrorResponse` instead
+/// of closing the connection, so the client is told why its request failed.
+async fn answer_query_result(
+    result: Result<Command, Error>,
+    tx: &Sender<Message>,
+) -> Command {
+    match result {
+        Ok(command) => {
+            METRICS.record_query();
+            command
+        }
+        Err(err @ Error::ChannelSend(_)) => command_of(Err(err)),
         Err(err) => {
             error!("{:?}", err);
+            send_error(err.to_server_error(), tx).await
+        }
+    }
+}
+
+/// Returns the command of a request handled by the query engine, exiting on its failure.
+///
+/// A response that can no longer be sent is not an error of the server, see `respond`.
+fn command_of(result: Result<Command, Error>) -> Command {
+    match result {
+        Ok(command) => command,
+        Err(Error::ChannelSend(err)) => {
+            debug!("connection closed before the {} response", err.0.message_type());
             Command::Exit
         }
+        Err(err) => {
+            METRICS.record_error();
+            error!("{:?}", err);
+            Command::Exit
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use liserk_shared::auth::challenge_mac;
+    use liserk_shared::name::{InvalidName, MAX_NAME_LENGTH};
+    use liserk_shared::query::{SingleQuery, SingleQueryBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_capabilities_only_list_handled_requests() {
+        let expected = vec![
+            MessageType::Setup,
+            MessageType::Authentification,
+            MessageType::Insert,
+            MessageType::Query,
+            MessageType::Count,
+            MessageType::Update,
+            MessageType::Delete,
+            MessageType::EndOfCommunication,
+            MessageType::InsertOpe,
+            MessageType::HealthCheck,
+            MessageType::TokenAuthentification,
+            MessageType::OpenCursor,
+            MessageType::NextPage,
+            MessageType::Explain,
+            MessageType::FetchAuditLog,
+            MessageType::QueryBatch,
+            MessageType::QueryDocuments,
+            MessageType::InsertStream,
+            MessageType::InsertChunk,
+            MessageType::FetchChunk,
+            MessageType::UpdateMetadata,
+            MessageType::ScanCollection,
+            MessageType::InsertBatch,
+            MessageType::StreamQuery,
+            MessageType::CloseCursor,
+            MessageType::ChallengeAuthentification,
+            MessageType::ChallengeResponse,
+            MessageType::DescribeDocument,
+            MessageType::QueryAndDelete,
+            MessageType::Purge,
+            MessageType::ResumeStream,
+            MessageType::SetReadOnly,
+            MessageType::CollectionStats,
+        ];
+        assert_eq!(capabilities(), expected);
+    }
+
+    #[test]
+    fn test_payload_for_other_message_type_is_refused() {
+        let payload = [MessageType::Insert as u8, 1, 2, 3];
+        assert!(is_payload_for(&payload, MessageType::Insert));
+        assert!(!is_payload_for(&payload, MessageType::Update));
+        assert!(!is_payload_for(&[], MessageType::Update));
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_not_resumed_without_authentication() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let message = Message::ResumeStream { cursor: "c".to_string(), position: 0 };
+        parse_message(message, tx, &mut session).await;
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::Unauthenticated)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_sent_as_a_request_is_refused() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        for message in [
+            Message::UnknownUsecase {
+                collection: "users".to_string(),
+                usecase: "adults".to_string(),
+            },
+            Message::CollectionStatsResponse(Vec::new()),
+            Message::HealthResponse,
+        ] {
+            assert_eq!(
+                parse_message(message, tx.clone(), &mut session).await,
+                Command::Continue
+            );
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                Message::ErrorResponse(ServerError::UnexpectedMessage)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_does_not_authenticate() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        parse_message(Message::HealthCheck, tx.clone(), &mut session).await;
+        assert_eq!(rx.recv().await.unwrap(), Message::HealthResponse);
+        assert_eq!(session.username, None);
+
+        let message = Message::FetchAuditLog(AuditFilter::default());
+        parse_message(message, tx, &mut session).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::Unauthenticated)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_is_refused_without_authentication() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let message = Message::FetchAuditLog(AuditFilter::default());
+        parse_message(message, tx, &mut session).await;
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::Unauthenticated)
+        );
+    }
+
+    /// Returns a Kyber public key for a setup.
+    fn public_key() -> Vec<u8> {
+        pqc_kyber::keypair(&mut rand::thread_rng()).public.to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_setup_without_a_kyber_public_key_is_refused() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let setup = ClientSetupSecureConnection::new(vec![1; 32]);
+        parse_message(Message::ClientSetup(setup), tx.clone(), &mut session).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::KeyExchangeFailed)
+        );
+        assert!(!session.set_up);
+
+        let setup = ClientSetupSecureConnection::new(public_key());
+        parse_message(Message::ClientSetup(setup), tx, &mut session).await;
+        let Message::SetupResponse { key_encapsulation, .. } = rx.recv().await.unwrap()
+        else {
+            panic!("the setup was not answered");
+        };
+        assert_eq!(key_encapsulation.len(), pqc_kyber::KYBER_CIPHERTEXTBYTES);
+        assert!(session.shared_secret.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_authentification_requires_the_setup() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let authentification = || {
+            Message::ClientAuthentification(ClientAuthentication {
+                username: "Bob".to_string(),
+                password: "Pomme".to_string(),
+            })
+        };
+        parse_message(authentification(), tx.clone(), &mut session).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::SetupRequired)
+        );
+        assert_eq!(session.username, None);
+
+        let setup = ClientSetupSecureConnection::new(public_key());
+        parse_message(Message::ClientSetup(setup), tx.clone(), &mut session).await;
+        let Message::SetupResponse { capabilities: announced, .. } =
+            rx.recv().await.unwrap()
+        else {
+            panic!("the setup was not answered");
+        };
+        assert_eq!(announced, capabilities());
+        parse_message(authentification(), tx, &mut session).await;
+        assert!(matches!(rx.recv().await.unwrap(), Message::AuthentificationResponse(_)));
+        assert_eq!(session.username.as_deref(), Some("Bob"));
+    }
+
+    async fn request_challenge(
+        tx: &Sender<Message>,
+        rx: &async_channel::Receiver<Message>,
+        session: &mut Session,
+    ) -> Vec<u8> {
+        let username = "billing-service".to_string();
+        let start = Message::ClientChallengeAuthentification { username };
+        parse_message(start, tx.clone(), session).await;
+        let Message::AuthChallenge { challenge } = rx.recv().await.unwrap() else {
+            panic!("no challenge was sent");
+        };
+        assert_eq!(challenge.len(), CHALLENGE_LENGTH);
+        challenge
+    }
+
+    #[tokio::test]
+    async fn test_challenge_response_authentification() {
+        credentials::set_pre_shared_key("billing-service", b"shared secret");
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let setup = ClientSetupSecureConnection::new(public_key())
+            .with_auth_mechanisms(vec![AuthMechanism::ChallengeResponse]);
+        parse_message(Message::ClientSetup(setup), tx.clone(), &mut session).await;
+        let Message::SetupResponse { auth_mechanism, .. } = rx.recv().await.unwrap()
+        else {
+            panic!("the setup was not answered");
+        };
+        assert_eq!(auth_mechanism, AuthMechanism::ChallengeResponse);
+
+        // The password of a user with a pre-shared key is refused, whatever was
+        // negotiated.
+        let mut password_session = Session::default();
+        let setup = ClientSetupSecureConnection::new(public_key());
+        parse_message(Message::ClientSetup(setup), tx.clone(), &mut password_session)
+            .await;
+        assert!(matches!(rx.recv().await.unwrap(), Message::SetupResponse { .. }));
+        let password = Message::ClientAuthentification(ClientAuthentication {
+            username: "billing-service".to_string(),
+            password: "shared secret".to_string(),
+        });
+        parse_message(password, tx.clone(), &mut password_session).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::AuthenticationFailed)
+        );
+        assert_eq!(password_session.username, None);
+
+        // The password is refused once challenge-response was negotiated.
+        let password = Message::ClientAuthentification(ClientAuthentication {
+            username: "billing-service".to_string(),
+            password: "shared secret".to_string(),
+        });
+        parse_message(password, tx.clone(), &mut session).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::AuthenticationFailed)
+        );
+
+        let first = request_challenge(&tx, &rx, &mut session).await;
+        let wrong = challenge_mac(b"guessed secret", "billing-service", &first);
+        parse_message(
+            Message::ClientChallengeResponse { mac: wrong },
+            tx.clone(),
+            &mut session,
+        )
+        .await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::AuthenticationFailed)
+        );
+        assert_eq!(session.username, None);
+
+        let second = request_challenge(&tx, &rx, &mut session).await;
+        assert_ne!(first, second);
+        let mac = challenge_mac(b"shared secret", "billing-service", &second);
+        let response = Message::ClientChallengeResponse { mac };
+        parse_message(response.clone(), tx.clone(), &mut session).await;
+        assert!(matches!(rx.recv().await.unwrap(), Message::AuthentificationResponse(_)));
+        assert_eq!(session.username.as_deref(), Some("billing-service"));
+
+        // A response is only good once.
+        parse_message(response, tx, &mut session).await;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::AuthenticationFailed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_only_session_refuses_an_insert() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session {
+            username: Some("Bob".to_string()),
+            ..Session::default()
+        };
+        parse_message(Message::SetReadOnly, tx.clone(), &mut session).await;
+        assert!(session.read_only);
+        let insertion = Insertion {
+            collection: "users".to_string(),
+            acl: Vec::new(),
+            data: vec![1, 2, 3],
+            usecases: Vec::new(),
+            nonce: vec![0; 12],
+            index: Vec::new(),
+            id: None,
+            upsert: false,
+        };
+        parse_message(Message::Insert(insertion), tx, &mut session).await;
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::ReadOnlySession)
+        );
+        assert!(rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_insertion_under_an_invalid_id_is_refused() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let insertion = Insertion {
+            collection: "users".to_string(),
+            acl: Vec::new(),
+            data: vec![MessageType::Insert as u8, 1, 2, 3],
+            usecases: Vec::new(),
+            nonce: vec![0; 12],
+            index: Vec::new(),
+            id: Some("1:acl".to_string()),
+            upsert: true,
+        };
+        parse_message(Message::Insert(insertion), tx, &mut session).await;
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::InvalidName(InvalidName::InvalidId(
+                "1:acl".to_string()
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunk_of_unknown_insertion_is_refused() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session::default();
+        let chunk = InsertChunk {
+            id: "1".to_string(),
+            index: 0,
+            chunk: vec![1],
+            last: true,
+        };
+        parse_message(Message::InsertChunk(chunk), tx, &mut session).await;
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::UnknownUpload)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refused_insert_is_counted_as_error() {
+        let before = METRICS.snapshot();
+        let (tx, rx) = async_channel::unbounded();
+        let insertion = Insertion {
+            collection: "users".to_string(),
+            acl: Vec::new(),
+            data: vec![MessageType::Update as u8, 1, 2, 3],
+            usecases: Vec::new(),
+            nonce: vec![0; 12],
+            index: Vec::new(),
+            id: None,
+            upsert: false,
+        };
+        let mut session = Session::default();
+        parse_message(Message::Insert(insertion), tx, &mut session).await;
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::InvalidPayload)
+        );
+        let after = METRICS.snapshot();
+        assert!(
+            after.messages_of(MessageType::Insert)
+                > before.messages_of(MessageType::Insert)
+        );
+        assert!(after.errors > before.errors);
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_exits_cleanly() {
+        let (tx, rx) = async_channel::unbounded();
+        drop(rx);
+        let mut session = Session::default();
+        let command = parse_message(Message::HealthCheck, tx.clone(), &mut session).await;
+        assert_eq!(command, Command::Exit);
+
+        let insertion = Insertion {
+            collection: "users".to_string(),
+            acl: Vec::new(),
+            data: vec![MessageType::Update as u8, 1, 2, 3],
+            usecases: Vec::new(),
+            nonce: vec![0; 12],
+            index: Vec::new(),
+            id: None,
+            upsert: false,
+        };
+        let command = parse_message(Message::Insert(insertion), tx.clone(), &mut session);
+        assert_eq!(command.await, Command::Exit);
+
+        let query = SingleQuery::new("users\n".to_string(), "filter".to_string());
+        let command =
+            parse_message(Message::Query(Query::Single(query)), tx, &mut session);
+        assert_eq!(command.await, Command::Exit);
+    }
+
+    #[tokio::test]
+    async fn test_empty_prefix_of_a_tenant_is_refused() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session {
+            username: Some("Bob".to_string()),
+            tenant: Some("acme".to_string()),
+            ..Session::default()
+        };
+        let query = SingleQueryBuilder::default()
+            .with_collection_prefix(String::new())
+            .with_usecase("filter".to_owned())
+            .build();
+        let query = Query::Single(query);
+        parse_message(Message::Query(query.clone()), tx.clone(), &mut session).await;
+        parse_message(Message::QueryBatch(vec![query]), tx, &mut session).await;
+
+        for _ in 0..2 {
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                Message::ErrorResponse(ServerError::InvalidQuery { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_names_of_a_tenant_are_checked_as_sent() {
+        let (tx, rx) = async_channel::unbounded();
+        let mut session = Session {
+            username: Some("Bob".to_string()),
+            tenant: Some("acme".to_string()),
+            ..Session::default()
+        };
+        let start = |collection: String| InsertStreamStart {
+            collection,
+            acl: Vec::new(),
+            usecases: Vec::new(),
+            nonce: vec![0; 12],
+        };
+        let longest = "c".repeat(MAX_NAME_LENGTH);
+        let message = Message::InsertStream(start(longest.clone()));
+        parse_message(message, tx.clone(), &mut session).await;
+        assert!(matches!(rx.recv().await.unwrap(), Message::InsertResponse { .. }));
+        let upload = session.uploads.values().next().unwrap();
+        assert_eq!(upload.start.collection, scoped_name("acme", &longest));
+
+        let message = Message::InsertStream(start("c".repeat(MAX_NAME_LENGTH + 1)));
+        parse_message(message, tx, &mut session).await;
+        let length = MAX_NAME_LENGTH + 1;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::InvalidName(InvalidName::TooLong {
+                length
+            }))
+        );
     }
 }
@@ -0,0 +1,175 @@
+//! Counters of the activity of the server, for monitoring.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use liserk_shared::message_type::MessageType;
+
+lazy_static! {
+    /// Counters of this server process.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Returns the current value of the counters of the server.
+pub fn metrics() -> MetricsSnapshot {
+    METRICS.snapshot()
+}
+
+/// Counters incremented by the message loop and the handlers.
+#[derive(Debug)]
+pub struct Metrics {
+    messages: [AtomicU64; 256],
+    inserts: AtomicU64,
+    queries: AtomicU64,
+    active_connections: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            messages: [(); 256].map(|_| AtomicU64::new(0)),
+            inserts: AtomicU64::new(0),
+            queries: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts a message received from a client.
+    pub fn record_message(&self, message_type: MessageType) {
+        self.messages[message_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a document stored by an insert.
+    pub fn record_insert(&self) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a query answered.
+    pub fn record_query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request refused or failed.
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as active until the returned guard is dropped.
+    pub fn track_connection(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: self }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let messages = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter_map(|(message_type, count)| {
+                let count = count.load(Ordering::Relaxed);
+                let message_type = MessageType::try_from(message_type as u8).ok()?;
+                (count > 0).then_some((message_type, count))
+            })
+            .collect();
+        MetricsSnapshot {
+            messages,
+            inserts: self.inserts.load(Ordering::Relaxed),
+            queries: self.queries.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decrements the active connections when the connection ends, however it ends.
+#[derive(Debug)]
+pub struct ConnectionGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Value of the counters at a point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Messages received per type, types never received are omitted.
+    pub messages: Vec<(MessageType, u64)>,
+    pub inserts: u64,
+    pub queries: u64,
+    pub active_connections: u64,
+    pub errors: u64,
+}
+
+impl MetricsSnapshot {
+    /// Number of messages of a type received.
+    pub fn messages_of(&self, message_type: MessageType) -> u64 {
+        self.messages
+            .iter()
+            .find(|(counted, _)| *counted == message_type)
+            .map_or(0, |(_, count)| *count)
+    }
+
+    /// Formats the counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        text.push_str("# TYPE liserk_messages_total counter\n");
+        for (message_type, count) in self.messages.iter() {
+            text.push_str(&format!(
+                "liserk_messages_total{{type=\"{:?}\"}} {}\n",
+                message_type, count
+            ));
+        }
+        text.push_str("# TYPE liserk_inserts_total counter\n");
+        text.push_str(&format!("liserk_inserts_total {}\n", self.inserts));
+        text.push_str("# TYPE liserk_queries_total counter\n");
+        text.push_str(&format!("liserk_queries_total {}\n", self.queries));
+        text.push_str("# TYPE liserk_active_connections gauge\n");
+        text.push_str(&format!(
+            "liserk_active_connections {}\n",
+            self.active_connections
+        ));
+        text.push_str("# TYPE liserk_errors_total counter\n");
+        text.push_str(&format!("liserk_errors_total {}\n", self.errors));
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_counter_increments() {
+        let metrics = Metrics::default();
+        metrics.record_message(MessageType::Insert);
+        metrics.record_insert();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.inserts, 1);
+        assert_eq!(snapshot.messages_of(MessageType::Insert), 1);
+        assert_eq!(snapshot.messages_of(MessageType::Query), 0);
+        assert!(snapshot.to_prometheus().contains("liserk_inserts_total 1\n"));
+        assert!(snapshot
+            .to_prometheus()
+            .contains("liserk_messages_total{type=\"Insert\"} 1\n"));
+    }
+
+    #[test]
+    fn test_connection_guard_tracks_active_connections() {
+        let metrics = Metrics::default();
+        let first = metrics.track_connection();
+        let second = metrics.track_connection();
+        assert_eq!(metrics.snapshot().active_connections, 2);
+        drop(first);
+        drop(second);
+        assert_eq!(metrics.snapshot().active_connections, 0);
+    }
+}
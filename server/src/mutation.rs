@@ -1,51 +1,331 @@
-use liserk_shared::message::{Delete, Insertion, InsertionOpe, Update, UpdateStatus};
-use tikv_client::TransactionClient;
+use liserk_shared::{
+    audit::AuditOperation,
+    message::{
+        Delete, InsertChunk, InsertStreamStart, Insertion, InsertionOpe, MetadataUpdate,
+        Update, UpdateStatus,
+    },
+    query::IndexEntry,
+};
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tikv_client::{Transaction, TransactionClient};
 use tracing::info;
 use uuid::Uuid;
 
-use crate::{config::TIKV_URL, Error};
+use crate::{
+    acl::{self, AclAction},
+    audit,
+    config::{SETTINGS, TIKV_URL},
+    derivation, Error,
+};
 
-pub async fn insert(insertion: Insertion) -> Result<String, Error> {
-    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+/// Refuses documents whose encrypted form exceeds the maximum size, before any processing.
+fn check_document_size(data: &[u8], max_size: usize) -> Result<(), Error> {
+    if data.len() > max_size {
+        return Err(Error::DocumentTooLarge { size: data.len(), max_size });
+    }
+    Ok(())
+}
 
-    let unique_id = Uuid::new_v4().to_string();
+/// Prefix of the keys registering the collection names, used to resolve collection
+/// prefixes. Each collection has its own key, so inserts into different collections
+/// never write the same key.
+///
+/// The names hold no `:`, so a registration key cannot collide with the keys of a
+/// collection, all of which hold one.
+const COLLECTIONS_PREFIX: &str = "collections/";
 
-    let data_key = format!("{}:{}", insertion.collection, unique_id);
-    info!("data_key: {}", data_key);
+/// First key after the registration keys, `0` following `/`.
+const COLLECTIONS_END: &str = "collections0";
 
-    let mut transaction = client.begin_optimistic().await?;
-    transaction.insert(data_key.clone(), insertion.data).await?;
+/// Key registering the name of a collection.
+fn collection_key(collection: &str) -> String {
+    format!("{}{}", COLLECTIONS_PREFIX, collection)
+}
 
-    let nonce_key = format!("{}:{}:nonce", insertion.collection, unique_id);
-    transaction.insert(nonce_key.clone(), insertion.nonce).await?;
-    info!("nonce_key: {}", nonce_key);
+/// Returns the collection registered by a key of the registration range, `None` for
+/// the keys of a collection whose name starts with `COLLECTIONS_PREFIX`.
+fn registered_collection(key: &str) -> Option<&str> {
+    key.strip_prefix(COLLECTIONS_PREFIX)
+        .filter(|collection| !collection.contains(':'))
+}
 
-    let acl_key = format!("{}:{}:acl", insertion.collection, unique_id);
-    let acl_json = serde_cbor::to_vec(&insertion.acl)?;
-    transaction.insert(acl_key, acl_json).await?;
+/// Registers the collection if it is not registered yet.
+///
+/// Its key is only written on the first insert, the later ones only reading it.
+async fn register_collection(
+    transaction: &mut Transaction,
+    collection: &str,
+) -> Result<(), Error> {
+    let key = collection_key(collection);
+    if transaction.get(key.clone()).await?.is_none() {
+        transaction.put(key, collection.as_bytes().to_vec()).await?;
+    }
+    Ok(())
+}
+
+/// Returns the names of the registered collections, in order.
+pub async fn registered_collections(
+    transaction: &mut Transaction,
+) -> Result<Vec<String>, Error> {
+    let keys = transaction
+        .scan_keys(COLLECTIONS_PREFIX.to_string()..COLLECTIONS_END.to_string(), u32::MAX)
+        .await?;
+    Ok(keys
+        .filter_map(|key| {
+            let key = String::from_utf8_lossy((&key).into()).to_string();
+            registered_collection(&key).map(str::to_string)
+        })
+        .collect())
+}
+
+/// Key of the list of documents indexed with a token.
+pub fn index_key(collection: &str, entry: &IndexEntry) -> String {
+    let token: String = entry.token.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}:{}:index:{}", collection, entry.field, token)
+}
+
+/// Adds the document to the index lists of its tokens and records them with the document.
+async fn add_to_index(
+    transaction: &mut Transaction,
+    collection: &str,
+    id: &str,
+    index: &[IndexEntry],
+) -> Result<(), Error> {
+    if index.is_empty() {
+        return Ok(());
+    }
+    let data_key = format!("{}:{}", collection, id).into_bytes();
+    let mut index_keys = Vec::with_capacity(index.len());
+    for entry in index {
+        let index_key = index_key(collection, entry);
+        let mut values: Vec<Vec<u8>> = match transaction.get(index_key.clone()).await? {
+            Some(value) => serde_cbor::from_slice(&value)?,
+            None => Vec::new(),
+        };
+        if !values.contains(&data_key) {
+            values.push(data_key.clone());
+        }
+        transaction
+            .put(index_key.clone(), serde_cbor::to_vec(&values)?)
+            .await?;
+        index_keys.push(index_key);
+    }
+    let document_index_key = format!("{}:{}:index", collection, id);
+    transaction
+        .put(document_index_key, serde_cbor::to_vec(&index_keys)?)
+        .await?;
+    Ok(())
+}
+
+/// Removes the document from every index list it was added to.
+async fn remove_from_index(
+    transaction: &mut Transaction,
+    collection: &str,
+    id: &str,
+) -> Result<(), Error> {
+    let data_key = format!("{}:{}", collection, id).into_bytes();
+    let document_index_key = format!("{}:{}:index", collection, id);
+    let Some(index_keys) = transaction.get(document_index_key.clone()).await? else {
+        return Ok(());
+    };
+    let index_keys: Vec<String> = serde_cbor::from_slice(&index_keys)?;
+    for index_key in index_keys {
+        let Some(value) = transaction.get(index_key.clone()).await? else {
+            continue;
+        };
+        let mut values: Vec<Vec<u8>> = serde_cbor::from_slice(&value)?;
+        values.retain(|value| value != &data_key);
+        if values.is_empty() {
+            transaction.delete(index_key).await?;
+        } else {
+            transaction.put(index_key, serde_cbor::to_vec(&values)?).await?;
+        }
+    }
+    transaction.delete(document_index_key).await?;
+    Ok(())
+}
 
-    for usecase in insertion.usecases {
-        let usecase_key = format!("{}:{}:usecase", insertion.collection, usecase);
+/// Adds the document to the lists of documents of its usecases and records them with it.
+async fn add_to_usecases(
+    transaction: &mut Transaction,
+    collection: &str,
+    data_key: &str,
+    usecases: &[String],
+) -> Result<(), Error> {
+    let document_usecases_key = format!("{}:usecases", data_key);
+    transaction
+        .put(document_usecases_key, serde_cbor::to_vec(usecases)?)
+        .await?;
+    for usecase in usecases {
+        let usecase_key = format!("{}:{}:usecase", collection, usecase);
         info!("usecase_key: {}", usecase_key);
         let values = match transaction.get(usecase_key.clone()).await? {
             Some(value) => {
                 let mut values: Vec<Vec<u8>> = serde_cbor::from_slice(&value)?;
-                values.push(data_key.clone().into_bytes());
+                values.push(data_key.as_bytes().to_vec());
                 values
             }
             None => {
-                vec![data_key.clone().into_bytes()]
+                vec![data_key.as_bytes().to_vec()]
             }
         };
         let bytes = serde_cbor::to_vec(&values)?;
         transaction.put(usecase_key, bytes).await?;
     }
+    Ok(())
+}
+
+/// Removes the document from the lists of documents of its usecases.
+async fn remove_from_usecases(
+    transaction: &mut Transaction,
+    collection: &str,
+    data_key: &str,
+) -> Result<(), Error> {
+    let usecases = read_usecases(transaction, collection, data_key).await?;
+    for usecase in usecases {
+        let usecase_key = format!("{}:{}:usecase", collection, usecase);
+        let Some(value) = transaction.get(usecase_key.clone()).await? else {
+            continue;
+        };
+        let mut values: Vec<Vec<u8>> = serde_cbor::from_slice(&value)?;
+        values.retain(|value| value != data_key.as_bytes());
+        if values.is_empty() {
+            transaction.delete(usecase_key).await?;
+        } else {
+            transaction.put(usecase_key, serde_cbor::to_vec(&values)?).await?;
+        }
+    }
+    transaction.delete(format!("{}:usecases", data_key)).await?;
+    Ok(())
+}
+
+/// Reads the usecases of a document, recorded with it or found in the usecase lists.
+pub async fn read_usecases(
+    transaction: &mut Transaction,
+    collection: &str,
+    data_key: &str,
+) -> Result<Vec<String>, Error> {
+    match transaction.get(format!("{}:usecases", data_key)).await? {
+        Some(value) => Ok(serde_cbor::from_slice(&value)?),
+        None => scan_usecases(transaction, collection, data_key).await,
+    }
+}
+
+/// Finds the usecases listing a document inserted before usecases were recorded with it.
+async fn scan_usecases(
+    transaction: &mut Transaction,
+    collection: &str,
+    data_key: &str,
+) -> Result<Vec<String>, Error> {
+    let start = format!("{}:", collection);
+    // `;` follows `:`, so the range holds every key of the collection.
+    let end = format!("{};", collection);
+    let mut usecases = Vec::new();
+    for pair in transaction.scan(start.clone()..end, u32::MAX).await? {
+        let key = String::from_utf8_lossy((&pair.0).into()).to_string();
+        let Some(usecase) =
+            key.strip_prefix(&start).and_then(|key| key.strip_suffix(":usecase"))
+        else {
+            continue;
+        };
+        let values: Vec<Vec<u8>> = serde_cbor::from_slice(pair.value())?;
+        if values.iter().any(|value| value == data_key.as_bytes()) {
+            usecases.push(usecase.to_string());
+        }
+    }
+    Ok(usecases)
+}
+
+/// Inserts a document under the id chosen by the client, or a generated one.
+///
+/// A document already stored under the chosen id, tombstoned or not, is refused with
+/// `Error::DuplicateId`, or replaced if the insertion is an upsert and the user may write
+/// it.
+pub async fn insert(
+    insertion: Insertion,
+    username: Option<&str>,
+) -> Result<String, Error> {
+    check_document_size(&insertion.data, SETTINGS.max_document_size)?;
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+
+    let unique_id = match &insertion.id {
+        Some(id) => id.clone(),
+        None => Uuid::new_v4().to_string(),
+    };
+
+    let data_key = format!("{}:{}", insertion.collection, unique_id);
+    info!("data_key: {}", data_key);
+
+    let mut transaction = client.begin_optimistic().await?;
+    // A chunked document stores nothing under its data key, see `insert_chunk`.
+    let replaced = insertion.id.is_some()
+        && document_exists(&mut transaction, &insertion.collection, &unique_id).await?;
+    if replaced {
+        if !insertion.upsert {
+            transaction.rollback().await?;
+            return Err(Error::DuplicateId);
+        }
+        if let Err(err) = acl::check_write(&mut transaction, &data_key, username).await {
+            transaction.rollback().await?;
+            return Err(err);
+        }
+        remove_document(&mut transaction, &insertion.collection, &unique_id, username)
+            .await?;
+    }
+    write_new(&mut transaction, data_key.clone(), insertion.data, replaced).await?;
+    register_collection(&mut transaction, &insertion.collection).await?;
+
+    let nonce_key = format!("{}:{}:nonce", insertion.collection, unique_id);
+    write_new(&mut transaction, nonce_key.clone(), insertion.nonce, replaced).await?;
+    info!("nonce_key: {}", nonce_key);
+
+    let acl_key = format!("{}:{}:acl", insertion.collection, unique_id);
+    let acl_json = serde_cbor::to_vec(&insertion.acl)?;
+    write_new(&mut transaction, acl_key, acl_json, replaced).await?;
+    let index = derivation::with_derived(&insertion.index);
+    add_to_index(&mut transaction, &insertion.collection, &unique_id, &index).await?;
+
+    add_to_usecases(
+        &mut transaction,
+        &insertion.collection,
+        &data_key,
+        &insertion.usecases,
+    )
+    .await?;
+    let inserted_at = serde_cbor::to_vec(&now_millis())?;
+    write_new(&mut transaction, inserted_at_key(&data_key), inserted_at, replaced)
+        .await?;
+    let entry =
+        audit::entry(username, &insertion.collection, AuditOperation::Insert, &unique_id);
+    audit::append(&mut transaction, &entry).await?;
     let commit = transaction.commit().await?;
     info!("insert commit: {:?}", commit);
     Ok(unique_id)
 }
 
-pub async fn insert_ope(insertion: InsertionOpe) -> Result<String, Error> {
+/// Writes a key of a new document, refusing to overwrite a stored one unless the keys
+/// of the document it replaces were deleted in the transaction, see `remove_document`.
+async fn write_new(
+    transaction: &mut Transaction,
+    key: String,
+    value: Vec<u8>,
+    replaced: bool,
+) -> Result<(), Error> {
+    if replaced {
+        transaction.put(key, value).await?;
+    } else {
+        transaction.insert(key, value).await?;
+    }
+    Ok(())
+}
+
+pub async fn insert_ope(
+    insertion: InsertionOpe,
+    username: Option<&str>,
+) -> Result<String, Error> {
     let client = TransactionClient::new(vec![TIKV_URL]).await?;
 
     let unique_id = Uuid::new_v4().to_string();
@@ -55,33 +335,152 @@ pub async fn insert_ope(insertion: InsertionOpe) -> Result<String, Error> {
 
     let mut transaction = client.begin_optimistic().await?;
     transaction.insert(data_key.clone(), insertion.data).await?;
+    register_collection(&mut transaction, &insertion.collection).await?;
 
     let acl_key = format!("{}:{}:acl", insertion.collection, unique_id);
     let acl_json = serde_cbor::to_vec(&insertion.acl)?;
     transaction.insert(acl_key, acl_json).await?;
 
-    for usecase in insertion.usecases {
-        let usecase_key = format!("{}:{}:usecase", insertion.collection, usecase);
-        info!("usecase_key: {}", usecase_key);
-        let values = match transaction.get(usecase_key.clone()).await? {
-            Some(value) => {
-                let mut values: Vec<Vec<u8>> = serde_cbor::from_slice(&value)?;
-                values.push(data_key.clone().into_bytes());
-                values
-            }
-            None => {
-                vec![data_key.clone().into_bytes()]
-            }
-        };
-        let bytes = serde_cbor::to_vec(&values)?;
-        transaction.put(usecase_key, bytes).await?;
-    }
+    add_to_usecases(
+        &mut transaction,
+        &insertion.collection,
+        &data_key,
+        &insertion.usecases,
+    )
+    .await?;
+    record_insertion_time(&mut transaction, &data_key).await?;
+    let entry =
+        audit::entry(username, &insertion.collection, AuditOperation::Insert, &unique_id);
+    audit::append(&mut transaction, &entry).await?;
     let commit = transaction.commit().await?;
     info!("insert commit: {:?}", commit);
     Ok(unique_id)
 }
 
-pub async fn update(query: Update) -> Result<UpdateStatus, Error> {
+/// Key of the time at which a document was inserted.
+pub fn inserted_at_key(data_key: &str) -> String {
+    format!("{}:inserted_at", data_key)
+}
+
+/// Records the time of the insertion of a document, in milliseconds since the Unix epoch.
+async fn record_insertion_time(
+    transaction: &mut Transaction,
+    data_key: &str,
+) -> Result<(), Error> {
+    transaction
+        .insert(inserted_at_key(data_key), serde_cbor::to_vec(&now_millis())?)
+        .await?;
+    Ok(())
+}
+
+/// Milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Key of the time at which a document was tombstoned, in milliseconds since the Unix
+/// epoch.
+pub fn deleted_at_key(data_key: &str) -> String {
+    format!("{}:deleted_at", data_key)
+}
+
+/// Returns whether a document is tombstoned, and so no longer updated until it is
+/// purged.
+async fn is_tombstoned(
+    transaction: &mut Transaction,
+    data_key: &str,
+) -> Result<bool, Error> {
+    Ok(transaction.get_for_update(deleted_at_key(data_key)).await?.is_some())
+}
+
+/// Returns the keys of the documents that are tombstoned, among the given data keys.
+pub async fn read_tombstones(
+    transaction: &mut Transaction,
+    data_keys: &[String],
+) -> Result<HashSet<String>, Error> {
+    let tombstone_keys: Vec<String> =
+        data_keys.iter().map(|key| deleted_at_key(key)).collect();
+    Ok(transaction
+        .batch_get(tombstone_keys)
+        .await?
+        .filter_map(|pair| {
+            let key = String::from_utf8_lossy((&pair.0).into()).to_string();
+            key.strip_suffix(":deleted_at").map(str::to_string)
+        })
+        .collect())
+}
+
+/// Key of a chunk of a document inserted as a sequence of chunks.
+pub fn chunk_key(collection: &str, id: &str, index: u32) -> String {
+    format!("{}:{}:chunk:{:010}", collection, id, index)
+}
+
+/// Key of the number of chunks of a document inserted as a sequence of chunks.
+///
+/// It is only written with the last chunk, a document is readable once it exists.
+pub fn chunk_count_key(collection: &str, id: &str) -> String {
+    format!("{}:{}:chunks", collection, id)
+}
+
+/// Stores a chunk of a document inserted as a sequence of chunks.
+///
+/// Every chunk is committed in its own transaction, so the server never holds the whole
+/// document. The last one commits the metadata of the document along, an abandoned
+/// insertion leaves its chunks stored but the document never becomes readable. No data is
+/// stored under the key of the document itself, so queries over its usecases skip it.
+pub async fn insert_chunk(
+    start: &InsertStreamStart,
+    chunk: InsertChunk,
+    username: Option<&str>,
+) -> Result<(), Error> {
+    check_document_size(&chunk.chunk, SETTINGS.max_document_size)?;
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let chunk_key = chunk_key(&start.collection, &chunk.id, chunk.index);
+    transaction.insert(chunk_key, chunk.chunk).await?;
+    if chunk.last {
+        register_collection(&mut transaction, &start.collection).await?;
+        let nonce_key = format!("{}:{}:nonce", start.collection, chunk.id);
+        transaction.insert(nonce_key, start.nonce.clone()).await?;
+        let acl_key = format!("{}:{}:acl", start.collection, chunk.id);
+        transaction.insert(acl_key, serde_cbor::to_vec(&start.acl)?).await?;
+        let count_key = chunk_count_key(&start.collection, &chunk.id);
+        transaction
+            .insert(count_key, serde_cbor::to_vec(&(chunk.index + 1))?)
+            .await?;
+        let data_key = format!("{}:{}", start.collection, chunk.id);
+        add_to_usecases(&mut transaction, &start.collection, &data_key, &start.usecases)
+            .await?;
+        record_insertion_time(&mut transaction, &data_key).await?;
+        let entry =
+            audit::entry(username, &start.collection, AuditOperation::Insert, &chunk.id);
+        audit::append(&mut transaction, &entry).await?;
+    }
+    let commit = transaction.commit().await?;
+    info!("insert chunk commit: {:?}", commit);
+    Ok(())
+}
+
+/// Key of the version of a document, absent until its first update.
+fn version_key(collection: &str, id: &str) -> String {
+    format!("{}:{}:version", collection, id)
+}
+
+/// Checks that a document at `current` is at the version an update expects, if any.
+fn check_version(expected: Option<u64>, current: u64) -> Result<(), Error> {
+    match expected {
+        Some(expected) if expected != current => Err(Error::VersionConflict { current }),
+        _ => Ok(()),
+    }
+}
+
+pub async fn update(
+    query: Update,
+    username: Option<&str>,
+) -> Result<UpdateStatus, Error> {
     let client = TransactionClient::new(vec![TIKV_URL]).await?;
 
     let data_key = format!("{}:{}", query.collection, query.id);
@@ -92,21 +491,259 @@ pub async fn update(query: Update) -> Result<UpdateStatus, Error> {
         let _ = transaction.commit().await?;
         return Ok(UpdateStatus::KeyNotFound);
     };
+    if is_tombstoned(&mut transaction, &data_key).await? {
+        let _ = transaction.commit().await?;
+        return Ok(UpdateStatus::KeyNotFound);
+    }
+    if let Err(err) = acl::check_write(&mut transaction, &data_key, username).await {
+        transaction.rollback().await?;
+        return Err(err);
+    }
+    let version_key = version_key(&query.collection, &query.id);
+    let version = match transaction.get_for_update(version_key.clone()).await? {
+        Some(version) => serde_cbor::from_slice(&version)?,
+        None => 0,
+    };
+    if let Err(err) = check_version(query.expected_version, version) {
+        transaction.rollback().await?;
+        return Err(err);
+    }
+    transaction
+        .put(version_key, serde_cbor::to_vec(&(version + 1))?)
+        .await?;
     transaction.put(data_key, query.new_value).await?;
+    let nonce_key = format!("{}:{}:nonce", query.collection, query.id);
+    transaction.put(nonce_key, query.nonce).await?;
+    remove_from_index(&mut transaction, &query.collection, &query.id).await?;
+    let index = derivation::with_derived(&query.index);
+    add_to_index(&mut transaction, &query.collection, &query.id, &index).await?;
+    let entry =
+        audit::entry(username, &query.collection, AuditOperation::Update, &query.id);
+    audit::append(&mut transaction, &entry).await?;
     let commit = transaction.commit().await?;
     info!("update commit: {:?}", commit);
     Ok(UpdateStatus::Success)
 }
 
-pub async fn delete(query: Delete) -> Result<bool, Error> {
+/// Replaces the acl and the usecases of a document without touching its data.
+///
+/// The change is authorized against the current acl of the document.
+pub async fn update_metadata(
+    update: MetadataUpdate,
+    username: Option<&str>,
+) -> Result<UpdateStatus, Error> {
     let client = TransactionClient::new(vec![TIKV_URL]).await?;
-    let key = format!("{}:{}", query.collection, query.id);
+    let data_key = format!("{}:{}", update.collection, update.id);
+    // Every document has an acl, whether its data is stored whole, as OPE or chunked.
+    let acl_key = format!("{}:acl", data_key);
+
     let mut transaction = client.begin_optimistic().await?;
-    let is_deleted = match transaction.delete(key).await {
-        Ok(_) => true,
-        Err(_) => false,
+    let Some(_) = transaction.get_for_update(acl_key.clone()).await? else {
+        let _ = transaction.commit().await?;
+        return Ok(UpdateStatus::KeyNotFound);
     };
+    if is_tombstoned(&mut transaction, &data_key).await? {
+        let _ = transaction.commit().await?;
+        return Ok(UpdateStatus::KeyNotFound);
+    }
+    if let Err(err) = acl::check_write(&mut transaction, &data_key, username).await {
+        transaction.rollback().await?;
+        return Err(err);
+    }
+    transaction.put(acl_key, serde_cbor::to_vec(&update.acl)?).await?;
+    remove_from_usecases(&mut transaction, &update.collection, &data_key).await?;
+    add_to_usecases(&mut transaction, &update.collection, &data_key, &update.usecases)
+        .await?;
+    let entry =
+        audit::entry(username, &update.collection, AuditOperation::Update, &update.id);
+    audit::append(&mut transaction, &entry).await?;
+    let commit = transaction.commit().await?;
+    info!("update metadata commit: {:?}", commit);
+    Ok(UpdateStatus::Success)
+}
+
+/// Deletes a document, or tombstones it, whether it was inserted whole or in chunks.
+///
+/// Returns `false` if there is no such document.
+pub async fn delete(query: Delete, username: Option<&str>) -> Result<bool, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let key = format!("{}:{}", query.collection, query.id);
+    let mut transaction = client.begin_optimistic().await?;
+    if let Err(err) = acl::check_write(&mut transaction, &key, username).await {
+        transaction.rollback().await?;
+        return Err(err);
+    }
+    if query.tombstone {
+        let is_deleted = tombstone(&mut transaction, &query, username).await?;
+        transaction.commit().await?;
+        return Ok(is_deleted);
+    }
+    let is_deleted =
+        document_exists(&mut transaction, &query.collection, &query.id).await?;
+    if is_deleted {
+        remove_document(&mut transaction, &query.collection, &query.id, username).await?;
+    }
     let commit = transaction.commit().await?;
     info!("delet commit: {:?}", commit);
     Ok(is_deleted)
 }
+
+/// Returns whether a document is stored, whole under its data key or as chunks under
+/// its chunk manifest, see `chunk_count_key`.
+async fn document_exists(
+    transaction: &mut Transaction,
+    collection: &str,
+    id: &str,
+) -> Result<bool, Error> {
+    if transaction.get(format!("{}:{}", collection, id)).await?.is_some() {
+        return Ok(true);
+    }
+    Ok(transaction.get(chunk_count_key(collection, id)).await?.is_some())
+}
+
+/// Marks a document as deleted at the current time, keeping its data until it is purged.
+///
+/// Returns `false` if the document does not exist or is already tombstoned.
+async fn tombstone(
+    transaction: &mut Transaction,
+    query: &Delete,
+    username: Option<&str>,
+) -> Result<bool, Error> {
+    let data_key = format!("{}:{}", query.collection, query.id);
+    if !document_exists(transaction, &query.collection, &query.id).await?
+        || transaction.get(deleted_at_key(&data_key)).await?.is_some()
+    {
+        return Ok(false);
+    }
+    transaction
+        .put(deleted_at_key(&data_key), serde_cbor::to_vec(&now_millis())?)
+        .await?;
+    let entry =
+        audit::entry(username, &query.collection, AuditOperation::Delete, &query.id);
+    audit::append(transaction, &entry).await?;
+    Ok(true)
+}
+
+/// Physically removes the documents of a collection tombstoned at least `older_than`
+/// milliseconds ago, returning how many were removed.
+///
+/// Only the documents the user may write are removed, the others are left tombstoned.
+pub async fn purge(
+    collection: &str,
+    older_than: u64,
+    username: Option<&str>,
+) -> Result<u64, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let cutoff = now_millis().saturating_sub(older_than);
+    let prefix = format!("{}:", collection);
+    // `;` follows `:`, so the range holds every key of the collection.
+    let end = format!("{};", collection);
+    let mut expired = Vec::new();
+    for pair in transaction.scan(prefix.clone()..end, u32::MAX).await? {
+        let key = String::from_utf8_lossy((&pair.0).into()).to_string();
+        let Some(data_key) = key.strip_suffix(":deleted_at") else {
+            continue;
+        };
+        let deleted_at: u64 = serde_cbor::from_slice(pair.value())?;
+        if deleted_at <= cutoff {
+            expired.push(data_key.to_string());
+        }
+    }
+
+    let acls = acl::read_acls(&mut transaction, &expired).await?;
+    let policy = acl::acl_policy();
+    let mut purged = 0;
+    for data_key in expired {
+        if !acl::is_allowed(&*policy, &acls, &data_key, username, AclAction::Write) {
+            continue;
+        }
+        let Some(id) = data_key.strip_prefix(&prefix) else {
+            continue;
+        };
+        remove_document(&mut transaction, collection, id, username).await?;
+        purged += 1;
+    }
+    transaction.commit().await?;
+    info!("purged {} documents of {}", purged, collection);
+    Ok(purged)
+}
+
+/// Removes a document and everything stored with it, its chunks included, recording it
+/// in the audit log.
+///
+/// The caller checks that the user may write the document.
+pub async fn remove_document(
+    transaction: &mut Transaction,
+    collection: &str,
+    id: &str,
+    username: Option<&str>,
+) -> Result<(), Error> {
+    let data_key = format!("{}:{}", collection, id);
+    remove_from_index(transaction, collection, id).await?;
+    remove_from_usecases(transaction, collection, &data_key).await?;
+    let count_key = chunk_count_key(collection, id);
+    if let Some(count) = transaction.get(count_key.clone()).await? {
+        let count: u32 = serde_cbor::from_slice(&count)?;
+        for index in 0..count {
+            transaction.delete(chunk_key(collection, id, index)).await?;
+        }
+        transaction.delete(count_key).await?;
+    }
+    for key in [
+        version_key(collection, id),
+        format!("{}:nonce", data_key),
+        format!("{}:acl", data_key),
+        inserted_at_key(&data_key),
+        deleted_at_key(&data_key),
+        data_key,
+    ] {
+        transaction.delete(key).await?;
+    }
+    let entry = audit::entry(username, collection, AuditOperation::Delete, id);
+    audit::append(transaction, &entry).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_version_is_a_conflict() {
+        assert!(check_version(None, 3).is_ok());
+        assert!(check_version(Some(3), 3).is_ok());
+        assert!(matches!(
+            check_version(Some(2), 3),
+            Err(Error::VersionConflict { current: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_document_over_the_limit_is_refused() {
+        let max_size = 1024;
+        assert!(check_document_size(&vec![0; max_size], max_size).is_ok());
+        let result = check_document_size(&vec![0; max_size + 1], max_size);
+        assert!(matches!(
+            result,
+            Err(Error::DocumentTooLarge { size: 1025, max_size: 1024 })
+        ));
+    }
+
+    #[test]
+    fn test_registration_keys_are_told_from_the_keys_of_collections() {
+        let key = collection_key("users");
+        assert_eq!(registered_collection(&key), Some("users"));
+        assert_eq!(registered_collection("collections/users:1234"), None);
+        assert_eq!(registered_collection("users:1234"), None);
+    }
+
+    #[test]
+    fn test_index_key_is_hex_encoded() {
+        let entry = IndexEntry {
+            field: "email".to_string(),
+            token: vec![0, 171, 255],
+        };
+        assert_eq!(index_key("users", &entry), "users:email:index:00abff");
+    }
+}
@@ -1,13 +1,39 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    time::Duration,
+};
+
 use async_channel::Sender;
+use lazy_static::lazy_static;
 use liserk_shared::{
-    message::{CountSubject, Message, QueryOutput},
+    message::{
+        ChunkRequest, CountSubject, DocumentMeta, Message, QueryOutput, ServerError,
+        StoredChunk, StoredDocument,
+    },
+    plan::{Access, PlanStep, QueryPlan},
     query::*,
 };
 use rug::Float;
-use tikv_client::{KvPair, Transaction, TransactionClient};
+use tikv_client::{Key, KvPair, Transaction, TransactionClient};
+use tokio::{
+    sync::Mutex,
+    time::{timeout_at, Instant},
+};
 use tracing::{debug, error, info};
 
-use crate::{command::Command, config::TIKV_URL, Error};
+use crate::{
+    acl::{self, AclAction},
+    command::Command,
+    config::{SETTINGS, TIKV_URL},
+    cursor::{CursorStore, Page, CURSORS},
+    mutation, tenant, Error,
+};
+
+lazy_static! {
+    /// Serializes the `QueryAndDelete` requests, see `query_and_delete`.
+    static ref QUERY_AND_DELETE_LOCK: Mutex<()> = Mutex::new(());
+}
 
 /// Encrypted data used in Repsonse
 pub type EncryptedData = Vec<KvPair>;
@@ -18,41 +44,1100 @@ pub type Nonces = Vec<KvPair>;
 /// QueryResponse Represent a query
 pub type QueryResponse = (EncryptedData, Option<Nonces>);
 
-pub async fn handle_query(query: Query, tx: Sender<Message>) -> Result<Command, Error> {
+/// Number of documents a query returning partial results reads at once, the documents
+/// of the batch read when its time budget runs out being left out.
+const PARTIAL_BATCH_SIZE: usize = 256;
+
+/// Runs a query within the time budget of the `query_time_budget_ms` setting.
+///
+/// A query exceeding it is answered by `ServerError::QueryTimeout`, or by the documents
+/// read so far for a single query asking for `SingleQuery::allow_partial_results`.
+pub async fn handle_query(
+    query: Query,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
     let client = TransactionClient::new(vec![TIKV_URL]).await;
     let client = client.expect("failed to connet to tikv");
     let mut transaction = client.begin_optimistic().await?;
-    let message_converter = MessageConverter::default();
+    let started = Instant::now();
+    let partial = allows_partial_results(&query);
+    let message = answer_within(query_deadline(), partial, |reading_deadline| {
+        run_query(&mut transaction, query, username, reading_deadline)
+    })
+    .await?;
+    debug!("query ran in {:?}", started.elapsed());
+    transaction.commit().await?;
+
+    info!("data found {:?}", message);
+    tx.send(message).await?;
+    Ok(Command::Continue)
+}
 
+/// Runs every query of a batch in one transaction and sends their responses together.
+///
+/// Responses keep the order of the queries. A query that fails is answered by an
+/// `ErrorResponse` in its slot without failing the others. The queries are checked with
+/// `validate_query` before, as the client sent them.
+pub async fn handle_query_batch(
+    queries: Vec<Query>,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    // The queries of a batch share the budget of a query.
+    let deadline = query_deadline();
+    let mut responses = Vec::with_capacity(queries.len());
+    for query in queries {
+        let run = run_query(&mut transaction, query, username, None);
+        let response = match within_deadline(deadline, run).await {
+            Ok(message) => message,
+            Err(err) => {
+                error!("query of batch failed: {}", err);
+                Message::ErrorResponse(err.to_server_error())
+            }
+        };
+        responses.push(response);
+    }
+    // Nothing was written: the responses read in the snapshot of the transaction stand
+    // even if it fails to commit, so the slots answered are not dropped.
+    if let Err(err) = transaction.commit().await {
+        error!("transaction of query batch failed to commit: {}", err);
+    }
+
+    tx.send(Message::QueryBatchResponse(responses)).await?;
+    Ok(Command::Continue)
+}
+
+/// Runs a query and returns the message answering it.
+///
+/// Documents the user may not read are left out of the response. A single query stops
+/// reading documents at the deadline, if any, and is answered by the documents read.
+async fn run_query(
+    transaction: &mut Transaction,
+    query: Query,
+    username: Option<&str>,
+    deadline: Option<Instant>,
+) -> Result<Message, Error> {
+    let message_converter = MessageConverter::default();
     let message = match query {
+        Query::Single(single_query) if single_query.collection_prefix => {
+            handle_prefix_query(transaction, single_query, username).await?
+        }
         Query::Single(single_query) => {
-            let data = handle_single_query(&mut transaction, single_query).await?;
-            message_converter.convert_to_message(data)
+            if single_query.warn_unknown_usecase
+                && !has_usecase(transaction, &single_query).await?
+            {
+                return Ok(Message::UnknownUsecase {
+                    collection: single_query.collection,
+                    usecase: single_query.usecase,
+                });
+            }
+            let latest = single_query.latest;
+            let (data, complete) =
+                handle_single_query_until(transaction, single_query, deadline).await?;
+            let data = retain_readable(transaction, data, username).await?;
+            let data = keep_latest(transaction, data, latest).await?;
+            if complete {
+                message_converter.convert_to_message(data)
+            } else {
+                info!("query exceeded its time budget, answering partial results");
+                Message::PartialQueryResponse(message_converter.convert_to_output(data))
+            }
         }
         Query::Compound(compound_query) => {
-            let data = handle_compound_query(&mut transaction, compound_query).await?;
+            let data = handle_compound_query(transaction, compound_query).await?;
+            let data = retain_readable(transaction, data, username).await?;
             message_converter.convert_to_message(data)
         }
         Query::GetById { id, collection } => {
-            let (data, nonce) = get_by_id(&mut transaction, id, collection).await?;
-            Message::SingleValueResponse { data, nonce }
+            let data_key = format!("{}:{}", collection, id);
+            let (data, nonce) = get_by_id(transaction, id, collection).await?;
+            let readable = retain_readable_keys(transaction, vec![data_key], username);
+            if readable.await?.is_empty() {
+                Message::SingleValueResponse { data: None, nonce: None }
+            } else {
+                Message::SingleValueResponse { data, nonce }
+            }
         }
         Query::GetByIds { ids, collection } => {
-            let (data, nonce) = get_by_ids(&mut transaction, ids, collection).await?;
+            let (data, nonce) = get_by_ids(transaction, ids, collection).await?;
             let formated = (data, Some(nonce));
+            let formated = retain_readable(transaction, formated, username).await?;
             message_converter.convert_to_message(formated)
         }
     };
+    Ok(message)
+}
+
+/// Runs a query and sends its readable documents along their ids and collections.
+///
+/// Lets the client tell which document fails to decrypt, see `StoredDocument`.
+pub async fn handle_query_documents(
+    query: Query,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let response = documents_response(&mut transaction, query, username);
+    let response = within_deadline(query_deadline(), response).await?;
+    // Nothing was written, the documents read stand even if the transaction fails to
+    // commit, as in `handle_query_batch`.
+    if let Err(err) = transaction.commit().await {
+        error!("transaction of query documents failed to commit: {}", err);
+    }
+
+    tx.send(Message::DocumentsResponse(stored_documents(response)))
+        .await?;
+    Ok(Command::Continue)
+}
+
+/// Deletes the documents matching a query and sends them, still encrypted.
+///
+/// Requests are serialized by a lock and each runs in a single transaction, so two
+/// consumers taking from the same usecase never get the same document, the second one
+/// only finding what the first one left. Only the documents the user may both read and
+/// write are taken. Queries with predicates are refused: the client evaluates them on
+/// decrypted documents, which would already be deleted.
+pub async fn query_and_delete(
+    query: Query,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    if has_predicates(&query) {
+        let reason = "predicates are not supported when deleting".to_string();
+        tx.send(Message::ErrorResponse(ServerError::InvalidQuery { reason }))
+            .await?;
+        return Ok(Command::Continue);
+    }
+    let _guard = QUERY_AND_DELETE_LOCK.lock().await;
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let (data, nonces) = documents_response(&mut transaction, query, username).await?;
+    let keys: Vec<String> =
+        data.iter().map(|pair| key_to_string(pair.0.clone())).collect();
+    let acls = acl::read_acls(&mut transaction, &keys).await?;
+    let policy = acl::acl_policy();
+    let mut taken = Vec::with_capacity(data.len());
+    for (pair, key) in data.into_iter().zip(keys) {
+        if !acl::is_allowed(&*policy, &acls, &key, username, AclAction::Write) {
+            continue;
+        }
+        let Some((collection, id)) = key.rsplit_once(':') else {
+            continue;
+        };
+        mutation::remove_document(&mut transaction, collection, id, username).await?;
+        taken.push(pair);
+    }
     transaction.commit().await?;
 
-    info!("data found {:?}", message);
-    if let Err(err) = tx.send(message).await {
-        error!("error while sending QueryResponse: {:?}", err);
+    tx.send(Message::DocumentsResponse(stored_documents((taken, nonces))))
+        .await?;
+    Ok(Command::Continue)
+}
+
+/// Runs a query and returns the documents the user may read, with their nonces.
+async fn documents_response(
+    transaction: &mut Transaction,
+    query: Query,
+    username: Option<&str>,
+) -> Result<QueryResponse, Error> {
+    // Prefix queries apply their limit themselves, over all their collections.
+    let latest = match &query {
+        Query::Single(single_query) if !single_query.collection_prefix => {
+            single_query.latest
+        }
+        _ => None,
+    };
+    let response = match query {
+        Query::Single(single_query) if single_query.collection_prefix => {
+            prefix_query_response(transaction, single_query, username).await?.1
+        }
+        Query::Single(single_query) => {
+            handle_single_query(transaction, single_query).await?
+        }
+        Query::Compound(compound_query) => {
+            handle_compound_query(transaction, compound_query).await?
+        }
+        Query::GetById { id, collection } => {
+            let (data, nonces) = get_by_ids(transaction, vec![id], collection).await?;
+            (data, Some(nonces))
+        }
+        Query::GetByIds { ids, collection } => {
+            let (data, nonces) = get_by_ids(transaction, ids, collection).await?;
+            (data, Some(nonces))
+        }
+    };
+    let response = retain_readable(transaction, response, username).await?;
+    keep_latest(transaction, response, latest).await
+}
+
+/// Sends a page of the scan of every document of a collection, in key order.
+///
+/// `limit` bounds the keys read rather than the documents sent: the keys of a collection
+/// also hold the nonces, lists and indexes of its documents, so a page may hold fewer
+/// documents, or none, before the last one. Documents the user may not read are left out,
+/// as are documents inserted as a sequence of chunks, having no data under their key.
+/// A limit over the `max_scan_limit` setting is lowered to it, the page then telling
+/// where the scan resumes as for any full page.
+pub async fn scan_collection(
+    collection: &str,
+    after: Option<String>,
+    limit: u32,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let prefix = format!("{}:", collection);
+    // `;` follows `:`, so the range holds every key of the collection.
+    let end = format!("{};", collection);
+    let limit = scan_limit(limit, SETTINGS.max_scan_limit);
+    let pairs: Vec<KvPair> = transaction
+        .scan(scan_start(&prefix, after)..end, limit)
+        .await?
+        .collect();
+    let next = match pairs.last() {
+        Some(last) if pairs.len() == limit as usize => {
+            Some(key_to_string(last.0.clone()))
+        }
+        _ => None,
+    };
+
+    let mut data: HashMap<String, Vec<u8>> = pairs
+        .into_iter()
+        .map(|pair| (key_to_string(pair.0), pair.1))
+        .filter(|(key, _)| is_document_key(key, &prefix))
+        .collect();
+    let mut keys: Vec<String> = data.keys().cloned().collect();
+    keys.sort();
+    let keys = retain_readable_keys(&mut transaction, keys, username).await?;
+    let nonce_keys: Vec<String> =
+        keys.iter().map(|key| format!("{}:nonce", key)).collect();
+    let mut nonces: HashMap<String, Vec<u8>> = transaction
+        .batch_get(nonce_keys)
+        .await?
+        .map(|pair| (key_to_string(pair.0), pair.1))
+        .collect();
+    transaction.commit().await?;
+
+    let documents = keys
+        .into_iter()
+        .filter_map(|key| {
+            let data = data.remove(&key)?;
+            let nonce = nonces.remove(&format!("{}:nonce", key));
+            Some(StoredDocument {
+                collection: collection.to_string(),
+                id: key[prefix.len()..].to_string(),
+                data,
+                nonce,
+            })
+        })
+        .collect();
+    tx.send(Message::ScanPage { documents, next }).await?;
+    Ok(Command::Continue)
+}
+
+/// Returns the number of keys read for a page of a scan asked to read `requested`, at
+/// least one and at most `max`.
+fn scan_limit(requested: u32, max: u32) -> u32 {
+    requested.clamp(1, max.max(1))
+}
+
+/// Counts the documents the user may read in each collection visible under `scope`, see
+/// `tenant::is_visible`, reading their keys and access lists only.
+pub async fn collection_stats(
+    scope: &str,
+    username: Option<&str>,
+) -> Result<Vec<(String, u64)>, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let mut collections = mutation::registered_collections(&mut transaction).await?;
+    collections.retain(|collection| tenant::is_visible(collection, scope));
+    let mut stats = Vec::with_capacity(collections.len());
+    for collection in collections {
+        let prefix = format!("{}:", collection);
+        // `;` follows `:`, so the range holds every key of the collection.
+        let end = format!("{};", collection);
+        let keys: Vec<String> = transaction
+            .scan_keys(prefix.clone()..end, u32::MAX)
+            .await?
+            .map(key_to_string)
+            .filter(|key| is_document_key(key, &prefix))
+            .collect();
+        let readable = retain_readable_keys(&mut transaction, keys, username).await?;
+        stats.push((collection, readable.len() as u64));
+    }
+    transaction.commit().await?;
+    Ok(stats)
+}
+
+/// First key of a page of a collection scan, the one following `after` if given.
+fn scan_start(prefix: &str, after: Option<String>) -> String {
+    match after {
+        // No key lies between a key and itself followed by a zero byte.
+        Some(after) if after.starts_with(prefix) => format!("{}\0", after),
+        _ => prefix.to_string(),
+    }
+}
+
+/// Returns whether the key is the one of a document of the collection with the prefix,
+/// the keys of its metadata and of the lists of the collection holding more separators.
+fn is_document_key(key: &str, prefix: &str) -> bool {
+    key.strip_prefix(prefix)
+        .map_or(false, |id| !id.is_empty() && !id.contains(':'))
+}
+
+/// Reads a chunk of a document inserted as a sequence of chunks.
+///
+/// Returns `None` if the document is not completely stored, if it has no such chunk or if
+/// the user may not read it.
+/// Reads the access metadata of a document, never touching its encrypted data.
+///
+/// The user must be allowed to read the metadata of the document, which reading the
+/// document itself allows too, see `AclAction::ReadMetadata`.
+pub async fn describe_document(
+    collection: &str,
+    id: &str,
+    username: Option<&str>,
+) -> Result<DocumentMeta, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let meta = read_document_meta(&mut transaction, collection, id, username).await;
+    transaction.commit().await?;
+    meta
+}
+
+async fn read_document_meta(
+    transaction: &mut Transaction,
+    collection: &str,
+    id: &str,
+    username: Option<&str>,
+) -> Result<DocumentMeta, Error> {
+    let data_key = format!("{}:{}", collection, id);
+    // Every document has an acl, whether its data is stored whole, as OPE or chunked.
+    let acls = acl::read_acls(transaction, &[data_key.clone()]).await?;
+    let Some(document_acl) = acls.get(&data_key) else {
+        return Err(Error::DocumentNotFound);
+    };
+    if transaction.get(mutation::deleted_at_key(&data_key)).await?.is_some() {
+        return Err(Error::DocumentNotFound);
+    }
+    let policy = acl::acl_policy();
+    if !policy.allows(username, document_acl, AclAction::ReadMetadata) {
+        return Err(Error::Forbidden);
+    }
+    let usecases = mutation::read_usecases(transaction, collection, &data_key).await?;
+    let inserted_at = match transaction.get(mutation::inserted_at_key(&data_key)).await? {
+        Some(value) => Some(serde_cbor::from_slice(&value)?),
+        None => None,
+    };
+    Ok(DocumentMeta {
+        id: id.to_string(),
+        collection: collection.to_string(),
+        acl: document_acl.clone(),
+        usecases,
+        inserted_at,
+    })
+}
+
+pub async fn fetch_chunk(
+    request: ChunkRequest,
+    username: Option<&str>,
+) -> Result<Option<StoredChunk>, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let chunk = read_chunk(&mut transaction, &request, username).await?;
+    transaction.commit().await?;
+    Ok(chunk)
+}
+
+async fn read_chunk(
+    transaction: &mut Transaction,
+    request: &ChunkRequest,
+    username: Option<&str>,
+) -> Result<Option<StoredChunk>, Error> {
+    let count_key = mutation::chunk_count_key(&request.collection, &request.id);
+    let Some(count) = transaction.get(count_key).await? else {
+        return Ok(None);
+    };
+    let count: u32 = serde_cbor::from_slice(&count)?;
+    if request.index >= count {
+        return Ok(None);
+    }
+    let data_key = format!("{}:{}", request.collection, request.id);
+    let acls = acl::read_acls(transaction, &[data_key.clone()]).await?;
+    let policy = acl::acl_policy();
+    if !acl::is_allowed(&*policy, &acls, &data_key, username, AclAction::Read) {
+        return Ok(None);
+    }
+    if transaction.get(mutation::deleted_at_key(&data_key)).await?.is_some() {
+        return Ok(None);
+    }
+    let chunk_key = mutation::chunk_key(&request.collection, &request.id, request.index);
+    let nonce_key = format!("{}:nonce", data_key);
+    let (Some(chunk), Some(nonce)) =
+        (transaction.get(chunk_key).await?, transaction.get(nonce_key).await?)
+    else {
+        return Ok(None);
+    };
+    Ok(Some(StoredChunk { nonce, chunk, last: request.index + 1 == count }))
+}
+
+/// Pairs the documents of a response with their nonces, by key.
+///
+/// A document whose nonce is missing keeps an empty one, so the client reports it as
+/// failing to decrypt instead of it silently vanishing.
+fn stored_documents((data, nonces): QueryResponse) -> Vec<StoredDocument> {
+    let with_nonces = nonces.is_some();
+    let mut nonces: HashMap<String, Vec<u8>> = nonces
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pair| (key_to_string(pair.0), pair.1))
+        .collect();
+    data.into_iter()
+        .filter_map(|pair| {
+            let key = key_to_string(pair.0);
+            let nonce = with_nonces
+                .then(|| nonces.remove(&format!("{}:nonce", key)).unwrap_or_default());
+            let (collection, id) = key.rsplit_once(':')?;
+            Some(StoredDocument {
+                collection: collection.to_string(),
+                id: id.to_string(),
+                data: pair.1,
+                nonce,
+            })
+        })
+        .collect()
+}
+
+/// Leaves out of a response the documents, and their nonces, the user may not read.
+async fn retain_readable(
+    transaction: &mut Transaction,
+    (data, nonces): QueryResponse,
+    username: Option<&str>,
+) -> Result<QueryResponse, Error> {
+    let keys: Vec<String> =
+        data.iter().map(|pair| key_to_string(pair.0.clone())).collect();
+    let readable: HashSet<String> = retain_readable_keys(transaction, keys, username)
+        .await?
+        .into_iter()
+        .collect();
+    let data = data
+        .into_iter()
+        .filter(|pair| readable.contains(&key_to_string(pair.0.clone())))
+        .collect();
+    let nonces = nonces.map(|nonces| {
+        nonces
+            .into_iter()
+            .filter(|pair| {
+                let key = key_to_string(pair.0.clone());
+                readable.contains(key.strip_suffix(":nonce").unwrap_or(&key))
+            })
+            .collect()
+    });
+    Ok((data, nonces))
+}
+
+/// Keeps the `latest` most recently inserted documents of a response, newest first.
+///
+/// Responses without a limit are left untouched.
+async fn keep_latest(
+    transaction: &mut Transaction,
+    (data, nonces): QueryResponse,
+    latest: Option<usize>,
+) -> Result<QueryResponse, Error> {
+    let Some(latest) = latest else {
+        return Ok((data, nonces));
+    };
+    let keys: Vec<String> =
+        data.iter().map(|pair| key_to_string(pair.0.clone())).collect();
+    let order = latest_of_keys(transaction, keys, latest).await?;
+    let data = order_pairs(data, &order, "");
+    let nonces = nonces.map(|nonces| order_pairs(nonces, &order, ":nonce"));
+    Ok((data, nonces))
+}
+
+/// Returns the `latest` most recently inserted documents among the given data keys,
+/// newest first, see `latest_keys`.
+async fn latest_of_keys(
+    transaction: &mut Transaction,
+    keys: Vec<String>,
+    latest: usize,
+) -> Result<Vec<String>, Error> {
+    let time_keys: Vec<String> =
+        keys.iter().map(|key| mutation::inserted_at_key(key)).collect();
+    let mut times = HashMap::with_capacity(keys.len());
+    for pair in transaction.batch_get(time_keys).await? {
+        let key = key_to_string(pair.0.clone());
+        if let Some(data_key) = key.strip_suffix(":inserted_at") {
+            times.insert(data_key.to_string(), serde_cbor::from_slice::<u64>(&pair.1)?);
+        }
+    }
+    let stamped = keys
+        .into_iter()
+        .map(|key| {
+            let time = times.get(&key).copied().unwrap_or(0);
+            (key, time)
+        })
+        .collect();
+    Ok(latest_keys(stamped, latest))
+}
+
+/// Sorts document keys newest first and keeps the `latest` first ones.
+///
+/// Documents inserted within the same millisecond are ordered by key, hence by id, and
+/// documents inserted before insertion times were recorded count as the oldest.
+fn latest_keys(mut stamped: Vec<(String, u64)>, latest: usize) -> Vec<String> {
+    stamped.sort_by(|(key, time), (other_key, other_time)| {
+        other_time.cmp(time).then_with(|| key.cmp(other_key))
+    });
+    stamped.truncate(latest);
+    stamped.into_iter().map(|(key, _)| key).collect()
+}
+
+/// Orders pairs as the documents they belong to are in `order`, dropping the others.
+///
+/// The key of a pair is the key of its document followed by `suffix`.
+fn order_pairs(pairs: Vec<KvPair>, order: &[String], suffix: &str) -> Vec<KvPair> {
+    let mut by_key: HashMap<String, KvPair> = pairs
+        .into_iter()
+        .map(|pair| (key_to_string(pair.0.clone()), pair))
+        .collect();
+    order
+        .iter()
+        .filter_map(|key| by_key.remove(&format!("{}{}", key, suffix)))
+        .collect()
+}
+
+/// Keeps the keys of the documents the user may read, in order.
+///
+/// Tombstoned documents are left out, as if they were already removed.
+async fn retain_readable_keys(
+    transaction: &mut Transaction,
+    keys: Vec<String>,
+    username: Option<&str>,
+) -> Result<Vec<String>, Error> {
+    let acls = acl::read_acls(transaction, &keys).await?;
+    let tombstones = mutation::read_tombstones(transaction, &keys).await?;
+    let policy = acl::acl_policy();
+    Ok(keys
+        .into_iter()
+        .filter(|key| !tombstones.contains(key))
+        .filter(|key| acl::is_allowed(&*policy, &acls, key, username, AclAction::Read))
+        .collect())
+}
+
+/// Checks that a query can be run before touching storage.
+///
+/// Collection prefixes must not be empty, to avoid querying every collection by mistake,
+/// and are only supported on single queries.
+pub fn validate_query(query: &Query) -> Result<(), ServerError> {
+    query.validate_names().map_err(ServerError::InvalidName)?;
+    match query {
+        Query::Single(single_query)
+            if single_query.collection_prefix && single_query.collection.is_empty() =>
+        {
+            Err(ServerError::InvalidQuery {
+                reason: "the collection prefix is empty".to_string(),
+            })
+        }
+        Query::Compound(compound_query)
+            if compound_query.queries.iter().any(has_prefix) =>
+        {
+            Err(ServerError::InvalidQuery {
+                reason: "collection prefixes are not supported in compound queries"
+                    .to_string(),
+            })
+        }
+        Query::Compound(compound_query)
+            if compound_query.queries.iter().any(has_latest) =>
+        {
+            Err(ServerError::InvalidQuery {
+                reason: "latest is not supported in compound queries".to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn has_latest(query: &Query) -> bool {
+    match query {
+        Query::Single(single_query) => single_query.latest.is_some(),
+        Query::Compound(compound_query) => compound_query.queries.iter().any(has_latest),
+        _ => false,
     }
-    // Todo send in a channel a message Ok(Command::Continue)
+}
+
+fn has_predicates(query: &Query) -> bool {
+    match query {
+        Query::Single(single_query) => !single_query.predicates.is_empty(),
+        Query::Compound(compound_query) => {
+            compound_query.queries.iter().any(has_predicates)
+        }
+        _ => false,
+    }
+}
+
+fn has_prefix(query: &Query) -> bool {
+    match query {
+        Query::Single(single_query) => single_query.collection_prefix,
+        Query::Compound(compound_query) => compound_query.queries.iter().any(has_prefix),
+        _ => false,
+    }
+}
+
+/// Runs a single query on every collection whose name starts with its collection.
+async fn handle_prefix_query(
+    client: &mut Transaction,
+    single_query: SingleQuery,
+    username: Option<&str>,
+) -> Result<Message, Error> {
+    let (collections, response) =
+        prefix_query_response(client, single_query, username).await?;
+    let output = MessageConverter::default().convert_to_output(response);
+    Ok(Message::PrefixQueryResponse { collections, output })
+}
+
+/// Returns the collections starting with the collection of a single query, and the
+/// readable documents of the query run on each of them.
+async fn prefix_query_response(
+    client: &mut Transaction,
+    single_query: SingleQuery,
+    username: Option<&str>,
+) -> Result<(Vec<String>, QueryResponse), Error> {
+    let collections = mutation::registered_collections(client).await?;
+    let collections = collections_with_prefix(collections, &single_query.collection);
+    debug!("collections matching {}: {:?}", single_query.collection, collections);
+
+    let mut data = Vec::new();
+    let mut nonces = Some(Vec::new());
+    for collection in collections.iter() {
+        let query = SingleQuery {
+            collection: collection.clone(),
+            ..single_query.clone()
+        };
+        let response = handle_single_query(client, query).await?;
+        let (results, result_nonces) =
+            retain_readable(client, response, username).await?;
+        data.extend(results);
+        nonces = match (nonces, result_nonces) {
+            (Some(mut nonces), Some(result_nonces)) => {
+                nonces.extend(result_nonces);
+                Some(nonces)
+            }
+            _ => None,
+        };
+    }
+    let response = keep_latest(client, (data, nonces), single_query.latest).await?;
+    Ok((collections, response))
+}
+
+fn collections_with_prefix(collections: Vec<String>, prefix: &str) -> Vec<String> {
+    collections
+        .into_iter()
+        .filter(|collection| tenant::is_visible(collection, prefix))
+        .collect()
+}
+
+/// Sends the plan the server would follow to run a query, without reading documents.
+///
+/// The estimates only count the documents the user may read, as the query would.
+pub async fn explain(
+    query: Query,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let mut steps = Vec::new();
+    match &query {
+        Query::Single(single_query) if single_query.collection_prefix => {
+            let collections = mutation::registered_collections(&mut transaction).await?;
+            for collection in
+                collections_with_prefix(collections, &single_query.collection)
+            {
+                let query = SingleQuery { collection, ..single_query.clone() };
+                let step = explain_single_query(&mut transaction, &query, username);
+                steps.push(step.await?);
+            }
+        }
+        Query::Single(single_query) => {
+            let step = explain_single_query(&mut transaction, single_query, username);
+            steps.push(step.await?);
+        }
+        Query::Compound(compound_query) => {
+            for query in compound_query.queries.iter() {
+                if let Query::Single(single_query) = query {
+                    let step =
+                        explain_single_query(&mut transaction, single_query, username);
+                    steps.push(step.await?);
+                }
+            }
+        }
+        Query::GetById { id, collection } => {
+            let keys = vec![format!("{}:{}", collection, id)];
+            let readable = retain_readable_keys(&mut transaction, keys, username).await?;
+            steps.push(key_lookup_step(collection, readable.len() as u64))
+        }
+        Query::GetByIds { ids, collection } => {
+            let keys = ids.iter().map(|id| format!("{}:{}", collection, id)).collect();
+            let readable = retain_readable_keys(&mut transaction, keys, username).await?;
+            steps.push(key_lookup_step(collection, readable.len() as u64))
+        }
+    }
+    transaction.commit().await?;
+
+    tx.send(Message::ExplainResponse(QueryPlan { steps })).await?;
+    Ok(Command::Continue)
+}
+
+async fn explain_single_query(
+    client: &mut Transaction,
+    single_query: &SingleQuery,
+    username: Option<&str>,
+) -> Result<PlanStep, Error> {
+    let key = candidates_key(single_query);
+    let documents = count_readable_in_cell(client, key, username).await?;
+    Ok(plan_single_query(single_query, documents))
+}
+
+/// Counts the documents listed in a cell of data keys that the user may read.
+async fn count_readable_in_cell(
+    transaction: &mut Transaction,
+    key: String,
+    username: Option<&str>,
+) -> Result<u64, Error> {
+    let keys = match transaction.get(key).await? {
+        Some(value) => extract_data_keys_from_value(value)?,
+        None => Vec::new(),
+    };
+    let readable = retain_readable_keys(transaction, keys, username).await?;
+    Ok(readable.len() as u64)
+}
+
+/// Describes how a single query reads the `documents` of its collection listed in the
+/// cell of `candidates_key`.
+///
+/// An index lookup reads the documents of the index, otherwise every document of the
+/// usecase is read. Predicates are always checked by the client on decrypted data.
+fn plan_single_query(single_query: &SingleQuery, documents: u64) -> PlanStep {
+    let access = match &single_query.index_lookup {
+        Some(entry) => Access::IndexLookup { field: entry.field.clone() },
+        None if is_ope_query(single_query) => Access::OpeRangeScan,
+        None => Access::UsecaseScan,
+    };
+    PlanStep {
+        collection: single_query.collection.clone(),
+        usecase: Some(single_query.usecase.clone()),
+        access,
+        estimated_documents: documents,
+        latest: single_query.latest,
+        client_filters: single_query
+            .predicates
+            .iter()
+            .map(|predicate| predicate.field().to_string())
+            .collect(),
+    }
+}
+
+fn key_lookup_step(collection: &str, documents: u64) -> PlanStep {
+    PlanStep {
+        collection: collection.to_string(),
+        usecase: None,
+        access: Access::KeyLookup,
+        estimated_documents: documents,
+        latest: None,
+        client_filters: Vec::new(),
+    }
+}
+
+/// Runs a query, caches its matching keys under a new cursor and sends the first page.
+pub async fn open_cursor(
+    query: Query,
+    page_size: u32,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    if has_prefix(&query) {
+        let reason = "collection prefixes are not supported by cursors".to_string();
+        tx.send(Message::ErrorResponse(ServerError::InvalidQuery { reason }))
+            .await?;
+        return Ok(Command::Continue);
+    }
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let deadline = query_deadline();
+    let (keys, with_nonces) =
+        readable_matching_keys(&mut transaction, query, username, deadline).await?;
+    let time_to_live = Duration::from_secs(SETTINGS.cursor_ttl);
+    let page =
+        CURSORS.open(keys, page_size as usize, with_nonces, time_to_live, username);
+    let message = within_deadline(deadline, fetch_page(&mut transaction, page)).await?;
+    transaction.commit().await?;
+
+    tx.send(message).await?;
+    Ok(Command::Continue)
+}
+
+/// Opens a cursor on the results of a query and sends all its pages without waiting for
+/// them to be asked.
+///
+/// The pages are sent from a task of their own, so the connection keeps reading requests
+/// meanwhile, among them the `CloseCursor` stopping the stream, see `stream_pages`. The
+/// cursor is resumable, a client losing its connection resumes with `resume_stream`.
+pub async fn stream_query(
+    query: Query,
+    page_size: u32,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    if has_prefix(&query) {
+        let reason = "collection prefixes are not supported by cursors".to_string();
+        tx.send(Message::ErrorResponse(ServerError::InvalidQuery { reason }))
+            .await?;
+        return Ok(Command::Continue);
+    }
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let deadline = query_deadline();
+    let (keys, with_nonces) =
+        readable_matching_keys(&mut transaction, query, username, deadline).await?;
+    transaction.commit().await?;
+    let time_to_live = Duration::from_secs(SETTINGS.cursor_ttl);
+    let page_size = page_size as usize;
+    let first =
+        CURSORS.open_resumable(keys, page_size, with_nonces, time_to_live, username);
+    spawn_stream(client, first, tx, username);
+    Ok(Command::Continue)
+}
+
+/// Streams the pages of a cursor again from `position`, the number of documents the
+/// client read before its connection was lost, as `stream_query` did.
+pub async fn resume_stream(
+    cursor: String,
+    position: u64,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    let position = usize::try_from(position).unwrap_or(usize::MAX);
+    let Some(first) = CURSORS.resume(&cursor, position, username) else {
+        tx.send(Message::ErrorResponse(ServerError::UnknownCursor)).await?;
+        return Ok(Command::Continue);
+    };
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    spawn_stream(client, first, tx, username);
+    Ok(Command::Continue)
+}
+
+/// Sends the pages of a cursor from a task of their own, see `stream_pages`.
+fn spawn_stream(
+    client: TransactionClient,
+    first: Page,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) {
+    let owner = username.map(str::to_string);
+    tokio::spawn(async move {
+        let client = &client;
+        // Every page is fetched within the budget of a query.
+        let fetch = move |page| async move {
+            let mut transaction = client.begin_optimistic().await?;
+            let fetch = fetch_page(&mut transaction, page);
+            let message = within_deadline(query_deadline(), fetch).await?;
+            transaction.commit().await?;
+            Ok(message)
+        };
+        match stream_pages(&CURSORS, first, tx, owner.as_deref(), fetch).await {
+            Ok(()) => {}
+            Err(Error::ChannelSend(_)) => debug!("connection closed during a stream"),
+            Err(err) => error!("error while streaming a query: {:?}", err),
+        }
+    });
+}
+
+/// Sends the pages of a cursor one after the other, until its last page or until the
+/// cursor is closed.
+///
+/// The cursor is read again before each page, so once it is closed no further page is
+/// fetched and the stream ends with an empty page without cursor. A page that cannot be
+/// sent, the connection being gone, ends the stream but leaves the cursor to expire, so
+/// the client can resume it from another connection. A page that cannot be fetched ends
+/// the stream with an `ErrorResponse`, leaving the cursor to expire as well. Once the
+/// last page is sent, the cursor is closed.
+async fn stream_pages<F, Fut>(
+    cursors: &CursorStore,
+    first: Page,
+    tx: Sender<Message>,
+    owner: Option<&str>,
+    mut fetch: F,
+) -> Result<(), Error>
+where
+    F: FnMut(Page) -> Fut,
+    Fut: Future<Output = Result<Message, Error>>,
+{
+    let with_nonces = first.with_nonces;
+    let mut page = first;
+    loop {
+        let id = page.id.clone();
+        let cursor = page.cursor.clone();
+        let message = match fetch(page).await {
+            Ok(message) => message,
+            Err(err) => {
+                tx.send(Message::ErrorResponse(err.to_server_error())).await?;
+                return Err(err);
+            }
+        };
+        tx.send(message).await?;
+        let Some(cursor) = cursor else {
+            cursors.close(&id, owner);
+            return Ok(());
+        };
+        match cursors.next_page(&cursor, owner) {
+            Some(next) => page = next,
+            None => {
+                let page = (Vec::new(), with_nonces.then(Vec::new));
+                tx.send(Message::QueryPageResponse { cursor: None, page }).await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Sends the next page of a cursor, slicing the keys cached when it was opened.
+///
+/// A cursor opened by another user is answered as unknown.
+pub async fn next_page(
+    cursor: String,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
+    let Some(page) = CURSORS.next_page(&cursor, username) else {
+        tx.send(Message::ErrorResponse(ServerError::UnknownCursor)).await?;
+        return Ok(Command::Continue);
+    };
+    let client = TransactionClient::new(vec![TIKV_URL]).await?;
+    let mut transaction = client.begin_optimistic().await?;
+    let fetch = fetch_page(&mut transaction, page);
+    let message = within_deadline(query_deadline(), fetch).await?;
+    transaction.commit().await?;
+
+    tx.send(message).await?;
     Ok(Command::Continue)
 }
 
+/// Lists the keys of the documents matching a query that the user may read, failing
+/// with `Error::QueryTimeout` if the deadline passes first.
+///
+/// The keys are sorted newest first for a query asking for the `latest` documents, by
+/// key otherwise, so a cursor opened again on the same documents pages through them in
+/// the same order.
+async fn readable_matching_keys(
+    client: &mut Transaction,
+    query: Query,
+    username: Option<&str>,
+    deadline: Option<Instant>,
+) -> Result<(Vec<String>, bool), Error> {
+    let latest = match &query {
+        Query::Single(single_query) => single_query.latest,
+        _ => None,
+    };
+    let keys = async {
+        let (keys, with_nonces) = matching_keys(client, query).await?;
+        let keys = retain_readable_keys(client, keys, username).await?;
+        let keys = match latest {
+            Some(latest) => latest_of_keys(client, keys, latest).await?,
+            None => {
+                let mut keys = keys;
+                keys.sort_unstable();
+                keys
+            }
+        };
+        Ok((keys, with_nonces))
+    };
+    within_deadline(deadline, keys).await
+}
+
+/// Lists the keys of the documents matching a query.
+///
+/// Also tells whether the documents are AES encrypted, OPE documents have no nonce.
+async fn matching_keys(
+    client: &mut Transaction,
+    query: Query,
+) -> Result<(Vec<String>, bool), Error> {
+    match query {
+        Query::Single(single_query) if is_ope_query(&single_query) => {
+            let (results, _) = handle_single_query(client, single_query).await?;
+            let keys = results.into_iter().map(|pair| key_to_string(pair.0)).collect();
+            Ok((keys, false))
+        }
+        Query::Single(single_query) => {
+            let Some(value) = client.get(candidates_key(&single_query)).await? else {
+                return Ok((Vec::new(), true));
+            };
+            Ok((extract_data_keys_from_value(value)?, true))
+        }
+        Query::Compound(compound_query) => {
+            let keys = retrieve_keys_from_query(&compound_query);
+            let values = get_kvpair_from_keys(keys, client).await?;
+            match compound_query.query_type {
+                QueryType::And => match values.into_iter().next() {
+                    Some((_, value)) => Ok((extract_data_keys_from_value(value)?, true)),
+                    None => Ok((Vec::new(), true)),
+                },
+                QueryType::Or => Ok((union_of_usecases(&values)?, true)),
+            }
+        }
+        Query::GetById { id, collection } => {
+            Ok((vec![format!("{}:{}", collection, id)], true))
+        }
+        Query::GetByIds { ids, collection } => {
+            let keys = ids.iter().map(|id| format!("{}:{}", collection, id)).collect();
+            Ok((keys, true))
+        }
+    }
+}
+
+/// Fetches the documents of a page, keeping the order of its keys.
+async fn fetch_page(client: &mut Transaction, page: Page) -> Result<Message, Error> {
+    let data = fetch_data_from_keys(client, page.keys.clone()).await?;
+    let mut data: HashMap<String, Vec<u8>> =
+        data.into_iter().map(|pair| (key_to_string(pair.0), pair.1)).collect();
+    let mut nonces: HashMap<String, Vec<u8>> = if page.with_nonces {
+        let nonces = fetch_nonce_from_keys(client, page.keys.clone()).await?;
+        nonces
+            .into_iter()
+            .map(|pair| (key_to_string(pair.0), pair.1))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let mut documents = Vec::with_capacity(page.keys.len());
+    let mut document_nonces = Vec::with_capacity(page.keys.len());
+    for key in page.keys.iter() {
+        let Some(document) = data.remove(key) else {
+            continue;
+        };
+        if page.with_nonces {
+            let Some(nonce) = nonces.remove(&(key.to_owned() + ":nonce")) else {
+                continue;
+            };
+            document_nonces.push(nonce);
+        }
+        documents.push(document);
+    }
+
+    let nonces = page.with_nonces.then_some(document_nonces);
+    Ok(Message::QueryPageResponse { cursor: page.cursor, page: (documents, nonces) })
+}
+
+fn key_to_string(key: Key) -> String {
+    String::from_utf8_lossy((&key).into()).to_string()
+}
+
 trait TokioSender {
     fn serialize_kv_pairs(pairs: &Vec<KvPair>) -> Vec<Vec<u8>> {
         let mut serialized_pairs = Vec::new();
@@ -113,14 +1198,28 @@ async fn handle_single_query(
     client: &mut Transaction,
     single_query: SingleQuery,
 ) -> Result<QueryResponse, Error> {
-    let key = format!("{}:{}:usecase", single_query.collection, single_query.usecase);
+    Ok(handle_single_query_until(client, single_query, None).await?.0)
+}
+
+/// Runs a single query, only reading documents until the deadline if any.
+///
+/// Returns the documents read and whether every document of the query was read.
+async fn handle_single_query_until(
+    client: &mut Transaction,
+    single_query: SingleQuery,
+    deadline: Option<Instant>,
+) -> Result<(QueryResponse, bool), Error> {
+    let key = candidates_key(&single_query);
     info!("key: {}", key);
 
     match client.get(key.clone()).await? {
         Some(value) => {
             println!("Got value for key {}: {:?}", key, value);
-            let data_keys = extract_data_keys_from_value(value)?;
-            let mut results = fetch_data_from_keys(client, data_keys.clone()).await?;
+            let mut data_keys = extract_data_keys_from_value(value)?;
+            let (mut results, read) =
+                fetch_data_until(client, &data_keys, deadline).await?;
+            let complete = read == data_keys.len();
+            data_keys.truncate(read);
             if is_ope_query(&single_query) {
                 results =
                     filter_results_by_upper_limit(results, single_query.upper_limit);
@@ -129,18 +1228,119 @@ async fn handle_single_query(
             } else {
                 let nonce = fetch_nonce_from_keys(client, data_keys).await?;
 
-                return Ok((results, Some(nonce)));
+                return Ok(((results, Some(nonce)), complete));
             }
 
-            Ok((results, None))
+            Ok(((results, None), complete))
         }
         None => {
             debug!("No value found for key {}", key);
-            Ok((Vec::new(), None))
+            Ok(((Vec::new(), None), true))
         }
     }
 }
 
+/// Returns when a query starting now must be answered, see `handle_query`.
+fn query_deadline() -> Option<Instant> {
+    let budget = SETTINGS.query_time_budget_ms?;
+    Some(Instant::now() + Duration::from_millis(budget))
+}
+
+/// Returns whether the query is answered by partial results once over its budget.
+fn allows_partial_results(query: &Query) -> bool {
+    matches!(
+        query,
+        Query::Single(single_query)
+            if single_query.allow_partial_results && !single_query.collection_prefix
+    )
+}
+
+/// Runs a whole query with `run` within the deadline, answering
+/// `ServerError::QueryTimeout` if it passes first.
+///
+/// `run` is given the deadline to stop reading documents at, for a query answered by
+/// partial results: halfway through the budget, the rest of it being left to filter the
+/// documents read.
+async fn answer_within<F, Fut>(
+    deadline: Option<Instant>,
+    partial: bool,
+    run: F,
+) -> Result<Message, Error>
+where
+    F: FnOnce(Option<Instant>) -> Fut,
+    Fut: Future<Output = Result<Message, Error>>,
+{
+    let now = Instant::now();
+    let reading_deadline = deadline
+        .filter(|_| partial)
+        .map(|deadline| now + deadline.saturating_duration_since(now) / 2);
+    match within_deadline(deadline, run(reading_deadline)).await {
+        Err(Error::QueryTimeout) => Ok(Message::ErrorResponse(ServerError::QueryTimeout)),
+        result => result,
+    }
+}
+
+/// Runs a step of a query, failing with `Error::QueryTimeout` if the deadline passes
+/// first.
+async fn within_deadline<T, F>(deadline: Option<Instant>, step: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    match deadline {
+        Some(deadline) => {
+            timeout_at(deadline, step).await.map_err(|_| Error::QueryTimeout)?
+        }
+        None => step.await,
+    }
+}
+
+/// Reads the data of the keys by batches until the deadline, if any.
+///
+/// Returns the data read and the number of keys read, the first ones of `data_keys`.
+async fn fetch_data_until(
+    client: &mut Transaction,
+    data_keys: &[String],
+    deadline: Option<Instant>,
+) -> Result<(Vec<KvPair>, usize), Error> {
+    if deadline.is_none() {
+        let results = fetch_data_from_keys(client, data_keys.to_vec()).await?;
+        return Ok((results, data_keys.len()));
+    }
+    let mut results = Vec::new();
+    let mut read = 0;
+    for batch in data_keys.chunks(PARTIAL_BATCH_SIZE) {
+        let fetch = fetch_data_from_keys(client, batch.to_vec());
+        match within_deadline(deadline, fetch).await {
+            Ok(batch) => results.extend(batch),
+            Err(Error::QueryTimeout) => break,
+            Err(err) => return Err(err),
+        }
+        read += batch.len();
+    }
+    Ok((results, read))
+}
+
+/// Returns whether a document of the collection of the query declares its usecase.
+///
+/// The list of a usecase is removed with its last document, so an absent list means
+/// the usecase is unknown.
+async fn has_usecase(
+    client: &mut Transaction,
+    single_query: &SingleQuery,
+) -> Result<bool, Error> {
+    let key = format!("{}:{}:usecase", single_query.collection, single_query.usecase);
+    Ok(client.get(key).await?.is_some())
+}
+
+/// Returns the key of the cell listing the documents a single query reads: the index of
+/// its lookup if any, read instead of the list of every document of its usecase.
+fn candidates_key(single_query: &SingleQuery) -> String {
+    match &single_query.index_lookup {
+        Some(entry) => mutation::index_key(&single_query.collection, entry),
+        None => format!("{}:{}:usecase", single_query.collection, single_query.usecase),
+    }
+}
+
 fn is_ope_query(query: &SingleQuery) -> bool {
     query.upper_limit.is_some() || query.lower_limit.is_some()
 }
@@ -212,9 +1412,7 @@ fn retrieve_keys_from_query(compound_query: &CompoundQuery) -> Vec<String> {
         .queries
         .iter()
         .filter_map(|query| match query {
-            Query::Single(single_query) => {
-                Some(format!("{}:{}", single_query.collection, single_query.usecase))
-            }
+            Query::Single(single_query) => Some(candidates_key(single_query)),
             _ => None,
         })
         .collect()
@@ -247,22 +1445,36 @@ async fn handle_compound_query(
         }
         QueryType::Or => {
             let values = get_kvpair_from_keys(keys, client).await?;
+            let data_keys = union_of_usecases(&values)?;
+            if data_keys.is_empty() {
+                debug!("No values found for keys");
+                return Ok((Vec::new(), None));
+            }
+            let data = fetch_data_from_keys(client, data_keys.clone()).await?;
+            let nonce = fetch_nonce_from_keys(client, data_keys).await?;
+            Ok((data, Some(nonce)))
+        }
+    }
+}
 
-            for (key, value) in values {
-                debug!("Got value for key {}: {:?}", key, value);
-                let data_keys: Vec<String> =
-                    serde_cbor::from_slice::<Vec<Vec<u8>>>(&value)?
-                        .iter()
-                        .map(|data_key| String::from_utf8_lossy(data_key).to_string())
-                        .collect();
-                let data = fetch_data_from_keys(client, data_keys.clone()).await?;
-                let nonce = fetch_nonce_from_keys(client, data_keys).await?;
-                return Ok((data, Some(nonce)));
+/// Returns the documents of any of the usecase cells, each once, in the order they are
+/// first met.
+///
+/// A document of several usecases matched by the subqueries of an `Or` is listed in each
+/// of their cells, but is only returned once.
+fn union_of_usecases(cells: &[(String, Vec<u8>)]) -> Result<Vec<String>, Error> {
+    let mut seen = HashSet::new();
+    let mut data_keys = Vec::new();
+    for (key, value) in cells {
+        debug!("Got value for key {}", key);
+        for data_key in serde_cbor::from_slice::<Vec<Vec<u8>>>(value)? {
+            let data_key = String::from_utf8_lossy(&data_key).to_string();
+            if seen.insert(data_key.clone()) {
+                data_keys.push(data_key);
             }
-            debug!("No values found for keys");
-            Ok((Vec::new(), None))
         }
     }
+    Ok(data_keys)
 }
 
 async fn get_kvpair_from_keys(
@@ -281,7 +1493,13 @@ async fn get_kvpair_from_keys(
     Ok(kv_pairs)
 }
 
-pub async fn count(count: CountSubject, tx: Sender<Message>) -> Result<Command, Error> {
+/// Counts the documents of a collection or of a usecase the user may read, leaving
+/// tombstoned ones out.
+pub async fn count(
+    count: CountSubject,
+    tx: Sender<Message>,
+    username: Option<&str>,
+) -> Result<Command, Error> {
     let key = match count {
         CountSubject::Collection(collection) => {
             format!("{}:keys", collection)
@@ -292,20 +1510,418 @@ pub async fn count(count: CountSubject, tx: Sender<Message>) -> Result<Command,
     };
     let client = TransactionClient::new(vec![TIKV_URL]).await?;
     let mut transaction = client.begin_optimistic().await?;
-    let values = transaction.get(key).await?;
+    let length = count_readable_in_cell(&mut transaction, key, username).await?;
     transaction.commit().await?;
-    let length = compute_length_of_cell(values)?;
-    tx.send(Message::CountResponse(length)).await?;
+    tx.send(Message::CountResponse(length as u32)).await?;
     Ok(Command::Continue)
 }
 
-fn compute_length_of_cell(values: Option<Vec<u8>>) -> Result<u32, Error> {
-    if values.is_none() {
-        return Ok(0);
+#[cfg(test)]
+mod tests {
+    use liserk_shared::name::{InvalidName, MAX_NAME_LENGTH};
+
+    use super::*;
+
+    #[test]
+    fn test_scan_limit_is_clamped_to_the_maximum() {
+        assert_eq!(scan_limit(0, 100), 1);
+        assert_eq!(scan_limit(50, 100), 50);
+        assert_eq!(scan_limit(u32::MAX, 100), 100);
+        assert_eq!(scan_limit(u32::MAX, 0), 1);
+    }
+
+    #[test]
+    fn test_latest_keys_are_newest_first_with_ties_by_id() {
+        let stamped = vec![
+            ("users:b".to_string(), 20),
+            ("users:old".to_string(), 0),
+            ("users:c".to_string(), 30),
+            ("users:a".to_string(), 20),
+        ];
+        assert_eq!(
+            latest_keys(stamped.clone(), 3),
+            vec!["users:c", "users:a", "users:b"]
+        );
+        assert_eq!(latest_keys(stamped.clone(), 10).len(), 4);
+        assert!(latest_keys(stamped, 0).is_empty());
+    }
+
+    #[test]
+    fn test_order_pairs_follows_the_order_of_documents() {
+        let nonces = vec![
+            KvPair::new("users:a:nonce".to_string(), vec![1]),
+            KvPair::new("users:b:nonce".to_string(), vec![2]),
+            KvPair::new("users:c:nonce".to_string(), vec![3]),
+        ];
+        let order = vec!["users:c".to_string(), "users:a".to_string()];
+        let ordered: Vec<Vec<u8>> = order_pairs(nonces, &order, ":nonce")
+            .into_iter()
+            .map(|pair| pair.1)
+            .collect();
+        assert_eq!(ordered, vec![vec![3], vec![1]]);
+    }
+
+    #[test]
+    fn test_stored_documents_pair_nonces_by_key() {
+        let data = vec![
+            KvPair::new("users:1".to_owned(), vec![1]),
+            KvPair::new("users:2".to_owned(), vec![2]),
+        ];
+        let nonces = vec![KvPair::new("users:1:nonce".to_owned(), vec![9])];
+        let documents = stored_documents((data, Some(nonces)));
+
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].collection, "users");
+        assert_eq!(documents[0].id, "1");
+        assert_eq!(documents[0].nonce, Some(vec![9]));
+        assert_eq!(documents[1].id, "2");
+        assert_eq!(documents[1].nonce, Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_collections_with_prefix() {
+        let collections = ["logs_a", "users", "logs_b", "blogs_a"]
+            .iter()
+            .map(|collection| collection.to_string())
+            .collect();
+        assert_eq!(
+            collections_with_prefix(collections, "logs_"),
+            vec!["logs_a", "logs_b"]
+        );
+    }
+
+    #[test]
+    fn test_document_matched_by_two_or_subqueries_is_returned_once() {
+        let subqueries = ["admins", "editors"].map(|usecase| {
+            Query::Single(SingleQuery::new("users".into(), usecase.into()))
+        });
+        let compound = CompoundQuery::new(QueryType::Or, subqueries.to_vec());
+        let keys = retrieve_keys_from_query(&compound);
+        assert_eq!(keys, vec!["users:admins:usecase", "users:editors:usecase"]);
+
+        let cell = |ids: &[&str]| {
+            let data_keys: Vec<Vec<u8>> =
+                ids.iter().map(|id| format!("users:{}", id).into_bytes()).collect();
+            serde_cbor::to_vec(&data_keys).unwrap()
+        };
+        let cells = vec![
+            (keys[0].clone(), cell(&["1", "2"])),
+            (keys[1].clone(), cell(&["3", "1"])),
+        ];
+        assert_eq!(
+            union_of_usecases(&cells).unwrap(),
+            vec!["users:1", "users:2", "users:3"]
+        );
+    }
+
+    #[test]
+    fn test_empty_prefix_is_refused() {
+        let query = SingleQueryBuilder::default()
+            .with_collection_prefix(String::new())
+            .with_usecase("filter".to_owned())
+            .build();
+        assert!(validate_query(&Query::Single(query.clone())).is_err());
+
+        let compound = CompoundQuery::new(QueryType::Or, vec![Query::Single(query)]);
+        assert!(validate_query(&Query::Compound(compound)).is_err());
+
+        let query = SingleQuery::new(String::new(), "filter".to_owned());
+        assert!(validate_query(&Query::Single(query)).is_ok());
+    }
+
+    #[test]
+    fn test_latest_is_refused_in_compound_queries() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("jobs".to_owned())
+            .with_usecase("pending".to_owned())
+            .latest(3)
+            .build();
+        assert!(validate_query(&Query::Single(query.clone())).is_ok());
+
+        let compound = CompoundQuery::new(QueryType::Or, vec![Query::Single(query)]);
+        assert!(matches!(
+            validate_query(&Query::Compound(compound)),
+            Err(ServerError::InvalidQuery { .. })
+        ));
+    }
+
+    #[test]
+    fn test_predicates_are_found_in_compound_queries() {
+        let plain = SingleQuery::new("jobs".to_owned(), "pending".to_owned());
+        let filtered = SingleQueryBuilder::default()
+            .with_collection("jobs".to_owned())
+            .with_usecase("pending".to_owned())
+            .with_field_exists("owner".to_owned())
+            .build();
+        assert!(!has_predicates(&Query::Single(plain.clone())));
+        assert!(has_predicates(&Query::Single(filtered.clone())));
+
+        let queries = vec![Query::Single(plain), Query::Single(filtered)];
+        let compound = CompoundQuery::new(QueryType::And, queries);
+        assert!(has_predicates(&Query::Compound(compound)));
+    }
+
+    #[test]
+    fn test_invalid_names_are_refused() {
+        let query =
+            SingleQuery::new("users\nINFO forged".to_owned(), "filter".to_owned());
+        assert!(matches!(
+            validate_query(&Query::Single(query)),
+            Err(ServerError::InvalidName(InvalidName::ControlCharacter(_)))
+        ));
+        let query = SingleQuery::new("users".to_owned(), "f".repeat(MAX_NAME_LENGTH + 1));
+        assert!(matches!(
+            validate_query(&Query::Single(query)),
+            Err(ServerError::InvalidName(InvalidName::TooLong { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_plan_shows_index_backed_and_scanned_queries() {
+        let entry = IndexEntry { field: "email".to_string(), token: vec![1; 32] };
+        let indexed = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("search".to_owned())
+            .with_index_lookup(entry)
+            .build();
+        let step = plan_single_query(&indexed, 3);
+        assert_eq!(step.access, Access::IndexLookup { field: "email".to_string() });
+        assert_eq!(step.estimated_documents, 3);
+
+        let scanned = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("search".to_owned())
+            .with_field_exists("email".to_owned())
+            .build();
+        let step = plan_single_query(&scanned, 1000);
+        assert_eq!(step.access, Access::UsecaseScan);
+        assert_eq!(step.estimated_documents, 1000);
+        assert_eq!(step.client_filters, vec!["email"]);
+        assert_eq!(step.latest, None);
+
+        let latest = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("search".to_owned())
+            .latest(10)
+            .build();
+        let step = plan_single_query(&latest, 1000);
+        assert_eq!(step.estimated_documents, 1000);
+        assert_eq!(step.latest, Some(10));
+    }
+
+    #[test]
+    fn test_collection_scan_only_keeps_document_keys() {
+        let keys = [
+            "users:1b4e28ba",
+            "users:1b4e28ba:acl",
+            "users:1b4e28ba:nonce",
+            "users:search:usecase",
+            "users:email:index:0a0b",
+            "users:",
+            "posts:1b4e28ba",
+        ];
+        let documents: Vec<&str> = keys
+            .into_iter()
+            .filter(|key| is_document_key(key, "users:"))
+            .collect();
+        assert_eq!(documents, vec!["users:1b4e28ba"]);
+    }
+
+    #[test]
+    fn test_collection_scan_resumes_after_the_last_key() {
+        assert_eq!(scan_start("users:", None), "users:");
+        let start = scan_start("users:", Some("users:1b4e28ba:nonce".to_string()));
+        assert!(start.as_str() > "users:1b4e28ba:nonce");
+        assert!(start.as_str() < "users:1b4e28ba:nonce:");
+        // A key outside of the collection does not move the scan out of it.
+        assert_eq!(scan_start("users:", Some("posts:1".to_string())), "users:");
+    }
+
+    #[test]
+    fn test_or_of_predicates_scans_the_usecase_once() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("search".to_owned())
+            .with_field_exists("email".to_owned())
+            .with_field_equal_to("age".to_owned(), liserk_shared::value::Value::Int(30))
+            .predicate_logic(QueryType::Or)
+            .build();
+        let step = plan_single_query(&query, 1000);
+        assert_eq!(step.access, Access::UsecaseScan);
+        assert_eq!(step.estimated_documents, 1000);
+        assert_eq!(step.client_filters, vec!["email", "age"]);
+    }
+
+    #[test]
+    fn test_index_lookup_reads_the_index_instead_of_the_usecase() {
+        let scanned = SingleQuery::new("users".to_owned(), "search".to_owned());
+        assert_eq!(candidates_key(&scanned), "users:search:usecase");
+
+        let entry = IndexEntry { field: "email".to_string(), token: vec![1; 32] };
+        let indexed = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("search".to_owned())
+            .with_index_lookup(entry.clone())
+            .build();
+        let key = candidates_key(&indexed);
+        assert_eq!(key, mutation::index_key("users", &entry));
+        assert!(!key.ends_with(":usecase"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_step_exceeds_a_tight_budget() {
+        let deadline = Some(Instant::now() + Duration::from_millis(20));
+        let slow_scan = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(Vec::<KvPair>::new())
+        };
+        let started = Instant::now();
+        let result = within_deadline(deadline, slow_scan).await;
+        assert!(matches!(result, Err(Error::QueryTimeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let fast_step = async { Ok(1) };
+        assert_eq!(within_deadline(deadline, fast_step).await.unwrap(), 1);
+        let unbounded = async { Ok(2) };
+        assert_eq!(within_deadline(None, unbounded).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_step_before_reading_times_the_query_out() {
+        let deadline = Some(Instant::now() + Duration::from_millis(20));
+        // A usecase scan slower than the budget, before any document is read.
+        let slow_scan = |reading_deadline: Option<Instant>| async move {
+            assert_eq!(reading_deadline, None);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(Message::DeleteResult(true))
+        };
+        let started = Instant::now();
+        let message = answer_within(deadline, false, slow_scan).await.unwrap();
+        assert_eq!(message, Message::ErrorResponse(ServerError::QueryTimeout));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let slow_filter = |_| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(Message::DeleteResult(true))
+        };
+        let message = answer_within(deadline, true, slow_filter).await.unwrap();
+        assert_eq!(message, Message::ErrorResponse(ServerError::QueryTimeout));
+    }
+
+    #[tokio::test]
+    async fn test_partial_query_stops_reading_halfway_through_its_budget() {
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let read_until = |reading_deadline: Option<Instant>| async move {
+            let reading_deadline = reading_deadline.unwrap();
+            assert!(reading_deadline < deadline);
+            tokio::time::sleep_until(reading_deadline).await;
+            Ok(Message::PartialQueryResponse((vec![], None)))
+        };
+        let message = answer_within(Some(deadline), true, read_until).await.unwrap();
+        assert!(matches!(message, Message::PartialQueryResponse(_)));
+
+        let unbounded = |reading_deadline| async move {
+            assert_eq!(reading_deadline, None);
+            Ok(Message::DeleteResult(true))
+        };
+        let message = answer_within(None, true, unbounded).await.unwrap();
+        assert_eq!(message, Message::DeleteResult(true));
+    }
+
+    #[tokio::test]
+    async fn test_completed_stream_drops_its_cursor() {
+        let cursors = CursorStore::default();
+        let keys = (0..3).map(|index| format!("users:{}", index)).collect();
+        let owner = Some("Bob");
+        let first =
+            cursors.open_resumable(keys, 2, false, Duration::from_secs(60), owner);
+        let cursor = first.id.clone();
+        let fetch = |page: Page| async move {
+            Ok(Message::QueryPageResponse { cursor: page.cursor, page: (vec![], None) })
+        };
+        let (tx, rx) = async_channel::unbounded();
+        stream_pages(&cursors, first, tx, owner, fetch).await.unwrap();
+
+        assert_eq!(rx.len(), 2);
+        assert!(cursors.resume(&cursor, 0, owner).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_page_ends_the_stream_with_an_error() {
+        let cursors = CursorStore::default();
+        let keys = (0..3).map(|index| format!("users:{}", index)).collect();
+        let owner = Some("Bob");
+        let first =
+            cursors.open_resumable(keys, 1, false, Duration::from_secs(60), owner);
+        let cursor = first.id.clone();
+        let fetch = |page: Page| async move {
+            match page.keys.first().map(String::as_str) {
+                Some("users:1") => Err(Error::QueryTimeout),
+                _ => Ok(Message::QueryPageResponse {
+                    cursor: page.cursor,
+                    page: (vec![], None),
+                }),
+            }
+        };
+        let (tx, rx) = async_channel::unbounded();
+        let streamed = stream_pages(&cursors, first, tx, owner, fetch).await;
+
+        assert!(matches!(streamed, Err(Error::QueryTimeout)));
+        assert!(matches!(rx.recv().await, Ok(Message::QueryPageResponse { .. })));
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Message::ErrorResponse(ServerError::QueryTimeout)
+        );
+        assert!(rx.is_empty());
+        // The cursor is left for the client to resume.
+        assert!(cursors.resume(&cursor, 1, owner).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_closed_stream_sends_no_further_page() {
+        let cursors = CursorStore::default();
+        let keys = (0..5).map(|index| format!("users:{}", index)).collect();
+        let owner = Some("Bob");
+        let first = cursors.open(keys, 1, false, Duration::from_secs(60), owner);
+        let cursor = first.cursor.clone().unwrap();
+        let fetch = |page: Page| async move {
+            let documents = page.keys.into_iter().map(String::into_bytes).collect();
+            Ok(Message::QueryPageResponse {
+                cursor: page.cursor,
+                page: (documents, None),
+            })
+        };
+        // A single slot, the stream waits for each page to be read before the next one.
+        let (tx, rx) = async_channel::bounded(1);
+        let client = async {
+            let mut pages = vec![rx.recv().await.unwrap()];
+            assert!(cursors.close(&cursor, owner));
+            while let Ok(page) = rx.recv().await {
+                pages.push(page);
+            }
+            pages
+        };
+
+        let (streamed, pages) =
+            tokio::join!(stream_pages(&cursors, first, tx, owner, fetch), client);
+        streamed.unwrap();
+        let documents: Vec<Vec<Vec<u8>>> = pages
+            .iter()
+            .map(|page| match page {
+                Message::QueryPageResponse { page: (documents, None), .. } => {
+                    documents.clone()
+                }
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        // The second page was taken from the cursor before it was closed.
+        assert_eq!(
+            documents,
+            vec![vec![b"users:0".to_vec()], vec![b"users:1".to_vec()], vec![]]
+        );
+        assert!(matches!(
+            pages.last(),
+            Some(Message::QueryPageResponse { cursor: None, .. })
+        ));
     }
-    Ok(serde_cbor::from_slice::<Vec<Vec<u8>>>(
-        &values.expect("values is Some check is none before"),
-    )?
-    .iter()
-    .count() as u32)
 }
@@ -0,0 +1,128 @@
+//! Refusal of replayed requests on authenticated sessions.
+//!
+//! Once a session is authenticated, the request id of every frame is a sequence number:
+//! the client numbers its requests in increasing order, wrapping around after
+//! `u32::MAX`, and the server accepts each number once. Numbers more than
+//! `REPLAY_WINDOW` behind the highest one accepted are refused too, so the server only
+//! remembers a window of them. A frame written again is answered with
+//! `ServerError::ReplayDetected` instead of being run twice.
+//!
+//! The window belongs to the session token, not to the connection: the frames of an
+//! authenticated client are tagged under its token, see `liserk_shared::auth`, so the
+//! request id cannot be edited and a frame is only accepted from a connection holding the
+//! token. Resuming a token issues a new one, so the token of a connection is never held
+//! by another and its sequence numbers start over with the new token.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Windows of the session tokens held by the open connections.
+    pub static ref WINDOWS: ReplayWindows = ReplayWindows::default();
+}
+
+/// Number of sequence numbers behind the highest one accepted that are still accepted
+/// once, for requests arriving out of order.
+pub const REPLAY_WINDOW: u32 = 64;
+
+/// The sequence numbers accepted on a session, within the window.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    /// The highest sequence number accepted, `None` before the first.
+    highest: Option<u32>,
+
+    /// Bit `n` is set once the number `n` behind the highest one was accepted.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Accepts a sequence number seen for the first time and not too old, returning
+    /// `false` for a replayed one.
+    ///
+    /// A number is ahead of the highest one if it follows it by less than half of the
+    /// `u32` range, as the numbers wrap around.
+    pub fn accept(&mut self, sequence: u32) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.seen = 1;
+            return true;
+        };
+        let ahead = sequence.wrapping_sub(highest);
+        if ahead != 0 && ahead <= i32::MAX as u32 {
+            self.seen = if ahead < REPLAY_WINDOW { self.seen << ahead } else { 0 };
+            self.seen |= 1;
+            self.highest = Some(sequence);
+            return true;
+        }
+        let behind = highest.wrapping_sub(sequence);
+        if behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// The windows of the session tokens, by token.
+#[derive(Debug, Default)]
+pub struct ReplayWindows {
+    windows: Mutex<HashMap<String, ReplayWindow>>,
+}
+
+impl ReplayWindows {
+    /// Accepts a sequence number in the window of the token, see `ReplayWindow::accept`.
+    pub fn accept(&self, token: &str, sequence: u32) -> bool {
+        let mut windows = self.windows.lock().expect("replay windows poisoned");
+        windows.entry(token.to_string()).or_default().accept(sequence)
+    }
+
+    /// Drops the window of a token once the connection holding it closes.
+    pub fn forget(&self, token: &str) {
+        let mut windows = self.windows.lock().expect("replay windows poisoned");
+        windows.remove(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_numbers_are_accepted_once_within_the_window() {
+        let mut window = ReplayWindow::default();
+        for sequence in [1, 2, 5, 4] {
+            assert!(window.accept(sequence), "{}", sequence);
+        }
+        for sequence in [1, 2, 4, 5] {
+            assert!(!window.accept(sequence), "{}", sequence);
+        }
+        assert!(window.accept(3));
+
+        assert!(window.accept(5 + REPLAY_WINDOW));
+        assert!(!window.accept(5));
+        assert!(window.accept(6));
+    }
+
+    #[test]
+    fn test_windows_are_kept_by_token() {
+        let windows = ReplayWindows::default();
+        assert!(windows.accept("first", 1));
+        assert!(windows.accept("second", 1));
+        assert!(!windows.accept("first", 1));
+        windows.forget("first");
+        assert!(windows.accept("first", 1));
+        assert!(!windows.accept("second", 1));
+    }
+
+    #[test]
+    fn test_sequence_numbers_wrap_around() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(u32::MAX - 1));
+        assert!(window.accept(u32::MAX));
+        assert!(window.accept(0));
+        assert!(window.accept(1));
+        assert!(!window.accept(u32::MAX));
+        assert!(!window.accept(u32::MAX / 2));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use liserk_shared::{
+    auth::{frame_key, AuthMechanism},
+    compression::Compression,
+    format::Format,
+    message::InsertStreamStart,
+};
+
+use crate::replay::WINDOWS;
+
+/// State kept by the server for the lifetime of a client connection.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// The username the client authenticated as, if it did.
+    pub username: Option<String>,
+
+    /// Whether the client set the connection up, which it must do before authenticating.
+    pub set_up: bool,
+
+    /// The compression of the frame bodies, negotiated during setup.
+    pub compression: Compression,
+
+    /// The serialization of the frame bodies, negotiated during setup.
+    pub format: Format,
+
+    /// The mechanism the client must authenticate with, negotiated during setup.
+    pub auth_mechanism: AuthMechanism,
+
+    /// The user and challenge of the `AuthChallenge` last sent, until it is answered.
+    pub challenge: Option<(String, Vec<u8>)>,
+
+    /// The chunked insertions opened on the connection and not finished yet, by id.
+    pub uploads: HashMap<String, StreamUpload>,
+
+    /// Whether the client made the session read-only, refusing any mutation.
+    pub read_only: bool,
+
+    /// The session token issued on the authentication, tagging the frames of the client
+    /// and keeping their window of request ids, see `replay`.
+    pub token: Option<String>,
+
+    /// The tenant the connection is scoped to at its setup, see `tenant`.
+    pub tenant: Option<String>,
+
+    /// The secret exchanged at the setup, never sent over the wire, keying the frame
+    /// tags along with the session token, see `auth::frame_key`.
+    pub shared_secret: Option<[u8; 32]>,
+}
+
+impl Session {
+    /// Name used to attribute operations in logs.
+    pub fn user(&self) -> &str {
+        self.username.as_deref().unwrap_or("anonymous")
+    }
+
+    /// Returns the key the frames of the authenticated session are tagged under, `None`
+    /// before the authentication or without a secret exchanged at the setup.
+    pub fn frame_key(&self) -> Option<[u8; 32]> {
+        let token = self.token.as_deref()?;
+        Some(frame_key(&self.shared_secret?, token))
+    }
+
+    /// Authenticates the session as the user of a newly issued token.
+    pub fn authenticate(&mut self, username: String, token: String) {
+        self.username = Some(username);
+        if let Some(previous) = self.token.replace(token) {
+            WINDOWS.forget(&previous);
+        }
+    }
+}
+
+/// A document being inserted as a sequence of chunks.
+#[derive(Debug)]
+pub struct StreamUpload {
+    pub start: InsertStreamStart,
+
+    /// Index of the next chunk expected.
+    pub next_index: u32,
+}
@@ -0,0 +1,305 @@
+//! Scoping of connections to tenants, see `ClientSetupSecureConnection::with_tenant`.
+//!
+//! The collections of a tenant are stored under their `scoped_name`, the tenant and the
+//! collection joined by `TENANT_SEPARATOR`. The server rewrites the collection names of
+//! every request of a scoped connection to their scoped names before handling it, and
+//! strips them back from its responses, so a client of a tenant only ever reaches the
+//! collections of its tenant, whatever names it sends. Clients cannot send the separator
+//! in names, so a connection without tenant cannot name the collections of one either,
+//! and prefix queries of such connections skip them.
+//!
+//! The client proposes the tenant of its connection, but the server only authenticates
+//! a user on a connection scoped to one of the tenants the `tenants` setting assigns it,
+//! and a user assigned none only on connections without tenant, so no client reaches
+//! the collections of a tenant its user is not assigned to.
+
+use async_channel::Receiver;
+use liserk_shared::{
+    message::{CountSubject, DropSubject, Message},
+    name::{scoped_name, validate_name, InvalidName, TENANT_SEPARATOR},
+    query::Query,
+};
+
+use crate::config::SETTINGS;
+
+/// Returns whether `username` may authenticate on a connection scoped to `tenant`.
+pub fn may_authenticate(username: &str, tenant: Option<&str>) -> bool {
+    is_assigned(SETTINGS.tenants.get(username).map(Vec::as_slice), tenant)
+}
+
+/// Returns whether a connection scoped to `tenant` is allowed to a user assigned the
+/// tenants `assigned`, if any.
+fn is_assigned(assigned: Option<&[String]>, tenant: Option<&str>) -> bool {
+    match (assigned, tenant) {
+        (Some(assigned), Some(tenant)) => assigned.iter().any(|name| name == tenant),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Returns whether a collection is visible to a connection querying a collection prefix.
+///
+/// A scoped prefix only matches the collections of its tenant, and a prefix without
+/// tenant only matches the collections of no tenant.
+pub fn is_visible(collection: &str, prefix: &str) -> bool {
+    collection.starts_with(prefix)
+        && (prefix.contains(TENANT_SEPARATOR) || !collection.contains(TENANT_SEPARATOR))
+}
+
+/// Rewrites the collection names of a request to the scoped names of `tenant`, if any.
+///
+/// Names are checked with `validate_name` before, and a name containing
+/// `TENANT_SEPARATOR` is refused whatever the tenant.
+pub fn scope_request(
+    message: &mut Message,
+    tenant: Option<&str>,
+) -> Result<(), InvalidName> {
+    for collection in request_collections(message) {
+        validate_name(collection)?;
+        if collection.contains(TENANT_SEPARATOR) {
+            return Err(InvalidName::ReservedCharacter(collection.clone()));
+        }
+        if let Some(tenant) = tenant {
+            *collection = scoped_name(tenant, collection);
+        }
+    }
+    Ok(())
+}
+
+/// Strips the scoped names of `tenant` from a response back to the names of the client.
+///
+/// Audit entries of other collections than those of the tenant are dropped.
+pub fn unscope_response(message: &mut Message, tenant: &str) {
+    match message {
+        Message::PrefixQueryResponse { collections, .. } => {
+            collections.retain_mut(|collection| unscope(tenant, collection));
+        }
+        Message::ExplainResponse(plan) => {
+            for step in &mut plan.steps {
+                unscope(tenant, &mut step.collection);
+            }
+        }
+        Message::UnknownUsecase { collection, .. } => {
+            unscope(tenant, collection);
+        }
+        Message::QueryBatchResponse(responses) => {
+            for response in responses {
+                unscope_response(response, tenant);
+            }
+        }
+        Message::DocumentsResponse(documents) | Message::ScanPage { documents, .. } => {
+            for document in documents {
+                unscope(tenant, &mut document.collection);
+            }
+        }
+        Message::DocumentMetaResponse(meta) => {
+            unscope(tenant, &mut meta.collection);
+        }
+        Message::AuditLogResponse(entries) => {
+            entries.retain_mut(|entry| unscope(tenant, &mut entry.collection));
+        }
+        Message::CollectionStatsResponse(stats) => {
+            stats.retain_mut(|(collection, _)| unscope(tenant, collection));
+        }
+        _ => {}
+    }
+}
+
+/// Forwards the responses of a request, unscoped from `tenant`.
+pub fn unscoped_responses(
+    tenant: String,
+    responses: Receiver<Message>,
+) -> Receiver<Message> {
+    let (tx, rx) = async_channel::bounded(SETTINGS.response_channel_capacity.max(1));
+    tokio::spawn(async move {
+        while let Ok(mut message) = responses.recv().await {
+            unscope_response(&mut message, &tenant);
+            if tx.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Strips the scope of `tenant` from a name, returning `false` for a name of another
+/// tenant.
+fn unscope(tenant: &str, collection: &mut String) -> bool {
+    let scope = scoped_name(tenant, "");
+    match collection.strip_prefix(&scope) {
+        Some(name) => {
+            *collection = name.to_string();
+            true
+        }
+        None => false,
+    }
+}
+
+/// The collection names of a request.
+fn request_collections(message: &mut Message) -> Vec<&mut String> {
+    let mut collections = Vec::new();
+    match message {
+        Message::Insert(insertion) => collections.push(&mut insertion.collection),
+        Message::InsertBatch(insertions) => {
+            collections.extend(insertions.iter_mut().map(|i| &mut i.collection));
+        }
+        Message::InsertOpe(insertion) => collections.push(&mut insertion.collection),
+        Message::Query(query)
+        | Message::QueryDocuments(query)
+        | Message::QueryAndDelete(query)
+        | Message::Explain(query)
+        | Message::OpenCursor { query, .. }
+        | Message::StreamQuery { query, .. } => {
+            query_collections(query, &mut collections)
+        }
+        Message::QueryBatch(queries) => {
+            for query in queries {
+                query_collections(query, &mut collections);
+            }
+        }
+        Message::Count(CountSubject::Collection(collection))
+        | Message::Count(CountSubject::Usecase { collection, .. })
+        | Message::Drop(DropSubject::Collection(collection))
+        | Message::Drop(DropSubject::Usecase { collection, .. }) => {
+            collections.push(collection)
+        }
+        Message::Update(update) => collections.push(&mut update.collection),
+        Message::Delete(delete) => collections.push(&mut delete.collection),
+        Message::UpdateMetadata(update) => collections.push(&mut update.collection),
+        Message::InsertStream(start) => collections.push(&mut start.collection),
+        Message::FetchChunk(request) => collections.push(&mut request.collection),
+        Message::FetchAuditLog(filter) => collections.extend(filter.collection.as_mut()),
+        Message::DescribeDocument { collection, .. }
+        | Message::Purge { collection, .. }
+        | Message::ScanCollection { collection, .. }
+        | Message::DeleteForUsecase { collection, .. } => collections.push(collection),
+        _ => {}
+    }
+    collections
+}
+
+fn query_collections<'a>(query: &'a mut Query, collections: &mut Vec<&'a mut String>) {
+    match query {
+        Query::Single(query) => collections.push(&mut query.collection),
+        Query::Compound(query) => {
+            for query in &mut query.queries {
+                query_collections(query, collections);
+            }
+        }
+        Query::GetById { collection, .. } | Query::GetByIds { collection, .. } => {
+            collections.push(collection)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use liserk_shared::{
+        audit::{AuditEntry, AuditOperation},
+        message::StoredDocument,
+        name::MAX_NAME_LENGTH,
+        query::{CompoundQuery, QueryType, SingleQuery},
+    };
+
+    use super::*;
+
+    fn get(collection: &str) -> Query {
+        Query::GetById {
+            id: "1".to_string(),
+            collection: collection.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_users_only_reach_the_tenants_assigned_to_them() {
+        let assigned = ["acme".to_string(), "globex".to_string()];
+        assert!(is_assigned(Some(&assigned), Some("acme")));
+        assert!(is_assigned(Some(&assigned), Some("globex")));
+        assert!(!is_assigned(Some(&assigned), Some("initech")));
+        assert!(!is_assigned(Some(&assigned), None));
+        assert!(!is_assigned(None, Some("acme")));
+        assert!(is_assigned(None, None));
+    }
+
+    #[test]
+    fn test_requests_of_a_tenant_reach_its_collections_only() {
+        let single = Query::Single(SingleQuery::new("users".into(), "admins".into()));
+        let compound = CompoundQuery::new(QueryType::Or, vec![single, get("orders")]);
+        let mut message = Message::Query(Query::Compound(compound));
+        scope_request(&mut message, Some("acme")).unwrap();
+        let Message::Query(Query::Compound(compound)) = message else {
+            panic!("the query changed shape");
+        };
+        assert_eq!(compound.queries[1], get(&scoped_name("acme", "orders")));
+        let Query::Single(single) = &compound.queries[0] else {
+            panic!("the query changed shape");
+        };
+        assert_eq!(single.collection, scoped_name("acme", "users"));
+
+        let mut unscoped = Message::Query(get("users"));
+        scope_request(&mut unscoped, None).unwrap();
+        assert_eq!(unscoped, Message::Query(get("users")));
+
+        let forged = scoped_name("acme", "users");
+        for tenant in [None, Some("globex")] {
+            let mut message = Message::Query(get(&forged));
+            assert_eq!(
+                scope_request(&mut message, tenant),
+                Err(InvalidName::ReservedCharacter(forged.clone()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_names_are_checked_before_they_are_scoped() {
+        let longest = "c".repeat(MAX_NAME_LENGTH);
+        let mut message = Message::Query(get(&longest));
+        scope_request(&mut message, Some("acme")).unwrap();
+        assert_eq!(message, Message::Query(get(&scoped_name("acme", &longest))));
+
+        let mut message = Message::Query(get("users\n"));
+        assert_eq!(
+            scope_request(&mut message, Some("acme")),
+            Err(InvalidName::ControlCharacter("users\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_responses_are_unscoped_for_the_tenant() {
+        let entry = |collection: String| AuditEntry {
+            timestamp: 0,
+            username: None,
+            collection,
+            operation: AuditOperation::Insert,
+            document_id: "1".to_string(),
+        };
+        let mut entries = Message::AuditLogResponse(vec![
+            entry(scoped_name("acme", "users")),
+            entry(scoped_name("globex", "users")),
+            entry("users".to_string()),
+        ]);
+        unscope_response(&mut entries, "acme");
+        assert_eq!(entries, Message::AuditLogResponse(vec![entry("users".to_string())]));
+
+        let mut documents = Message::DocumentsResponse(vec![StoredDocument {
+            collection: scoped_name("acme", "users"),
+            id: "1".to_string(),
+            data: Vec::new(),
+            nonce: None,
+        }]);
+        unscope_response(&mut documents, "acme");
+        let Message::DocumentsResponse(documents) = documents else {
+            panic!("the response changed shape");
+        };
+        assert_eq!(documents[0].collection, "users");
+    }
+
+    #[test]
+    fn test_prefixes_only_match_collections_of_their_tenant() {
+        let scoped = scoped_name("acme", "logs_a");
+        assert!(is_visible(&scoped, &scoped_name("acme", "logs_")));
+        assert!(!is_visible(&scoped, &scoped_name("acm", "")));
+        assert!(!is_visible(&scoped, "acme"));
+        assert!(is_visible("logs_a", "logs_"));
+    }
+}
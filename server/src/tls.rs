@@ -0,0 +1,57 @@
+//! TLS of the TCP connections, see the `tls_certificate` and `tls_private_key` settings.
+//!
+//! The handshake of an accepted connection runs on the task serving it, so a slow or
+//! failed handshake holds back no other connection. Connections over a Unix domain socket
+//! are never wrapped, access to them being controlled by the permissions of the socket.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    sync::Arc,
+};
+
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+use crate::config::SETTINGS;
+
+/// Returns the acceptor running the handshake of the connections, `None` when the
+/// settings configure no TLS.
+///
+/// Fails when only one of the settings is set or when their files are not readable.
+pub fn acceptor_from_settings() -> io::Result<Option<TlsAcceptor>> {
+    let (certificate, private_key) =
+        match (&SETTINGS.tls_certificate, &SETTINGS.tls_private_key) {
+            (Some(certificate), Some(private_key)) => (certificate, private_key),
+            (None, None) => return Ok(None),
+            _ => {
+                let message = "tls_certificate and tls_private_key must be set together";
+                return Err(io::Error::new(ErrorKind::InvalidInput, message));
+            }
+        };
+    let mut certificate = BufReader::new(File::open(certificate)?);
+    let certificates = rustls_pemfile::certs(&mut certificate)?;
+    let mut private_key = BufReader::new(File::open(private_key)?);
+    let Some(private_key) = rustls_pemfile::pkcs8_private_keys(&mut private_key)?.pop()
+    else {
+        return Err(io::Error::new(ErrorKind::InvalidData, "no PKCS #8 private key"));
+    };
+    acceptor(certificates, private_key).map(Some)
+}
+
+/// Returns the acceptor presenting the DER encoded certificate chain, signed by the DER
+/// encoded PKCS #8 private key.
+pub fn acceptor(
+    certificates: Vec<Vec<u8>>,
+    private_key: Vec<u8>,
+) -> io::Result<TlsAcceptor> {
+    let certificates = certificates.into_iter().map(Certificate).collect();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, PrivateKey(private_key))
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
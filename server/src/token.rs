@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use liserk_shared::message::SessionToken;
+use uuid::Uuid;
+
+lazy_static! {
+    /// Tokens issued by this server process.
+    pub static ref TOKENS: TokenStore = TokenStore::default();
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// In-memory store of the session tokens issued on authentication.
+///
+/// Tokens are random UUIDs, they do not survive a server restart.
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    tokens: Mutex<HashMap<String, SessionToken>>,
+}
+
+impl TokenStore {
+    /// Issues a token resuming the identity of `username` until `time_to_live` elapses.
+    pub fn issue(&self, username: &str, time_to_live: Duration) -> SessionToken {
+        let session_token = SessionToken {
+            token: Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            expires_at: now() + time_to_live.as_secs(),
+        };
+        let mut tokens = self.tokens.lock().expect("token store poisoned");
+        tokens.retain(|_, issued| issued.expires_at > now());
+        tokens.insert(session_token.token.clone(), session_token.clone());
+        session_token
+    }
+
+    /// Returns the session of a token if it was issued and has not expired.
+    pub fn validate(&self, token: &str) -> Option<SessionToken> {
+        let mut tokens = self.tokens.lock().expect("token store poisoned");
+        match tokens.get(token) {
+            Some(issued) if issued.expires_at > now() => Some(issued.clone()),
+            Some(_) => {
+                tokens.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Replaces a valid token by a new one for the same user and expiry, returning it.
+    ///
+    /// A token is resumed once: the frames of a session are tagged under its token, so no
+    /// two connections hold the same one, see `replay`.
+    pub fn resume(&self, token: &str) -> Option<SessionToken> {
+        let resumed = self.validate(token)?;
+        let session_token = SessionToken { token: Uuid::new_v4().to_string(), ..resumed };
+        let mut tokens = self.tokens.lock().expect("token store poisoned");
+        tokens.remove(token)?;
+        tokens.insert(session_token.token.clone(), session_token.clone());
+        Some(session_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issued_token_is_valid() {
+        let store = TokenStore::default();
+        let issued = store.issue("Bob", Duration::from_secs(60));
+        let validated = store.validate(&issued.token).unwrap();
+        assert_eq!(validated.username, "Bob");
+        assert!(store.validate("unknown").is_none());
+    }
+
+    #[test]
+    fn test_resumed_token_is_replaced() {
+        let store = TokenStore::default();
+        let issued = store.issue("Bob", Duration::from_secs(60));
+        let resumed = store.resume(&issued.token).unwrap();
+        assert_ne!(resumed.token, issued.token);
+        assert_eq!(resumed.username, "Bob");
+        assert_eq!(resumed.expires_at, issued.expires_at);
+        assert!(store.validate(&issued.token).is_none());
+        assert!(store.resume(&issued.token).is_none());
+        assert!(store.validate(&resumed.token).is_some());
+    }
+
+    #[test]
+    fn test_expired_token_is_refused() {
+        let store = TokenStore::default();
+        let issued = store.issue("Bob", Duration::from_secs(0));
+        assert!(store.validate(&issued.token).is_none());
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use liserk_shared::message::decode_message;
+
+fuzz_target!(|bytes: &[u8]| {
+    let _ = decode_message(bytes);
+});
@@ -0,0 +1,116 @@
+//! Entries of the audit log the server keeps of the mutations of documents.
+
+use serde::{Deserialize, Serialize};
+
+/// Mutation recorded by an audit entry.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum AuditOperation {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A mutation of a document, as recorded by the server.
+///
+/// The entry holds no data of the document, only which document was changed, by whom
+/// and when.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch at which the mutation was committed.
+    pub timestamp: u64,
+
+    /// User of the session, `None` if the session was not authenticated.
+    pub username: Option<String>,
+    pub collection: String,
+    pub operation: AuditOperation,
+    pub document_id: String,
+}
+
+/// Selects entries of the audit log, every field left to `None` matches any entry.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct AuditFilter {
+    pub collection: Option<String>,
+    pub username: Option<String>,
+    pub operation: Option<AuditOperation>,
+
+    /// Only keeps entries whose timestamp is at or after this one.
+    pub since: Option<u64>,
+}
+
+impl AuditFilter {
+    pub fn with_collection(mut self, collection: String) -> Self {
+        self.collection = Some(collection);
+        self
+    }
+
+    pub fn with_username(mut self, username: String) -> Self {
+        self.username = Some(username);
+        self
+    }
+
+    pub fn with_operation(mut self, operation: AuditOperation) -> Self {
+        self.operation = Some(operation);
+        self
+    }
+
+    pub fn since(mut self, timestamp: u64) -> Self {
+        self.since = Some(timestamp);
+        self
+    }
+
+    /// Returns whether the entry is selected by the filter.
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.collection
+            .as_ref()
+            .map_or(true, |collection| &entry.collection == collection)
+            && self
+                .username
+                .as_ref()
+                .map_or(true, |username| entry.username.as_ref() == Some(username))
+            && self.operation.map_or(true, |operation| entry.operation == operation)
+            && self.since.map_or(true, |since| entry.timestamp >= since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AuditEntry {
+        AuditEntry {
+            timestamp: 1_000,
+            username: Some("alice".to_string()),
+            collection: "users".to_string(),
+            operation: AuditOperation::Insert,
+            document_id: "42".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_default_filter_matches_everything() {
+        assert!(AuditFilter::default().matches(&entry()));
+    }
+
+    #[test]
+    fn test_filter_fields_all_have_to_match() {
+        let filter = AuditFilter::default()
+            .with_collection("users".to_string())
+            .with_username("alice".to_string())
+            .with_operation(AuditOperation::Insert)
+            .since(1_000);
+        assert!(filter.matches(&entry()));
+
+        assert!(!filter.clone().with_collection("posts".to_string()).matches(&entry()));
+        assert!(!filter.clone().with_username("bob".to_string()).matches(&entry()));
+        assert!(!filter
+            .clone()
+            .with_operation(AuditOperation::Delete)
+            .matches(&entry()));
+        assert!(!filter.since(1_001).matches(&entry()));
+
+        let anonymous = AuditEntry { username: None, ..entry() };
+        assert!(!AuditFilter::default()
+            .with_username("alice".to_string())
+            .matches(&anonymous));
+    }
+}
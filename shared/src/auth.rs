@@ -0,0 +1,196 @@
+//! Mechanisms a client authenticates with, negotiated during the setup of a connection.
+//!
+//! With `Password` the client sends its credentials in a `ClientAuthentification`. With
+//! `ChallengeResponse`, meant for services sharing a key with the server, the client names
+//! itself in a `ClientChallengeAuthentification`, the server answers an `AuthChallenge`
+//! holding `CHALLENGE_LENGTH` random bytes, and the client proves it holds the key by
+//! sending back `challenge_mac` in a `ClientChallengeResponse`. The key never goes over
+//! the wire and a response is only good for the challenge it answers.
+//!
+//! Once authenticated, the client closes the body of every frame it sends with a
+//! `frame_tag`: an HMAC-SHA256 under the `frame_key` of the message type, the request id
+//! and the body. The request id is the sequence number the server checks for replays,
+//! so a frame whose id was edited, or written on another connection, is refused.
+//!
+//! The frame key is derived from the session token and from the secret of the Kyber key
+//! exchange of the setup: the client sends a public key in its `ClientSetup` and the
+//! server answers the secret encapsulated under it. The token travels in the clear on a
+//! connection without TLS, the secret never does, so a frame cannot be tagged by anyone
+//! who only read the connection. The exchange does not authenticate the server: against
+//! an attacker able to stand in for it, only TLS protects the session.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Length of the challenges sent by the server.
+pub const CHALLENGE_LENGTH: usize = 32;
+
+/// Separates the challenge responses of this protocol from other uses of the key.
+const CHALLENGE_DOMAIN: &[u8] = b"liserk challenge-response v1";
+
+/// Length of the tag closing the frames sent by an authenticated client.
+pub const FRAME_TAG_LENGTH: usize = 32;
+
+/// Separates the frame tags of this protocol from other uses of the frame key.
+const FRAME_DOMAIN: &[u8] = b"liserk frame v1";
+
+/// Separates the derivation of the frame key from other uses of the exchanged secret.
+const FRAME_KEY_DOMAIN: &[u8] = b"liserk frame key v1";
+
+/// Derives the key tagging the frames of a session from the secret of the key exchange of
+/// its connection and from its session token.
+pub fn frame_key(shared_secret: &[u8], session_token: &str) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(shared_secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(FRAME_KEY_DOMAIN);
+    mac.update(session_token.as_bytes());
+    let mut key = [0; 32];
+    key.copy_from_slice(&mac.finalize().into_bytes());
+    key
+}
+
+/// How the client proves its identity on a connection.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum AuthMechanism {
+    /// Username and password, the mechanism of clients proposing none.
+    #[default]
+    Password,
+
+    /// HMAC-SHA256 of a server challenge under a key shared with the server.
+    ChallengeResponse,
+}
+
+impl AuthMechanism {
+    /// Picks the first mechanism proposed by the client, in its order of preference.
+    ///
+    /// Every mechanism is supported, `Password` is used when the client proposes nothing.
+    /// The negotiated mechanism only says how the client goes on: the server still
+    /// refuses a user authenticating otherwise than with the mechanism configured for
+    /// it.
+    pub fn negotiate(proposed: &[AuthMechanism]) -> AuthMechanism {
+        proposed.first().copied().unwrap_or_default()
+    }
+}
+
+fn challenge_hmac(key: &[u8], username: &str, challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(CHALLENGE_DOMAIN);
+    mac.update(&(username.len() as u64).to_be_bytes());
+    mac.update(username.as_bytes());
+    mac.update(challenge);
+    mac
+}
+
+/// Computes the response of `username` to a challenge, under its pre-shared key.
+pub fn challenge_mac(key: &[u8], username: &str, challenge: &[u8]) -> Vec<u8> {
+    challenge_hmac(key, username, challenge)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Checks the response of `username` to a challenge in constant time.
+pub fn verify_challenge_mac(
+    key: &[u8],
+    username: &str,
+    challenge: &[u8],
+    mac: &[u8],
+) -> bool {
+    challenge_hmac(key, username, challenge).verify_slice(mac).is_ok()
+}
+
+fn frame_hmac(
+    frame_key: &[u8],
+    message_type: u8,
+    request_id: u32,
+    body: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(frame_key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(FRAME_DOMAIN);
+    mac.update(&[message_type]);
+    mac.update(&request_id.to_be_bytes());
+    mac.update(body);
+    mac
+}
+
+/// Computes the tag of a frame body sent under the frame key of the session.
+pub fn frame_tag(
+    frame_key: &[u8],
+    message_type: u8,
+    request_id: u32,
+    body: &[u8],
+) -> Vec<u8> {
+    frame_hmac(frame_key, message_type, request_id, body)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Splits the tag off a frame body and checks it in constant time, returning the body
+/// without its tag, or `None` if it was not tagged under the frame key.
+pub fn open_tagged_body(
+    frame_key: &[u8],
+    message_type: u8,
+    request_id: u32,
+    mut body: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let tag = body.split_off(body.len().checked_sub(FRAME_TAG_LENGTH)?);
+    frame_hmac(frame_key, message_type, request_id, &body)
+        .verify_slice(&tag)
+        .ok()?;
+    Some(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_is_bound_to_the_key_the_user_and_the_challenge() {
+        let mac = challenge_mac(b"key", "service", &[1; CHALLENGE_LENGTH]);
+        assert_eq!(mac.len(), 32);
+        assert!(verify_challenge_mac(b"key", "service", &[1; CHALLENGE_LENGTH], &mac));
+        assert!(!verify_challenge_mac(b"other", "service", &[1; CHALLENGE_LENGTH], &mac));
+        assert!(!verify_challenge_mac(b"key", "servic", &[1; CHALLENGE_LENGTH], &mac));
+        assert!(!verify_challenge_mac(b"key", "service", &[2; CHALLENGE_LENGTH], &mac));
+        assert!(!verify_challenge_mac(
+            b"key",
+            "service",
+            &[1; CHALLENGE_LENGTH],
+            &mac[1..]
+        ));
+    }
+
+    #[test]
+    fn test_frame_tag_is_bound_to_the_key_the_type_and_the_request_id() {
+        let key = frame_key(&[1; 32], "token");
+        let tag = frame_tag(&key, 4, 7, b"body");
+        assert_eq!(tag.len(), FRAME_TAG_LENGTH);
+        let tagged = [&b"body"[..], &tag].concat();
+        assert_eq!(open_tagged_body(&key, 4, 7, tagged.clone()), Some(b"body".to_vec()));
+        let other = frame_key(&[1; 32], "other");
+        assert_eq!(open_tagged_body(&other, 4, 7, tagged.clone()), None);
+        assert_eq!(open_tagged_body(&key, 5, 7, tagged.clone()), None);
+        assert_eq!(open_tagged_body(&key, 4, 8, tagged), None);
+        assert_eq!(open_tagged_body(&key, 4, 7, tag[1..].to_vec()), None);
+    }
+
+    #[test]
+    fn test_frame_key_needs_the_exchanged_secret() {
+        // The token alone, sent in the clear, does not give the key of the frames.
+        let key = frame_key(&[1; 32], "token");
+        assert_ne!(key, frame_key(&[2; 32], "token"));
+        assert_ne!(&key[..], b"token");
+        assert_eq!(key, frame_key(&[1; 32], "token"));
+    }
+
+    #[test]
+    fn test_negotiation_prefers_the_client_order() {
+        assert_eq!(AuthMechanism::negotiate(&[]), AuthMechanism::Password);
+        let proposed = [AuthMechanism::ChallengeResponse, AuthMechanism::Password];
+        assert_eq!(AuthMechanism::negotiate(&proposed), AuthMechanism::ChallengeResponse);
+    }
+}
@@ -0,0 +1,137 @@
+//! Compression of frame bodies, negotiated during the setup of a connection.
+//!
+//! A frame is a message type byte, the big endian request id, the big endian length of
+//! the body and the body, serialized in the format of the session, see `FrameHeader` and
+//! `format`. Only the body is compressed, the type and the length stay readable
+//! and the length is the one of the compressed body. Documents are encrypted by the
+//! client before being put in a message, so compression only ever sees ciphertexts
+//! and the CBOR envelope around them, never plaintext.
+
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum size of a decompressed frame body, guarding against decompression bombs.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Compression applied to the frame bodies of a session.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// Error while building or reading a frame.
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("failed to encode or decode the frame body: {0}")]
+    Serialization(#[from] serde_cbor::Error),
+
+    #[error("failed to compress or decompress the frame body: {0}")]
+    Compression(#[from] std::io::Error),
+
+    #[error("failed to encode or decode the JSON frame body: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("the frame holds {got} bytes where its header announces {expected}")]
+    Truncated { expected: usize, got: usize },
+
+    #[error("the frame header names the unknown message type {0}")]
+    UnknownMessageType(u8),
+
+    #[error("the frame header names another message type than its body")]
+    MismatchedMessageType,
+
+    #[error("received response chunk {got} where chunk {expected} was expected")]
+    InvalidChunk { expected: u32, got: u32 },
+
+    #[error("another frame was received before the last chunk of a response")]
+    InterruptedChunks,
+
+    #[error("the frame or response of {length} bytes exceeds the maximum of {max}")]
+    TooLarge { length: usize, max: usize },
+}
+
+impl Compression {
+    /// Compresses a frame body.
+    pub fn compress(self, body: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        match self {
+            Compression::None => Ok(body),
+            Compression::Zstd => Ok(zstd::stream::encode_all(body.as_slice(), 0)?),
+        }
+    }
+
+    /// Decompresses a frame body, failing if it exceeds `MAX_DECOMPRESSED_SIZE`.
+    pub fn decompress(self, body: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        match self {
+            Compression::None => Ok(body),
+            Compression::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(body.as_slice())?;
+                let mut decompressed = Vec::new();
+                decoder
+                    .take(MAX_DECOMPRESSED_SIZE + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                    return Err(FrameError::Compression(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "decompressed frame body is too large",
+                    )));
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+
+    /// Picks the first mode proposed by the client, in its order of preference.
+    ///
+    /// Every mode is supported, `None` is used when the client proposes nothing.
+    pub fn negotiate(proposed: &[Compression]) -> Compression {
+        proposed.first().copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{FrameHeader, Message};
+
+    #[test]
+    fn test_compressed_frame_round_trip() {
+        let message = Message::InsertResponse { inserted_id: "a".repeat(512) };
+        let frame = message.setup_for_network_as(7, Compression::Zstd).unwrap();
+        let header =
+            FrameHeader::from_bytes(frame[..FrameHeader::LEN].try_into().unwrap());
+        assert_eq!(header.request_id, 7);
+        assert_eq!(frame.len(), FrameHeader::LEN + header.length as usize);
+        assert!(frame.len() < message.setup_for_network().unwrap().len());
+
+        let body = frame[FrameHeader::LEN..].to_vec();
+        let decoded = Message::from_network_body(body, Compression::Zstd).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_zstd_round_trip() {
+        let body = vec![7; 4096];
+        let compressed = Compression::Zstd.compress(body.clone()).unwrap();
+        assert!(compressed.len() < body.len());
+        assert_eq!(Compression::Zstd.decompress(compressed).unwrap(), body);
+    }
+
+    #[test]
+    fn test_none_is_identity() {
+        let body = vec![1, 2, 3];
+        assert_eq!(Compression::None.compress(body.clone()).unwrap(), body);
+        assert_eq!(Compression::None.decompress(body.clone()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_negotiation_follows_client_preference() {
+        assert_eq!(Compression::negotiate(&[]), Compression::None);
+        assert_eq!(
+            Compression::negotiate(&[Compression::Zstd, Compression::None]),
+            Compression::Zstd
+        );
+    }
+}
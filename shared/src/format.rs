@@ -0,0 +1,76 @@
+//! Serialization of frame bodies, negotiated during the setup of a connection.
+//!
+//! Sessions use CBOR unless the client proposes otherwise. JSON is meant for debugging,
+//! frames can then be read by any tool, at the cost of larger bodies: byte strings are
+//! written as arrays of numbers. The setup frames themselves are always CBOR, the format
+//! applies to every frame after them, in both directions.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::compression::FrameError;
+
+/// Serialization of the frame bodies of a session.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Format {
+    #[default]
+    Cbor,
+    Json,
+}
+
+impl Format {
+    /// Serializes a frame body.
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, FrameError> {
+        match self {
+            Format::Cbor => Ok(serde_cbor::to_vec(value)?),
+            Format::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+
+    /// Deserializes a frame body.
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, FrameError> {
+        match self {
+            Format::Cbor => Ok(serde_cbor::from_slice(body)?),
+            Format::Json => Ok(serde_json::from_slice(body)?),
+        }
+    }
+
+    /// Picks the first format proposed by the client, in its order of preference.
+    ///
+    /// Every format is supported, `Cbor` is used when the client proposes nothing.
+    pub fn negotiate(proposed: &[Format]) -> Format {
+        proposed.first().copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compression::Compression, message::Message};
+
+    #[test]
+    fn test_json_frame_round_trip() {
+        let message = Message::InsertResponse { inserted_id: "42".to_string() };
+        let frame = message.setup_for_network_in(3, Compression::None, Format::Json);
+        let body = frame.unwrap()[crate::message::FrameHeader::LEN..].to_vec();
+        assert_eq!(body, br#"{"InsertResponse":{"inserted_id":"42"}}"#.to_vec());
+
+        let decoded =
+            Message::from_network_body_in(body, Compression::None, Format::Json);
+        assert_eq!(decoded.unwrap(), message);
+    }
+
+    #[test]
+    fn test_formats_decode_the_same_message() {
+        let message = Message::QueryResponse((vec![vec![1, 2]], Some(vec![vec![3; 12]])));
+        for format in [Format::Cbor, Format::Json] {
+            let body = format.encode(&message).unwrap();
+            assert_eq!(format.decode::<Message>(&body).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_negotiation_follows_client_preference() {
+        assert_eq!(Format::negotiate(&[]), Format::Cbor);
+        assert_eq!(Format::negotiate(&[Format::Json, Format::Cbor]), Format::Json);
+    }
+}
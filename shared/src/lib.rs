@@ -1,3 +1,10 @@
+pub mod audit;
+pub mod auth;
+pub mod compression;
+pub mod format;
 pub mod message;
 pub mod message_type;
+pub mod name;
+pub mod plan;
 pub mod query;
+pub mod value;
@@ -1,5 +1,23 @@
-use crate::{message_type::MessageType, query::Query};
+use crate::{
+    audit::{AuditEntry, AuditFilter},
+    auth::{frame_tag, AuthMechanism},
+    compression::{Compression, FrameError, MAX_DECOMPRESSED_SIZE},
+    format::Format,
+    message_type::MessageType,
+    name::{validate_name, InvalidName},
+    plan::QueryPlan,
+    query::{IndexEntry, Query},
+};
 use serde::{Deserialize, Serialize};
+
+/// Largest frame a peer sends or accepts, header included.
+///
+/// A response whose frame would be larger is sent as `ResponseChunk`s, see
+/// `Message::setup_for_network_chunked`.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Bytes of a `ResponseChunk` frame besides its chunk, in any format.
+const CHUNK_FRAME_OVERHEAD: usize = 128;
 ///
 /// QueryOutput is a serialized output of the query
 pub type QueryOutput = (Vec<Vec<u8>>, Option<Vec<Vec<u8>>>);
@@ -11,10 +29,48 @@ pub enum Message {
     /// The associated `ClientSetupSecureConnection` contains the necessary information for establishing the secure connection.
     ClientSetup(ClientSetupSecureConnection),
 
+    /// Sent by the server in response to `ClientSetup`.
+    /// Contains the compression applied to every following frame body of the session.
+    SetupResponse {
+        compression: Compression,
+        /// Serialization of every following frame body of the session.
+        #[serde(default)]
+        format: Format,
+        /// Requests the server handles, empty for a server predating capabilities.
+        /// Requests unknown to the client are left out.
+        #[serde(default, deserialize_with = "crate::message_type::deserialize_known")]
+        capabilities: Vec<MessageType>,
+        /// Mechanism the client must authenticate with, see `liserk_shared::auth`.
+        #[serde(default)]
+        auth_mechanism: AuthMechanism,
+        /// Kyber ciphertext of the secret the frame key is derived from, encapsulated
+        /// under the public key of the `ClientSetup`, see `auth::frame_key`.
+        #[serde(default)]
+        key_encapsulation: Vec<u8>,
+    },
+
     /// Message used for client authentication.
     /// The associated `ClientAuthentication` typically contains the credentials needed for authentication.
     ClientAuthentification(ClientAuthentication),
 
+    /// Message used to authenticate a new connection with a token issued on a previous one.
+    ClientTokenAuthentification { token: String },
+
+    /// Starts a `ChallengeResponse` authentication as the user, answered by an
+    /// `AuthChallenge`.
+    ClientChallengeAuthentification { username: String },
+
+    /// Sent by the server in response to a `ClientChallengeAuthentification`.
+    AuthChallenge { challenge: Vec<u8> },
+
+    /// Answers the last `AuthChallenge` of the connection with `auth::challenge_mac`.
+    /// Answered by an `AuthentificationResponse` if the key of the user produced it.
+    ClientChallengeResponse { mac: Vec<u8> },
+
+    /// Sent by the server once the client is authenticated.
+    /// Contains the session token which can authenticate further connections until it expires.
+    AuthentificationResponse(SessionToken),
+
     /// Used by the client to insert data into the database.
     /// The `Insertion` structure typically contains the data to be inserted along with metadata such as the collection in which the data should be stored.
     Insert(Insertion),
@@ -26,6 +82,15 @@ pub enum Message {
     /// Contains the ID of the inserted data.
     InsertResponse { inserted_id: String },
 
+    /// Inserts several documents, each in its own transaction.
+    /// Answered by an `InsertBatchResponse`.
+    InsertBatch(Vec<Insertion>),
+
+    /// Sent by the server in response to an `InsertBatch` message.
+    /// Holds the id of each inserted document, or why it was refused, in the order of the
+    /// batch. A refused document does not keep the others from being inserted.
+    InsertBatchResponse(Vec<Result<String, ServerError>>),
+
     /// Used by the client to query data from the database.
     /// The `Query` structure contains the necessary information to perform the data query.
     Query(Query),
@@ -34,6 +99,16 @@ pub enum Message {
     /// Contains the data retrieved as a result of the query.
     QueryResponse(QueryOutput),
 
+    /// Asks the server how it would run a query, without running it.
+    Explain(Query),
+
+    /// Sent by the server in response to an `Explain` message.
+    ExplainResponse(QueryPlan),
+
+    /// Sent by the server in response to a query on a collection prefix.
+    /// Contains the collections matching the prefix, whose keys decrypt the data.
+    PrefixQueryResponse { collections: Vec<String>, output: QueryOutput },
+
     /// Sent by the server in response to a query that requests a single value.
     /// Contains the requested data, or None if it doesn't exist.
     SingleValueResponse { data: Option<Vec<u8>>, nonce: Option<Vec<u8>> },
@@ -76,21 +151,167 @@ pub enum Message {
 
     /// Message requesting the termination of the communication channel.
     CloseCommunication,
+
+    /// Liveness probe, answered by the server without authentication nor storage access.
+    HealthCheck,
+
+    /// Sent by the server in response to a `HealthCheck` message.
+    HealthResponse,
+
+    /// Sent by the server when it refuses or fails to process a request.
+    ErrorResponse(ServerError),
+
+    /// Runs a query once and keeps its matching ids on the server, to be read by pages.
+    /// Answered by a `QueryPageResponse` holding the first page. The pages list the
+    /// documents by id, each once, so opening the cursor again yields the same pages.
+    OpenCursor { query: Query, page_size: u32 },
+
+    /// Requests the next page of a cursor opened with `OpenCursor`.
+    NextPage { cursor: String },
+
+    /// Opens a cursor like `OpenCursor`, but the server sends every page without waiting
+    /// for a `NextPage`, as `QueryPageResponse`s answering this request.
+    /// Sending a `CloseCursor` for the cursor of the pages stops the stream early.
+    StreamQuery { query: Query, page_size: u32 },
+
+    /// Resumes a `StreamQuery` whose connection was lost, from another connection of the
+    /// same user. The server sends the pages again from `position`, the number of keys of
+    /// the cursor in the pages the client read, `page_size` a page, or a
+    /// `ServerError::UnknownCursor` once the cursor expired or its last page was sent.
+    ResumeStream { cursor: String, position: u64 },
+
+    /// Drops a cursor before its last page, which also stops a `StreamQuery`.
+    /// A stopped stream ends with an empty page without cursor, the close is not answered.
+    CloseCursor { cursor: String },
+
+    /// A page of the results of a cursor.
+    /// `cursor` is `None` once the last page has been sent.
+    QueryPageResponse { cursor: Option<String>, page: QueryOutput },
+
+    /// Runs several independent queries in one round trip.
+    QueryBatch(Vec<Query>),
+
+    /// Sent by the server in response to a `QueryBatch` message.
+    /// Holds the response of each query, in the order of the queries of the batch.
+    QueryBatchResponse(Vec<Message>),
+
+    /// Runs a query and asks for every document along its id and collection.
+    QueryDocuments(Query),
+
+    /// Sent by the server instead of an empty `QueryResponse` to a single query asking for
+    /// `SingleQuery::warn_unknown_usecase`, when no document of the collection declares
+    /// the usecase.
+    UnknownUsecase { collection: String, usecase: String },
+
+    /// Sent by the server instead of `ServerError::QueryTimeout` to a single query asking
+    /// for `SingleQuery::allow_partial_results`, with the documents read within the time
+    /// budget of the query.
+    PartialQueryResponse(QueryOutput),
+
+    /// Physically removes the documents of a collection tombstoned at least
+    /// `older_than_ms` milliseconds ago, see `Delete::tombstone`. Only answered for an
+    /// authenticated session, by a `PurgeResult` holding the number of documents removed.
+    Purge { collection: String, older_than_ms: u64 },
+
+    /// Sent by the server in response to a `Purge` message.
+    PurgeResult(u64),
+
+    /// Makes the session of the connection read-only for its lifetime: every later
+    /// request inserting, changing or deleting documents is refused with a
+    /// `ServerError::ReadOnlySession`. The server does not answer.
+    SetReadOnly,
+
+    /// Part of a response too large for a single frame, sent in consecutive frames
+    /// answering the same request. The chunks, numbered from 0, are the serialized
+    /// response, the one flagged `last` completing it, see `ChunkedResponse`.
+    ResponseChunk { index: u32, last: bool, chunk: Vec<u8> },
+
+    /// Sent by the server in response to a `QueryDocuments` or a `QueryAndDelete` message.
+    DocumentsResponse(Vec<StoredDocument>),
+
+    /// Deletes the documents matching a query in one atomic step and asks for them.
+    /// Answered by a `DocumentsResponse` holding the deleted documents.
+    QueryAndDelete(Query),
+
+    /// Replaces the acl and the usecases of a document, leaving its data untouched.
+    /// Answered by an `UpdateResponse`.
+    UpdateMetadata(MetadataUpdate),
+
+    /// Requests the access metadata of a document, without its data.
+    /// Answered by a `DocumentMetaResponse`.
+    DescribeDocument { collection: String, id: String },
+
+    /// Sent by the server in response to a `DescribeDocument` message.
+    DocumentMetaResponse(DocumentMeta),
+
+    /// Opens the insertion of a document sent as a sequence of chunks.
+    /// Answered by an `InsertResponse` holding the id the document will be stored under.
+    InsertStream(InsertStreamStart),
+
+    /// Sends the next chunk of a document opened by `InsertStream`.
+    /// Every chunk is answered by an `InsertResponse` once stored.
+    InsertChunk(InsertChunk),
+
+    /// Requests a chunk of a document inserted by `InsertStream`.
+    FetchChunk(ChunkRequest),
+
+    /// Sent by the server in response to a `FetchChunk` message.
+    /// `None` if the document or the chunk does not exist, or may not be read.
+    ChunkResponse(Option<StoredChunk>),
+
+    /// Requests the entries of the audit log selected by the filter.
+    /// Only answered for an authenticated session.
+    FetchAuditLog(AuditFilter),
+
+    /// Sent by the server in response to a `FetchAuditLog` message.
+    /// Contains the selected entries, oldest first.
+    AuditLogResponse(Vec<AuditEntry>),
+
+    /// Reads a page of every document of a collection, whatever their usecases, in key
+    /// order. The scan resumes after the key `after`, `None` for the first page, and
+    /// reads at most `limit` keys, no more than the maximum of the server. Answered by a
+    /// `ScanPage`.
+    ScanCollection { collection: String, after: Option<String>, limit: u32 },
+
+    /// Sent by the server in response to a `ScanCollection` message.
+    /// `next` is the key to resume after, `None` once the collection is fully read.
+    ScanPage { documents: Vec<StoredDocument>, next: Option<String> },
+
+    /// Counts the documents of every collection the user may read, without reading their
+    /// data. Answered by a `CollectionStatsResponse`.
+    CollectionStats,
+
+    /// Sent by the server in response to a `CollectionStats` message.
+    /// Contains each collection with its number of documents, in name order.
+    CollectionStatsResponse(Vec<(String, u64)>),
 }
 
 impl Message {
     pub fn message_type(&self) -> MessageType {
         match self {
             Message::ClientSetup(_) => MessageType::Setup,
+            Message::SetupResponse { .. } => MessageType::SetupResponse,
             Message::ClientAuthentification(_) => MessageType::Authentification,
+            Message::ClientTokenAuthentification { .. } => {
+                MessageType::TokenAuthentification
+            }
+            Message::AuthentificationResponse(_) => MessageType::AuthentificationResponse,
+            Message::ClientChallengeAuthentification { .. } => {
+                MessageType::ChallengeAuthentification
+            }
+            Message::AuthChallenge { .. } => MessageType::AuthChallenge,
+            Message::ClientChallengeResponse { .. } => MessageType::ChallengeResponse,
             Message::Insert(_) => MessageType::Insert,
             Message::InsertOpe(_) => MessageType::InsertOpe,
             Message::InsertResponse { .. } => MessageType::InsertResponse,
             Message::Query(_) => MessageType::Query,
             Message::QueryResponse { .. } => MessageType::QueryResponse,
             Message::SingleValueResponse { .. } => MessageType::SingleValueResponse,
+            Message::PrefixQueryResponse { .. } => MessageType::PrefixQueryResponse,
+            Message::Explain(_) => MessageType::Explain,
+            Message::ExplainResponse(_) => MessageType::ExplainResponse,
             Message::Count(_) => MessageType::Count,
-            Message::CountResponse(_) => todo!(),
+            Message::CountResponse(_) => MessageType::CountResponse,
             Message::Update { .. } => MessageType::Update,
             Message::UpdateResponse { .. } => MessageType::UpdateResponse,
             Message::Delete(_) => MessageType::Delete,
@@ -100,21 +321,372 @@ impl Message {
             Message::DropResult(_) => MessageType::DropResult,
             Message::EndOfCommunication => MessageType::EndOfCommunication,
             Message::CloseCommunication => MessageType::CloseCommunication,
+            Message::HealthCheck => MessageType::HealthCheck,
+            Message::HealthResponse => MessageType::HealthResponse,
+            Message::ErrorResponse(_) => MessageType::ErrorResponse,
+            Message::OpenCursor { .. } => MessageType::OpenCursor,
+            Message::NextPage { .. } => MessageType::NextPage,
+            Message::StreamQuery { .. } => MessageType::StreamQuery,
+            Message::CloseCursor { .. } => MessageType::CloseCursor,
+            Message::ResumeStream { .. } => MessageType::ResumeStream,
+            Message::QueryPageResponse { .. } => MessageType::QueryPageResponse,
+            Message::QueryBatch(_) => MessageType::QueryBatch,
+            Message::QueryBatchResponse(_) => MessageType::QueryBatchResponse,
+            Message::QueryDocuments(_) => MessageType::QueryDocuments,
+            Message::QueryAndDelete(_) => MessageType::QueryAndDelete,
+            Message::UnknownUsecase { .. } => MessageType::UnknownUsecase,
+            Message::PartialQueryResponse(_) => MessageType::PartialQueryResponse,
+            Message::Purge { .. } => MessageType::Purge,
+            Message::PurgeResult(_) => MessageType::PurgeResult,
+            Message::ResponseChunk { .. } => MessageType::ResponseChunk,
+            Message::SetReadOnly => MessageType::SetReadOnly,
+            Message::DocumentsResponse(_) => MessageType::DocumentsResponse,
+            Message::UpdateMetadata(_) => MessageType::UpdateMetadata,
+            Message::DescribeDocument { .. } => MessageType::DescribeDocument,
+            Message::DocumentMetaResponse(_) => MessageType::DocumentMetaResponse,
+            Message::InsertStream(_) => MessageType::InsertStream,
+            Message::InsertChunk(_) => MessageType::InsertChunk,
+            Message::FetchChunk(_) => MessageType::FetchChunk,
+            Message::ChunkResponse(_) => MessageType::ChunkResponse,
+            Message::FetchAuditLog(_) => MessageType::FetchAuditLog,
+            Message::AuditLogResponse(_) => MessageType::AuditLogResponse,
+            Message::ScanCollection { .. } => MessageType::ScanCollection,
+            Message::ScanPage { .. } => MessageType::ScanPage,
+            Message::InsertBatch(_) => MessageType::InsertBatch,
+            Message::InsertBatchResponse(_) => MessageType::InsertBatchResponse,
+            Message::CollectionStats => MessageType::CollectionStats,
+            Message::CollectionStatsResponse(_) => MessageType::CollectionStatsResponse,
         }
     }
 
     pub fn setup_for_network(&self) -> Result<Vec<u8>, serde_cbor::Error> {
-        let message_type: MessageType = self.message_type();
-        let message_type: u8 = message_type as u8;
         let message = serde_cbor::to_vec(&self)?;
-        let message_length = message.len() as u32;
-        let message_length = message_length.to_be_bytes();
+        let header = FrameHeader::new(self.message_type(), 0, message.len() as u32);
+        Ok([&header.to_bytes()[..], &message].concat())
+    }
+
+    /// Builds the frame of the message, compressing its body.
+    ///
+    /// See `compression` for the layout of a compressed frame.
+    pub fn setup_for_network_with(
+        &self,
+        compression: Compression,
+    ) -> Result<Vec<u8>, FrameError> {
+        self.setup_for_network_as(0, compression)
+    }
+
+    /// Builds the frame of the message tagged with a request id, compressing its body.
+    ///
+    /// The server echoes the id of a request in the frames answering it.
+    pub fn setup_for_network_as(
+        &self,
+        request_id: u32,
+        compression: Compression,
+    ) -> Result<Vec<u8>, FrameError> {
+        self.setup_for_network_in(request_id, compression, Format::Cbor)
+    }
+
+    /// Builds the frame of the message tagged with a request id, serializing its body in
+    /// the format of the session before compressing it.
+    pub fn setup_for_network_in(
+        &self,
+        request_id: u32,
+        compression: Compression,
+        format: Format,
+    ) -> Result<Vec<u8>, FrameError> {
+        let message = compression.compress(format.encode(self)?)?;
+        let header =
+            FrameHeader::new(self.message_type(), request_id, message.len() as u32);
+        Ok([&header.to_bytes()[..], &message].concat())
+    }
+
+    /// Builds the frame of a message sent by an authenticated client, its body closed by
+    /// the tag of the request id and the body under the frame key of the session, see
+    /// `auth::frame_key`.
+    ///
+    /// The length in the header covers the tag, see `auth::open_tagged_body`.
+    pub fn setup_for_network_tagged(
+        &self,
+        request_id: u32,
+        compression: Compression,
+        format: Format,
+        frame_key: &[u8],
+    ) -> Result<Vec<u8>, FrameError> {
+        let message_type = self.message_type();
+        let mut message = compression.compress(format.encode(self)?)?;
+        let tag = frame_tag(frame_key, message_type as u8, request_id, &message);
+        message.extend_from_slice(&tag);
+        let header = FrameHeader::new(message_type, request_id, message.len() as u32);
+        Ok([&header.to_bytes()[..], &message].concat())
+    }
+
+    /// Builds the frames of the message tagged with a request id, none longer than
+    /// `max_frame_len`.
+    ///
+    /// A message fitting a frame is a single frame, otherwise its serialized body is
+    /// split into `ResponseChunk` frames, each compressed on its own.
+    pub fn setup_for_network_chunked(
+        &self,
+        request_id: u32,
+        compression: Compression,
+        format: Format,
+        max_frame_len: usize,
+    ) -> Result<Vec<Vec<u8>>, FrameError> {
+        let frame = self.setup_for_network_in(request_id, compression, format)?;
+        if frame.len() <= max_frame_len {
+            return Ok(vec![frame]);
+        }
+        // A byte of a chunk takes at most 4 bytes once serialized, in JSON.
+        let chunk_len = max_frame_len.saturating_sub(CHUNK_FRAME_OVERHEAD) / 4;
+        if chunk_len == 0 {
+            return Err(FrameError::TooLarge { length: frame.len(), max: max_frame_len });
+        }
+        let body = format.encode(self)?;
+        let count = body.len().div_ceil(chunk_len);
+        body.chunks(chunk_len)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let chunk = Message::ResponseChunk {
+                    index: index as u32,
+                    last: index + 1 == count,
+                    chunk: chunk.to_vec(),
+                };
+                chunk.setup_for_network_in(request_id, compression, format)
+            })
+            .collect()
+    }
+
+    /// Decodes the body of a frame built by `setup_for_network_with`.
+    pub fn from_network_body(
+        body: Vec<u8>,
+        compression: Compression,
+    ) -> Result<Message, FrameError> {
+        Self::from_network_body_in(body, compression, Format::Cbor)
+    }
 
-        let message_type_as_bytes = [message_type];
-        Ok([&message_type_as_bytes[..], &message_length, &message].concat())
+    /// Decodes the body of a frame built by `setup_for_network_in`.
+    pub fn from_network_body_in(
+        body: Vec<u8>,
+        compression: Compression,
+        format: Format,
+    ) -> Result<Message, FrameError> {
+        let body = compression.decompress(body)?;
+        format.decode(&body)
     }
 }
 
+/// Header read before the body of every frame.
+///
+/// It is the message type byte, the big endian request id, then the big endian length of
+/// the body that follows.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FrameHeader {
+    pub message_type: u8,
+    pub request_id: u32,
+    pub length: u32,
+}
+
+impl FrameHeader {
+    /// Size of an encoded header.
+    pub const LEN: usize = 9;
+
+    pub fn new(message_type: MessageType, request_id: u32, length: u32) -> Self {
+        Self {
+            message_type: message_type as u8,
+            request_id,
+            length,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0] = self.message_type;
+        bytes[1..5].copy_from_slice(&self.request_id.to_be_bytes());
+        bytes[5..].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::LEN]) -> Self {
+        Self {
+            message_type: bytes[0],
+            request_id: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+            length: u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]),
+        }
+    }
+}
+
+/// Decodes a whole frame, header included, whose body is neither compressed nor in
+/// another format than CBOR.
+///
+/// Never panics, whatever the bytes: a frame shorter or longer than its header announces,
+/// of an unknown message type or whose body does not decode is an error. This is the
+/// entry point of the `decode_message` fuzz target of the `shared/fuzz` crate.
+pub fn decode_message(bytes: &[u8]) -> Result<Message, FrameError> {
+    let header: &[u8; FrameHeader::LEN] = bytes
+        .get(..FrameHeader::LEN)
+        .and_then(|header| header.try_into().ok())
+        .ok_or(FrameError::Truncated { expected: FrameHeader::LEN, got: bytes.len() })?;
+    let header = FrameHeader::from_bytes(header);
+    let body = &bytes[FrameHeader::LEN..];
+    if body.len() as u64 != u64::from(header.length) {
+        let expected = (header.length as usize).saturating_add(FrameHeader::LEN);
+        return Err(FrameError::Truncated { expected, got: bytes.len() });
+    }
+    let message_type = MessageType::try_from(header.message_type)
+        .map_err(|_| FrameError::UnknownMessageType(header.message_type))?;
+    let message = Message::from_network_body(body.to_vec(), Compression::None)?;
+    if message.message_type() != message_type {
+        return Err(FrameError::MismatchedMessageType);
+    }
+    Ok(message)
+}
+
+/// Reassembles a response received as `ResponseChunk`s.
+#[derive(Debug, Default)]
+pub struct ChunkedResponse {
+    body: Vec<u8>,
+    next_index: u32,
+}
+
+impl ChunkedResponse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next chunk, returning the response once the last chunk is added.
+    ///
+    /// Fails on a chunk out of order, or once the chunks exceed `MAX_DECOMPRESSED_SIZE`.
+    pub fn push(
+        &mut self,
+        index: u32,
+        last: bool,
+        chunk: Vec<u8>,
+        format: Format,
+    ) -> Result<Option<Message>, FrameError> {
+        if index != self.next_index {
+            return Err(FrameError::InvalidChunk {
+                expected: self.next_index,
+                got: index,
+            });
+        }
+        let length = self.body.len() + chunk.len();
+        if length as u64 > MAX_DECOMPRESSED_SIZE {
+            return Err(FrameError::TooLarge {
+                length,
+                max: MAX_DECOMPRESSED_SIZE as usize,
+            });
+        }
+        self.body.extend_from_slice(&chunk);
+        self.next_index += 1;
+        if !last {
+            return Ok(None);
+        }
+        format.decode(&std::mem::take(&mut self.body)).map(Some)
+    }
+}
+
+/// Reason the server gives when it refuses or fails to process a request.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, thiserror::Error)]
+pub enum ServerError {
+    /// The encrypted document is larger than the maximum size accepted by the server.
+    #[error("the document of {size} bytes exceeds the maximum of {max_size} bytes")]
+    DocumentTooLarge { size: usize, max_size: usize },
+
+    /// The payload was not encrypted for the message carrying it.
+    #[error("the payload was not encrypted for the message carrying it")]
+    InvalidPayload,
+
+    /// The session token is unknown or expired.
+    #[error("the session token is unknown or expired")]
+    InvalidToken,
+
+    /// The cursor is unknown, exhausted or expired.
+    #[error("the cursor is unknown, exhausted or expired")]
+    UnknownCursor,
+
+    /// The chunk does not continue an insertion opened on this connection.
+    #[error("the chunk does not continue an insertion of the connection")]
+    UnknownUpload,
+
+    /// The document is not at the version a conditional update expects, but at `current`.
+    #[error("the document is at version {current}, not the expected one")]
+    VersionConflict { current: u64 },
+
+    /// The query cannot be run, for the given reason.
+    #[error("the query cannot be run: {reason}")]
+    InvalidQuery { reason: String },
+
+    /// The request needs an authenticated session.
+    #[error("the request needs an authenticated session")]
+    Unauthenticated,
+
+    /// The credentials were refused, or sent for another mechanism than the negotiated one.
+    #[error("the credentials were refused")]
+    AuthenticationFailed,
+
+    /// The connection must be set up with `ClientSetup` before authenticating.
+    #[error("the connection must be set up before authenticating")]
+    SetupRequired,
+
+    /// The access control list of the document does not allow the request.
+    #[error("the access control list of the document does not allow the request")]
+    Forbidden,
+
+    /// No document is stored under the id in the collection.
+    #[error("no document is stored under the id in the collection")]
+    DocumentNotFound,
+
+    /// A document is already stored under the id chosen for an insertion without upsert.
+    #[error("a document is already stored under the id in the collection")]
+    DuplicateId,
+
+    /// The query ran longer than the time budget of the server and was aborted.
+    #[error("the query exceeded the time budget of the server")]
+    QueryTimeout,
+
+    /// The server already serves its maximum number of connections, the connection is
+    /// closed after this response to its setup.
+    #[error("the server already serves its maximum of {max_connections} connections")]
+    TooManyConnections { max_connections: usize },
+
+    /// A collection or usecase name of the request is refused, see `validate_name`.
+    #[error("a name of the request is refused: {0}")]
+    InvalidName(InvalidName),
+
+    /// The request would change documents from a session made read-only by a
+    /// `SetReadOnly` message.
+    #[error("the session is read-only")]
+    ReadOnlySession,
+
+    /// The request id of the frame was already used on the authenticated session, or is
+    /// too far behind the latest one to be checked, so the frame may be a replay.
+    #[error("the request id was already used on the session")]
+    ReplayDetected,
+
+    /// The frame of an authenticated session was not tagged under its session token, so
+    /// it was edited or written by someone else, see `auth::open_tagged_body`.
+    #[error("the frame was not tagged under the session token")]
+    InvalidFrameTag,
+
+    /// The connection is already authenticated under another tenant than the one of the
+    /// setup.
+    #[error("the connection is scoped to another tenant")]
+    TenantMismatch,
+
+    /// The public key of the setup is not a Kyber public key, so no secret can be
+    /// exchanged to key the frame tags, see `auth::frame_key`.
+    #[error("the public key of the setup is not a Kyber public key")]
+    KeyExchangeFailed,
+
+    /// The message is not a request the server accepts, for instance a response.
+    #[error("the message is not a request the server accepts")]
+    UnexpectedMessage,
+
+    /// The server failed to process the request.
+    #[error("the server failed to process the request")]
+    Internal,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum CountSubject {
     Collection(String),
@@ -132,7 +704,18 @@ pub struct ClientSetupSecureConnection {
     protocol_version: String,
     client_public_key: Vec<u8>,
     cipher_suits: Vec<String>,
-    compression: String,
+    /// Compressions supported by the client, in its order of preference.
+    #[serde(default)]
+    compression: Vec<Compression>,
+    /// Serialization formats supported by the client, in its order of preference.
+    #[serde(default)]
+    format: Vec<Format>,
+    /// Authentication mechanisms supported by the client, in its order of preference.
+    #[serde(default)]
+    auth_mechanisms: Vec<AuthMechanism>,
+    /// Tenant the connection is scoped to, if any.
+    #[serde(default)]
+    tenant: Option<String>,
 }
 
 impl ClientSetupSecureConnection {
@@ -141,9 +724,62 @@ impl ClientSetupSecureConnection {
             protocol_version: String::from("0.1.0"),
             client_public_key: public_key,
             cipher_suits: vec![String::from("kyber768"), String::from("falcon")],
-            compression: String::from("0"),
+            compression: Vec::new(),
+            format: Vec::new(),
+            auth_mechanisms: Vec::new(),
+            tenant: None,
         }
     }
+
+    /// Kyber public key the server encapsulates the secret of the frame key under.
+    pub fn public_key(&self) -> &[u8] {
+        &self.client_public_key
+    }
+
+    /// Proposes compressions to the server, in order of preference.
+    pub fn with_compression(mut self, compression: Vec<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compressions proposed by the client, in order of preference.
+    pub fn compression(&self) -> &[Compression] {
+        &self.compression
+    }
+
+    /// Proposes serialization formats to the server, in order of preference.
+    pub fn with_format(mut self, format: Vec<Format>) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Serialization formats proposed by the client, in order of preference.
+    pub fn format(&self) -> &[Format] {
+        &self.format
+    }
+
+    /// Proposes authentication mechanisms to the server, in order of preference.
+    pub fn with_auth_mechanisms(mut self, auth_mechanisms: Vec<AuthMechanism>) -> Self {
+        self.auth_mechanisms = auth_mechanisms;
+        self
+    }
+
+    /// Authentication mechanisms proposed by the client, in order of preference.
+    pub fn auth_mechanisms(&self) -> &[AuthMechanism] {
+        &self.auth_mechanisms
+    }
+
+    /// Scopes the connection to a tenant: the collections it reads and writes are those
+    /// of the tenant, whatever their names.
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// Tenant the connection is scoped to, if any.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -152,11 +788,105 @@ pub struct ClientAuthentication {
     pub password: String,
 }
 
+/// Token issued by the server to resume an authenticated identity on another connection.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct SessionToken {
+    pub token: String,
+    pub username: String,
+    /// Expiry of the token, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// A document as stored by the server, still encrypted.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct StoredDocument {
+    pub collection: String,
+    pub id: String,
+    pub data: Vec<u8>,
+
+    /// Nonce of the document, `None` for an OPE value.
+    pub nonce: Option<Vec<u8>>,
+}
+
+/// Access metadata of a stored document, read without its encrypted data.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct DocumentMeta {
+    pub id: String,
+    pub collection: String,
+    pub acl: Vec<String>,
+    pub usecases: Vec<String>,
+    /// Time of the insertion in milliseconds since the Unix epoch, `None` for documents
+    /// inserted before insertion times were recorded.
+    pub inserted_at: Option<u64>,
+}
+
+/// New access metadata of a document.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct MetadataUpdate {
+    pub collection: String,
+    pub id: String,
+    pub acl: Vec<String>,
+    pub usecases: Vec<String>,
+}
+
+/// Metadata of a document inserted as a sequence of chunks.
+///
+/// The chunks are sent one by one with `InsertChunk` messages, the server stores each of
+/// them on its own and never holds the whole document.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct InsertStreamStart {
+    pub collection: String,
+    pub acl: Vec<String>,
+    pub usecases: Vec<String>,
+
+    /// Nonce of the stream, from which the nonce of every chunk is derived.
+    pub nonce: Vec<u8>,
+}
+
+/// A chunk of a document opened by `InsertStreamStart`.
+///
+/// Chunks are sent in order starting at index 0, the document is stored, and becomes
+/// readable, once its last chunk is.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct InsertChunk {
+    /// Id returned by the server for the `InsertStreamStart`.
+    pub id: String,
+    pub index: u32,
+    pub chunk: Vec<u8>,
+    pub last: bool,
+}
+
+/// Identifies a chunk of a document inserted as a sequence of chunks.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct ChunkRequest {
+    pub collection: String,
+    pub id: String,
+    pub index: u32,
+}
+
+/// A chunk of a document, still encrypted.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct StoredChunk {
+    /// Nonce of the stream the chunk belongs to.
+    pub nonce: Vec<u8>,
+    pub chunk: Vec<u8>,
+    pub last: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct Update {
     pub collection: String,
     pub id: String,
     pub new_value: Vec<u8>,
+    pub nonce: Vec<u8>,
+    /// Index tokens of the new value, replacing the ones of the previous value.
+    #[serde(default)]
+    pub index: Vec<IndexEntry>,
+    /// Version the document must be at for the update to apply, any version if `None`.
+    ///
+    /// A document is at version 0 when inserted, and every update bumps its version.
+    #[serde(default)]
+    pub expected_version: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -173,6 +903,140 @@ pub struct Insertion {
     pub data: Vec<u8>,
     pub usecases: Vec<String>,
     pub nonce: Vec<u8>,
+    /// Index tokens of the fields the document can be looked up by.
+    #[serde(default)]
+    pub index: Vec<IndexEntry>,
+    /// Id chosen by the client for the document, the server generating one if `None`.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Whether a document already stored under `id` is replaced, instead of the insertion
+    /// being refused with `ServerError::DuplicateId`.
+    #[serde(default)]
+    pub upsert: bool,
+}
+
+/// A document to insert as built by `InsertionBuilder`, its data still in plaintext.
+///
+/// Not a message: the client encrypts it into the `Insertion` sent to the server, see
+/// `AuthenticatedClient::insert_built`, so plaintext never travels in a wire type.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PlainInsertion {
+    pub collection: String,
+    pub acl: Vec<String>,
+    /// The plaintext document.
+    pub data: Vec<u8>,
+    pub usecases: Vec<String>,
+    /// Index tokens of the fields the document can be looked up by.
+    pub index: Vec<IndexEntry>,
+    /// Id chosen by the client for the document, the server generating one if `None`.
+    pub id: Option<String>,
+    /// Whether a document already stored under `id` is replaced, see `Insertion::upsert`.
+    pub upsert: bool,
+}
+
+impl PlainInsertion {
+    /// Starts building an insertion, see `InsertionBuilder`.
+    pub fn builder() -> InsertionBuilder {
+        InsertionBuilder::default()
+    }
+}
+
+/// Error while building a `PlainInsertion`.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InsertionError {
+    #[error("the collection of the insertion is missing or empty")]
+    MissingCollection,
+
+    #[error("the collection {0:?} contains ':', which separates the keys of documents")]
+    InvalidCollection(String),
+
+    #[error("the data of the insertion is missing")]
+    MissingData,
+
+    #[error("usecases cannot be empty")]
+    EmptyUsecase,
+
+    #[error("a name of the insertion is refused: {0}")]
+    InvalidName(#[from] InvalidName),
+}
+
+/// Builds a `PlainInsertion`, checking it before it is encrypted and sent.
+#[derive(Debug, Default, Clone)]
+pub struct InsertionBuilder {
+    collection: Option<String>,
+    data: Option<Vec<u8>>,
+    acl: Vec<String>,
+    usecases: Vec<String>,
+    index: Vec<IndexEntry>,
+    id: Option<String>,
+    upsert: bool,
+}
+
+impl InsertionBuilder {
+    pub fn collection(mut self, collection: impl Into<String>) -> Self {
+        self.collection = Some(collection.into());
+        self
+    }
+
+    pub fn data(mut self, data: Vec<u8>) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Adds an entry to the access control list.
+    pub fn acl(mut self, entry: impl Into<String>) -> Self {
+        self.acl.push(entry.into());
+        self
+    }
+
+    /// Adds a usecase the document can be queried by.
+    pub fn usecase(mut self, usecase: impl Into<String>) -> Self {
+        self.usecases.push(usecase.into());
+        self
+    }
+
+    /// Adds the index tokens of the fields the document can be looked up by.
+    pub fn index(mut self, index: Vec<IndexEntry>) -> Self {
+        self.index.extend(index);
+        self
+    }
+
+    /// Stores the document under this id instead of one generated by the server.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Replaces the document already stored under the id, if any.
+    pub fn upsert(mut self, upsert: bool) -> Self {
+        self.upsert = upsert;
+        self
+    }
+
+    /// Checks the insertion and builds it.
+    pub fn build(self) -> Result<PlainInsertion, InsertionError> {
+        let collection = match self.collection {
+            Some(collection) if !collection.is_empty() => collection,
+            _ => return Err(InsertionError::MissingCollection),
+        };
+        if collection.contains(':') {
+            return Err(InsertionError::InvalidCollection(collection));
+        }
+        let data = self.data.ok_or(InsertionError::MissingData)?;
+        if self.usecases.iter().any(String::is_empty) {
+            return Err(InsertionError::EmptyUsecase);
+        }
+        validate_insertion_names(&collection, &self.usecases)?;
+        Ok(PlainInsertion {
+            collection,
+            acl: self.acl,
+            data,
+            usecases: self.usecases,
+            index: self.index,
+            id: self.id,
+            upsert: self.upsert,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -183,8 +1047,200 @@ pub struct InsertionOpe {
     pub usecases: Vec<String>,
 }
 
+/// Checks the collection and the usecases of an insertion, see `validate_name`.
+pub fn validate_insertion_names(
+    collection: &str,
+    usecases: &[String],
+) -> Result<(), InvalidName> {
+    validate_name(collection)?;
+    usecases.iter().try_for_each(|usecase| validate_name(usecase))
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct Delete {
     pub collection: String,
     pub id: String,
+    /// Marks the document as deleted instead of removing it, keeping it out of queries
+    /// until a `Purge` removes it.
+    #[serde(default)]
+    pub tombstone: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_cbor::Value;
+
+    use super::*;
+
+    #[test]
+    fn test_large_response_is_chunked_within_the_frame_limit() {
+        let data = (0..=255).cycle().take(10_000).collect();
+        let message = Message::SingleValueResponse { data: Some(data), nonce: None };
+        for (compression, format) in
+            [(Compression::None, Format::Cbor), (Compression::Zstd, Format::Json)]
+        {
+            let frames = message
+                .setup_for_network_chunked(5, compression, format, 1024)
+                .unwrap();
+            assert!(frames.len() > 1);
+            let mut response = ChunkedResponse::new();
+            let mut reassembled = None;
+            for frame in frames {
+                assert!(frame.len() <= 1024);
+                let header = FrameHeader::from_bytes(
+                    frame[..FrameHeader::LEN].try_into().unwrap(),
+                );
+                assert_eq!(header.request_id, 5);
+                let body = frame[FrameHeader::LEN..].to_vec();
+                let chunk = Message::from_network_body_in(body, compression, format);
+                let Ok(Message::ResponseChunk { index, last, chunk }) = chunk else {
+                    panic!("unexpected frame {:?}", chunk);
+                };
+                assert!(reassembled.is_none());
+                reassembled = response.push(index, last, chunk, format).unwrap();
+            }
+            assert_eq!(reassembled, Some(message.clone()));
+        }
+
+        let small = Message::PurgeResult(3);
+        let frames = small
+            .setup_for_network_chunked(5, Compression::None, Format::Cbor, 1024)
+            .unwrap();
+        assert_eq!(
+            frames,
+            vec![small.setup_for_network_as(5, Compression::None).unwrap()]
+        );
+        let mut response = ChunkedResponse::new();
+        let err = response.push(1, true, vec![], Format::Cbor).unwrap_err();
+        assert!(matches!(err, FrameError::InvalidChunk { expected: 0, got: 1 }));
+    }
+
+    #[test]
+    fn test_insertion_builder_builds_a_valid_insertion() {
+        let insertion = PlainInsertion::builder()
+            .collection("users")
+            .data(vec![1, 2, 3])
+            .acl("read:alice")
+            .usecase("profile")
+            .usecase("billing")
+            .build()
+            .unwrap();
+        assert_eq!(insertion.collection, "users");
+        assert_eq!(insertion.data, vec![1, 2, 3]);
+        assert_eq!(insertion.acl, vec!["read:alice".to_string()]);
+        assert_eq!(
+            insertion.usecases,
+            vec!["profile".to_string(), "billing".to_string()]
+        );
+        assert_eq!(insertion.id, None);
+    }
+
+    #[test]
+    fn test_insertion_builder_refuses_a_missing_collection() {
+        let builder = PlainInsertion::builder().data(vec![1]);
+        assert_eq!(builder.clone().build(), Err(InsertionError::MissingCollection));
+        assert_eq!(
+            builder.clone().collection("").build(),
+            Err(InsertionError::MissingCollection)
+        );
+        assert_eq!(
+            builder.clone().collection("a:b").build(),
+            Err(InsertionError::InvalidCollection("a:b".to_string()))
+        );
+        assert_eq!(
+            PlainInsertion::builder().collection("users").build(),
+            Err(InsertionError::MissingData)
+        );
+        assert_eq!(
+            builder.clone().collection("users").usecase("").build(),
+            Err(InsertionError::EmptyUsecase)
+        );
+        assert_eq!(
+            builder.collection("users").usecase("\n").build(),
+            Err(InsertionError::InvalidName(InvalidName::ControlCharacter(
+                "\n".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_decode_message_round_trips_a_frame() {
+        let message = Message::DeleteResult(true);
+        let frame = message.setup_for_network().unwrap();
+        assert_eq!(decode_message(&frame).unwrap(), message);
+    }
+
+    #[test]
+    fn test_count_response_is_framed() {
+        let message = Message::CountResponse(7);
+        assert_eq!(message.message_type(), MessageType::CountResponse);
+        let frame = message.setup_for_network().unwrap();
+        assert_eq!(frame[0], MessageType::CountResponse as u8);
+        assert_eq!(decode_message(&frame).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_message_refuses_crashing_inputs() {
+        let frame = Message::HealthCheck.setup_for_network().unwrap();
+        let mut unknown_type = frame.clone();
+        unknown_type[0] = 255;
+        let mut mismatched_type = frame.clone();
+        mismatched_type[0] = MessageType::Insert as u8;
+        let mut long_header = frame.clone();
+        long_header[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        let garbage_body =
+            [&FrameHeader::new(MessageType::Query, 0, 3).to_bytes()[..], &[0xff; 3]]
+                .concat();
+        let trailing_byte = [&frame[..], &[0]].concat();
+
+        let inputs: [&[u8]; 8] = [
+            &[],
+            &frame[..FrameHeader::LEN - 1],
+            &frame[..frame.len() - 1],
+            &trailing_byte,
+            &unknown_type,
+            &mismatched_type,
+            &long_header,
+            &garbage_body,
+        ];
+        for input in inputs {
+            assert!(decode_message(input).is_err(), "decoded {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_unknown_capabilities_are_left_out() {
+        let setup = Message::SetupResponse {
+            compression: Compression::None,
+            format: Format::Cbor,
+            capabilities: vec![MessageType::Insert, MessageType::Delete],
+            auth_mechanism: AuthMechanism::default(),
+            key_encapsulation: Vec::new(),
+        };
+        let mut value = serde_cbor::value::to_value(&setup).unwrap();
+        let Value::Map(message) = &mut value else { panic!("not a map") };
+        let Some(Value::Map(fields)) = message.values_mut().next() else {
+            panic!("not a map")
+        };
+        let Some(Value::Array(capabilities)) =
+            fields.get_mut(&Value::Text("capabilities".to_string()))
+        else {
+            panic!("no capabilities")
+        };
+        capabilities.insert(1, Value::Text("FutureRequest".to_string()));
+
+        let bytes = serde_cbor::to_vec(&value).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Message>(&bytes).unwrap(), setup);
+    }
+
+    #[test]
+    fn test_unknown_message_type_name_is_an_error() {
+        let name = serde_cbor::to_vec("NotAMessageType").unwrap();
+        assert!(serde_cbor::from_slice::<MessageType>(&name).is_err());
+        let name = serde_cbor::to_vec("DropResult").unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<MessageType>(&name).unwrap(),
+            MessageType::DropResult
+        );
+    }
 }
@@ -1,29 +1,77 @@
 use std::fmt::Display;
 
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{de::value::StringDeserializer, Deserialize, Deserializer, Serialize};
 use tracing::debug;
 
-#[derive(Debug, Serialize)]
+/// Type of a message, sent as the first byte of every frame.
+///
+/// Discriminants are the values on the wire and must stay in sync with `TryFrom<u8>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[repr(u8)]
 pub enum MessageType {
-    Setup,
-    Authentification,
-    Insert,
-    InsertOpe,
-    InsertResponse,
-    Query,
-    QueryResponse,
-    SingleValueResponse,
-    Count,
-    Update,
-    UpdateResponse,
-    Delete,
-    DeleteResult,
-    DeleteForUsecase,
-    Drop,
-    DropResult,
-    EndOfCommunication,
-    CloseCommunication,
+    Setup = 0,
+    Authentification = 1,
+    Insert = 2,
+    InsertOpe = 17,
+    InsertResponse = 3,
+    Query = 4,
+    QueryResponse = 5,
+    SingleValueResponse = 6,
+    Count = 7,
+    Update = 8,
+    UpdateResponse = 9,
+    Delete = 10,
+    DeleteResult = 11,
+    DeleteForUsecase = 12,
+    Drop = 13,
+    DropResult = 14,
+    EndOfCommunication = 15,
+    CloseCommunication = 16,
+    HealthCheck = 18,
+    HealthResponse = 19,
+    ErrorResponse = 20,
+    TokenAuthentification = 21,
+    AuthentificationResponse = 22,
+    OpenCursor = 23,
+    NextPage = 24,
+    QueryPageResponse = 25,
+    SetupResponse = 26,
+    PrefixQueryResponse = 27,
+    Explain = 28,
+    ExplainResponse = 29,
+    FetchAuditLog = 30,
+    AuditLogResponse = 31,
+    QueryBatch = 32,
+    QueryBatchResponse = 33,
+    QueryDocuments = 34,
+    DocumentsResponse = 35,
+    InsertStream = 36,
+    InsertChunk = 37,
+    FetchChunk = 38,
+    ChunkResponse = 39,
+    UpdateMetadata = 40,
+    ScanCollection = 41,
+    ScanPage = 42,
+    InsertBatch = 43,
+    InsertBatchResponse = 44,
+    StreamQuery = 45,
+    CloseCursor = 46,
+    ChallengeAuthentification = 47,
+    AuthChallenge = 48,
+    ChallengeResponse = 49,
+    DescribeDocument = 50,
+    DocumentMetaResponse = 51,
+    QueryAndDelete = 52,
+    UnknownUsecase = 53,
+    PartialQueryResponse = 54,
+    Purge = 55,
+    PurgeResult = 56,
+    ResumeStream = 57,
+    ResponseChunk = 58,
+    SetReadOnly = 59,
+    CollectionStats = 60,
+    CollectionStatsResponse = 61,
+    CountResponse = 62,
 }
 
 impl Display for MessageType {
@@ -37,7 +85,7 @@ impl Display for MessageType {
             MessageType::Query => write!(f, "Query"),
             MessageType::QueryResponse => write!(f, "QueryResponse"),
             MessageType::SingleValueResponse => write!(f, "SingleValueResponse"),
-            MessageType::Count => todo!(),
+            MessageType::Count => write!(f, "Count"),
             MessageType::Update => write!(f, "Update"),
             MessageType::UpdateResponse => write!(f, "UpdateResponse"),
             MessageType::Delete => write!(f, "Delete"),
@@ -47,6 +95,55 @@ impl Display for MessageType {
             MessageType::DropResult => write!(f, "DropResult"),
             MessageType::EndOfCommunication => write!(f, "EndOfCommunication"),
             MessageType::CloseCommunication => write!(f, "CloseCommunication"),
+            MessageType::HealthCheck => write!(f, "HealthCheck"),
+            MessageType::HealthResponse => write!(f, "HealthResponse"),
+            MessageType::ErrorResponse => write!(f, "ErrorResponse"),
+            MessageType::TokenAuthentification => write!(f, "TokenAuthentification"),
+            MessageType::AuthentificationResponse => {
+                write!(f, "AuthentificationResponse")
+            }
+            MessageType::OpenCursor => write!(f, "OpenCursor"),
+            MessageType::NextPage => write!(f, "NextPage"),
+            MessageType::QueryPageResponse => write!(f, "QueryPageResponse"),
+            MessageType::SetupResponse => write!(f, "SetupResponse"),
+            MessageType::PrefixQueryResponse => write!(f, "PrefixQueryResponse"),
+            MessageType::Explain => write!(f, "Explain"),
+            MessageType::ExplainResponse => write!(f, "ExplainResponse"),
+            MessageType::FetchAuditLog => write!(f, "FetchAuditLog"),
+            MessageType::AuditLogResponse => write!(f, "AuditLogResponse"),
+            MessageType::QueryBatch => write!(f, "QueryBatch"),
+            MessageType::QueryBatchResponse => write!(f, "QueryBatchResponse"),
+            MessageType::QueryDocuments => write!(f, "QueryDocuments"),
+            MessageType::DocumentsResponse => write!(f, "DocumentsResponse"),
+            MessageType::InsertStream => write!(f, "InsertStream"),
+            MessageType::InsertChunk => write!(f, "InsertChunk"),
+            MessageType::FetchChunk => write!(f, "FetchChunk"),
+            MessageType::ChunkResponse => write!(f, "ChunkResponse"),
+            MessageType::UpdateMetadata => write!(f, "UpdateMetadata"),
+            MessageType::ScanCollection => write!(f, "ScanCollection"),
+            MessageType::ScanPage => write!(f, "ScanPage"),
+            MessageType::InsertBatch => write!(f, "InsertBatch"),
+            MessageType::InsertBatchResponse => write!(f, "InsertBatchResponse"),
+            MessageType::StreamQuery => write!(f, "StreamQuery"),
+            MessageType::CloseCursor => write!(f, "CloseCursor"),
+            MessageType::ChallengeAuthentification => {
+                write!(f, "ChallengeAuthentification")
+            }
+            MessageType::AuthChallenge => write!(f, "AuthChallenge"),
+            MessageType::ChallengeResponse => write!(f, "ChallengeResponse"),
+            MessageType::DescribeDocument => write!(f, "DescribeDocument"),
+            MessageType::DocumentMetaResponse => write!(f, "DocumentMetaResponse"),
+            MessageType::QueryAndDelete => write!(f, "QueryAndDelete"),
+            MessageType::UnknownUsecase => write!(f, "UnknownUsecase"),
+            MessageType::PartialQueryResponse => write!(f, "PartialQueryResponse"),
+            MessageType::Purge => write!(f, "Purge"),
+            MessageType::PurgeResult => write!(f, "PurgeResult"),
+            MessageType::ResumeStream => write!(f, "ResumeStream"),
+            MessageType::ResponseChunk => write!(f, "ResponseChunk"),
+            MessageType::SetReadOnly => write!(f, "SetReadOnly"),
+            MessageType::CollectionStats => write!(f, "CollectionStats"),
+            MessageType::CollectionStatsResponse => write!(f, "CollectionStatsResponse"),
+            MessageType::CountResponse => write!(f, "CountResponse"),
         }
     }
 }
@@ -119,7 +216,7 @@ impl<'de> Deserialize<'de> for MessageType {
             return Ok(MessageType::Drop);
         }
 
-        if s == "DeleteResult" {
+        if s == "DropResult" {
             return Ok(MessageType::DropResult);
         }
 
@@ -130,10 +227,206 @@ impl<'de> Deserialize<'de> for MessageType {
         if s == "CloseCommunication" {
             return Ok(MessageType::CloseCommunication);
         }
-        panic!("panic deserialize message type");
+
+        if s == "HealthCheck" {
+            return Ok(MessageType::HealthCheck);
+        }
+
+        if s == "HealthResponse" {
+            return Ok(MessageType::HealthResponse);
+        }
+
+        if s == "ErrorResponse" {
+            return Ok(MessageType::ErrorResponse);
+        }
+
+        if s == "TokenAuthentification" {
+            return Ok(MessageType::TokenAuthentification);
+        }
+
+        if s == "AuthentificationResponse" {
+            return Ok(MessageType::AuthentificationResponse);
+        }
+
+        if s == "OpenCursor" {
+            return Ok(MessageType::OpenCursor);
+        }
+
+        if s == "NextPage" {
+            return Ok(MessageType::NextPage);
+        }
+
+        if s == "QueryPageResponse" {
+            return Ok(MessageType::QueryPageResponse);
+        }
+
+        if s == "SetupResponse" {
+            return Ok(MessageType::SetupResponse);
+        }
+
+        if s == "PrefixQueryResponse" {
+            return Ok(MessageType::PrefixQueryResponse);
+        }
+
+        if s == "Explain" {
+            return Ok(MessageType::Explain);
+        }
+
+        if s == "ExplainResponse" {
+            return Ok(MessageType::ExplainResponse);
+        }
+
+        if s == "FetchAuditLog" {
+            return Ok(MessageType::FetchAuditLog);
+        }
+
+        if s == "AuditLogResponse" {
+            return Ok(MessageType::AuditLogResponse);
+        }
+
+        if s == "QueryBatch" {
+            return Ok(MessageType::QueryBatch);
+        }
+
+        if s == "QueryBatchResponse" {
+            return Ok(MessageType::QueryBatchResponse);
+        }
+
+        if s == "QueryDocuments" {
+            return Ok(MessageType::QueryDocuments);
+        }
+
+        if s == "DocumentsResponse" {
+            return Ok(MessageType::DocumentsResponse);
+        }
+
+        if s == "InsertStream" {
+            return Ok(MessageType::InsertStream);
+        }
+
+        if s == "InsertChunk" {
+            return Ok(MessageType::InsertChunk);
+        }
+
+        if s == "FetchChunk" {
+            return Ok(MessageType::FetchChunk);
+        }
+
+        if s == "ChunkResponse" {
+            return Ok(MessageType::ChunkResponse);
+        }
+
+        if s == "UpdateMetadata" {
+            return Ok(MessageType::UpdateMetadata);
+        }
+
+        if s == "ScanCollection" {
+            return Ok(MessageType::ScanCollection);
+        }
+
+        if s == "ScanPage" {
+            return Ok(MessageType::ScanPage);
+        }
+
+        if s == "InsertBatch" {
+            return Ok(MessageType::InsertBatch);
+        }
+
+        if s == "InsertBatchResponse" {
+            return Ok(MessageType::InsertBatchResponse);
+        }
+
+        if s == "StreamQuery" {
+            return Ok(MessageType::StreamQuery);
+        }
+
+        if s == "CloseCursor" {
+            return Ok(MessageType::CloseCursor);
+        }
+
+        if s == "ChallengeAuthentification" {
+            return Ok(MessageType::ChallengeAuthentification);
+        }
+
+        if s == "AuthChallenge" {
+            return Ok(MessageType::AuthChallenge);
+        }
+
+        if s == "ChallengeResponse" {
+            return Ok(MessageType::ChallengeResponse);
+        }
+
+        if s == "DescribeDocument" {
+            return Ok(MessageType::DescribeDocument);
+        }
+
+        if s == "DocumentMetaResponse" {
+            return Ok(MessageType::DocumentMetaResponse);
+        }
+
+        if s == "QueryAndDelete" {
+            return Ok(MessageType::QueryAndDelete);
+        }
+
+        if s == "UnknownUsecase" {
+            return Ok(MessageType::UnknownUsecase);
+        }
+
+        if s == "PartialQueryResponse" {
+            return Ok(MessageType::PartialQueryResponse);
+        }
+
+        if s == "Purge" {
+            return Ok(MessageType::Purge);
+        }
+
+        if s == "PurgeResult" {
+            return Ok(MessageType::PurgeResult);
+        }
+
+        if s == "ResumeStream" {
+            return Ok(MessageType::ResumeStream);
+        }
+
+        if s == "ResponseChunk" {
+            return Ok(MessageType::ResponseChunk);
+        }
+
+        if s == "SetReadOnly" {
+            return Ok(MessageType::SetReadOnly);
+        }
+
+        if s == "CollectionStats" {
+            return Ok(MessageType::CollectionStats);
+        }
+
+        if s == "CollectionStatsResponse" {
+            return Ok(MessageType::CollectionStatsResponse);
+        }
+
+        if s == "CountResponse" {
+            return Ok(MessageType::CountResponse);
+        }
+        Err(serde::de::Error::custom(format!("unknown message type {:?}", s)))
     }
 }
 
+/// Deserializes a list of message types, leaving out the names this version does not
+/// know, so that a peer announcing newer message types is still understood.
+pub fn deserialize_known<'de, D>(deserializer: D) -> Result<Vec<MessageType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names = Vec::<String>::deserialize(deserializer)?;
+    Ok(names
+        .into_iter()
+        .filter_map(|name| {
+            let name = StringDeserializer::<serde::de::value::Error>::new(name);
+            MessageType::deserialize(name).ok()
+        })
+        .collect())
+}
+
 #[derive(Debug, Default, thiserror::Error)]
 #[error("fail to parse MessageType")]
 pub struct MessageTypeError {}
@@ -161,6 +454,51 @@ impl TryFrom<u8> for MessageType {
             15 => Ok(MessageType::EndOfCommunication),
             16 => Ok(MessageType::CloseCommunication),
             17 => Ok(MessageType::InsertOpe),
+            18 => Ok(MessageType::HealthCheck),
+            19 => Ok(MessageType::HealthResponse),
+            20 => Ok(MessageType::ErrorResponse),
+            21 => Ok(MessageType::TokenAuthentification),
+            22 => Ok(MessageType::AuthentificationResponse),
+            23 => Ok(MessageType::OpenCursor),
+            24 => Ok(MessageType::NextPage),
+            25 => Ok(MessageType::QueryPageResponse),
+            26 => Ok(MessageType::SetupResponse),
+            27 => Ok(MessageType::PrefixQueryResponse),
+            28 => Ok(MessageType::Explain),
+            29 => Ok(MessageType::ExplainResponse),
+            30 => Ok(MessageType::FetchAuditLog),
+            31 => Ok(MessageType::AuditLogResponse),
+            32 => Ok(MessageType::QueryBatch),
+            33 => Ok(MessageType::QueryBatchResponse),
+            34 => Ok(MessageType::QueryDocuments),
+            35 => Ok(MessageType::DocumentsResponse),
+            36 => Ok(MessageType::InsertStream),
+            37 => Ok(MessageType::InsertChunk),
+            38 => Ok(MessageType::FetchChunk),
+            39 => Ok(MessageType::ChunkResponse),
+            40 => Ok(MessageType::UpdateMetadata),
+            41 => Ok(MessageType::ScanCollection),
+            42 => Ok(MessageType::ScanPage),
+            43 => Ok(MessageType::InsertBatch),
+            44 => Ok(MessageType::InsertBatchResponse),
+            45 => Ok(MessageType::StreamQuery),
+            46 => Ok(MessageType::CloseCursor),
+            47 => Ok(MessageType::ChallengeAuthentification),
+            48 => Ok(MessageType::AuthChallenge),
+            49 => Ok(MessageType::ChallengeResponse),
+            50 => Ok(MessageType::DescribeDocument),
+            51 => Ok(MessageType::DocumentMetaResponse),
+            52 => Ok(MessageType::QueryAndDelete),
+            53 => Ok(MessageType::UnknownUsecase),
+            54 => Ok(MessageType::PartialQueryResponse),
+            55 => Ok(MessageType::Purge),
+            56 => Ok(MessageType::PurgeResult),
+            57 => Ok(MessageType::ResumeStream),
+            58 => Ok(MessageType::ResponseChunk),
+            59 => Ok(MessageType::SetReadOnly),
+            60 => Ok(MessageType::CollectionStats),
+            61 => Ok(MessageType::CollectionStatsResponse),
+            62 => Ok(MessageType::CountResponse),
             _ => Err(MessageTypeError::default()),
         }
     }
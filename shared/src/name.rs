@@ -0,0 +1,136 @@
+//! Checks of the collection and usecase names, and of the document ids chosen by clients,
+//! sent over the wire.
+//!
+//! Names end up in the keys documents are stored under and in the logs of the server, so
+//! control characters, which could forge log lines or break key parsing, are refused
+//! along with names too long to be keys. `:` separates the parts of the keys and is
+//! refused too, a name holding it forging the keys of another collection.
+//! `TENANT_SEPARATOR` is reserved for the names the server stores the collections of
+//! tenants under, see `scoped_name`.
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of a collection or usecase name, in bytes of its UTF-8 encoding.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Separates the tenant from the collection in the name the server stores a collection
+/// of a tenant under. Clients cannot send it in collection names.
+pub const TENANT_SEPARATOR: char = '\u{241f}';
+
+/// A collection or usecase name refused by `validate_name`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, thiserror::Error)]
+pub enum InvalidName {
+    #[error("the name is {length} bytes long, the maximum is {MAX_NAME_LENGTH}")]
+    TooLong { length: usize },
+
+    #[error("the name {0:?} contains a control character")]
+    ControlCharacter(String),
+
+    #[error("the document id {0:?} is empty or contains ':'")]
+    InvalidId(String),
+
+    #[error("the name {0:?} contains ':' or the reserved {TENANT_SEPARATOR:?}")]
+    ReservedCharacter(String),
+}
+
+/// Checks that a collection or usecase name has no control character nor `:` and is at
+/// most `MAX_NAME_LENGTH` bytes long.
+pub fn validate_name(name: &str) -> Result<(), InvalidName> {
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(InvalidName::TooLong { length: name.len() });
+    }
+    if name.chars().any(char::is_control) {
+        return Err(InvalidName::ControlCharacter(name.to_string()));
+    }
+    if name.contains(':') {
+        return Err(InvalidName::ReservedCharacter(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks a tenant name like a name, also refusing an empty one and one containing
+/// `TENANT_SEPARATOR` or `:`, the keys of the collections of a tenant `x:` otherwise
+/// falling within the range of those of the collection `x`.
+pub fn validate_tenant(tenant: &str) -> Result<(), InvalidName> {
+    if tenant.is_empty() || tenant.contains(TENANT_SEPARATOR) || tenant.contains(':') {
+        return Err(InvalidName::ReservedCharacter(tenant.to_string()));
+    }
+    validate_name(tenant)
+}
+
+/// Returns the name the server stores the collection of a tenant under.
+pub fn scoped_name(tenant: &str, collection: &str) -> String {
+    format!("{}{}{}", tenant, TENANT_SEPARATOR, collection)
+}
+
+/// Checks a document id chosen by the client like a name, also refusing an empty id and
+/// one containing `:`, which separates the parts of the keys of a document.
+pub fn validate_document_id(id: &str) -> Result<(), InvalidName> {
+    if id.is_empty() || id.contains(':') {
+        return Err(InvalidName::InvalidId(id.to_string()));
+    }
+    validate_name(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_too_long_name_is_refused() {
+        assert!(validate_name(&"a".repeat(MAX_NAME_LENGTH)).is_ok());
+        assert_eq!(
+            validate_name(&"a".repeat(MAX_NAME_LENGTH + 1)),
+            Err(InvalidName::TooLong { length: MAX_NAME_LENGTH + 1 })
+        );
+        // The length is counted in bytes, not characters.
+        assert!(validate_name(&"é".repeat(MAX_NAME_LENGTH / 2 + 1)).is_err());
+    }
+
+    #[test]
+    fn test_name_with_control_characters_is_refused() {
+        for name in ["users\nINFO forged line", "users\r", "us\0ers", "\u{1b}[31musers"] {
+            assert_eq!(
+                validate_name(name),
+                Err(InvalidName::ControlCharacter(name.to_string()))
+            );
+        }
+        assert!(validate_name("utilisateurs-été 2023").is_ok());
+    }
+
+    #[test]
+    fn test_name_with_the_key_separator_is_refused() {
+        for name in ["users:1", "users:", ":users"] {
+            assert_eq!(
+                validate_name(name),
+                Err(InvalidName::ReservedCharacter(name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_tenant_with_a_separator_is_refused() {
+        assert!(validate_tenant("acme").is_ok());
+        for tenant in ["", "x:", "ac:me", "ac\u{241f}me"] {
+            assert_eq!(
+                validate_tenant(tenant),
+                Err(InvalidName::ReservedCharacter(tenant.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_document_id_without_separator_is_accepted() {
+        assert!(validate_document_id("invoice-2023-0042").is_ok());
+        for id in ["", "users:1", "1:acl"] {
+            assert_eq!(
+                validate_document_id(id),
+                Err(InvalidName::InvalidId(id.to_string()))
+            );
+        }
+        assert!(matches!(
+            validate_document_id("1\n"),
+            Err(InvalidName::ControlCharacter(_))
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! Execution plans returned by the server to explain how a query is run.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// How the server finds the documents of a step.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum Access {
+    /// Reads the documents listed under a blind index token of the field.
+    IndexLookup { field: String },
+
+    /// Reads every document of the usecase.
+    UsecaseScan,
+
+    /// Reads every document of the usecase and compares their OPE values.
+    OpeRangeScan,
+
+    /// Reads documents by their ids.
+    KeyLookup,
+}
+
+/// A collection read by a query.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct PlanStep {
+    pub collection: String,
+    pub usecase: Option<String>,
+    pub access: Access,
+
+    /// Number of documents the server reads for the step.
+    pub estimated_documents: u64,
+
+    /// Number of most recently inserted documents returned out of those read, see
+    /// `SingleQuery::latest`.
+    #[serde(default)]
+    pub latest: Option<usize>,
+
+    /// Fields of the predicates checked by the client after decryption.
+    pub client_filters: Vec<String>,
+}
+
+/// Execution plan of a query, one step per collection read.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct QueryPlan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl QueryPlan {
+    /// Total number of documents the server reads.
+    pub fn estimated_documents(&self) -> u64 {
+        self.steps.iter().map(|step| step.estimated_documents).sum()
+    }
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::IndexLookup { field } => write!(f, "index lookup on {}", field),
+            Access::UsecaseScan => write!(f, "usecase scan"),
+            Access::OpeRangeScan => write!(f, "OPE range scan"),
+            Access::KeyLookup => write!(f, "key lookup"),
+        }
+    }
+}
+
+impl Display for QueryPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for step in self.steps.iter() {
+            write!(f, "{}", step.collection)?;
+            if let Some(usecase) = &step.usecase {
+                write!(f, " [{}]", usecase)?;
+            }
+            write!(f, ": {}, ~{} documents", step.access, step.estimated_documents)?;
+            if let Some(latest) = step.latest {
+                write!(f, ", {} latest kept", latest)?;
+            }
+            if !step.client_filters.is_empty() {
+                write!(
+                    f,
+                    ", filtered by the client on {}",
+                    step.client_filters.join(", ")
+                )?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "total: ~{} documents", self.estimated_documents())
+    }
+}
@@ -1,12 +1,30 @@
+use std::cmp::Ordering;
+
 use serde::{Deserialize, Serialize};
+use serde_cbor::Value as CborValue;
+
+use crate::name::{validate_name, InvalidName};
+use crate::value::Value;
 
 /// Specifies the type of a `CompoundQuery`, defining how its `Query`s are combined.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+///
+/// Also combines the predicates of a `SingleQuery`, see `SingleQuery::predicate_logic`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
 pub enum QueryType {
+    #[default]
     And,
     Or,
 }
 
+impl QueryType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QueryType::And => "AND",
+            QueryType::Or => "OR",
+        }
+    }
+}
+
 /// The root Query type, which can be either a `SingleQuery` or a `CompoundQuery`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Query {
@@ -36,6 +54,197 @@ impl PartialEq for Query {
 
 impl Eq for Query {}
 
+impl Query {
+    /// Checks the collections and usecases of the query, see `validate_name`.
+    pub fn validate_names(&self) -> Result<(), InvalidName> {
+        match self {
+            Query::Single(query) => {
+                validate_name(&query.collection)?;
+                validate_name(&query.usecase)
+            }
+            Query::Compound(query) => {
+                query.queries.iter().try_for_each(Query::validate_names)
+            }
+            Query::GetById { collection, .. } => validate_name(collection),
+            Query::GetByIds { collection, .. } => validate_name(collection),
+        }
+    }
+
+    /// Renders the query tree readably for logs, for instance
+    /// `AND(orders:filter, OR(users:filter, products:filter))`.
+    ///
+    /// Only the shape of the query is rendered: collections, usecases, ids and fields.
+    /// The operands of predicates, the bounds of the encrypted range and index tokens are
+    /// replaced with `?`, so the string can be logged without leaking the values queried.
+    pub fn to_debug_string(&self) -> String {
+        match self {
+            Query::Single(query) => query.to_debug_string(),
+            Query::Compound(query) => {
+                let queries: Vec<String> =
+                    query.queries.iter().map(Query::to_debug_string).collect();
+                format!("{}({})", query.query_type.as_str(), queries.join(", "))
+            }
+            Query::GetById { id, collection } => format!("{}:get({})", collection, id),
+            Query::GetByIds { ids, collection } => {
+                format!("{}:get({})", collection, ids.join(", "))
+            }
+        }
+    }
+
+    /// Serializes the query to JSON, for instance to keep a saved search in a
+    /// configuration file.
+    ///
+    /// The JSON only depends on the query, not on the format negotiated by a connection,
+    /// and `from_json` parses it back to an equal query. A float bound or operand that is
+    /// not finite is written as `null`, which does not parse back.
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// Parses a query serialized by `to_json`, fields missing from it taking their
+    /// default value.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// A condition on a field of a decrypted document.
+///
+/// The server only stores ciphertexts, so predicates are evaluated by the client
+/// once the documents returned for the usecase have been decrypted. Operands are
+/// compared with fields by their type, see `liserk_shared::value`.
+///
+/// Predicates do not filter on the server: every document of the usecase, or of the
+/// index lookup, is sent and decrypted before the predicates drop the ones not matching,
+/// so they save no reads nor transfer. Use an index lookup to narrow what the server
+/// reads, see `SingleQuery::index_lookup`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum Predicate {
+    /// Matches documents in which the field is present, whatever its value.
+    Exists(String),
+
+    /// Matches documents in which the field is equal to the value.
+    Equals(String, Value),
+
+    /// Matches documents in which the field is equal to one of the values.
+    In(String, Vec<Value>),
+
+    /// Matches documents in which the field is an array holding the value.
+    ///
+    /// A field present in the document but not an array is a `FieldTypeMismatch`.
+    Contains(String, Value),
+
+    /// Matches documents in which the field is lower than the value.
+    LessThan(String, Value),
+
+    /// Matches documents in which the field is greater than the value.
+    GreaterThan(String, Value),
+}
+
+/// A field of a document does not have the type a predicate expects.
+#[derive(Debug, PartialEq, Eq, Clone, thiserror::Error)]
+#[error("the field {field:?} is not an array")]
+pub struct FieldTypeMismatch {
+    pub field: String,
+}
+
+impl Predicate {
+    /// Name of the field the predicate is about.
+    pub fn field(&self) -> &str {
+        match self {
+            Predicate::Exists(field) => field,
+            Predicate::Equals(field, _) => field,
+            Predicate::In(field, _) => field,
+            Predicate::Contains(field, _) => field,
+            Predicate::LessThan(field, _) => field,
+            Predicate::GreaterThan(field, _) => field,
+        }
+    }
+
+    /// Renders the predicate with its operands hidden, see `Query::to_debug_string`.
+    fn to_debug_string(&self) -> String {
+        match self {
+            Predicate::Exists(field) => format!("{} exists", field),
+            Predicate::Equals(field, _) => format!("{} = ?", field),
+            Predicate::In(field, values) => {
+                format!("{} in ({})", field, vec!["?"; values.len()].join(", "))
+            }
+            Predicate::Contains(field, _) => format!("{} contains ?", field),
+            Predicate::LessThan(field, _) => format!("{} < ?", field),
+            Predicate::GreaterThan(field, _) => format!("{} > ?", field),
+        }
+    }
+
+    /// Evaluates the predicate against a deserialized document.
+    ///
+    /// When `case_insensitive` is set, text values are compared ignoring their case. A
+    /// field of the wrong type does not match, see `evaluate` to tell it apart.
+    pub fn matches(&self, document: &CborValue, case_insensitive: bool) -> bool {
+        self.evaluate(document, case_insensitive).unwrap_or(false)
+    }
+
+    /// Evaluates the predicate against a deserialized document, failing if a field does
+    /// not have the type the predicate expects.
+    pub fn evaluate(
+        &self,
+        document: &CborValue,
+        case_insensitive: bool,
+    ) -> Result<bool, FieldTypeMismatch> {
+        let matches = match self {
+            Predicate::Exists(field) => lookup_field(document, field).is_some(),
+            Predicate::Equals(field, expected) => match lookup_field(document, field) {
+                Some(value) => expected.equals_field(value, case_insensitive),
+                None => false,
+            },
+            Predicate::In(field, values) => match lookup_field(document, field) {
+                Some(value) => values
+                    .iter()
+                    .any(|expected| expected.equals_field(value, case_insensitive)),
+                None => false,
+            },
+            Predicate::Contains(field, expected) => match lookup_field(document, field) {
+                Some(CborValue::Array(values)) => values
+                    .iter()
+                    .any(|value| expected.equals_field(value, case_insensitive)),
+                Some(_) => return Err(FieldTypeMismatch { field: field.clone() }),
+                None => false,
+            },
+            Predicate::LessThan(field, bound) => lookup_field(document, field)
+                .and_then(|value| bound.compare_field(value, case_insensitive))
+                .map_or(false, Ordering::is_lt),
+            Predicate::GreaterThan(field, bound) => lookup_field(document, field)
+                .and_then(|value| bound.compare_field(value, case_insensitive))
+                .map_or(false, Ordering::is_gt),
+        };
+        Ok(matches)
+    }
+}
+
+/// Resolves a field of a document, `address.city` walking into nested maps.
+///
+/// A top level key equal to the whole path takes precedence, so keys containing dots
+/// keep working. A missing intermediate key or a non map value resolves to nothing.
+fn lookup_field<'a>(document: &'a CborValue, field: &str) -> Option<&'a CborValue> {
+    let CborValue::Map(fields) = document else {
+        return None;
+    };
+    if let Some(value) = fields.get(&CborValue::Text(field.to_string())) {
+        return Some(value);
+    }
+    let (head, rest) = field.split_once('.')?;
+    lookup_field(fields.get(&CborValue::Text(head.to_string()))?, rest)
+}
+
+/// A blind index token of a field value.
+///
+/// The token is a keyed hash of the value computed by the client, equal values of a
+/// field give equal tokens, so the server can look documents up without learning values.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Hash)]
+pub struct IndexEntry {
+    pub field: String,
+    pub token: Vec<u8>,
+}
+
 /// Represents a single query on a collection for a given use case.
 ///
 /// This is the basic unit of querying in this system.
@@ -46,6 +255,52 @@ pub struct SingleQuery {
     pub usecase: String,
     pub upper_limit: Option<f64>,
     pub lower_limit: Option<f64>,
+    #[serde(default)]
+    pub predicates: Vec<Predicate>,
+    /// Compares text values of the predicates ignoring case.
+    ///
+    /// Only applies to predicates, which are evaluated by the client on decrypted
+    /// documents after every document of the usecase is fetched, see `Predicate`. Fields
+    /// compared by the server on their encrypted form, index lookups included, are not
+    /// affected.
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// How the predicates are combined, a document matches every predicate with `And`
+    /// and at least one with `Or`.
+    ///
+    /// Either way the usecase is scanned once and the predicates are evaluated together
+    /// on each decrypted document.
+    #[serde(default)]
+    pub predicate_logic: QueryType,
+    /// Reads the documents of the collection indexed with this token.
+    ///
+    /// The server reads the index instead of the list of every document of the usecase,
+    /// so the documents indexed with the token are returned whatever their usecases.
+    #[serde(default)]
+    pub index_lookup: Option<IndexEntry>,
+    /// Treats `collection` as a prefix, querying every collection whose name starts with it.
+    ///
+    /// The prefix must not be empty.
+    #[serde(default)]
+    pub collection_prefix: bool,
+    /// Only returns the given number of most recently inserted documents, newest first.
+    ///
+    /// Applied by the server before predicates, which the client evaluates on decrypted
+    /// documents, so a query with predicates may return fewer documents. Cursors and
+    /// streams page through the kept documents, and the server refuses the limit in the
+    /// queries of a compound query.
+    #[serde(default)]
+    pub latest: Option<usize>,
+    /// Asks the server to answer `Message::UnknownUsecase` rather than an empty result
+    /// when no document of the collection declares the usecase, to catch typos in its
+    /// name. Ignored for collection prefixes.
+    #[serde(default)]
+    pub warn_unknown_usecase: bool,
+    /// Asks the server to answer `Message::PartialQueryResponse` with the documents read
+    /// so far rather than `ServerError::QueryTimeout` when the query exceeds its time
+    /// budget. Ignored for collection prefixes.
+    #[serde(default)]
+    pub allow_partial_results: bool,
 }
 
 impl PartialEq for SingleQuery {
@@ -54,6 +309,14 @@ impl PartialEq for SingleQuery {
             && self.usecase == other.usecase
             && self.upper_limit == other.upper_limit
             && self.lower_limit == other.lower_limit
+            && self.predicates == other.predicates
+            && self.case_insensitive == other.case_insensitive
+            && self.predicate_logic == other.predicate_logic
+            && self.index_lookup == other.index_lookup
+            && self.collection_prefix == other.collection_prefix
+            && self.latest == other.latest
+            && self.warn_unknown_usecase == other.warn_unknown_usecase
+            && self.allow_partial_results == other.allow_partial_results
     }
 }
 
@@ -76,8 +339,77 @@ impl SingleQuery {
             usecase,
             upper_limit: None,
             lower_limit: None,
+            predicates: Vec::new(),
+            case_insensitive: false,
+            predicate_logic: QueryType::And,
+            index_lookup: None,
+            collection_prefix: false,
+            latest: None,
+            warn_unknown_usecase: false,
+            allow_partial_results: false,
         }
     }
+
+    /// Renders the query without its values, see `Query::to_debug_string`.
+    fn to_debug_string(&self) -> String {
+        let mut rendered = format!(
+            "{}{}:{}",
+            self.collection,
+            if self.collection_prefix { "*" } else { "" },
+            self.usecase
+        );
+        if self.lower_limit.is_some() || self.upper_limit.is_some() {
+            let bound = |limit: Option<f64>| if limit.is_some() { "?" } else { "" };
+            rendered += &format!(
+                " range({}..{})",
+                bound(self.lower_limit),
+                bound(self.upper_limit)
+            );
+        }
+        if let Some(entry) = &self.index_lookup {
+            rendered += &format!(" index({})", entry.field);
+        }
+        if !self.predicates.is_empty() {
+            let predicates: Vec<String> =
+                self.predicates.iter().map(Predicate::to_debug_string).collect();
+            let separator = format!(" {} ", self.predicate_logic.as_str());
+            rendered += &format!(" where({})", predicates.join(&separator));
+            if self.case_insensitive {
+                rendered += " ignoring case";
+            }
+        }
+        if let Some(count) = self.latest {
+            rendered += &format!(" latest({})", count);
+        }
+        rendered
+    }
+
+    /// Checks whether a decrypted CBOR document satisfies the predicates of the query,
+    /// combined as given by `predicate_logic`.
+    ///
+    /// A query without predicates matches any document, a document that is not a
+    /// CBOR value never matches a query with predicates.
+    pub fn matches(&self, document: &[u8]) -> bool {
+        self.evaluate(document).unwrap_or(false)
+    }
+
+    /// Same as `matches`, but fails on a field that does not have the type a predicate
+    /// expects instead of not matching the document.
+    pub fn evaluate(&self, document: &[u8]) -> Result<bool, FieldTypeMismatch> {
+        if self.predicates.is_empty() {
+            return Ok(true);
+        }
+        let Ok(document) = serde_cbor::from_slice::<CborValue>(document) else {
+            return Ok(false);
+        };
+        let decisive = self.predicate_logic == QueryType::Or;
+        for predicate in &self.predicates {
+            if predicate.evaluate(&document, self.case_insensitive)? == decisive {
+                return Ok(decisive);
+            }
+        }
+        Ok(!decisive)
+    }
 }
 
 /// Represents a compound query composed of multiple `Query`s.
@@ -99,17 +431,33 @@ impl PartialEq for CompoundQuery {
 impl Eq for CompoundQuery {}
 
 /// Builder for `SingleQuery`
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct SingleQueryBuilder {
     collection: String,
     usecase: String,
     upper_limit: Option<f64>,
     lower_limit: Option<f64>,
+    predicates: Vec<Predicate>,
+    case_insensitive: bool,
+    predicate_logic: QueryType,
+    index_lookup: Option<IndexEntry>,
+    collection_prefix: bool,
+    latest: Option<usize>,
+    warn_unknown_usecase: bool,
+    allow_partial_results: bool,
 }
 
 impl SingleQueryBuilder {
     pub fn with_collection(mut self, collection: String) -> Self {
         self.collection = collection;
+        self.collection_prefix = false;
+        self
+    }
+
+    /// Queries every collection whose name starts with `prefix`, see `SingleQuery::collection_prefix`.
+    pub fn with_collection_prefix(mut self, prefix: String) -> Self {
+        self.collection = prefix;
+        self.collection_prefix = true;
         self
     }
 
@@ -128,12 +476,90 @@ impl SingleQueryBuilder {
         self
     }
 
+    pub fn with_predicate(mut self, predicate: Predicate) -> Self {
+        self.predicates.push(predicate);
+        self
+    }
+
+    /// Keeps the documents holding the field, checked by the client, see `Predicate`.
+    pub fn with_field_exists(self, field: String) -> Self {
+        self.with_predicate(Predicate::Exists(field))
+    }
+
+    pub fn with_field_equal_to(self, field: String, value: Value) -> Self {
+        self.with_predicate(Predicate::Equals(field, value))
+    }
+
+    /// Keeps the documents whose field is one of the values, checked by the client after
+    /// every document of the usecase is fetched, see `Predicate`.
+    pub fn with_field_in(self, field: String, values: Vec<Value>) -> Self {
+        self.with_predicate(Predicate::In(field, values))
+    }
+
+    pub fn with_field_containing(self, field: String, value: Value) -> Self {
+        self.with_predicate(Predicate::Contains(field, value))
+    }
+
+    pub fn with_field_less_than(self, field: String, value: Value) -> Self {
+        self.with_predicate(Predicate::LessThan(field, value))
+    }
+
+    pub fn with_field_greater_than(self, field: String, value: Value) -> Self {
+        self.with_predicate(Predicate::GreaterThan(field, value))
+    }
+
+    /// Compares text values of the predicates ignoring case, see `SingleQuery::case_insensitive`.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Combines the predicates with `logic`, see `SingleQuery::predicate_logic`.
+    pub fn predicate_logic(mut self, logic: QueryType) -> Self {
+        self.predicate_logic = logic;
+        self
+    }
+
+    /// Looks the documents up in the index, see `SingleQuery::index_lookup`.
+    pub fn with_index_lookup(mut self, entry: IndexEntry) -> Self {
+        self.index_lookup = Some(entry);
+        self
+    }
+
+    /// Keeps the `count` most recently inserted documents, see `SingleQuery::latest`.
+    pub fn latest(mut self, count: usize) -> Self {
+        self.latest = Some(count);
+        self
+    }
+
+    /// Tells an unknown usecase from one without match, see
+    /// `SingleQuery::warn_unknown_usecase`.
+    pub fn warn_unknown_usecase(mut self) -> Self {
+        self.warn_unknown_usecase = true;
+        self
+    }
+
+    /// Accepts the documents read within the time budget of the server, see
+    /// `SingleQuery::allow_partial_results`.
+    pub fn allow_partial_results(mut self) -> Self {
+        self.allow_partial_results = true;
+        self
+    }
+
     pub fn build(self) -> SingleQuery {
         SingleQuery {
             collection: self.collection,
             usecase: self.usecase,
             upper_limit: self.upper_limit,
             lower_limit: self.lower_limit,
+            predicates: self.predicates,
+            case_insensitive: self.case_insensitive,
+            predicate_logic: self.predicate_logic,
+            index_lookup: self.index_lookup,
+            collection_prefix: self.collection_prefix,
+            latest: self.latest,
+            warn_unknown_usecase: self.warn_unknown_usecase,
+            allow_partial_results: self.allow_partial_results,
         }
     }
 }
@@ -185,3 +611,341 @@ impl CompoundQueryBuilder {
         CompoundQuery { query_type: self.query_type, queries: self.queries }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn document(fields: &[(&str, i32)]) -> Vec<u8> {
+        let fields: BTreeMap<&str, i32> = fields.iter().cloned().collect();
+        serde_cbor::to_vec(&fields).unwrap()
+    }
+
+    #[test]
+    fn test_exists_predicate() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_exists("email".to_owned())
+            .build();
+
+        let documents = [
+            document(&[("email", 1), ("age", 30)]),
+            document(&[("age", 42)]),
+            document(&[("email", 2)]),
+            vec![12, 112, 29, 176],
+        ];
+        let matching: Vec<usize> = documents
+            .iter()
+            .enumerate()
+            .filter(|(_, document)| query.matches(document))
+            .map(|(index, _)| index)
+            .collect();
+
+        assert_eq!(matching, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_in_predicate() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_in("age".to_owned(), vec![Value::Int(30), Value::Int(42)])
+            .build();
+
+        assert!(query.matches(&document(&[("age", 30)])));
+        assert!(query.matches(&document(&[("age", 42), ("email", 1)])));
+        assert!(!query.matches(&document(&[("age", 31)])));
+        assert!(!query.matches(&document(&[("email", 30)])));
+    }
+
+    fn tagged_document(tags: &[&str]) -> Vec<u8> {
+        let tags = tags.iter().map(|tag| CborValue::Text(tag.to_string())).collect();
+        let fields: BTreeMap<&str, CborValue> = [("tags", CborValue::Array(tags))].into();
+        serde_cbor::to_vec(&fields).unwrap()
+    }
+
+    #[test]
+    fn test_contains_predicate() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("posts".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_containing("tags".to_owned(), Value::Str("rust".to_owned()))
+            .build();
+
+        assert!(query.matches(&tagged_document(&["crypto", "rust"])));
+        assert!(!query.matches(&tagged_document(&["crypto"])));
+        assert!(!query.matches(&tagged_document(&[])));
+        assert_eq!(query.evaluate(&document(&[("age", 30)])), Ok(false));
+    }
+
+    #[test]
+    fn test_contains_on_a_non_array_field_is_a_mismatch() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("posts".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_containing("tags".to_owned(), Value::Int(1))
+            .build();
+
+        let mismatch = FieldTypeMismatch { field: "tags".to_owned() };
+        assert_eq!(query.evaluate(&document(&[("tags", 1)])), Err(mismatch));
+        assert!(!query.matches(&document(&[("tags", 1)])));
+    }
+
+    #[test]
+    fn test_comparisons_follow_the_type_of_the_field() {
+        let builder = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned());
+        let older_than_nine = builder
+            .clone()
+            .with_field_greater_than("age".to_owned(), Value::Int(9))
+            .build();
+        assert!(older_than_nine.matches(&document(&[("age", 10)])));
+        assert!(!older_than_nine.matches(&document(&[("age", 9)])));
+        let younger = builder
+            .clone()
+            .with_field_less_than("age".to_owned(), Value::Float(10.5))
+            .build();
+        assert!(younger.matches(&document(&[("age", 10)])));
+
+        // Compared as text "10" sorts before "9", and text never compares with a number.
+        let text_age: BTreeMap<&str, &str> = [("age", "10")].into();
+        let text_age = serde_cbor::to_vec(&text_age).unwrap();
+        let after_nine = builder
+            .with_field_greater_than("age".to_owned(), Value::from("9"))
+            .build();
+        assert!(!after_nine.matches(&text_age));
+        assert!(!older_than_nine.matches(&text_age));
+    }
+
+    #[test]
+    fn test_or_of_predicates() {
+        let builder = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_equal_to("age".to_owned(), Value::Int(30))
+            .with_field_exists("email".to_owned());
+
+        let documents = [
+            document(&[("age", 30)]),
+            document(&[("email", 1)]),
+            document(&[("age", 30), ("email", 1)]),
+            document(&[("age", 42)]),
+        ];
+        let matching = |query: &SingleQuery| -> Vec<usize> {
+            documents
+                .iter()
+                .enumerate()
+                .filter(|(_, document)| query.matches(document))
+                .map(|(index, _)| index)
+                .collect()
+        };
+
+        assert_eq!(matching(&builder.clone().build()), vec![2]);
+        assert_eq!(
+            matching(&builder.predicate_logic(QueryType::Or).build()),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_equality() {
+        let stored: BTreeMap<&str, &str> = [("name", "bob")].into_iter().collect();
+        let stored = serde_cbor::to_vec(&stored).unwrap();
+
+        let builder = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("search".to_owned())
+            .with_field_equal_to("name".to_owned(), Value::Str("Bob".to_owned()));
+
+        assert!(!builder.clone().build().matches(&stored));
+        assert!(builder.with_case_insensitive(true).build().matches(&stored));
+    }
+
+    fn nested_document() -> Vec<u8> {
+        let address: BTreeMap<&str, &str> = [("city", "Paris")].into_iter().collect();
+        let mut fields: BTreeMap<&str, CborValue> = BTreeMap::new();
+        fields.insert("address", serde_cbor::value::to_value(address).unwrap());
+        fields.insert("name", CborValue::Text("Bob".to_owned()));
+        serde_cbor::to_vec(&fields).unwrap()
+    }
+
+    #[test]
+    fn test_nested_field_predicate() {
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_equal_to(
+                "address.city".to_owned(),
+                Value::Str("Paris".to_owned()),
+            )
+            .build();
+        assert!(query.matches(&nested_document()));
+
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_field_equal_to("address.city".to_owned(), Value::Str("Lyon".to_owned()))
+            .build();
+        assert!(!query.matches(&nested_document()));
+    }
+
+    #[test]
+    fn test_missing_intermediate_key_does_not_match() {
+        for field in ["location.city", "name.first", "address.city.district"] {
+            let query = SingleQueryBuilder::default()
+                .with_collection("users".to_owned())
+                .with_usecase("filter".to_owned())
+                .with_field_exists(field.to_owned())
+                .build();
+            assert!(!query.matches(&nested_document()));
+        }
+    }
+
+    #[test]
+    fn test_debug_string_of_a_nested_query() {
+        let single = |collection: &str| {
+            Query::Single(
+                SingleQueryBuilder::default()
+                    .with_collection(collection.to_owned())
+                    .with_usecase("filter".to_owned())
+                    .build(),
+            )
+        };
+        let sub_query = CompoundQueryBuilder::default()
+            .with_query_type(QueryType::Or)
+            .with_query(single("users"))
+            .with_query(single("products"))
+            .build();
+        let main_query = CompoundQueryBuilder::default()
+            .with_query_type(QueryType::And)
+            .with_query(single("orders"))
+            .with_query(Query::Compound(sub_query))
+            .build();
+
+        assert_eq!(
+            Query::Compound(main_query).to_debug_string(),
+            "AND(orders:filter, OR(users:filter, products:filter))"
+        );
+    }
+
+    #[test]
+    fn test_nested_query_round_trips_through_json() {
+        let single = |collection: &str| {
+            Query::Single(
+                SingleQueryBuilder::default()
+                    .with_collection(collection.to_owned())
+                    .with_usecase("filter".to_owned())
+                    .build(),
+            )
+        };
+        let sub_query = CompoundQueryBuilder::default()
+            .with_query_type(QueryType::Or)
+            .with_query(single("users"))
+            .with_query(single("products"))
+            .build();
+        let main_query = CompoundQueryBuilder::default()
+            .with_query_type(QueryType::And)
+            .with_query(single("orders"))
+            .with_query(Query::Compound(sub_query))
+            .build();
+
+        let query = Query::Compound(main_query);
+        let json = query.to_json().unwrap();
+        assert_eq!(Query::from_json(&json).unwrap(), query);
+    }
+
+    #[test]
+    fn test_every_query_variant_round_trips_through_json() {
+        let single = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_encrypted_field_higher_than(10.5)
+            .with_field_equal_to("name".to_owned(), Value::from("bob"))
+            .with_field_in("tag".to_owned(), vec![Value::Int(1), Value::Null])
+            .with_field_less_than("key".to_owned(), Value::Bytes(vec![0, 255]))
+            .predicate_logic(QueryType::Or)
+            .with_index_lookup(IndexEntry { field: "age".into(), token: vec![1, 2] })
+            .latest(3)
+            .warn_unknown_usecase()
+            .allow_partial_results()
+            .build();
+        let queries = [
+            Query::Single(single),
+            Query::GetById { id: "1".into(), collection: "users".into() },
+            Query::GetByIds {
+                ids: vec!["1".into(), "2".into()],
+                collection: "users".into(),
+            },
+        ];
+        for query in queries {
+            let json = query.to_json().unwrap();
+            assert_eq!(Query::from_json(&json).unwrap(), query);
+        }
+        assert!(Query::from_json("{\"Single\": {}}").is_err());
+    }
+
+    #[test]
+    fn test_debug_string_hides_the_values() {
+        let query = SingleQueryBuilder::default()
+            .with_collection_prefix("users".to_owned())
+            .with_usecase("filter".to_owned())
+            .with_encrypted_field_higher_than(18.0)
+            .with_index_lookup(IndexEntry { field: "email".into(), token: vec![7; 32] })
+            .with_field_exists("name".to_owned())
+            .with_field_equal_to("city".to_owned(), Value::from("Pomme"))
+            .with_field_in("age".to_owned(), vec![Value::Int(3), Value::Int(4)])
+            .with_field_containing("tags".to_owned(), Value::from("admin"))
+            .with_field_less_than("score".to_owned(), Value::Float(9.5))
+            .with_field_greater_than("rank".to_owned(), Value::Int(2))
+            .predicate_logic(QueryType::Or)
+            .with_case_insensitive(true)
+            .latest(5)
+            .build();
+        assert_eq!(
+            Query::Single(query).to_debug_string(),
+            "users*:filter range(?..) index(email) where(name exists OR city = ? OR \
+             age in (?, ?) OR tags contains ? OR score < ? OR rank > ?) ignoring case \
+             latest(5)"
+        );
+
+        let by_ids = Query::GetByIds {
+            ids: vec!["1".to_owned(), "2".to_owned()],
+            collection: "users".to_owned(),
+        };
+        assert_eq!(by_ids.to_debug_string(), "users:get(1, 2)");
+    }
+
+    #[test]
+    fn test_names_of_nested_queries_are_validated() {
+        let single = |collection: &str, usecase: &str| {
+            Query::Single(SingleQuery::new(collection.to_owned(), usecase.to_owned()))
+        };
+        let valid = CompoundQuery::new(
+            QueryType::And,
+            vec![single("orders", "filter"), single("users", "filter")],
+        );
+        assert_eq!(Query::Compound(valid).validate_names(), Ok(()));
+
+        let nested = CompoundQuery::new(QueryType::Or, vec![single("users", "filter\n")]);
+        let query = CompoundQuery::new(
+            QueryType::And,
+            vec![single("orders", "filter"), Query::Compound(nested)],
+        );
+        assert_eq!(
+            Query::Compound(query).validate_names(),
+            Err(InvalidName::ControlCharacter("filter\n".to_owned()))
+        );
+        let by_id = Query::GetById { id: "1".to_owned(), collection: "a".repeat(256) };
+        assert_eq!(by_id.validate_names(), Err(InvalidName::TooLong { length: 256 }));
+    }
+
+    #[test]
+    fn test_query_without_predicate_matches_everything() {
+        let query = SingleQuery::new("users".to_owned(), "filter".to_owned());
+        assert!(query.matches(&[12, 112, 29, 176]));
+    }
+}
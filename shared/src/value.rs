@@ -0,0 +1,158 @@
+//! Typed operands of the predicates of a query.
+//!
+//! An operand is compared with the value of a field of a decrypted document by its type:
+//! numbers by their numeric value, whether integer or float, text and bytes by their
+//! content. Values of different kinds never compare, so the text `"10"` neither equals
+//! nor is ordered against the number `10`.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value as CborValue;
+
+/// An operand of a predicate.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Bytes(Vec<u8>),
+    Null,
+}
+
+impl Value {
+    /// Compares the value of a field with the operand, `None` if they cannot be compared.
+    ///
+    /// When `case_insensitive` is set, text is compared ignoring its case.
+    pub fn compare_field(
+        &self,
+        field: &CborValue,
+        case_insensitive: bool,
+    ) -> Option<Ordering> {
+        match (field, self) {
+            (CborValue::Integer(field), Value::Int(operand)) => {
+                Some(field.cmp(&i128::from(*operand)))
+            }
+            (CborValue::Integer(field), Value::Float(operand)) => {
+                (*field as f64).partial_cmp(operand)
+            }
+            (CborValue::Float(field), Value::Int(operand)) => {
+                field.partial_cmp(&(*operand as f64))
+            }
+            (CborValue::Float(field), Value::Float(operand)) => {
+                field.partial_cmp(operand)
+            }
+            (CborValue::Bool(field), Value::Bool(operand)) => Some(field.cmp(operand)),
+            (CborValue::Text(field), Value::Str(operand)) if case_insensitive => {
+                Some(field.to_lowercase().cmp(&operand.to_lowercase()))
+            }
+            (CborValue::Text(field), Value::Str(operand)) => Some(field.cmp(operand)),
+            (CborValue::Bytes(field), Value::Bytes(operand)) => Some(field.cmp(operand)),
+            (CborValue::Null, Value::Null) => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the value of a field is equal to the operand.
+    pub fn equals_field(&self, field: &CborValue, case_insensitive: bool) -> bool {
+        self.compare_field(field, case_insensitive) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialEq for Value {
+    /// Operands are equal when they are the same, floats being compared by their bits.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(left), Value::Int(right)) => left == right,
+            (Value::Float(left), Value::Float(right)) => {
+                left.to_bits() == right.to_bits()
+            }
+            (Value::Bool(left), Value::Bool(right)) => left == right,
+            (Value::Str(left), Value::Str(right)) => left == right,
+            (Value::Bytes(left), Value::Bytes(right)) => left == right,
+            (Value::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int(value.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Bytes(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers_compare_by_value() {
+        let ten = CborValue::Integer(10);
+        assert_eq!(Value::Int(9).compare_field(&ten, false), Some(Ordering::Greater));
+        assert_eq!(Value::Float(9.5).compare_field(&ten, false), Some(Ordering::Greater));
+        assert_eq!(
+            Value::Int(10).compare_field(&CborValue::Float(10.5), false),
+            Some(Ordering::Greater)
+        );
+        assert!(Value::Float(10.0).equals_field(&ten, false));
+    }
+
+    #[test]
+    fn test_text_compares_by_content() {
+        // As text, "10" sorts before "9", which is why numbers are not stored as text.
+        let ten = CborValue::Text("10".to_string());
+        assert_eq!(Value::from("9").compare_field(&ten, false), Some(Ordering::Less));
+        assert!(Value::from("BOB").equals_field(&CborValue::Text("bob".into()), true));
+        assert!(!Value::from("BOB").equals_field(&CborValue::Text("bob".into()), false));
+    }
+
+    #[test]
+    fn test_values_of_different_kinds_never_compare() {
+        let ten = CborValue::Integer(10);
+        assert_eq!(Value::from("10").compare_field(&ten, false), None);
+        assert!(!Value::from("10").equals_field(&ten, false));
+        assert!(!Value::Int(1).equals_field(&CborValue::Bool(true), false));
+        assert!(!Value::Null.equals_field(&CborValue::Bytes(vec![]), false));
+        assert!(Value::Null.equals_field(&CborValue::Null, false));
+    }
+}
@@ -2,21 +2,31 @@
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
-    use std::{assert, sync::Once};
+    use std::{assert, collections::HashSet, ops::ControlFlow, sync::Once};
 
+    use futures::{StreamExt, TryStreamExt};
     use liserk_shared::query::{
-        CompoundQueryBuilder, Query, QueryType, SingleQueryBuilder,
+        CompoundQueryBuilder, IndexEntry, Query, QueryType, SingleQueryBuilder,
     };
     use tracing::{error, info, Level};
     use tracing_subscriber::FmtSubscriber;
 
-    use liserk_client::{AuthenticatedClient, UnconnectedClient};
+    use liserk_client::builder::ClientBuilder;
+    use liserk_client::chunked::StreamVerifyMode;
+    use liserk_client::shared_client::SharedClient;
+    use liserk_client::stream::{AuthenticatedClient, QueryResult, UnconnectedClient};
     use liserk_server::BINDED_URL_PORT;
-    use liserk_shared::message::Message;
+    use liserk_shared::audit::{AuditFilter, AuditOperation};
+    use liserk_shared::compression::Compression;
+    use liserk_shared::format::Format;
     use liserk_shared::message::UpdateStatus;
+    use liserk_shared::message::{Message, PlainInsertion, ServerError};
+    use liserk_shared::plan::Access;
+    use liserk_shared::value::Value;
 
     pub const USERNAME: &str = "Bob";
     pub const PASSWORD: &str = "Pomme";
+    pub const KEY: [u8; 32] = [42; 32];
 
     pub trait ToStringVec {
         fn to_string_vec(&self) -> Vec<String>;
@@ -30,10 +40,18 @@ mod tests {
 
     pub async fn connect_and_auth_client(
         client: UnconnectedClient,
+    ) -> AuthenticatedClient {
+        connect_and_auth_as(client, USERNAME).await
+    }
+
+    /// Authenticates as a user of a tenant, see the `tenants` of `config/server.toml`.
+    pub async fn connect_and_auth_as(
+        client: UnconnectedClient,
+        username: &str,
     ) -> AuthenticatedClient {
         let client = client.connect(BINDED_URL_PORT).await.unwrap();
         client
-            .authenticate(USERNAME.to_string(), PASSWORD.to_string())
+            .authenticate(username.to_string(), PASSWORD.to_string(), KEY)
             .await
             .unwrap()
     }
@@ -43,6 +61,7 @@ mod tests {
             .insert(
                 "users".to_string(),
                 [12, 112, 29, 176].to_vec(),
+                vec![],
                 ["read", "write"].to_string_vec(),
                 ["authentification", "authorization"].to_string_vec(),
             )
@@ -53,6 +72,7 @@ mod tests {
             .insert(
                 "users".to_string(),
                 [12, 1, 2, 178, 76, 23, 145].to_vec(),
+                vec![],
                 ["read"].to_string_vec(),
                 ["search"].to_string_vec(),
             )
@@ -63,6 +83,7 @@ mod tests {
             .insert(
                 "".to_string(),
                 [12, 122, 221, 234, 178, 76, 23, 178, 97, 23, 18, 7, 6, 23, 145].to_vec(),
+                vec![],
                 ["read"].to_string_vec(),
                 ["logging"].to_string_vec(),
             )
@@ -73,6 +94,7 @@ mod tests {
             .insert(
                 "posts".to_string(),
                 [76, 231, 15, 13, 42, 54, 78].to_vec(),
+                vec![],
                 [].to_vec(),
                 [].to_vec(),
             )
@@ -83,6 +105,7 @@ mod tests {
             .insert(
                 "documents".to_string(),
                 [1, 2, 3, 4, 65, 68, 67].to_vec(),
+                vec![],
                 ["read", "write", "delete"].to_string_vec(),
                 ["storage", "search"].to_string_vec(),
             )
@@ -100,19 +123,25 @@ mod tests {
 
         // Insert user data
         client
-            .insert("users".to_string(), user_data, acl.clone(), user_usecases)
+            .insert("users".to_string(), user_data, vec![], acl.clone(), user_usecases)
             .await
             .unwrap();
 
         // Insert product data
         client
-            .insert("products".to_string(), product_data, acl.clone(), product_usecases)
+            .insert(
+                "products".to_string(),
+                product_data,
+                vec![],
+                acl.clone(),
+                product_usecases,
+            )
             .await
             .unwrap();
 
         // Insert order data
         client
-            .insert("orders".to_string(), order_data, acl.clone(), order_usecases)
+            .insert("orders".to_string(), order_data, vec![], acl.clone(), order_usecases)
             .await
             .unwrap();
     }
@@ -139,15 +168,146 @@ mod tests {
         let client = UnconnectedClient::default();
         let client = client.connect(BINDED_URL_PORT).await.unwrap();
         let mut client = client
-            .authenticate(USERNAME.to_string(), PASSWORD.to_string())
+            .authenticate(USERNAME.to_string(), PASSWORD.to_string(), KEY)
             .await
             .unwrap();
         assert!(client.is_alive());
+        assert_eq!(client.username(), USERNAME);
+        if let Err(err) = client.terminate_connection().await {
+            error!("{:?}", err);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_connect_with_session_token() {
+        initialize();
+
+        let mut first = connect_and_auth_client(UnconnectedClient::default()).await;
+        let session_token = first.session_token().clone();
+
+        let mut second = UnconnectedClient::default()
+            .connect_with_token(BINDED_URL_PORT, &session_token, KEY)
+            .await
+            .unwrap();
+        assert_eq!(second.username(), USERNAME);
+
+        let mut forged = session_token.clone();
+        forged.token = "not-a-token".to_string();
+        let refused = UnconnectedClient::default()
+            .connect_with_token(BINDED_URL_PORT, &forged, KEY)
+            .await;
+        assert!(refused.is_err());
+
+        let _ = second.terminate_connection().await;
+        let _ = first.terminate_connection().await;
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_zstd_compressed_session() {
+        initialize();
+
+        let client = UnconnectedClient::default()
+            .connect_with_compression(BINDED_URL_PORT, vec![Compression::Zstd])
+            .await
+            .unwrap();
+        let mut client = client
+            .authenticate(USERNAME.to_string(), PASSWORD.to_string(), KEY)
+            .await
+            .unwrap();
+
+        let id = client
+            .insert(
+                "users".to_string(),
+                vec![0; 2048],
+                vec![],
+                vec![],
+                ["compressed"].to_string_vec(),
+            )
+            .await
+            .unwrap();
+        let query = Query::GetById { id, collection: "users".to_string() };
+        match client.query(query).await.unwrap() {
+            QueryResult::SingleValue(value) => assert_eq!(value, vec![0; 2048]),
+            result => panic!("unexpected result {:?}", result),
+        }
+
         if let Err(err) = client.terminate_connection().await {
             error!("{:?}", err);
         }
     }
 
+    #[cfg(unix)]
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_and_query_over_a_unix_socket() {
+        initialize();
+
+        let path =
+            std::env::temp_dir().join(format!("liserk-{}.sock", uuid::Uuid::new_v4()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(liserk_server::serve_unix(listener));
+
+        let client = UnconnectedClient::default().connect_unix(&path).await.unwrap();
+        let mut client = client
+            .authenticate(USERNAME.to_string(), PASSWORD.to_string(), KEY)
+            .await
+            .unwrap();
+        let collection = format!("unix-{}", uuid::Uuid::new_v4());
+        let id = client
+            .insert(
+                collection.clone(),
+                vec![2, 7, 1, 8],
+                vec![],
+                vec![],
+                ["unix"].to_string_vec(),
+            )
+            .await
+            .unwrap();
+        let query = Query::GetById { id, collection };
+        match client.query(query).await.unwrap() {
+            QueryResult::SingleValue(value) => assert_eq!(value, vec![2, 7, 1, 8]),
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+
+        server.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_json_session_behaves_like_cbor() {
+        initialize();
+
+        let collection = format!("format-{}", uuid::Uuid::new_v4());
+        let mut results = Vec::new();
+        for format in [Format::Json, Format::Cbor] {
+            let client = ClientBuilder::new().format(vec![format]).build();
+            let mut client = connect_and_auth_client(client).await;
+            let id = client
+                .insert(
+                    collection.clone(),
+                    vec![3, 1, 4, 1, 5],
+                    vec![],
+                    vec![],
+                    ["format"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+            let query = Query::GetById { id, collection: collection.clone() };
+            match client.query(query).await.unwrap() {
+                QueryResult::SingleValue(value) => results.push(value),
+                result => panic!("unexpected result {:?}", result),
+            }
+            client.close().await.unwrap();
+        }
+
+        assert_eq!(results[0], vec![3, 1, 4, 1, 5]);
+        assert_eq!(results[0], results[1]);
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_insert() {
@@ -164,6 +324,7 @@ mod tests {
                     76, 23, 145,
                 ]
                 .to_vec(),
+                vec![],
                 [].to_vec(),
                 ["Tomate"].to_string_vec(),
             )
@@ -193,7 +354,13 @@ mod tests {
         let user_data = vec![122, 122, 122, 122, 211]; // Some binary data for a user
 
         let _inserted_id = client
-            .insert("users".to_string(), user_data, vec![], ["filter"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                user_data,
+                vec![],
+                vec![],
+                ["filter"].to_string_vec(),
+            )
             .await
             .unwrap();
 
@@ -211,6 +378,316 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_query_cursor_pages() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        for value in 0..3u8 {
+            client
+                .insert(
+                    "users".to_string(),
+                    vec![value],
+                    vec![],
+                    vec![],
+                    ["cursor_pages"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("cursor_pages".to_owned())
+            .build();
+        let mut page = client.open_cursor(Query::Single(query), 1).await.unwrap();
+        for _ in 0..2 {
+            assert_eq!(page.values.len(), 1);
+            let cursor = page.cursor.expect("more pages to read");
+            page = client.next_page(cursor).await.unwrap();
+        }
+        assert_eq!(page.values.len(), 1);
+
+        if let Err(err) = client.terminate_connection().await {
+            error!("{:?}", err);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cursor_over_an_or_query_reads_every_subquery() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let first = format!("or-first-{}", uuid::Uuid::new_v4());
+        let second = format!("or-second-{}", uuid::Uuid::new_v4());
+        let usecases = [
+            vec![first.clone()],
+            vec![second.clone()],
+            vec![first.clone(), second.clone()],
+        ];
+        for (value, usecases) in usecases.into_iter().enumerate() {
+            client
+                .insert("users".to_string(), vec![value as u8], vec![], vec![], usecases)
+                .await
+                .unwrap();
+        }
+
+        let subquery = |usecase: &str| {
+            Query::Single(
+                SingleQueryBuilder::default()
+                    .with_collection("users".to_owned())
+                    .with_usecase(usecase.to_owned())
+                    .build(),
+            )
+        };
+        let query = CompoundQueryBuilder::default()
+            .with_query_type(QueryType::Or)
+            .with_query(subquery(&first))
+            .with_query(subquery(&second))
+            .build();
+        let page = client.open_cursor(Query::Compound(query), 10).await.unwrap();
+        let mut values = page.values;
+        values.sort();
+        assert_eq!(values, vec![vec![0], vec![1], vec![2]]);
+        assert!(page.cursor.is_none());
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_indexed_query_reads_only_matching_documents() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let marker = uuid::Uuid::new_v4().to_string();
+        for index in 0..20 {
+            let email = if index == 7 { marker.clone() } else { format!("{}", index) };
+            let document: std::collections::BTreeMap<&str, String> =
+                [("email", email)].into_iter().collect();
+            client
+                .insert_indexed(
+                    "users".to_string(),
+                    serde_cbor::to_vec(&document).unwrap(),
+                    vec![],
+                    vec![],
+                    ["indexed_emails"].to_string_vec(),
+                    &["email".to_string()],
+                )
+                .await
+                .unwrap();
+        }
+
+        let entry = client
+            .index_lookup("users", "email", &serde_cbor::Value::Text(marker))
+            .unwrap();
+        let query = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("indexed_emails".to_owned())
+            .with_index_lookup(entry)
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => assert_eq!(values.len(), 1),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        if let Err(err) = client.terminate_connection().await {
+            error!("{:?}", err);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_indexes_the_configured_derived_field() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("people-{}", uuid::Uuid::new_v4());
+        let marker = uuid::Uuid::new_v4().to_string();
+        for last_name in [marker.as_str(), "Martin"] {
+            let document: std::collections::BTreeMap<&str, &str> =
+                [("first_name", "Ada"), ("last_name", last_name)]
+                    .into_iter()
+                    .collect();
+            client
+                .insert_indexed(
+                    collection.clone(),
+                    serde_cbor::to_vec(&document).unwrap(),
+                    vec![],
+                    vec![],
+                    ["people"].to_string_vec(),
+                    &["first_name".to_string(), "last_name".to_string()],
+                )
+                .await
+                .unwrap();
+        }
+
+        // `full_name` is derived by the server, see `config/server.toml`.
+        let token = |field: &str, value: &str| {
+            let value = serde_cbor::Value::Text(value.to_string());
+            client.index_lookup(&collection, field, &value).unwrap().token
+        };
+        let token = [token("first_name", "Ada"), token("last_name", &marker)].concat();
+        let entry = IndexEntry { field: "full_name".to_string(), token };
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection.clone())
+            .with_usecase("people".to_owned())
+            .with_index_lookup(entry)
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => assert_eq!(values.len(), 1),
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_search_documents_by_keyword() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("posts-{}", uuid::Uuid::new_v4());
+        let bios = ["Writes Rust every day", "Enjoys gardening", "Rust and Go at work"];
+        for bio in bios {
+            let document: std::collections::BTreeMap<&str, &str> =
+                [("bio", bio)].into_iter().collect();
+            client
+                .insert_searchable(
+                    collection.clone(),
+                    serde_cbor::to_vec(&document).unwrap(),
+                    vec![],
+                    vec![],
+                    ["search"].to_string_vec(),
+                    &["bio".to_string()],
+                )
+                .await
+                .unwrap();
+        }
+
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection.clone())
+            .with_usecase("search".to_owned())
+            .with_index_lookup(client.search_lookup(&collection, "bio", "rust"))
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => assert_eq!(values.len(), 2),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_explain_index_backed_and_scanned_queries() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let entry = client
+            .index_lookup("users", "email", &serde_cbor::Value::Text("bob".to_string()))
+            .unwrap();
+        let indexed = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("indexed_emails".to_owned())
+            .with_index_lookup(entry)
+            .build();
+        let plan = client.explain(Query::Single(indexed)).await.unwrap();
+        assert_eq!(
+            plan.steps[0].access,
+            Access::IndexLookup { field: "email".to_string() }
+        );
+
+        let scanned = SingleQueryBuilder::default()
+            .with_collection("users".to_owned())
+            .with_usecase("indexed_emails".to_owned())
+            .with_field_exists("email".to_owned())
+            .build();
+        let plan = client.explain(Query::Single(scanned)).await.unwrap();
+        assert_eq!(plan.steps[0].access, Access::UsecaseScan);
+        info!("plan:\n{}", plan);
+
+        if let Err(err) = client.terminate_connection().await {
+            error!("{:?}", err);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_explain_only_estimates_the_readable_documents() {
+        initialize();
+
+        let collection = format!("explained-{}", uuid::Uuid::new_v4());
+        let mut bob = connect_and_auth_client(UnconnectedClient::default()).await;
+        let mut alice = connect_and_auth_as(UnconnectedClient::default(), "Alice").await;
+        let usecases = vec!["explained".to_string()];
+        bob.insert(collection.clone(), vec![1], vec![], vec![], usecases.clone())
+            .await
+            .unwrap();
+        let private = ["read:Alice", "write:Alice"].to_string_vec();
+        alice
+            .insert(collection.clone(), vec![2], vec![], private, usecases)
+            .await
+            .unwrap();
+
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection)
+            .with_usecase("explained".to_owned())
+            .build();
+        let plan = bob.explain(Query::Single(query.clone())).await.unwrap();
+        assert_eq!(plan.steps[0].estimated_documents, 1);
+        let plan = alice.explain(Query::Single(query)).await.unwrap();
+        assert_eq!(plan.steps[0].estimated_documents, 2);
+        bob.close().await.unwrap();
+        alice.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_query_collection_prefix() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let marker = uuid::Uuid::new_v4().to_string().into_bytes();
+        for collection in ["logs_a", "logs_b", "users"] {
+            client
+                .insert(
+                    collection.to_string(),
+                    [collection.as_bytes(), &marker].concat(),
+                    vec![],
+                    vec![],
+                    ["prefix_search"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let query = SingleQueryBuilder::default()
+            .with_collection_prefix("logs_".to_owned())
+            .with_usecase("prefix_search".to_owned())
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => {
+                assert!(values.contains(&[&b"logs_a"[..], &marker].concat()));
+                assert!(values.contains(&[&b"logs_b"[..], &marker].concat()));
+                assert!(!values.contains(&[&b"users"[..], &marker].concat()));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        if let Err(err) = client.terminate_connection().await {
+            error!("{:?}", err);
+        }
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_insert_and_query() {
@@ -267,7 +744,13 @@ mod tests {
         let user_data = vec![212]; // Some binary data for a user
 
         let inserted_id = client
-            .insert("users".to_string(), user_data, vec![], ["filter"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                user_data,
+                vec![],
+                vec![],
+                ["filter"].to_string_vec(),
+            )
             .await
             .unwrap();
 
@@ -275,8 +758,8 @@ mod tests {
         let result = client.query(query).await.unwrap();
         info!("query result {:?}", result);
         match result {
-            Message::SingleValueResponse { data } => {
-                assert_eq!(data.unwrap()[0], 212);
+            QueryResult::SingleValue(data) => {
+                assert_eq!(data[0], 212);
             }
             _ => assert!(false),
         }
@@ -295,19 +778,43 @@ mod tests {
         let mut client = connect_and_auth_client(client).await;
 
         let inserted_id_1 = client
-            .insert("users".to_string(), vec![1], vec![], ["filter"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                vec![1],
+                vec![],
+                vec![],
+                ["filter"].to_string_vec(),
+            )
             .await
             .unwrap();
         let inserted_id_2 = client
-            .insert("users".to_string(), vec![2], vec![], ["filter"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                vec![2],
+                vec![],
+                vec![],
+                ["filter"].to_string_vec(),
+            )
             .await
             .unwrap();
         let inserted_id_3 = client
-            .insert("users".to_string(), vec![3], vec![], ["filter"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                vec![3],
+                vec![],
+                vec![],
+                ["filter"].to_string_vec(),
+            )
             .await
             .unwrap();
         let inserted_id_4 = client
-            .insert("users".to_string(), vec![4], vec![], ["filter"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                vec![4],
+                vec![],
+                vec![],
+                ["filter"].to_string_vec(),
+            )
             .await
             .unwrap();
 
@@ -319,7 +826,7 @@ mod tests {
         info!("query result {:?}", result);
 
         match result {
-            Message::QueryResponse { data } => {
+            QueryResult::MultipleValues(data) => {
                 assert_eq!(data.len(), 4);
             }
             _ => assert!(false),
@@ -339,7 +846,13 @@ mod tests {
         let mut client = connect_and_auth_client(client).await;
 
         let inserted_id = client
-            .insert("users".to_string(), vec![1], vec![], ["users"].to_string_vec())
+            .insert(
+                "users".to_string(),
+                vec![1],
+                vec![],
+                vec![],
+                ["users"].to_string_vec(),
+            )
             .await
             .unwrap();
         client
@@ -351,8 +864,8 @@ mod tests {
         let result = client.query(query).await.unwrap();
         info!("query result {:?}", result);
         match result {
-            Message::SingleValueResponse { data } => {
-                assert_eq!(data.unwrap()[0], 2);
+            QueryResult::SingleValue(data) => {
+                assert_eq!(data[0], 2);
             }
             _ => assert!(false),
         }
@@ -386,6 +899,1103 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_close_waits_for_pending_insert() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let client = SharedClient::from(connect_and_auth_client(client).await);
+        // The insert is polled first, so it is sent and waits for its response when
+        // close sends EndOfCommunication.
+        let (inserted, closed) = tokio::join!(
+            client.insert("users".to_string(), vec![77], vec![], vec![], vec![]),
+            client.close()
+        );
+        let inserted_id = inserted.unwrap();
+        closed.unwrap();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let query = Query::GetById { id: inserted_id, collection: "users".to_string() };
+        match client.query(query).await.unwrap() {
+            QueryResult::SingleValue(data) => assert_eq!(data, vec![77]),
+            _ => assert!(false),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_appends_one_audit_entry() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("audited-{}", uuid::Uuid::new_v4());
+        let inserted_id = client
+            .insert(collection.clone(), vec![5], vec![], vec![], vec![])
+            .await
+            .unwrap();
+
+        let filter = AuditFilter::default().with_collection(collection.clone());
+        let entries = client.fetch_audit_log(filter).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, AuditOperation::Insert);
+        assert_eq!(entries[0].document_id, inserted_id);
+        assert_eq!(entries[0].username.as_deref(), Some(USERNAME));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_audit_log_only_holds_the_entries_of_its_user() {
+        initialize();
+
+        let collection = format!("audited-{}", uuid::Uuid::new_v4());
+        let mut bob = connect_and_auth_client(UnconnectedClient::default()).await;
+        let mut alice = connect_and_auth_as(UnconnectedClient::default(), "Alice").await;
+        for client in [&mut bob, &mut alice] {
+            client
+                .insert(collection.clone(), vec![5], vec![], vec![], vec![])
+                .await
+                .unwrap();
+        }
+
+        let filter = AuditFilter::default().with_collection(collection.clone());
+        let entries = bob.fetch_audit_log(filter.clone()).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].username.as_deref(), Some(USERNAME));
+        let result = bob
+            .fetch_audit_log(filter.clone().with_username("Alice".into()))
+            .await;
+        assert!(matches!(
+            result,
+            Err(liserk_client::error::Error::ServerError {
+                error: ServerError::Forbidden,
+                ..
+            })
+        ));
+
+        let mut auditor =
+            connect_and_auth_as(UnconnectedClient::default(), "auditor").await;
+        assert_eq!(auditor.fetch_audit_log(filter).await.unwrap().len(), 2);
+        for client in [bob, alice, auditor] {
+            client.close().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_query_many_keeps_results_in_query_order() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("batched-{}", uuid::Uuid::new_v4());
+        let mut ids = Vec::new();
+        for data in [vec![1], vec![2]] {
+            let id = client
+                .insert(collection.clone(), data, vec![], vec![], vec![])
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let queries = vec![
+            Query::GetById { id: ids[1].clone(), collection: collection.clone() },
+            Query::GetById { id: ids[0].clone(), collection: collection.clone() },
+        ];
+        let results = client.query_many(queries).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(
+            matches!(&results[0], QueryResult::SingleValue(data) if data == &vec![2])
+        );
+        assert!(
+            matches!(&results[1], QueryResult::SingleValue(data) if data == &vec![1])
+        );
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_built_insertion() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("built-{}", uuid::Uuid::new_v4());
+        let insertion = PlainInsertion::builder()
+            .collection(collection.clone())
+            .data(vec![7])
+            .build();
+        let id = client.insert_built(insertion.unwrap(), vec![]).await.unwrap();
+
+        let result = client.query(Query::GetById { id, collection }).await.unwrap();
+        assert!(matches!(result, QueryResult::SingleValue(data) if data == vec![7]));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_collection_stats_count_the_readable_documents() {
+        initialize();
+
+        let client = ClientBuilder::new().tenant("stats").build();
+        let mut client = connect_and_auth_as(client, "stats-user").await;
+        let run = uuid::Uuid::new_v4().to_string();
+        let orders = format!("{}-orders", run);
+        let users = format!("{}-users", run);
+        for (collection, count) in [(&orders, 3), (&users, 2)] {
+            for data in 0..count {
+                client
+                    .insert(collection.clone(), vec![data], vec![], vec![], vec![])
+                    .await
+                    .unwrap();
+            }
+        }
+        let private = ["read:Alice"].to_string_vec();
+        client
+            .insert(users.clone(), vec![9], vec![], private, vec![])
+            .await
+            .unwrap();
+
+        // Earlier runs left their collections in the tenant.
+        let stats = client.collection_stats().await.unwrap();
+        let stats: Vec<_> =
+            stats.into_iter().filter(|(name, _)| name.starts_with(&run)).collect();
+        assert_eq!(stats, vec![(orders, 3), (users, 2)]);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_tenants_do_not_see_the_documents_of_each_other() {
+        initialize();
+
+        let collection = format!("tenanted-{}", uuid::Uuid::new_v4());
+        let acme = ClientBuilder::new().tenant("acme").build();
+        let mut acme = connect_and_auth_as(acme, "acme-user").await;
+        let globex = ClientBuilder::new().tenant("globex").build();
+        let mut globex = connect_and_auth_as(globex, "globex-user").await;
+        let mut untenanted = connect_and_auth_client(UnconnectedClient::default()).await;
+        // Readable by anyone, tenants alone keep the documents apart.
+        let acl = Vec::new();
+        let id = acme
+            .insert_with_id(
+                collection.clone(),
+                "1".to_string(),
+                vec![1],
+                vec![],
+                acl.clone(),
+                vec![],
+            )
+            .await
+            .unwrap();
+        globex
+            .insert_with_id(collection.clone(), id.clone(), vec![2], vec![], acl, vec![])
+            .await
+            .unwrap();
+
+        let get = Query::GetById { id: id.clone(), collection: collection.clone() };
+        let result = acme.query(get.clone()).await.unwrap();
+        assert!(matches!(result, QueryResult::SingleValue(data) if data == vec![1]));
+        let result = globex.query(get.clone()).await.unwrap();
+        assert!(matches!(result, QueryResult::SingleValue(data) if data == vec![2]));
+        let result = untenanted.query(get).await.unwrap();
+        assert!(matches!(result, QueryResult::EmptyResult), "{:?}", result);
+
+        let scanned: Vec<_> =
+            acme.scan_collection(&collection).try_collect().await.unwrap();
+        assert_eq!(scanned.len(), 1);
+        assert_eq!(scanned[0].collection, collection);
+        assert_eq!(scanned[0].data, vec![1]);
+        for client in [&mut acme, &mut globex, &mut untenanted] {
+            client.close().await.unwrap();
+        }
+
+        // A user only scopes its connections to the tenants it is assigned.
+        for (user, tenant) in [(USERNAME, "acme"), ("globex-user", "acme")] {
+            let client = ClientBuilder::new().tenant(tenant).build();
+            let client = client.connect(BINDED_URL_PORT).await.unwrap();
+            let refused =
+                client.authenticate(user.to_string(), PASSWORD.to_string(), KEY).await;
+            assert!(matches!(
+                refused,
+                Err(liserk_client::error::Error::ServerError {
+                    error: ServerError::AuthenticationFailed,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_with_id_refuses_a_taken_id_unless_upserting() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("chosen-{}", uuid::Uuid::new_v4());
+        let acl = [USERNAME].to_string_vec();
+        let id = client
+            .insert_with_id(
+                collection.clone(),
+                "invoice-1".to_string(),
+                vec![1],
+                vec![],
+                acl.clone(),
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert_eq!(id, "invoice-1");
+
+        let taken = client
+            .insert_with_id(collection.clone(), id.clone(), vec![2], vec![], acl, vec![])
+            .await;
+        assert!(matches!(
+            taken,
+            Err(liserk_client::error::Error::DuplicateId { id, .. }) if id == "invoice-1"
+        ));
+        let get = Query::GetById { id: id.clone(), collection: collection.clone() };
+        let result = client.query(get.clone()).await.unwrap();
+        assert!(matches!(result, QueryResult::SingleValue(data) if data == vec![1]));
+
+        let upsert = PlainInsertion::builder()
+            .collection(collection.clone())
+            .id(id.clone())
+            .upsert(true)
+            .acl(USERNAME)
+            .data(vec![3])
+            .build();
+        assert_eq!(client.insert_built(upsert.unwrap(), vec![]).await.unwrap(), id);
+        let result = client.query(get).await.unwrap();
+        assert!(matches!(result, QueryResult::SingleValue(data) if data == vec![3]));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_id_of_a_chunked_document_is_taken_unless_upserting() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("chosen-{}", uuid::Uuid::new_v4());
+        let acl = [USERNAME].to_string_vec();
+        let document = vec![7; 1024];
+        let id = client
+            .insert_stream(collection.clone(), document.as_slice(), acl.clone(), vec![])
+            .await
+            .unwrap();
+
+        let taken = client
+            .insert_with_id(collection.clone(), id.clone(), vec![2], vec![], acl, vec![])
+            .await;
+        assert!(matches!(
+            taken,
+            Err(liserk_client::error::Error::DuplicateId { id: taken, .. }) if taken == id
+        ));
+
+        let upsert = PlainInsertion::builder()
+            .collection(collection.clone())
+            .id(id.clone())
+            .upsert(true)
+            .acl(USERNAME)
+            .data(vec![3])
+            .build();
+        assert_eq!(client.insert_built(upsert.unwrap(), vec![]).await.unwrap(), id);
+        let get = Query::GetById { id, collection };
+        let result = client.query(get).await.unwrap();
+        assert!(matches!(result, QueryResult::SingleValue(data) if data == vec![3]));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_stream_and_query_it_back() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("streamed-{}", uuid::Uuid::new_v4());
+        let document: Vec<u8> = (0..=255).cycle().take(3 * 1024 * 1024 + 17).collect();
+        let id = client
+            .insert_stream(collection.clone(), document.as_slice(), vec![], vec![])
+            .await
+            .unwrap();
+
+        let read_back = client.query_stream(collection.clone(), id).await.unwrap();
+        assert_eq!(read_back, Some(document));
+        let missing = client.query_stream(collection, "missing".to_string()).await;
+        assert_eq!(missing.unwrap(), None);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_document_inserted_whole_is_downloaded() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("whole-{}", uuid::Uuid::new_v4());
+        let acl = [USERNAME].to_string_vec();
+        let id = client
+            .insert(collection.clone(), vec![1, 2, 3], vec![], acl, vec![])
+            .await
+            .unwrap();
+
+        let mut written = Vec::new();
+        let mode = StreamVerifyMode::PerChunk;
+        let downloaded = client.download_to(collection.clone(), id, &mut written, mode);
+        assert_eq!(downloaded.await.unwrap(), Some(3));
+        assert_eq!(written, vec![1, 2, 3]);
+        let missing = client.query_stream(collection, "missing".to_string()).await;
+        assert_eq!(missing.unwrap(), None);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_streamed_documents_are_deleted_with_their_chunks() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("streamed-deleted-{}", uuid::Uuid::new_v4());
+        let document: Vec<u8> = (0..=255).cycle().take(2 * 1024 * 1024 + 5).collect();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let id = client
+                .insert_stream(collection.clone(), document.as_slice(), vec![], vec![])
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let deleted = client.delete(ids[0].clone(), collection.clone()).await.unwrap();
+        assert_eq!(deleted, Message::DeleteResult(true));
+        let tombstoned = client.delete_tombstone(ids[1].clone(), collection.clone());
+        assert_eq!(tombstoned.await.unwrap(), Message::DeleteResult(true));
+        let read_back = client.query_stream(collection.clone(), ids[1].clone()).await;
+        assert_eq!(read_back.unwrap(), None);
+        let read_back = client.query_stream(collection.clone(), ids[0].clone()).await;
+        assert_eq!(read_back.unwrap(), None);
+
+        let removed = client.delete(ids[0].clone(), collection).await.unwrap();
+        assert_eq!(removed, Message::DeleteResult(false));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_query_documents_whose_tags_contain_a_value() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("tagged-{}", uuid::Uuid::new_v4());
+        for tags in [&["rust", "crypto"][..], &["crypto"], &["rust"]] {
+            let tags = tags
+                .iter()
+                .map(|tag| serde_cbor::Value::Text(tag.to_string()))
+                .collect();
+            let document: std::collections::BTreeMap<&str, serde_cbor::Value> =
+                [("tags", serde_cbor::Value::Array(tags))].into();
+            client
+                .insert(
+                    collection.clone(),
+                    serde_cbor::to_vec(&document).unwrap(),
+                    vec![],
+                    vec![],
+                    ["tagged"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection)
+            .with_usecase("tagged".to_owned())
+            .with_field_containing("tags".to_owned(), Value::from("rust"))
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => assert_eq!(values.len(), 2),
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_oversized_document_does_not_block_the_rest_of_a_batch() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("batched-{}", uuid::Uuid::new_v4());
+        // Encrypted, it exceeds the default maximum document size of the server.
+        let oversized = vec![0; 16 * 1024 * 1024];
+        let documents = [b"first".to_vec(), oversized, b"third".to_vec()];
+        let insertions = documents
+            .into_iter()
+            .map(|data| {
+                PlainInsertion::builder()
+                    .collection(collection.clone())
+                    .data(data)
+                    .usecase("batch")
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+
+        let results = client.insert_many(insertions).await.unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(ServerError::DocumentTooLarge { .. })));
+        assert!(results[2].is_ok());
+
+        let ids = vec![results[0].clone().unwrap(), results[2].clone().unwrap()];
+        let query = Query::GetByIds { ids, collection };
+        match client.query(query).await.unwrap() {
+            QueryResult::MultipleValues(mut values) => {
+                values.sort();
+                assert_eq!(values, vec![b"first".to_vec(), b"third".to_vec()])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_rekey_collection_under_a_new_master_key() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("rekeyed-{}", uuid::Uuid::new_v4());
+        for index in 0..3 {
+            client
+                .insert(
+                    collection.clone(),
+                    format!("secret {}", index).into_bytes(),
+                    vec![],
+                    vec![],
+                    ["vault"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let new_key = [7; 32];
+        let report = client
+            .rekey_collection(&collection, &[KEY], new_key, &[])
+            .await
+            .unwrap();
+        assert_eq!((report.rekeyed, report.skipped), (3, 0));
+        assert!(report.failed.is_empty());
+
+        // Running it again resumes an interrupted rekey, every document is done already.
+        let report = client
+            .rekey_collection(&collection, &[KEY], new_key, &[])
+            .await
+            .unwrap();
+        assert_eq!((report.rekeyed, report.skipped), (0, 3));
+
+        client.key = new_key;
+        let mut documents: Vec<Vec<u8>> = client
+            .scan_collection(&collection)
+            .map_ok(|document| document.data)
+            .try_collect()
+            .await
+            .unwrap();
+        documents.sort();
+        assert_eq!(documents, vec![b"secret 0", b"secret 1", b"secret 2"]);
+
+        client.key = KEY;
+        let undecryptable: Vec<_> = client.scan_collection(&collection).collect().await;
+        assert_eq!(undecryptable.len(), 3);
+        assert!(undecryptable.iter().all(Result::is_err));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_scan_every_document_of_a_collection() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("exported-{}", uuid::Uuid::new_v4());
+        let mut inserted = Vec::new();
+        for (index, usecase) in ["billing", "search", "billing", "audit", "search"]
+            .into_iter()
+            .enumerate()
+        {
+            let data = format!("document {}", index).into_bytes();
+            let id = client
+                .insert(
+                    collection.clone(),
+                    data.clone(),
+                    vec![],
+                    vec![],
+                    [usecase].to_string_vec(),
+                )
+                .await
+                .unwrap();
+            inserted.push((id, data));
+        }
+
+        let mut scanned: Vec<(String, Vec<u8>)> = client
+            .scan_collection(&collection)
+            .map_ok(|document| (document.id, document.data))
+            .try_collect()
+            .await
+            .unwrap();
+        scanned.sort();
+        inserted.sort();
+        assert_eq!(scanned, inserted);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_streamed_query_stops_at_the_first_match() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("streamed-{}", uuid::Uuid::new_v4());
+        for index in 0..20 {
+            let data = format!("document {}", index).into_bytes();
+            client
+                .insert(
+                    collection.clone(),
+                    data,
+                    vec![],
+                    vec![],
+                    ["filter"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection.clone())
+            .with_usecase("filter".to_owned())
+            .build();
+        let mut seen = 0;
+        client
+            .query_each(Query::Single(query), 1, |_| {
+                seen += 1;
+                ControlFlow::Break(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(seen, 1);
+
+        // The pages sent before the stop were read, the connection is usable again.
+        let documents = client.scan_collection(&collection).count().await;
+        assert_eq!(documents, 20);
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_download_large_document_to_a_file() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("download-{}", uuid::Uuid::new_v4());
+        let document: Vec<u8> = (0..=255).cycle().take(5 * 1024 * 1024 + 3).collect();
+        let id = client
+            .insert_stream(collection.clone(), document.as_slice(), vec![], vec![])
+            .await
+            .unwrap();
+
+        for mode in [StreamVerifyMode::PerChunk, StreamVerifyMode::BufferAndVerify] {
+            let path =
+                std::env::temp_dir().join(format!("liserk-{}", uuid::Uuid::new_v4()));
+            let file = std::fs::File::create(&path).unwrap();
+            let written = client
+                .download_to(collection.clone(), id.clone(), file, mode)
+                .await
+                .unwrap();
+            assert_eq!(written, Some(document.len() as u64));
+            assert_eq!(std::fs::read(&path).unwrap(), document);
+            std::fs::remove_file(path).unwrap();
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_stale_version_update_is_rejected() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("versioned-{}", uuid::Uuid::new_v4());
+        let id = client
+            .insert(collection.clone(), vec![1], vec![], vec![], ["cas"].to_string_vec())
+            .await
+            .unwrap();
+
+        let status = client
+            .update_if(collection.clone(), id.clone(), 0, vec![2], &[])
+            .await
+            .unwrap();
+        assert_eq!(status, UpdateStatus::Success);
+        let stale = client.update_if(collection.clone(), id.clone(), 0, vec![3], &[]);
+        let stale = stale.await;
+        assert!(matches!(
+            stale,
+            Err(liserk_client::error::Error::VersionConflict { expected: 0, current: 1 })
+        ));
+
+        let query = Query::GetById { id, collection };
+        match client.query(query).await.unwrap() {
+            QueryResult::SingleValue(value) => assert_eq!(value, vec![2]),
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_conditional_update_keeps_the_index() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("versioned-indexed-{}", uuid::Uuid::new_v4());
+        let marker = uuid::Uuid::new_v4().to_string();
+        let document = |name: &str| {
+            let document: std::collections::BTreeMap<&str, &str> =
+                [("email", marker.as_str()), ("name", name)].into_iter().collect();
+            serde_cbor::to_vec(&document).unwrap()
+        };
+        let indexed_fields = ["email".to_string()];
+        let id = client
+            .insert_indexed(
+                collection.clone(),
+                document("Alice"),
+                vec![],
+                vec![],
+                ["indexed_emails"].to_string_vec(),
+                &indexed_fields,
+            )
+            .await
+            .unwrap();
+
+        let status = client
+            .update_if(collection.clone(), id, 0, document("Alicia"), &indexed_fields)
+            .await
+            .unwrap();
+        assert_eq!(status, UpdateStatus::Success);
+
+        let entry = client
+            .index_lookup(&collection, "email", &serde_cbor::Value::Text(marker.clone()))
+            .unwrap();
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection)
+            .with_usecase("indexed_emails".to_owned())
+            .with_index_lookup(entry)
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => {
+                assert_eq!(values, vec![document("Alicia")])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_describe_document_returns_its_metadata() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("describe-{}", uuid::Uuid::new_v4());
+        let acl = vec!["metadata:all".to_string()];
+        let id = client
+            .insert(
+                collection.clone(),
+                vec![4],
+                vec![],
+                acl.clone(),
+                ["audit"].to_string_vec(),
+            )
+            .await
+            .unwrap();
+
+        let meta = client
+            .describe_document(collection.clone(), id.clone())
+            .await
+            .unwrap();
+        assert_eq!(meta.id, id);
+        assert_eq!(meta.collection, collection);
+        assert_eq!(meta.acl, acl);
+        assert_eq!(meta.usecases, vec!["audit".to_string()]);
+        assert!(meta.inserted_at.is_some());
+
+        let missing = client.describe_document(collection, "missing".to_string()).await;
+        assert!(matches!(
+            missing,
+            Err(liserk_client::error::Error::ServerError {
+                error: ServerError::DocumentNotFound,
+                ..
+            })
+        ));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_verify_key_for_document_tells_the_right_key() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("verify-key-{}", uuid::Uuid::new_v4());
+        let id = client
+            .insert(collection.clone(), vec![5], vec![], vec![], ["keys"].to_string_vec())
+            .await
+            .unwrap();
+
+        let verified =
+            client.verify_key_for_document(&KEY, collection.clone(), id.clone(), &[]);
+        assert!(verified.await.unwrap());
+        let wrong_key = [7; 32];
+        let verified =
+            client.verify_key_for_document(&wrong_key, collection.clone(), id, &[]);
+        assert!(!verified.await.unwrap());
+
+        let associated_data = b"header".to_vec();
+        let id = client
+            .insert(collection.clone(), vec![6], associated_data.clone(), vec![], vec![])
+            .await
+            .unwrap();
+        let verified = client.verify_key_for_document(
+            &KEY,
+            collection.clone(),
+            id,
+            &associated_data,
+        );
+        assert!(verified.await.unwrap());
+
+        let id = client
+            .insert_stream(collection.clone(), &[1, 2, 3][..], vec![], vec![])
+            .await
+            .unwrap();
+        let chunked = client.verify_key_for_document(&KEY, collection.clone(), id, &[]);
+        assert!(matches!(
+            chunked.await,
+            Err(liserk_client::error::Error::UnverifiableDocument { .. })
+        ));
+        let id = client
+            .insert_ope(12.0, vec![], vec![], collection.clone())
+            .await
+            .unwrap();
+        let ope = client.verify_key_for_document(&KEY, collection.clone(), id, &[]);
+        assert!(matches!(
+            ope.await,
+            Err(liserk_client::error::Error::UnverifiableDocument { .. })
+        ));
+
+        let missing = client
+            .verify_key_for_document(&KEY, collection, "missing".to_string(), &[])
+            .await;
+        assert!(matches!(
+            missing,
+            Err(liserk_client::error::Error::ServerError {
+                error: ServerError::DocumentNotFound,
+                ..
+            })
+        ));
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_racing_query_and_delete_take_disjoint_documents() {
+        initialize();
+
+        let mut producer = connect_and_auth_client(UnconnectedClient::default()).await;
+        let collection = format!("queue-{}", uuid::Uuid::new_v4());
+        let mut inserted = HashSet::new();
+        for value in 0..20u8 {
+            let id = producer
+                .insert(
+                    collection.clone(),
+                    vec![value],
+                    vec![],
+                    vec![],
+                    vec!["jobs".into()],
+                )
+                .await
+                .unwrap();
+            inserted.insert(id);
+        }
+
+        let take = |collection: String| async move {
+            let mut consumer =
+                connect_and_auth_client(UnconnectedClient::default()).await;
+            let query = SingleQueryBuilder::default()
+                .with_collection(collection)
+                .with_usecase("jobs".to_string())
+                .build();
+            let taken = consumer.query_and_delete(Query::Single(query)).await.unwrap();
+            consumer.close().await.unwrap();
+            taken
+                .into_iter()
+                .map(|document| document.unwrap().id)
+                .collect::<HashSet<_>>()
+        };
+        let (first, second) =
+            tokio::join!(take(collection.clone()), take(collection.clone()));
+        assert!(first.is_disjoint(&second));
+        assert_eq!(&first | &second, inserted);
+
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection)
+            .with_usecase("jobs".to_string())
+            .build();
+        assert!(producer
+            .query_documents(Query::Single(query))
+            .await
+            .unwrap()
+            .is_empty());
+        producer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_typo_in_usecase_is_reported() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("typo-{}", uuid::Uuid::new_v4());
+        client
+            .insert(collection.clone(), vec![1], vec![], vec![], vec!["filter".into()])
+            .await
+            .unwrap();
+
+        let query = |usecase: &str| {
+            let query = SingleQueryBuilder::default()
+                .with_collection(collection.clone())
+                .with_usecase(usecase.to_string())
+                .warn_unknown_usecase()
+                .build();
+            Query::Single(query)
+        };
+        match client.query(query("filtr")).await.unwrap() {
+            QueryResult::UnknownUsecase { collection: unknown_in, usecase } => {
+                assert_eq!(unknown_in, collection);
+                assert_eq!(usecase, "filtr");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        match client.query(query("filter")).await.unwrap() {
+            QueryResult::MultipleValues(values) => assert_eq!(values, vec![vec![1]]),
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_update_metadata_moves_document_between_usecases() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("metadata-{}", uuid::Uuid::new_v4());
+        let id = client
+            .insert(
+                collection.clone(),
+                vec![3],
+                vec![],
+                vec![],
+                vec!["before".to_string()],
+            )
+            .await
+            .unwrap();
+
+        let status = client
+            .update_metadata(collection.clone(), id, vec![], vec!["after".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(status, UpdateStatus::Success);
+
+        for (usecase, expected) in [("before", 0), ("after", 1)] {
+            let query = SingleQueryBuilder::default()
+                .with_collection(collection.clone())
+                .with_usecase(usecase.to_owned())
+                .build();
+            let found = match client.query(Query::Single(query)).await.unwrap() {
+                QueryResult::MultipleValues(values) => values.len(),
+                QueryResult::EmptyResult => 0,
+                result => panic!("unexpected result {:?}", result),
+            };
+            assert_eq!(found, expected, "documents of usecase {}", usecase);
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_latest_returns_newest_documents_first() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("latest-{}", uuid::Uuid::new_v4());
+        for data in 1..=3u8 {
+            client
+                .insert(
+                    collection.clone(),
+                    vec![data],
+                    vec![],
+                    vec![],
+                    vec!["feed".to_string()],
+                )
+                .await
+                .unwrap();
+        }
+
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection)
+            .with_usecase("feed".to_owned())
+            .latest(2)
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => {
+                assert_eq!(values, vec![vec![3], vec![2]])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_shared_client_serves_concurrent_tasks() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let client = SharedClient::from(connect_and_auth_client(client).await);
+        let collection = format!("shared-{}", uuid::Uuid::new_v4());
+        let mut ids = Vec::new();
+        for data in 0..8u8 {
+            let id = client
+                .insert(collection.clone(), vec![data], vec![], vec![], vec![])
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let tasks: Vec<_> = ids
+            .into_iter()
+            .enumerate()
+            .map(|(data, id)| {
+                let client = client.clone();
+                let collection = collection.clone();
+                tokio::spawn(async move {
+                    let query = Query::GetById { id, collection };
+                    match client.query(query).await.unwrap() {
+                        QueryResult::SingleValue(value) => {
+                            assert_eq!(value, vec![data as u8])
+                        }
+                        result => panic!("unexpected result {:?}", result),
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_tombstoned_document_is_hidden_until_purged() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        let mut client = connect_and_auth_client(client).await;
+        let collection = format!("tombstones-{}", uuid::Uuid::new_v4());
+        let mut ids = Vec::new();
+        for data in 0..2u8 {
+            let id = client
+                .insert(
+                    collection.clone(),
+                    vec![data],
+                    vec![],
+                    vec![],
+                    ["kept"].to_string_vec(),
+                )
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let result = client.delete_tombstone(ids[0].clone(), collection.clone()).await;
+        assert_eq!(result.unwrap(), Message::DeleteResult(true));
+        let query = SingleQueryBuilder::default()
+            .with_collection(collection.clone())
+            .with_usecase("kept".to_owned())
+            .build();
+        match client.query(Query::Single(query)).await.unwrap() {
+            QueryResult::MultipleValues(values) => assert_eq!(values, vec![vec![1]]),
+            QueryResult::SingleValue(value) => assert_eq!(value, vec![1]),
+            result => panic!("unexpected result {:?}", result),
+        }
+        let get = Query::GetById { id: ids[0].clone(), collection: collection.clone() };
+        let result = client.query(get.clone()).await.unwrap();
+        assert!(matches!(result, QueryResult::EmptyResult), "{:?}", result);
+        let updated =
+            client.update_if(collection.clone(), ids[0].clone(), 0, vec![2], &[]);
+        assert_eq!(updated.await.unwrap(), UpdateStatus::KeyNotFound);
+        let usecases = ["moved"].to_string_vec();
+        let updated =
+            client.update_metadata(collection.clone(), ids[0].clone(), vec![], usecases);
+        assert_eq!(updated.await.unwrap(), UpdateStatus::KeyNotFound);
+
+        let hour = std::time::Duration::from_secs(3600);
+        assert_eq!(client.purge(collection.clone(), hour).await.unwrap(), 0);
+        let zero = std::time::Duration::ZERO;
+        assert_eq!(client.purge(collection.clone(), zero).await.unwrap(), 1);
+        assert_eq!(client.purge(collection.clone(), zero).await.unwrap(), 0);
+        let result = client.delete_tombstone(ids[0].clone(), collection.clone()).await;
+        assert_eq!(result.unwrap(), Message::DeleteResult(false));
+
+        let get = Query::GetById { id: ids[1].clone(), collection };
+        match client.query(get).await.unwrap() {
+            QueryResult::SingleValue(value) => assert_eq!(value, vec![1]),
+            result => panic!("unexpected result {:?}", result),
+        }
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_health_check() {
+        initialize();
+
+        let client = UnconnectedClient::default();
+        assert!(client.health_check(BINDED_URL_PORT).await.is_ok());
+    }
+
     #[tokio::test]
     #[serial]
     #[ignore = "Count is not finish"]